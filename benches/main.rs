@@ -29,6 +29,31 @@ fn simd_compare_equal(b: &mut Bencher) {
     b.bytes = vec1.len() as u64;
 }
 
+/// Values going through the spsc queue between 2 threads
+#[bench]
+fn spsc_threads(b: &mut Bencher) {
+    const NVALUES: usize = 100_000;
+
+    b.iter(|| {
+        let (mut sender, mut recv) = rustorrent::spsc::bounded(64);
+
+        let producer = std::thread::spawn(move || {
+            for n in 0..NVALUES {
+                while sender.push(n).is_err() {
+                    std::hint::spin_loop();
+                }
+            }
+        });
+
+        for n in 0..NVALUES {
+            assert_eq!(recv.recv(), Ok(n));
+        }
+
+        producer.join().unwrap();
+    });
+    b.bytes = (NVALUES * std::mem::size_of::<usize>()) as u64;
+}
+
 // #[bench]
 // fn sha1_string_extern_crate(b: &mut Bencher) {
 //     use sha1::Sha1;
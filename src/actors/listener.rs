@@ -0,0 +1,125 @@
+use crossbeam_channel::Sender as SyncSender;
+use hashbrown::HashSet;
+use kv_log_macro::{debug, warn};
+use parking_lot::RwLock;
+use tokio::net::{TcpListener, TcpStream};
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use crate::peer::{
+    handshake::{Handshake, HandshakeError},
+    peer::PeerExternId,
+};
+
+/// Info hashes of the torrents in the session
+pub type ServedTorrents = Arc<RwLock<HashSet<Arc<[u8]>>>>;
+
+/// Duration given to a peer to send its handshake after connecting
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A peer connected to one of the listen addresses, its handshake
+/// says which torrent it wants
+pub struct IncomingPeer {
+    pub stream: TcpStream,
+    pub addr: SocketAddr,
+    pub info_hash: [u8; 20],
+    pub extern_id: PeerExternId,
+}
+
+/// Actor accepting the peers on all the listen addresses of the
+/// session. The connections of every address go through the same
+/// pipeline: the handshake is read, then the peer is sent to the
+/// session to find its torrent
+pub struct ListenerActor {
+    listeners: Vec<std::net::TcpListener>,
+    served: ServedTorrents,
+    session: SyncSender<IncomingPeer>,
+}
+
+impl ListenerActor {
+    /// `listeners` are already bound, so the session knows their
+    /// addresses before the actor starts
+    pub fn new(
+        listeners: Vec<std::net::TcpListener>,
+        served: ServedTorrents,
+        session: SyncSender<IncomingPeer>,
+    ) -> ListenerActor {
+        ListenerActor {
+            listeners,
+            served,
+            session,
+        }
+    }
+
+    pub async fn start(self) {
+        let (accepted, pipeline) = async_channel::unbounded();
+
+        for listener in self.listeners {
+            let listener = match listener
+                .set_nonblocking(true)
+                .and_then(|_| TcpListener::from_std(listener))
+            {
+                Ok(listener) => listener,
+                Err(e) => {
+                    warn!("[listener] Can't listen {:?}", e);
+                    continue;
+                }
+            };
+
+            let accepted = accepted.clone();
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok(peer) => {
+                            if accepted.send(peer).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) => warn!("[listener] Accept error {:?}", e),
+                    }
+                }
+            });
+        }
+
+        drop(accepted);
+
+        while let Ok((stream, addr)) = pipeline.recv().await {
+            let session = self.session.clone();
+            let served = Arc::clone(&self.served);
+
+            // A slow peer doesn't delay the others
+            tokio::spawn(async move {
+                match read_handshake(stream, addr, &served).await {
+                    Ok(peer) => {
+                        session.send(peer).ok();
+                    }
+                    Err(e) => debug!("[listener] Invalid handshake {:?}", e, {
+                        addr: addr.to_string()
+                    }),
+                }
+            });
+        }
+    }
+}
+
+/// A torrent not in the session is rejected here, the session then
+/// drops the peers of its queued torrents
+async fn read_handshake(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    served: &ServedTorrents,
+) -> Result<IncomingPeer, HandshakeError> {
+    let handshake = Handshake::read_from(&mut stream, |info_hash| {
+        served.read().contains(&info_hash[..])
+    });
+    let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    Ok(IncomingPeer {
+        stream,
+        addr,
+        info_hash: handshake.info_hash,
+        extern_id: PeerExternId::new(&handshake.peer_id),
+    })
+}
@@ -1,2 +1,4 @@
+pub mod listener;
+pub mod peer_source;
 pub mod sha1;
 pub mod tracker;
@@ -0,0 +1,55 @@
+use async_channel::Sender;
+use async_trait::async_trait;
+use kv_log_macro::info;
+
+use std::net::SocketAddr;
+
+use crate::supervisors::torrent::{PeerOrigin, TorrentNotification};
+
+/// A source of peers, in addition to the trackers and PEX.
+///
+/// Implement this trait to inject peers from custom sources (a central
+/// coordinator, a local database, ..), then register it with
+/// [`Session::add_peer_source`](crate::session::Session::add_peer_source).
+/// The peers go through the same deduplication than the other sources.
+#[async_trait]
+pub trait PeerSource: Send {
+    /// Wait for new peer candidates.
+    /// Returns `None` when the source won't yield peers anymore
+    async fn next_peers(&mut self) -> Option<Vec<SocketAddr>>;
+}
+
+/// Actor sending the peers of a [`PeerSource`] to its `TorrentSupervisor`
+pub struct PeerSourceActor {
+    source: Box<dyn PeerSource>,
+    supervisor: Sender<TorrentNotification>,
+}
+
+impl PeerSourceActor {
+    pub fn new(
+        source: Box<dyn PeerSource>,
+        supervisor: Sender<TorrentNotification>,
+    ) -> PeerSourceActor {
+        PeerSourceActor { source, supervisor }
+    }
+
+    pub async fn start(mut self) {
+        while let Some(addrs) = self.source.next_peers().await {
+            if addrs.is_empty() {
+                continue;
+            }
+
+            info!("[peer source] Peers found {:?}", addrs);
+
+            let msg = TorrentNotification::PeerDiscovered {
+                addrs: addrs.into_boxed_slice(),
+                origin: PeerOrigin::PeerSource,
+            };
+
+            if self.supervisor.send(msg).await.is_err() {
+                // The torrent has been removed
+                return;
+            }
+        }
+    }
+}
@@ -1,12 +1,18 @@
 use async_channel::{Sender, TrySendError};
 use crossbeam_channel::{unbounded, Receiver as SyncReceiver, Sender as SyncSender};
 use tokio::runtime::Runtime;
-use TorrentNotification::ValidatePiece;
+use TorrentNotification::{PieceVerified, ValidatePiece};
 
-use std::{ptr::read_unaligned, sync::Arc};
+use kv_log_macro::warn;
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    ptr::read_unaligned,
+    sync::Arc,
+};
 
 use crate::{
-    fs::FSMessage,
+    fs::{FSMessage, WriteRequest},
     piece_picker::PieceIndex,
     supervisors::torrent::{TorrentId, TorrentNotification},
 };
@@ -21,6 +27,32 @@ pub enum Sha1Task {
         addr: Sender<TorrentNotification>,
         piece_index: PieceIndex,
     },
+    /// Check a piece read from the disk, nothing is written
+    Verify {
+        piece: Box<[u8]>,
+        sum_metadata: Arc<[u8; 20]>,
+        addr: Sender<TorrentNotification>,
+        piece_index: PieceIndex,
+    },
+    /// Compute the sum of a piece of a new torrent, see `create`
+    Hash {
+        piece: Box<[u8]>,
+        piece_index: usize,
+        respond: SyncSender<(usize, [u8; 20])>,
+    },
+    /// Many tasks submitted at once, to amortize the channel overhead
+    /// with small pieces
+    Batch(Vec<Sha1Task>),
+    /// Make the worker panic
+    #[cfg(test)]
+    Panic,
+    /// Send the name of the worker thread to `started`, then
+    /// wait for `release`
+    #[cfg(test)]
+    Block {
+        started: SyncSender<String>,
+        release: SyncReceiver<()>,
+    },
 }
 
 use std::thread;
@@ -75,40 +107,111 @@ impl Sha1Worker {
                 addr,
                 piece_index,
                 torrent_id,
+            } => {
+                let valid = check_sum(&piece, &sum_metadata);
+
+                // Written before the supervisor knows, so a `ReadPiece`
+                // reads it back after the write
+                if valid {
+                    self.send_fs(FSMessage::Write {
+                        id: torrent_id,
+                        piece: piece_index,
+                        data: piece,
+                    });
+                }
+
+                send_result(&addr, ValidatePiece { piece_index, valid });
+            }
+            Sha1Task::Verify {
+                piece,
+                sum_metadata,
+                addr,
+                piece_index,
+            } => {
+                let valid = check_sum(&piece, &sum_metadata);
+                send_result(&addr, PieceVerified { piece_index, valid });
+            }
+            Sha1Task::Hash {
+                piece,
+                piece_index,
+                respond,
             } => {
                 let sha1 = crate::sha1::sha1(&piece);
-
-                let valid = compare_20_bytes(&sha1[..], &sum_metadata[..]);
-
-                self.send_result(torrent_id, piece, valid, piece_index, addr);
+                respond.send((piece_index, sha1)).ok();
+            }
+            Sha1Task::Batch(tasks) => {
+                // The valid pieces are written with a single message per
+                // torrent, the disk coalesces the contiguous ones
+                let mut batches: Vec<(TorrentId, Vec<WriteRequest>)> = Vec::new();
+                let mut results = Vec::new();
+
+                for task in tasks {
+                    match task {
+                        Sha1Task::CheckSum {
+                            torrent_id,
+                            piece,
+                            sum_metadata,
+                            addr,
+                            piece_index,
+                        } => {
+                            let valid = check_sum(&piece, &sum_metadata);
+                            results.push((addr, ValidatePiece { piece_index, valid }));
+
+                            if !valid {
+                                continue;
+                            }
+
+                            let write = WriteRequest {
+                                piece: piece_index,
+                                block: 0.into(),
+                                data: piece,
+                            };
+                            match batches.iter_mut().find(|(id, _)| *id == torrent_id) {
+                                Some((_, writes)) => writes.push(write),
+                                None => batches.push((torrent_id, vec![write])),
+                            }
+                        }
+                        task => self.process(task),
+                    }
+                }
+
+                for (id, writes) in batches {
+                    self.send_fs(FSMessage::WriteBatch { id, writes });
+                }
+                for (addr, msg) in results {
+                    send_result(&addr, msg);
+                }
+            }
+            #[cfg(test)]
+            Sha1Task::Panic => panic!("Sha1Task::Panic"),
+            #[cfg(test)]
+            Sha1Task::Block { started, release } => {
+                let name = thread::current().name().unwrap_or_default().to_string();
+                started.send(name).ok();
+                release.recv().ok();
             }
         }
     }
 
-    fn send_result(
-        &mut self,
-        torrent_id: TorrentId,
-        piece: Box<[u8]>,
-        valid: bool,
-        piece_index: PieceIndex,
-        addr: Sender<TorrentNotification>,
-    ) {
-        let msg = ValidatePiece { piece_index, valid };
-        if let Err(TrySendError::Full(msg)) = addr.try_send(msg) {
-            tokio::spawn(async move { addr.send(msg).await });
+    /// Blocks when the channel is full: the messages to the disk are
+    /// kept in order
+    fn send_fs(&self, msg: FSMessage) {
+        if let Err(TrySendError::Full(msg)) = self.fs.try_send(msg) {
+            self.runtime.block_on(self.fs.send(msg)).ok();
         }
+    }
+}
 
-        if valid {
-            let msg = FSMessage::Write {
-                id: torrent_id,
-                piece: piece_index,
-                data: piece,
-            };
-            if let Err(TrySendError::Full(msg)) = self.fs.try_send(msg) {
-                let fs = self.fs.clone();
-                tokio::spawn(async move { fs.send(msg).await });
-            }
-        }
+fn check_sum(piece: &[u8], sum_metadata: &[u8; 20]) -> bool {
+    let sha1 = crate::sha1::sha1(piece);
+
+    compare_20_bytes(&sha1[..], &sum_metadata[..])
+}
+
+fn send_result(addr: &Sender<TorrentNotification>, msg: TorrentNotification) {
+    if let Err(TrySendError::Full(msg)) = addr.try_send(msg) {
+        let addr = addr.clone();
+        tokio::spawn(async move { addr.send(msg).await });
     }
 }
 
@@ -116,17 +219,27 @@ impl Sha1Worker {
 pub struct Sha1Workers;
 
 impl Sha1Workers {
-    pub fn new_pool(runtime: Arc<Runtime>, fs: Sender<FSMessage>) -> SyncSender<Sha1Task> {
+    /// Pool of `nworkers` threads, at least one
+    pub fn new_pool(
+        runtime: Arc<Runtime>,
+        fs: Sender<FSMessage>,
+        nworkers: usize,
+    ) -> SyncSender<Sha1Task> {
         let (sender, receiver) = unbounded();
 
-        thread::spawn(move || Self::start(receiver, runtime, fs));
+        thread::spawn(move || Self::start(receiver, runtime, fs, nworkers.max(1)));
 
         sender
     }
 
-    fn start(recv: SyncReceiver<Sha1Task>, runtime: Arc<Runtime>, fs: Sender<FSMessage>) {
+    fn start(
+        recv: SyncReceiver<Sha1Task>,
+        runtime: Arc<Runtime>,
+        fs: Sender<FSMessage>,
+        nworkers: usize,
+    ) {
         if let Ok(first_task) = recv.recv() {
-            let handles = Self::init_pool(first_task, recv, runtime, fs);
+            let handles = Self::init_pool(first_task, recv, runtime, fs, nworkers);
 
             for handle in handles {
                 let _ = handle.join();
@@ -139,12 +252,12 @@ impl Sha1Workers {
         receiver: SyncReceiver<Sha1Task>,
         runtime: Arc<Runtime>,
         fs: Sender<FSMessage>,
+        nworkers: usize,
     ) -> Vec<thread::JoinHandle<()>> {
-        let num_cpus = num_cpus::get().max(1).min(2);
-        let mut handles = Vec::with_capacity(num_cpus);
+        let mut handles = Vec::with_capacity(nworkers);
         let mut task = Some(task);
 
-        for index in 0..num_cpus {
+        for index in 0..nworkers {
             let recv = receiver.clone();
             let runtime_clone = runtime.clone();
             let fs_clone = fs.clone();
@@ -152,18 +265,134 @@ impl Sha1Workers {
             handles.push(
                 thread::Builder::new()
                     .name(format!("sha-{}", index + 1))
-                    .spawn(|| Sha1Worker::new(runtime_clone, fs_clone).start(recv, task))
+                    .spawn(move || Self::run_worker(index, recv, runtime_clone, fs_clone, task))
                     .unwrap(),
             );
         }
 
         handles
     }
+
+    /// Run a worker until the channel is closed, a new worker is
+    /// started when it panics
+    fn run_worker(
+        index: usize,
+        recv: SyncReceiver<Sha1Task>,
+        runtime: Arc<Runtime>,
+        fs: Sender<FSMessage>,
+        mut task: Option<Sha1Task>,
+    ) {
+        loop {
+            let worker = Sha1Worker::new(runtime.clone(), fs.clone());
+            let first_task = task.take();
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                worker.start(recv.clone(), first_task);
+            }));
+
+            match result {
+                Ok(()) => return,
+                Err(_) => {
+                    warn!("[sha1] Worker {} panicked, respawning it", index + 1);
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::compare_20_bytes;
+    use std::sync::Arc;
+
+    use tokio::runtime::Runtime;
+
+    use crate::{
+        fs::FSMessage,
+        piece_picker::PieceIndex,
+        supervisors::torrent::{TorrentId, TorrentNotification},
+    };
+
+    use super::{compare_20_bytes, Sha1Task, Sha1Worker, Sha1Workers};
+
+    #[test]
+    fn respawn_after_panic() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let (fs, _fs_recv) = async_channel::unbounded();
+        let (addr, results) = async_channel::unbounded();
+
+        let pool = Sha1Workers::new_pool(Arc::clone(&runtime), fs, 2);
+
+        // Enough panics to kill all the workers
+        for _ in 0..4 {
+            pool.send(Sha1Task::Panic).unwrap();
+        }
+
+        let piece = vec![1; 64].into_boxed_slice();
+        pool.send(Sha1Task::Verify {
+            sum_metadata: Arc::new(crate::sha1::sha1(&piece)),
+            piece,
+            addr,
+            piece_index: 3.into(),
+        })
+        .unwrap();
+
+        match runtime.block_on(results.recv()) {
+            Ok(TorrentNotification::PieceVerified { piece_index, valid }) => {
+                assert_eq!(piece_index, 3.into());
+                assert!(valid);
+            }
+            _ => panic!("Missing result"),
+        }
+    }
+
+    #[test]
+    fn batch() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let (fs, fs_recv) = async_channel::unbounded();
+        let (addr, results) = async_channel::unbounded();
+        let torrent_id = TorrentId::new();
+
+        let tasks = (0..10u8)
+            .map(|index| {
+                let piece = vec![index; 64].into_boxed_slice();
+                let mut sum = crate::sha1::sha1(&piece);
+                // Odd pieces don't match their sum
+                sum[0] ^= index % 2;
+
+                Sha1Task::CheckSum {
+                    torrent_id,
+                    piece,
+                    sum_metadata: Arc::new(sum),
+                    addr: addr.clone(),
+                    piece_index: (index as u32).into(),
+                }
+            })
+            .collect();
+
+        Sha1Worker::new(runtime, fs).process(Sha1Task::Batch(tasks));
+
+        for index in 0..10u32 {
+            match results.try_recv() {
+                Ok(TorrentNotification::ValidatePiece { piece_index, valid }) => {
+                    assert_eq!(piece_index, index.into());
+                    assert_eq!(valid, index % 2 == 0);
+                }
+                _ => panic!("Missing result"),
+            }
+        }
+        assert!(results.try_recv().is_err());
+        // Only valid pieces are written, in a single batch
+        match fs_recv.try_recv() {
+            Ok(FSMessage::WriteBatch { id, writes }) => {
+                assert_eq!(id, torrent_id);
+                let pieces: Vec<_> = writes.iter().map(|w| w.piece).collect();
+                let expected: Vec<_> = (0..10u32).step_by(2).map(PieceIndex::from).collect();
+                assert_eq!(pieces, expected);
+            }
+            _ => panic!("Missing write batch"),
+        }
+        assert!(fs_recv.is_empty());
+    }
 
     #[test]
     fn compare_sum_simd() {
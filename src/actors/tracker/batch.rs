@@ -0,0 +1,394 @@
+use async_channel::{bounded, unbounded, Receiver, Sender};
+use futures::future::join_all;
+use hashbrown::{HashMap, HashSet};
+use kv_log_macro::{info, warn};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use url::Url;
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use super::http::{
+    announce_to, announced, http_get, AnnounceQuery, AnnounceResponse, Escaped, ToQuery,
+};
+use super::{schedule::AnnounceEvent, Announced};
+use crate::{
+    errors::TorrentError,
+    supervisors::{torrent::Result, tracker::TrackerData},
+};
+
+/// Announces arriving during this window after the first one are
+/// grouped with it
+const BATCH_WINDOW: Duration = Duration::from_secs(2);
+
+/// Announce of multiple torrents in a single request: the info hash and
+/// the stats of each torrent are repeated, in order
+pub struct BatchAnnounceQuery<'a> {
+    pub queries: Vec<AnnounceQuery<'a>>,
+}
+
+impl<'a> ToQuery for BatchAnnounceQuery<'a> {
+    fn to_query(&self) -> String {
+        let mut query = String::with_capacity(self.queries.len() * 128);
+
+        for torrent in &self.queries {
+            query.push_str(&format!(
+                "info_hash={}&uploaded={}&downloaded={}&left={}&event={}&",
+                torrent.info_hash.escape(),
+                torrent.uploaded,
+                torrent.downloaded,
+                torrent.left,
+                torrent.event,
+            ));
+        }
+
+        if let Some(first) = self.queries.first() {
+            query.push_str(&format!(
+                "peer_id={}&port={}&compact={}",
+                first.peer_id.escape(),
+                first.port,
+                first.compact
+            ));
+        }
+
+        query
+    }
+}
+
+/// Response of a tracker supporting the batched announces, by info hash.
+/// The other trackers answer without `files`
+#[derive(Deserialize, Debug)]
+pub struct BatchAnnounceResponse {
+    pub files: Option<HashMap<ByteBuf, AnnounceResponse>>,
+}
+
+struct BatchRequest {
+    data: Arc<TrackerData>,
+    addrs: Vec<Arc<SocketAddr>>,
+    event: AnnounceEvent,
+    respond: Sender<Result<Announced>>,
+}
+
+/// Key of the torrents announced in the same request
+fn batch_key(url: &Url) -> String {
+    format!(
+        "{}://{}:{}{}",
+        url.scheme(),
+        url.host_str().unwrap_or(""),
+        url.port_or_known_default().unwrap_or(0),
+        url.path()
+    )
+}
+
+/// Shared by the HTTP trackers of all torrents of the session, to group
+/// the torrents announced to the same tracker in a single request
+#[derive(Clone)]
+pub struct AnnounceBatcher {
+    sender: Sender<BatchRequest>,
+}
+
+impl std::fmt::Debug for AnnounceBatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AnnounceBatcher").finish()
+    }
+}
+
+impl AnnounceBatcher {
+    /// The actor must be started for the announces to be sent
+    pub fn new() -> (AnnounceBatcher, AnnounceBatcherActor) {
+        let (sender, recv) = unbounded();
+
+        (
+            AnnounceBatcher { sender },
+            AnnounceBatcherActor {
+                recv,
+                unsupported: HashSet::new(),
+            },
+        )
+    }
+
+    pub(super) async fn announce(
+        &self,
+        data: &Arc<TrackerData>,
+        addrs: &[Arc<SocketAddr>],
+        event: AnnounceEvent,
+    ) -> Result<Announced> {
+        let (respond, response) = bounded(1);
+
+        let request = BatchRequest {
+            data: Arc::clone(data),
+            addrs: addrs.to_vec(),
+            event,
+            respond,
+        };
+
+        if self.sender.send(request).await.is_err() {
+            // The batcher is gone, announce alone
+            return announce_to(data, addrs, event).await;
+        }
+
+        response
+            .recv()
+            .await
+            .unwrap_or(Err(TorrentError::Unresponsive))
+    }
+}
+
+pub struct AnnounceBatcherActor {
+    recv: Receiver<BatchRequest>,
+    /// Trackers not answering the batched announces, the torrents
+    /// are announced one by one
+    unsupported: HashSet<String>,
+}
+
+impl AnnounceBatcherActor {
+    pub async fn start(mut self) {
+        while let Ok(first) = self.recv.recv().await {
+            let mut pending = vec![first];
+            let deadline = tokio::time::Instant::now() + BATCH_WINDOW;
+
+            while let Ok(Ok(request)) = tokio::time::timeout_at(deadline, self.recv.recv()).await {
+                pending.push(request);
+            }
+
+            // The trackers are announced to at the same time
+            let unsupported = &self.unsupported;
+            let announces =
+                Self::group_by_tracker(pending)
+                    .into_iter()
+                    .map(|(key, group)| async move {
+                        if group.len() == 1 || unsupported.contains(&key) {
+                            Self::announce_each(group).await;
+                            None
+                        } else {
+                            Self::announce_batch(key, group).await
+                        }
+                    });
+
+            for key in join_all(announces).await.into_iter().flatten() {
+                self.unsupported.insert(key);
+            }
+        }
+    }
+
+    fn group_by_tracker(requests: Vec<BatchRequest>) -> Vec<(String, Vec<BatchRequest>)> {
+        let mut groups: Vec<(String, Vec<BatchRequest>)> = Vec::new();
+
+        for request in requests {
+            let key = batch_key(&request.data.url);
+
+            match groups.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, group)) => group.push(request),
+                None => groups.push((key, vec![request])),
+            }
+        }
+
+        groups
+    }
+
+    async fn announce_each(requests: Vec<BatchRequest>) {
+        join_all(requests.into_iter().map(|request| async move {
+            let result = announce_to(&request.data, &request.addrs, request.event).await;
+            request.respond.try_send(result).ok();
+        }))
+        .await;
+    }
+
+    /// Returns the key of the tracker when it doesn't support the
+    /// batched announces
+    async fn announce_batch(key: String, requests: Vec<BatchRequest>) -> Option<String> {
+        let (index, mut files) = match Self::send_batch(&requests).await {
+            Ok(Some(files)) => files,
+            Ok(None) => {
+                info!("[tracker] Batched announces not supported", { tracker: key });
+                Self::announce_each(requests).await;
+                return Some(key);
+            }
+            Err(e) => {
+                warn!("[tracker] Batched announce failed {:?}", e);
+                Self::announce_each(requests).await;
+                return None;
+            }
+        };
+
+        let mut missing = Vec::new();
+
+        for request in requests {
+            let info_hash = request.data.metadata.info_hash.as_ref();
+
+            match files.remove(&ByteBuf::from(info_hash.to_vec())) {
+                Some(response) => {
+                    let announced = announced(index, &response).await;
+                    request.respond.try_send(Ok(announced)).ok();
+                }
+                None => missing.push(request),
+            }
+        }
+
+        Self::announce_each(missing).await;
+        None
+    }
+
+    /// The responses by info hash, with the index of the address which
+    /// answered. Returns `None` when the tracker doesn't support the
+    /// batched announces
+    async fn send_batch(
+        requests: &[BatchRequest],
+    ) -> Result<Option<(usize, HashMap<ByteBuf, AnnounceResponse>)>> {
+        let first = match requests.first() {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        let query = BatchAnnounceQuery {
+            queries: requests
+                .iter()
+                .map(|r| AnnounceQuery::new(r.data.as_ref(), r.event))
+                .collect(),
+        };
+
+        let mut last_err = None;
+
+        for (index, addr) in first.addrs.iter().enumerate() {
+            match http_get::<BatchAnnounceResponse, _>(&first.data.url, &query, addr).await {
+                Ok(response) => return Ok(response.files.map(|files| (index, files))),
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        Err(last_err.unwrap_or(TorrentError::Unresponsive))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering::Relaxed},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{AnnounceBatcher, AnnounceEvent};
+    use crate::{
+        metadata::{TestTorrent, Torrent},
+        peer::peer::PeerExternId,
+        supervisors::{
+            torrent::{ByteCounters, TorrentGauges},
+            tracker::TrackerData,
+        },
+    };
+
+    fn torrent(announce: &str, info_hash: u8) -> Torrent {
+        TestTorrent::new()
+            .announce(announce)
+            .info_hash(info_hash)
+            .build()
+    }
+
+    /// Tracker answering the batched announces with 1 peer per torrent,
+    /// the peer port is the info hash byte
+    async fn batch_tracker(listener: TcpListener, nrequests: Arc<AtomicUsize>) {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            nrequests.fetch_add(1, Relaxed);
+
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..n]);
+            }
+
+            let mut body = b"d5:filesd".to_vec();
+            for byte in &[1u8, 2] {
+                body.extend_from_slice(b"20:");
+                body.extend_from_slice(&[*byte; 20]);
+                body.extend_from_slice(b"d8:intervali1800e5:peers6:");
+                body.extend_from_slice(&[127, 0, 0, 1, 0, *byte]);
+                body.extend_from_slice(b"e");
+            }
+            body.extend_from_slice(b"ee");
+
+            let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn batched_announce() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let nrequests = Arc::new(AtomicUsize::new(0));
+
+        tokio::spawn(batch_tracker(listener, Arc::clone(&nrequests)));
+
+        let (batcher, actor) = AnnounceBatcher::new();
+        tokio::spawn(actor.start());
+
+        let announce = format!("http://{}/announce", addr);
+        let extern_id = Arc::new(PeerExternId::generate());
+
+        // The 1st address of the tracker refuses the connections
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let announces: Vec<_> = (1..=2)
+            .map(|info_hash| {
+                let metadata = Arc::new(torrent(&announce, info_hash));
+                let (supervisor, _) = async_channel::unbounded();
+
+                let data = Arc::new(TrackerData {
+                    url: metadata.get_urls_tiers().remove(0),
+                    metadata,
+                    supervisor,
+                    extern_id: Arc::clone(&extern_id),
+                    counters: Arc::new(ByteCounters::default()),
+                    gauges: Arc::new(TorrentGauges::default()),
+                    port: 6881,
+                    batcher: Some(batcher.clone()),
+                    completion: tokio::sync::watch::channel(false).1,
+                    announce_completed: true,
+                    paused: tokio::sync::watch::channel(false).1,
+                    shutdown: tokio::sync::watch::channel(false).1,
+                });
+                let batcher = batcher.clone();
+
+                tokio::spawn(async move {
+                    batcher
+                        .announce(
+                            &data,
+                            &[Arc::new(closed), Arc::new(addr)],
+                            AnnounceEvent::Started,
+                        )
+                        .await
+                })
+            })
+            .collect();
+
+        let mut results = Vec::new();
+        for announce in announces {
+            results.push(announce.await.unwrap());
+        }
+
+        // Both torrents share the tracker, they're announced in 1 request
+        assert_eq!(nrequests.load(Relaxed), 1);
+
+        for (result, port) in results.into_iter().zip(1..) {
+            let peer: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+            let announced = result.unwrap();
+            assert_eq!((announced.index, announced.peers), (1, vec![peer]));
+            assert_eq!(announced.interval, Some(Duration::from_secs(1800)));
+        }
+    }
+}
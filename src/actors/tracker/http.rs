@@ -1,7 +1,7 @@
 use async_trait::async_trait;
 use kv_log_macro::{debug, info};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use tokio::{
     io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
@@ -10,12 +10,15 @@ use url::Url;
 
 use std::sync::Arc;
 
-use super::{TrackerConnection, TrackerData};
+use super::{schedule::AnnounceEvent, Announced, TrackerConnection, TrackerData};
 use crate::{errors::TorrentError, supervisors::torrent::Result};
 
+/// The `ip` of a peer is an IPv4 or IPv6 address, or a DNS name
 async fn peers_from_dict(peers: &[Peer], addrs: &mut Vec<SocketAddr>) {
     for peer in peers {
-        if let Ok(s_addrs) = tokio::net::lookup_host((peer.ip.as_str(), peer.port)).await {
+        if let Ok(ip) = peer.ip.parse::<IpAddr>() {
+            addrs.push(SocketAddr::new(ip, peer.port));
+        } else if let Ok(s_addrs) = tokio::net::lookup_host((peer.ip.as_str(), peer.port)).await {
             for addr in s_addrs {
                 addrs.push(addr);
             }
@@ -23,7 +26,7 @@ async fn peers_from_dict(peers: &[Peer], addrs: &mut Vec<SocketAddr>) {
     }
 }
 
-async fn get_peers_addrs(response: &AnnounceResponse) -> Vec<SocketAddr> {
+pub(super) async fn get_peers_addrs(response: &AnnounceResponse) -> Vec<SocketAddr> {
     let mut addrs = Vec::new();
 
     match response.peers6 {
@@ -54,6 +57,18 @@ async fn get_peers_addrs(response: &AnnounceResponse) -> Vec<SocketAddr> {
     addrs
 }
 
+/// Peers and intervals of the response
+pub(super) async fn announced(index: usize, response: &AnnounceResponse) -> Announced {
+    let secs = |secs: i64| Duration::from_secs(secs.max(0) as u64);
+
+    Announced {
+        index,
+        peers: get_peers_addrs(response).await,
+        interval: Some(secs(response.interval)),
+        min_interval: response.min_interval.map(secs),
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct AnnounceQuery<'a> {
     pub info_hash: &'a [u8],
@@ -61,21 +76,24 @@ pub struct AnnounceQuery<'a> {
     pub port: i64,
     pub uploaded: i64,
     pub downloaded: i64,
-    //left: i64,
-    pub event: String,
+    pub left: i64,
+    pub event: &'static str,
     pub compact: i64,
 }
 
-impl<'a> From<&'a TrackerData> for AnnounceQuery<'a> {
-    fn from(data: &'a TrackerData) -> AnnounceQuery {
+impl<'a> AnnounceQuery<'a> {
+    pub fn new(data: &'a TrackerData, event: AnnounceEvent) -> AnnounceQuery {
+        let stats = data.counters.stats();
+
         AnnounceQuery {
             info_hash: data.metadata.info_hash.as_ref(),
             peer_id: std::str::from_utf8(&**data.extern_id)
                 .expect("Fail to convert extern id to str"),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            event: "started".to_owned(),
+            port: data.port as i64,
+            uploaded: stats.payload_uploaded as i64,
+            downloaded: stats.payload_downloaded as i64,
+            left: data.left() as i64,
+            event: event.as_str(),
             compact: 1,
         }
     }
@@ -119,14 +137,20 @@ pub struct AnnounceResponse {
     pub peers6: Option<Peers6>,
 }
 
-use crate::bencode::de::{from_bytes, DeserializeError};
+use crate::bencode::de::{from_bytes, BencodeError};
 
 #[derive(Debug)]
 pub enum HttpError {
     ResponseCode(String),
     Malformed,
     MissingContentLength,
-    Deserialize(DeserializeError),
+    /// The tracker answered with something else than bencode, usually
+    /// an HTML error page. `snippet` is the start of the body
+    NotBencode {
+        content_type: Option<String>,
+        snippet: String,
+    },
+    Deserialize(BencodeError),
     HostResolution,
     IO(std::io::Error),
     IOAsync(tokio::io::Error),
@@ -138,8 +162,8 @@ impl From<std::io::Error> for HttpError {
     }
 }
 
-impl From<DeserializeError> for HttpError {
-    fn from(e: DeserializeError) -> HttpError {
+impl From<BencodeError> for HttpError {
+    fn from(e: BencodeError) -> HttpError {
         HttpError::Deserialize(e)
     }
 }
@@ -163,21 +187,27 @@ pub trait ToQuery {
 
 impl<'a> ToQuery for AnnounceQuery<'a> {
     fn to_query(&self) -> String {
-        format!(
-            "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&event={}&compact={}",
+        let mut query = format!(
+            "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact={}",
             self.info_hash.escape(),
             self.peer_id.escape(),
             self.port,
             self.uploaded,
             self.downloaded,
-            self.event,
+            self.left,
             self.compact,
-        )
+        );
+        // The periodic announces don't have an event
+        if !self.event.is_empty() {
+            query.push_str("&event=");
+            query.push_str(self.event);
+        }
+        query
     }
 }
 
 const UNRESERVED_CHAR: &[u8] =
-	//"%+;?:@=&,$/"
+    //"%+;?:@=&,$/"
     b"-_!.~*()ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
 
 const HEXCHARS: &[u8] = b"0123456789abcdef";
@@ -239,22 +269,52 @@ async fn send<T: DeserializeOwned, Q: ToQuery>(
     stream.write(req.as_bytes()).await?;
     stream.flush().await?;
 
-    let response = read_response(stream).await?;
+    let (content_type, response) = read_response(stream).await?;
 
     // println!("DATA {:x?}", String::from_utf8_lossy(&response));
 
-    let value = from_bytes(&response)?;
-
-    Ok(value)
+    decode(content_type, &response)
 }
 
 use memchr::memchr;
 use tokio::io::BufReader;
 
-async fn read_response(stream: TcpStream) -> Result<Vec<u8>> {
+/// Length of the body included in `HttpError::NotBencode`
+const SNIPPET_LENGTH: usize = 128;
+
+/// Some trackers serve valid bencode as text/html, the content type
+/// only explains a body failing to decode
+fn decode<T: DeserializeOwned>(content_type: Option<String>, body: &[u8]) -> Result<T> {
+    let e = match from_bytes(body) {
+        Ok(value) => return Ok(value),
+        Err(e) => e,
+    };
+
+    let is_html = content_type
+        .as_deref()
+        .map(|c| c.starts_with("text/html"))
+        .unwrap_or(false);
+    let is_bencode = matches!(body.first(), Some(b'd' | b'l' | b'i' | b'0'..=b'9'));
+
+    if is_bencode && !is_html {
+        return Err(e.into());
+    }
+
+    let snippet = &body[..body.len().min(SNIPPET_LENGTH)];
+
+    Err(HttpError::NotBencode {
+        content_type,
+        snippet: String::from_utf8_lossy(snippet).trim().to_string(),
+    }
+    .into())
+}
+
+/// Returns the content type and the body
+async fn read_response(stream: TcpStream) -> Result<(Option<String>, Vec<u8>)> {
     let mut reader = BufReader::with_capacity(4 * 1024, stream);
 
     let mut content_length = None;
+    let mut content_type = None;
 
     // String containing headers
     let mut string = String::with_capacity(1024);
@@ -281,6 +341,8 @@ async fn read_response(stream: TcpStream) -> Result<Vec<u8>> {
 
         if name == "content-length" {
             content_length = Some(value.parse().map_err(|_| HttpError::Malformed)?);
+        } else if name == "content-type" {
+            content_type = Some(value.to_lowercase());
         }
     }
 
@@ -293,7 +355,7 @@ async fn read_response(stream: TcpStream) -> Result<Vec<u8>> {
 
     reader.take(content_length).read_to_end(&mut buffer).await?;
 
-    Ok(buffer)
+    Ok((content_type, buffer))
 }
 
 pub async fn http_get<R, Q>(url: &Url, query: &Q, addr: &SocketAddr) -> Result<R>
@@ -318,26 +380,36 @@ pub struct HttpConnection {
     addr: Vec<Arc<SocketAddr>>,
 }
 
+/// Announce the torrent alone
+pub(super) async fn announce_to(
+    data: &TrackerData,
+    addrs: &[Arc<SocketAddr>],
+    event: AnnounceEvent,
+) -> Result<Announced> {
+    let query = AnnounceQuery::new(data, event);
+    let mut last_err = None;
+    for (index, addr) in addrs.iter().enumerate() {
+        let response = match http_get(&data.url, &query, addr).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                last_err = Some(e);
+                continue;
+            }
+        };
+        return Ok(announced(index, &response).await);
+    }
+    match last_err {
+        Some(e) => Err(e),
+        _ => Err(TorrentError::Unresponsive),
+    }
+}
+
 #[async_trait]
 impl TrackerConnection for HttpConnection {
-    async fn announce(&mut self, connected_addr: &mut usize) -> Result<Vec<SocketAddr>> {
-        let query = AnnounceQuery::from(self.data.as_ref());
-        let mut last_err = None;
-        for (index, addr) in self.addr.iter().enumerate() {
-            let response = match http_get(&self.data.url, &query, addr).await {
-                Ok(resp) => resp,
-                Err(e) => {
-                    last_err = Some(e);
-                    continue;
-                }
-            };
-            *connected_addr = index;
-            let peers = get_peers_addrs(&response).await;
-            return Ok(peers);
-        }
-        match last_err {
-            Some(e) => Err(e),
-            _ => Err(TorrentError::Unresponsive),
+    async fn announce(&mut self, event: AnnounceEvent) -> Result<Announced> {
+        match self.data.batcher.as_ref() {
+            Some(batcher) => batcher.announce(&self.data, &self.addr, event).await,
+            None => announce_to(&self.data, &self.addr, event).await,
         }
     }
 
@@ -355,3 +427,212 @@ impl HttpConnection {
         Box::new(Self { data, addr })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{announce_to, decode, AnnounceEvent, AnnounceResponse, HttpError, TrackerData};
+    use crate::{
+        errors::TorrentError,
+        metadata::{TestTorrent, Torrent},
+        peer::peer::PeerExternId,
+        supervisors::torrent::{ByteCounters, TorrentGauges},
+    };
+
+    fn torrent(announce: String) -> Torrent {
+        TestTorrent::new()
+            .announce(&announce)
+            .pieces(3)
+            .info_hash(9)
+            .build()
+    }
+
+    /// Answers each request with the next body, returns the requests
+    async fn tracker(listener: TcpListener, bodies: Vec<&'static [u8]>) -> Vec<String> {
+        let mut requests = Vec::new();
+
+        for body in bodies {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..n]);
+            }
+            requests.push(String::from_utf8(request).unwrap());
+
+            let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+
+        requests
+    }
+
+    #[tokio::test]
+    async fn announce_peers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let compact: &[u8] = b"d8:intervali900e12:min intervali60e5:peers12:\
+            \x0a\x00\x00\x01\x1a\xe1\xc0\xa8\x01\x02\x00\x50e";
+        let dict: &[u8] = b"d8:intervali1800e5:peersld2:ip8:10.0.0.34:porti6881eeee";
+        let server = tokio::spawn(tracker(listener, vec![compact, dict]));
+
+        let metadata = Arc::new(torrent(format!("http://{}/announce", addr)));
+        let counters = Arc::new(ByteCounters::default());
+        counters.add_downloaded(1000, 1100);
+        // The size of the torrent, 1 of its 3 pieces is verified
+        let gauges = Arc::new(TorrentGauges::new(3000, 3));
+        gauges.set(0, 1, 1000);
+        let (supervisor, _) = async_channel::unbounded();
+
+        let data = TrackerData {
+            url: metadata.get_urls_tiers().remove(0),
+            metadata,
+            supervisor,
+            extern_id: Arc::new(PeerExternId::generate()),
+            counters,
+            gauges,
+            port: 51413,
+            batcher: None,
+            completion: tokio::sync::watch::channel(false).1,
+            announce_completed: true,
+            paused: tokio::sync::watch::channel(false).1,
+            shutdown: tokio::sync::watch::channel(false).1,
+        };
+        let addrs = [Arc::new(addr)];
+
+        let announced = announce_to(&data, &addrs, AnnounceEvent::Started)
+            .await
+            .unwrap();
+        let expected: Vec<SocketAddr> = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "192.168.1.2:80".parse().unwrap(),
+        ];
+        assert_eq!(announced.peers, expected);
+        assert_eq!(announced.interval, Some(Duration::from_secs(900)));
+        assert_eq!(announced.min_interval, Some(Duration::from_secs(60)));
+
+        let announced = announce_to(&data, &addrs, AnnounceEvent::Periodic)
+            .await
+            .unwrap();
+        assert_eq!(announced.peers, vec!["10.0.0.3:6881".parse().unwrap()]);
+        assert_eq!(announced.interval, Some(Duration::from_secs(1800)));
+        assert_eq!(announced.min_interval, None);
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("GET /announce?info_hash=%09%09"));
+        for param in &[
+            "port=51413",
+            "downloaded=1000",
+            "left=2000",
+            "compact=1",
+            "event=started",
+        ] {
+            assert!(
+                requests[0].contains(param),
+                "{} not in {}",
+                param,
+                requests[0]
+            );
+        }
+        assert!(!requests[1].contains("event="));
+    }
+
+    #[tokio::test]
+    async fn announce_peers6() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // BEP 7: `peers6` has 18 bytes per peer, next to the IPv4 `peers`
+        let compact: &[u8] = b"d8:intervali900e5:peers6:\x0a\x00\x00\x01\x1a\xe1\
+            6:peers618:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe1e";
+        let dict: &[u8] = b"d8:intervali900e6:peers6ld2:ip11:2001:db8::24:porti80eeee";
+        let server = tokio::spawn(tracker(listener, vec![compact, dict]));
+
+        let metadata = Arc::new(torrent(format!("http://{}/announce", addr)));
+        let (supervisor, _) = async_channel::unbounded();
+
+        let data = TrackerData {
+            url: metadata.get_urls_tiers().remove(0),
+            metadata,
+            supervisor,
+            extern_id: Arc::new(PeerExternId::generate()),
+            counters: Arc::new(ByteCounters::default()),
+            gauges: Arc::new(TorrentGauges::default()),
+            port: 6881,
+            batcher: None,
+            completion: tokio::sync::watch::channel(false).1,
+            announce_completed: true,
+            paused: tokio::sync::watch::channel(false).1,
+            shutdown: tokio::sync::watch::channel(false).1,
+        };
+        let addrs = [Arc::new(addr)];
+
+        let announced = announce_to(&data, &addrs, AnnounceEvent::Started)
+            .await
+            .unwrap();
+        let mut peers = announced.peers;
+        peers.sort();
+        let expected: Vec<SocketAddr> = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "[2001:db8::1]:6881".parse().unwrap(),
+        ];
+        assert_eq!(peers, expected);
+
+        let announced = announce_to(&data, &addrs, AnnounceEvent::Periodic)
+            .await
+            .unwrap();
+        assert_eq!(announced.peers, vec!["[2001:db8::2]:80".parse().unwrap()]);
+
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn html_response() {
+        let body = b"<html><body><h1>502 Bad Gateway</h1></body></html>";
+
+        match decode::<AnnounceResponse>(Some("text/html; charset=utf-8".to_string()), body) {
+            Err(TorrentError::Http(HttpError::NotBencode {
+                content_type,
+                snippet,
+            })) => {
+                assert_eq!(content_type.as_deref(), Some("text/html; charset=utf-8"));
+                assert!(snippet.contains("502 Bad Gateway"));
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Without content type, the body alone is enough to tell
+        let long_page = format!("  <!DOCTYPE html>{}", "x".repeat(1000));
+        match decode::<AnnounceResponse>(None, long_page.as_bytes()) {
+            Err(TorrentError::Http(HttpError::NotBencode { snippet, .. })) => {
+                assert!(snippet.starts_with("<!DOCTYPE html>"));
+                assert!(snippet.len() < 128);
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Valid bencode is decoded whatever the content type
+        let announce = b"d8:intervali1800e5:peers0:e";
+        for content_type in [None, Some("text/plain"), Some("text/html")].iter() {
+            let content_type = content_type.map(str::to_string);
+            let response = decode::<AnnounceResponse>(content_type, announce).unwrap();
+            assert_eq!(response.interval, 1800);
+        }
+
+        // Bencode failing to decode isn't reported as a page
+        match decode::<AnnounceResponse>(None, b"d8:intervali1800e") {
+            Err(TorrentError::Deserialization(_)) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+}
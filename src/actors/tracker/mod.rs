@@ -1,9 +1,12 @@
+pub mod batch;
 pub mod http;
+mod schedule;
 mod udp;
 
 use async_channel::Sender;
 use async_trait::async_trait;
 use kv_log_macro::{error, info, warn};
+use tokio::sync::watch;
 
 use std::{
     net::SocketAddr,
@@ -11,18 +14,29 @@ use std::{
     time::{Duration, Instant},
 };
 
+use self::schedule::{AnnounceEvent, AnnounceScheduler};
 use crate::{
     errors::TorrentError,
     metadata::UrlHash,
     supervisors::{
-        torrent::{Result, TorrentNotification},
-        tracker::{TrackerData, TrackerStatus},
+        torrent::{PeerOrigin, Result, TorrentNotification},
+        tracker::{wait_shutdown, TrackerData, TrackerStatus},
     },
 };
 
+/// Result of an announce
+#[derive(Debug)]
+pub struct Announced {
+    /// Index of the address we connected to
+    pub index: usize,
+    pub peers: Vec<SocketAddr>,
+    pub interval: Option<Duration>,
+    pub min_interval: Option<Duration>,
+}
+
 #[async_trait]
 pub trait TrackerConnection {
-    async fn announce(&mut self, connected_addr: &mut usize) -> Result<Vec<SocketAddr>>;
+    async fn announce(&mut self, event: AnnounceEvent) -> Result<Announced>;
     async fn scrape(&mut self) -> Result<()>;
 }
 
@@ -33,6 +47,7 @@ pub struct Tracker {
     /// so later requests will use this address first.
     addrs: Vec<Arc<SocketAddr>>,
     tracker_supervisor: Sender<(UrlHash, Instant, TrackerStatus)>,
+    scheduler: AnnounceScheduler,
 }
 
 impl Tracker {
@@ -44,15 +59,93 @@ impl Tracker {
             data,
             addrs: Vec::new(),
             tracker_supervisor,
+            scheduler: AnnounceScheduler::default(),
         }
     }
 
     pub async fn start(&mut self) {
+        let mut event = AnnounceEvent::Started;
+
+        // A torrent already complete when added, or completed before a
+        // restart, never announces `Completed`
+        let mut completion = self.data.completion.clone();
+        let mut wait_completion = self.data.announce_completed && !*completion.borrow();
+
+        let mut paused = self.data.paused.clone();
+        let mut wait_pause = true;
+
+        let mut shutdown = self.data.shutdown.clone();
+
         loop {
-            self.resolve_and_start().await;
+            if *paused.borrow() {
+                if !self.wait_resume(&mut paused, &mut shutdown).await {
+                    return;
+                }
+                event = AnnounceEvent::Started;
+            }
+
+            let delay = self.scheduler.delay(event, Instant::now());
 
-            // TODO: Use interval from announce response
-            tokio::time::sleep(Duration::from_secs(120)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                changed = completion.changed(), if wait_completion => {
+                    match changed {
+                        Ok(()) if *completion.borrow() => {
+                            wait_completion = false;
+                            event = AnnounceEvent::Completed;
+                        }
+                        Ok(()) => {}
+                        // The torrent is gone
+                        Err(_) => wait_completion = false,
+                    }
+                    continue;
+                }
+                changed = paused.changed(), if wait_pause => {
+                    if changed.is_err() {
+                        wait_pause = false;
+                    }
+                    continue;
+                }
+                _ = wait_shutdown(&mut shutdown) => {
+                    self.announce_stopped().await;
+                    return;
+                }
+            }
+
+            self.resolve_and_start(event).await;
+
+            event = AnnounceEvent::Periodic;
+        }
+    }
+
+    /// Announce `Stopped` and wait until the torrent is resumed.
+    /// Returns `false` when the torrent is gone or shutting down
+    async fn wait_resume(
+        &mut self,
+        paused: &mut watch::Receiver<bool>,
+        shutdown: &mut watch::Receiver<bool>,
+    ) -> bool {
+        self.announce_stopped().await;
+
+        while *paused.borrow() {
+            tokio::select! {
+                changed = paused.changed() => {
+                    if changed.is_err() {
+                        return false;
+                    }
+                }
+                // `Stopped` was already announced
+                _ = wait_shutdown(shutdown) => return false,
+            }
+        }
+
+        true
+    }
+
+    /// Tell the tracker we leave the swarm, when it knows about us
+    async fn announce_stopped(&mut self) {
+        if self.scheduler.has_announced() {
+            self.resolve_and_start(AnnounceEvent::Stopped).await;
         }
     }
 
@@ -62,7 +155,7 @@ impl Tracker {
         }
     }
 
-    async fn resolve_and_start(&mut self) {
+    async fn resolve_and_start(&mut self, event: AnnounceEvent) {
         use TrackerStatus::*;
 
         self.addrs = self.resolve_host().await;
@@ -74,7 +167,7 @@ impl Tracker {
             return;
         }
 
-        match self.connect_and_request().await {
+        match self.connect_and_request(event).await {
             Ok(peer_addrs) => {
                 info!(
                     "[tracker] Peers found {:?}\nLength = {:?}",
@@ -92,18 +185,26 @@ impl Tracker {
         }
     }
 
-    async fn connect_and_request(&mut self) -> Result<Vec<SocketAddr>> {
+    async fn connect_and_request(&mut self, event: AnnounceEvent) -> Result<Vec<SocketAddr>> {
         let data = Arc::clone(&self.data);
         let mut connection = Self::new_connection(data, self.addrs.clone());
 
-        let mut connected_index = 0;
+        let result = connection.announce(event).await;
+
+        // Failed announces are retried after the interval too
+        let (interval, min_interval) = match result.as_ref() {
+            Ok(announced) => (announced.interval, announced.min_interval),
+            Err(_) => (None, None),
+        };
+        self.scheduler
+            .announced(Instant::now(), interval, min_interval);
 
-        match connection.announce(&mut connected_index).await {
-            Ok(addrs) if !addrs.is_empty() => {
-                self.set_connected_addr(connected_index);
-                Ok(addrs)
+        match result {
+            Ok(announced) if !announced.peers.is_empty() => {
+                self.set_connected_addr(announced.index);
+                Ok(announced.peers)
             }
-            Ok(empty) => Ok(empty),
+            Ok(announced) => Ok(announced.peers),
             Err(e) => {
                 error!("[tracker] Announce failed {:?}", e);
                 Err(e)
@@ -127,6 +228,7 @@ impl Tracker {
             .supervisor
             .send(PeerDiscovered {
                 addrs: addrs.into_boxed_slice(),
+                origin: PeerOrigin::Tracker,
             })
             .await
             .unwrap();
@@ -161,3 +263,146 @@ impl Drop for Tracker {
         warn!("[tracker] Dropped: {:?}", self.data.url);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use async_channel::Sender;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::watch,
+    };
+
+    use std::sync::Arc;
+
+    use super::Tracker;
+    use crate::{
+        metadata::{TestTorrent, Torrent},
+        peer::peer::PeerExternId,
+        supervisors::{
+            torrent::{ByteCounters, TorrentGauges},
+            tracker::TrackerData,
+        },
+    };
+
+    fn torrent(announce: String) -> Torrent {
+        TestTorrent::new().announce(&announce).info_hash(1).build()
+    }
+
+    /// Tracker without interval, sending the query of each announce
+    async fn tracker(listener: TcpListener, queries: Sender<String>) {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..n]);
+            }
+
+            let request = String::from_utf8_lossy(&request);
+            let query = request.split_whitespace().nth(1).unwrap_or("");
+            queries.send(query.to_string()).await.ok();
+
+            let body = b"d8:intervali0e5:peers0:e";
+            let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+    }
+
+    fn count_completed(queries: &[String]) -> usize {
+        queries
+            .iter()
+            .filter(|q| q.contains("event=completed"))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn completed_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let metadata = Arc::new(torrent(format!(
+            "http://{}/announce",
+            listener.local_addr().unwrap()
+        )));
+
+        let (queries_sender, queries) = async_channel::unbounded();
+        tokio::spawn(tracker(listener, queries_sender));
+
+        let (supervisor, _supervisor_recv) = async_channel::unbounded();
+        let (tracker_supervisor, _tracker_recv) = async_channel::unbounded();
+
+        let spawn_tracker = |complete: bool, announce_completed: bool| {
+            let (completion, completion_recv) = watch::channel(complete);
+            let data = Arc::new(TrackerData {
+                url: metadata.get_urls_tiers().remove(0),
+                metadata: Arc::clone(&metadata),
+                supervisor: supervisor.clone(),
+                extern_id: Arc::new(PeerExternId::generate()),
+                counters: Arc::new(ByteCounters::default()),
+                gauges: Arc::new(TorrentGauges::default()),
+                port: 6881,
+                batcher: None,
+                completion: completion_recv,
+                announce_completed,
+                paused: watch::channel(false).1,
+                shutdown: watch::channel(false).1,
+            });
+            let tracker_supervisor = tracker_supervisor.clone();
+            let handle = tokio::spawn(async move {
+                Tracker::new(data, tracker_supervisor).start().await;
+            });
+            (completion, handle)
+        };
+
+        let (completion, handle) = spawn_tracker(false, true);
+
+        let first = queries.recv().await.unwrap();
+        assert!(first.contains("event=started"));
+
+        completion.send(true).unwrap();
+
+        let mut announces = Vec::new();
+        for _ in 0..10 {
+            announces.push(queries.recv().await.unwrap());
+        }
+        // The tracker may send periodic announces before seeing
+        // the completion, but only 1 `Completed`
+        assert_eq!(count_completed(&announces), 1);
+        assert!(!announces.last().unwrap().contains("event="));
+
+        handle.abort();
+        while queries.try_recv().is_ok() {}
+
+        // Restart of a complete torrent
+        let (_completion, handle) = spawn_tracker(true, true);
+
+        let mut announces = Vec::new();
+        for _ in 0..10 {
+            announces.push(queries.recv().await.unwrap());
+        }
+        assert!(announces[0].contains("event=started"));
+        assert_eq!(count_completed(&announces), 0);
+
+        handle.abort();
+        while queries.try_recv().is_ok() {}
+
+        // Restart of a torrent completed before, its pieces are checked
+        // again before it's complete
+        let (completion, handle) = spawn_tracker(false, false);
+
+        let first = queries.recv().await.unwrap();
+        assert!(first.contains("event=started"));
+
+        completion.send(true).unwrap();
+
+        let mut announces = Vec::new();
+        for _ in 0..10 {
+            announces.push(queries.recv().await.unwrap());
+        }
+        assert_eq!(count_completed(&announces), 0);
+
+        handle.abort();
+    }
+}
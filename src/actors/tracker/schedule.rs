@@ -0,0 +1,116 @@
+use std::time::{Duration, Instant};
+
+/// Interval between the announces when the tracker doesn't give one
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    Started,
+    Stopped,
+    Completed,
+    /// Regular announce, without event
+    Periodic,
+}
+
+impl AnnounceEvent {
+    /// Value of the `event` parameter of the HTTP announces,
+    /// empty for the periodic ones
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Stopped => "stopped",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Periodic => "",
+        }
+    }
+}
+
+/// Decides when the next announce to a tracker can be sent.
+///
+/// Announces never happen more often than the `min interval` of the
+/// tracker, except `Stopped` and `Completed` which are always sent
+#[derive(Debug)]
+pub struct AnnounceScheduler {
+    last_announce: Option<Instant>,
+    interval: Duration,
+    min_interval: Option<Duration>,
+}
+
+impl Default for AnnounceScheduler {
+    fn default() -> Self {
+        AnnounceScheduler {
+            last_announce: None,
+            interval: DEFAULT_INTERVAL,
+            min_interval: None,
+        }
+    }
+}
+
+impl AnnounceScheduler {
+    /// Duration to wait before sending an announce with this event,
+    /// zero when it can be sent now
+    pub fn delay(&self, event: AnnounceEvent, now: Instant) -> Duration {
+        let last = match self.last_announce {
+            Some(last) => last,
+            None => return Duration::from_secs(0),
+        };
+
+        let wait = match event {
+            AnnounceEvent::Stopped | AnnounceEvent::Completed => return Duration::from_secs(0),
+            AnnounceEvent::Started => self.min_interval.unwrap_or_default(),
+            AnnounceEvent::Periodic => self.interval.max(self.min_interval.unwrap_or_default()),
+        };
+
+        (last + wait).saturating_duration_since(now)
+    }
+
+    /// Whether an announce was sent already, a `Stopped` is useless
+    /// before that
+    pub fn has_announced(&self) -> bool {
+        self.last_announce.is_some()
+    }
+
+    /// Record an announce sent at `now`, with the intervals of the response
+    pub fn announced(
+        &mut self,
+        now: Instant,
+        interval: Option<Duration>,
+        min_interval: Option<Duration>,
+    ) {
+        self.last_announce = Some(now);
+        if let Some(interval) = interval {
+            self.interval = interval;
+        }
+        if min_interval.is_some() {
+            self.min_interval = min_interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{AnnounceEvent::*, AnnounceScheduler};
+
+    #[test]
+    fn min_interval() {
+        let mut scheduler = AnnounceScheduler::default();
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+
+        // First resume
+        assert_eq!(scheduler.delay(Started, start), secs(0));
+        scheduler.announced(start, Some(secs(1800)), Some(secs(60)));
+
+        // Paused and resumed right after: the announce waits for
+        // the min interval
+        let resume = start + secs(5);
+        assert_eq!(scheduler.delay(Stopped, resume), secs(0));
+        assert_eq!(scheduler.delay(Started, resume), secs(55));
+        assert_eq!(scheduler.delay(Started, start + secs(60)), secs(0));
+
+        assert_eq!(scheduler.delay(Completed, resume), secs(0));
+        assert_eq!(scheduler.delay(Periodic, resume), secs(1795));
+    }
+}
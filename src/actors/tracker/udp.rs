@@ -8,7 +8,7 @@ use std::{
 use async_trait::async_trait;
 use std::net::SocketAddr;
 
-use super::{TrackerConnection, TrackerData};
+use super::{schedule::AnnounceEvent, Announced, TrackerConnection, TrackerData};
 use crate::{errors::TorrentError, peer::peer::PeerExternId, supervisors::torrent::Result};
 
 #[derive(Debug)]
@@ -76,6 +76,17 @@ pub enum Event {
     Stopped,
 }
 
+impl From<AnnounceEvent> for Event {
+    fn from(event: AnnounceEvent) -> Event {
+        match event {
+            AnnounceEvent::Started => Event::Started,
+            AnnounceEvent::Stopped => Event::Stopped,
+            AnnounceEvent::Completed => Event::Completed,
+            AnnounceEvent::Periodic => Event::None,
+        }
+    }
+}
+
 impl TryFrom<u32> for Event {
     type Error = TorrentError;
 
@@ -132,20 +143,21 @@ impl<'a> From<&'a UdpConnection> for AnnounceRequest {
     fn from(c: &'a UdpConnection) -> AnnounceRequest {
         let metadata = &c.data.metadata;
         let state = c.state.as_ref().unwrap();
+        let stats = c.data.counters.stats();
         AnnounceRequest {
             connection_id: state.connection_id,
             action: Action::Announce,
             transaction_id: state.transaction_id,
             info_hash: Arc::clone(&metadata.info_hash),
             peer_id: Arc::clone(&c.data.extern_id),
-            downloaded: 0,
-            left: metadata.files_total_size() as u64,
-            uploaded: 0,
+            downloaded: stats.payload_downloaded,
+            left: c.data.left(),
+            uploaded: stats.payload_uploaded,
             event: Event::Started,
             ip_address: 0,
             key: 0,
             num_want: 100,
-            port: 6881,
+            port: c.data.port,
         }
     }
 }
@@ -276,12 +288,39 @@ pub struct UdpConnection {
     /// This avoid to give up when there are remaining addresses
     /// even after some delay
     all_addrs_tried: bool,
+    /// Timeout of the first request, doubled at each retransmission
+    timeout: Duration,
 }
 
 use tokio::net::UdpSocket;
 
-use crate::udp_ext::WithTimeout;
-use tokio::io::ErrorKind;
+/// Timeout of the first request (BEP 15)
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The request is sent again at most 8 times, after 15 * 2 ^ n seconds
+const MAX_RETRANSMIT: u32 = 8;
+
+/// Wait for the response of the transaction, the other datagrams are
+/// discarded. Returns `None` on timeout
+async fn recv_transaction(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+    transaction_id: u32,
+    timeout: Duration,
+) -> Result<Option<usize>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let n = match tokio::time::timeout_at(deadline, socket.recv(buffer)).await {
+            Ok(n) => n?,
+            Err(_) => return Ok(None),
+        };
+
+        if n >= 8 && buffer[4..8] == transaction_id.to_be_bytes() {
+            return Ok(Some(n));
+        }
+    }
+}
 
 impl UdpConnection {
     #[allow(clippy::new_ret_no_self)]
@@ -296,9 +335,14 @@ impl UdpConnection {
             buffer: smallvec![0; 16],
             current_addr: 0,
             all_addrs_tried: false,
+            timeout: TIMEOUT,
         })
     }
 
+    fn retransmit_timeout(&self, attempt: u32) -> Duration {
+        self.timeout * 2u32.pow(attempt)
+    }
+
     fn next_addr(&mut self) -> &Arc<SocketAddr> {
         if self.current_addr >= self.addrs.len() {
             self.all_addrs_tried = true;
@@ -313,75 +357,67 @@ impl UdpConnection {
         &self.addrs[self.current_addr - 1]
     }
 
+    /// Send the request in the buffer until the tracker answers. Each
+    /// attempt has its own transaction id
     async fn get_response<T>(&mut self, send_size: usize) -> Result<T>
     where
         T: TryFrom<TrackerMessage, Error = TorrentError>,
     {
-        let mut attempts = 0;
+        // The buffer is overwritten by the responses
+        let mut request = self.buffer[..send_size].to_vec();
+        let mut attempt = 0;
 
         loop {
-            println!("RETRY {:?} {:?}", attempts, self.addrs);
             if self.id_expired() {
                 self.connect().await?;
             }
 
-            let socket = &self.state.as_ref().unwrap().socket;
-
-            socket.send(&self.buffer[..send_size]).await?;
-
-            let timeout = Duration::from_secs(10);
+            let state = self.state.as_ref().unwrap();
+            let transaction_id: u32 = rand::random();
+            let timeout = self.retransmit_timeout(attempt);
 
-            let n = match socket.recv_timeout(&mut self.buffer, timeout).await {
-                Ok(n) => n,
-                Err(e) if e.kind() == ErrorKind::TimedOut => {
-                    if attempts == 6 {
-                        return Err(TorrentError::Unresponsive);
-                    }
-                    attempts += 1;
-                    continue;
-                }
-                Err(e) => return Err(e.into()),
-            };
+            request[0..8].copy_from_slice(&state.connection_id.to_be_bytes());
+            request[12..16].copy_from_slice(&transaction_id.to_be_bytes());
 
-            let buffer = self.buffer.get(..n).ok_or(TorrentError::InvalidInput)?;
+            state.socket.send(&request).await?;
 
-            return T::try_from(self.read_response(buffer)?);
+            match recv_transaction(&state.socket, &mut self.buffer, transaction_id, timeout).await?
+            {
+                Some(n) => return T::try_from(self.read_response(&self.buffer[..n])?),
+                None if attempt >= MAX_RETRANSMIT => return Err(TorrentError::Unresponsive),
+                None => attempt += 1,
+            }
         }
     }
 
     async fn connect(&mut self) -> Result<ConnectResponse> {
-        let mut attempts = 0;
+        let mut attempt = 0;
 
         loop {
             let socket = UdpSocket::bind("0:0").await?;
 
             socket.connect(self.next_addr().as_ref()).await?;
 
-            println!("RETRY CONNECT {:?} {:?}", self.addrs, socket.local_addr());
-
             let transaction_id = rand::random();
+            let timeout = self.retransmit_timeout(attempt);
 
             self.write_to_buffer(ConnectRequest::new(transaction_id).into());
 
             socket.send(&self.buffer[..16]).await?;
 
-            let timeout = Duration::from_secs(10);
-
-            let n = match socket.recv_timeout(&mut self.buffer, timeout).await {
-                Ok(n) => n,
-                Err(e) if e.kind() == ErrorKind::TimedOut => {
-                    if attempts >= 6 && self.all_addrs_tried {
-                        return Err(TorrentError::Unresponsive);
+            let n =
+                match recv_transaction(&socket, &mut self.buffer, transaction_id, timeout).await? {
+                    Some(n) => n,
+                    None => {
+                        if attempt >= MAX_RETRANSMIT && self.all_addrs_tried {
+                            return Err(TorrentError::Unresponsive);
+                        }
+                        attempt = (attempt + 1).min(MAX_RETRANSMIT);
+                        continue;
                     }
-                    attempts += 1;
-                    continue;
-                }
-                Err(e) => return Err(e.into()),
-            };
-
-            let buffer = self.buffer.get(0..n).ok_or(TorrentError::InvalidInput)?;
+                };
 
-            let resp: ConnectResponse = self.read_response(buffer)?.try_into()?;
+            let resp: ConnectResponse = self.read_response(&self.buffer[..n])?.try_into()?;
 
             self.state = Some(UdpState {
                 transaction_id,
@@ -521,7 +557,9 @@ impl UdpConnection {
                 }))
             }
             Action::Error => {
-                unreachable!() // TODO
+                let message = &buffer[cursor.position() as usize..];
+                let message = String::from_utf8_lossy(message).into_owned();
+                Err(TorrentError::Tracker(message))
             }
         }
     }
@@ -536,20 +574,24 @@ impl UdpConnection {
 
 #[async_trait]
 impl TrackerConnection for UdpConnection {
-    async fn announce(&mut self, addr: &mut usize) -> Result<Vec<SocketAddr>> {
+    async fn announce(&mut self, event: AnnounceEvent) -> Result<Announced> {
         if self.state.is_none() {
             self.connect().await?;
             self.buffer = smallvec![0; 16 * 1024];
         }
 
-        let req = AnnounceRequest::from(&*self).into();
-        let n = self.write_to_buffer(req);
+        let mut req = AnnounceRequest::from(&*self);
+        req.event = event.into();
+        let n = self.write_to_buffer(req.into());
 
         let resp: AnnounceResponse = self.get_response(n).await?;
 
-        *addr = self.current_addr - 1;
-
-        Ok(resp.addrs)
+        Ok(Announced {
+            index: self.current_addr - 1,
+            peers: resp.addrs,
+            interval: Some(Duration::from_secs(resp.interval as u64)),
+            min_interval: None,
+        })
     }
 
     async fn scrape(&mut self) -> Result<()> {
@@ -566,3 +608,119 @@ impl TrackerConnection for UdpConnection {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use smallvec::smallvec;
+    use tokio::net::UdpSocket;
+
+    use super::{AnnounceEvent, TrackerConnection, TrackerData, UdpConnection};
+    use crate::{
+        errors::TorrentError,
+        metadata::{TestTorrent, Torrent},
+        peer::peer::PeerExternId,
+        supervisors::torrent::{ByteCounters, TorrentGauges},
+    };
+
+    fn torrent(announce: &str) -> Torrent {
+        TestTorrent::new().announce(announce).build()
+    }
+
+    /// Drops the first connect, answers the second one with a wrong
+    /// transaction id before the right one. The first announce gets
+    /// 1 peer, the next ones an error
+    async fn tracker(socket: UdpSocket) {
+        let mut buffer = [0; 1024];
+        let mut nconnects = 0;
+        let mut nannounces = 0;
+
+        loop {
+            let (n, addr) = socket.recv_from(&mut buffer).await.unwrap();
+            let request = &buffer[..n];
+            let transaction_id = &request[12..16];
+
+            let mut response = Vec::new();
+
+            if request[8..12] == 0u32.to_be_bytes() {
+                assert_eq!(request[..8], 0x0417_2710_1980u64.to_be_bytes());
+                nconnects += 1;
+                if nconnects == 1 {
+                    continue;
+                }
+
+                let mut wrong = vec![0, 0, 0, 0, 0, 0, 0, 0];
+                wrong.extend_from_slice(&1u64.to_be_bytes());
+                socket.send_to(&wrong, addr).await.unwrap();
+
+                response.extend_from_slice(&0u32.to_be_bytes());
+                response.extend_from_slice(transaction_id);
+                response.extend_from_slice(&42u64.to_be_bytes());
+            } else {
+                assert_eq!(request[..8], 42u64.to_be_bytes());
+                assert_eq!(request[16..36], [7; 20]);
+                nannounces += 1;
+
+                if nannounces == 1 {
+                    response.extend_from_slice(&1u32.to_be_bytes());
+                    response.extend_from_slice(transaction_id);
+                    for n in &[1800u32, 3, 5] {
+                        response.extend_from_slice(&n.to_be_bytes());
+                    }
+                    response.extend_from_slice(&[0xC0, 0xA8, 0x00, 0x01, 0x1A, 0xE1]);
+                } else {
+                    response.extend_from_slice(&3u32.to_be_bytes());
+                    response.extend_from_slice(transaction_id);
+                    response.extend_from_slice(b"unregistered torrent");
+                }
+            }
+
+            socket.send_to(&response, addr).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_retransmit() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(tracker(socket));
+
+        let metadata = Arc::new(torrent(&format!("udp://{}", addr)));
+        let (supervisor, _) = async_channel::unbounded();
+
+        let data = Arc::new(TrackerData {
+            url: metadata.get_urls_tiers().remove(0),
+            metadata,
+            supervisor,
+            extern_id: Arc::new(PeerExternId::generate()),
+            counters: Arc::new(ByteCounters::default()),
+            gauges: Arc::new(TorrentGauges::default()),
+            port: 6881,
+            batcher: None,
+            completion: tokio::sync::watch::channel(false).1,
+            announce_completed: true,
+            paused: tokio::sync::watch::channel(false).1,
+            shutdown: tokio::sync::watch::channel(false).1,
+        });
+
+        let mut connection = UdpConnection {
+            data,
+            addrs: vec![Arc::new(addr)],
+            state: None,
+            buffer: smallvec![0; 16],
+            current_addr: 0,
+            all_addrs_tried: false,
+            timeout: Duration::from_millis(100),
+        };
+
+        let announced = connection.announce(AnnounceEvent::Started).await.unwrap();
+        assert_eq!(announced.peers, vec!["192.168.0.1:6881".parse().unwrap()]);
+        assert_eq!(announced.interval, Some(Duration::from_secs(1800)));
+
+        match connection.announce(AnnounceEvent::Periodic).await {
+            Err(TorrentError::Tracker(message)) => assert_eq!(message, "unregistered torrent"),
+            res => panic!("Expected an error {:?}", res),
+        }
+    }
+}
@@ -5,97 +5,175 @@ use serde::{
 
 use serde::Deserialize;
 
+use hashbrown::{HashMap, HashSet};
 use std::{cell::Cell, fmt};
 
+/// The offsets are from the start of the input, where the parsing failed
 #[derive(Debug, PartialEq)]
-pub enum DeserializeError {
-    UnexpectedEOF,
-    WrongCharacter(u8),
+pub enum BencodeError {
+    UnexpectedEof {
+        offset: usize,
+    },
+    /// Not a digit, or the integer overflows
+    InvalidInteger {
+        offset: usize,
+    },
+    /// The length prefix of a string is invalid or longer than the
+    /// remaining bytes
+    InvalidLength {
+        offset: usize,
+    },
+    /// Not the start of a value, or not a string where a key is expected
+    ExpectedDelimiter {
+        offset: usize,
+        found: u8,
+    },
     End,
     InfoHashMissing,
     TooDeep,
     NoFile,
     EmptyFile,
     UnalignedPieces,
+    /// The `piece length` is not a power of 2, or the number of
+    /// `pieces` doesn't match the size of the files
+    InconsistentMetadata(String),
+    /// Data after the top-level value
+    TrailingBytes,
+    /// A key is repeated in a dictionary, with `DuplicateKeyPolicy::Error`
+    DuplicateKey(String),
     Message(String),
 }
 
-type Result<T> = std::result::Result<T, DeserializeError>;
+type Result<T> = std::result::Result<T, BencodeError>;
 
-impl serde::de::Error for DeserializeError {
+/// Entry kept when a key is repeated in a dictionary
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DuplicateKeyPolicy {
+    First,
+    Last,
+    Error,
+}
+
+/// How `read_meta_with_options` handles the non-conforming files
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// Ignore the bytes after the top-level dictionary
+    pub allow_trailing_bytes: bool,
+    pub duplicate_key: DuplicateKeyPolicy,
+}
+
+/// Same as `read_meta`: duplicate keys and trailing bytes are errors
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            allow_trailing_bytes: false,
+            duplicate_key: DuplicateKeyPolicy::Error,
+        }
+    }
+}
+
+impl serde::de::Error for BencodeError {
     fn custom<T: fmt::Display>(msg: T) -> Self {
-        DeserializeError::Message(msg.to_string())
+        BencodeError::Message(msg.to_string())
     }
 }
 
-impl fmt::Display for DeserializeError {
+impl fmt::Display for BencodeError {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str(&format!("{:?}", self))
+        match self {
+            BencodeError::UnexpectedEof { offset } => {
+                write!(formatter, "Unexpected end of input at byte {}", offset)
+            }
+            BencodeError::InvalidInteger { offset } => {
+                write!(formatter, "Invalid integer at byte {}", offset)
+            }
+            BencodeError::InvalidLength { offset } => {
+                write!(formatter, "Invalid string length at byte {}", offset)
+            }
+            BencodeError::ExpectedDelimiter { offset, found } => write!(
+                formatter,
+                "Unexpected character {:?} at byte {}",
+                *found as char, offset
+            ),
+            e => formatter.write_str(&format!("{:?}", e)),
+        }
         //formatter.write_str(std::error::Error::description(self))
     }
 }
 
-impl std::error::Error for DeserializeError {
+impl std::error::Error for BencodeError {
     fn description(&self) -> &str {
         "aa"
         //self.msg.as_str()
     }
 }
 
+/// The bytes after the value are ignored, the extended messages carry
+/// data after their dictionary. The repeated keys are given as is to
+/// the value, only the metainfo is decoded with a `DuplicateKeyPolicy`
 pub fn from_bytes<'de, T>(s: &'de [u8]) -> Result<T>
 where
     T: Deserialize<'de>,
 {
-    let mut de: Deserializer = Deserializer::new(s);
+    let mut de: Deserializer = Deserializer::new(s, None);
     T::deserialize(&mut de)
 }
 
+/// Same as `from_bytes`, with the bytes following the value
+pub fn from_bytes_with_rest<'de, T>(s: &'de [u8]) -> Result<(T, &'de [u8])>
+where
+    T: Deserialize<'de>,
+{
+    let mut de: Deserializer = Deserializer::new(s, None);
+    let value = T::deserialize(&mut de)?;
+    Ok((value, de.input))
+}
+
 pub fn from_bytes_with_hash<'de, T>(s: &'de [u8]) -> Result<(T, Vec<u8>)>
 where
     T: Deserialize<'de>,
 {
-    let mut de: Deserializer = Deserializer::new(s);
+    let (value, info) = from_bytes_with_options(s, DecodeOptions::default())?;
+    Ok((value, sha1::Sha1::from(info).digest().bytes().to_vec()))
+}
+
+/// Returns the value with the bytes of its `info` dictionary
+fn from_bytes_with_options<'de, T>(s: &'de [u8], options: DecodeOptions) -> Result<(T, &'de [u8])>
+where
+    T: Deserialize<'de>,
+{
+    let mut de: Deserializer = Deserializer::new(s, Some(options.duplicate_key));
     let res = T::deserialize(&mut de)?;
 
-    let info_hash = if !de.start_info.is_null() && de.end_info > de.start_info {
+    if !options.allow_trailing_bytes && !de.input.is_empty() {
+        return Err(BencodeError::TrailingBytes);
+    }
+
+    let info = if !de.start_info.is_null() && de.end_info > de.start_info {
         let len = de.end_info as usize - de.start_info as usize;
-        let slice = unsafe { std::slice::from_raw_parts(de.start_info, len) };
-        sha1::Sha1::from(&slice[..]).digest().bytes().to_vec()
+        unsafe { std::slice::from_raw_parts(de.start_info, len) }
     } else {
         //eprintln!("START={:?} END={:?}", de.start_info, de.end_info);
 
-        return Err(DeserializeError::InfoHashMissing);
+        return Err(BencodeError::InfoHashMissing);
     };
 
-    Ok((res, info_hash))
+    Ok((res, info))
 }
 
-use crate::metadata::{InfoFile, MetaTorrent, Torrent};
+use crate::metadata::{MetaTorrent, Torrent};
 
 pub fn read_meta(s: &[u8]) -> Result<Torrent> {
-    let (meta, info_hash): (MetaTorrent, Vec<u8>) = from_bytes_with_hash(s)?;
+    read_meta_with_options(s, DecodeOptions::default())
+}
 
-    if meta.info.pieces.len() % 20 != 0 {
-        return Err(DeserializeError::UnalignedPieces);
-    }
+pub fn read_meta_with_options(s: &[u8], options: DecodeOptions) -> Result<Torrent> {
+    let (meta, info): (MetaTorrent, _) = from_bytes_with_options(s, options)?;
 
-    match &meta.info.files {
-        InfoFile::Multiple { files, .. } => {
-            if files.is_empty() {
-                return Err(DeserializeError::NoFile);
-            }
-        }
-        InfoFile::Single { length, .. } => {
-            if *length == 0 {
-                return Err(DeserializeError::EmptyFile);
-            }
-        }
-    }
+    let torrent = Torrent::from_meta(meta, info);
+    torrent.validate()?;
 
-    Ok(Torrent {
-        meta,
-        info_hash: info_hash.into(),
-    })
+    Ok(torrent)
 }
 
 // 4b3ea6a5b1e62537dceb67230248ff092a723e4d
@@ -109,20 +187,39 @@ pub struct Deserializer<'de> {
     info_depth: i64,
     // Fix v2_deep_recursion.torrent
     depth: Cell<u16>,
+    /// `None` gives the repeated keys to the visitor
+    duplicate_key: Option<DuplicateKeyPolicy>,
+    /// Length of the whole input, to compute the offsets
+    len: usize,
 }
 
 #[doc(hidbn)]
 impl<'de> Deserializer<'de> {
-    fn new(input: &'de [u8]) -> Self {
+    fn new(input: &'de [u8], duplicate_key: Option<DuplicateKeyPolicy>) -> Self {
         Deserializer {
             input,
+            len: input.len(),
             start_info: std::ptr::null(),
             end_info: std::ptr::null(),
             info_depth: 0,
             depth: Cell::new(0),
+            duplicate_key,
         }
     }
 
+    /// Offset of `input`, a suffix of the whole input
+    fn offset_of(&self, input: &[u8]) -> usize {
+        self.len - input.len()
+    }
+
+    fn offset(&self) -> usize {
+        self.offset_of(self.input)
+    }
+
+    fn eof(&self) -> BencodeError {
+        BencodeError::UnexpectedEof { offset: self.len }
+    }
+
     fn peek(&self) -> Option<u8> {
         self.input.get(0).copied()
     }
@@ -132,36 +229,119 @@ impl<'de> Deserializer<'de> {
             let _ = self.consume();
             return Ok(c);
         }
-        Err(DeserializeError::UnexpectedEOF)
+        Err(self.eof())
     }
 
     fn consume(&mut self) -> Result<()> {
-        self.input = &self.input.get(1..).ok_or(DeserializeError::UnexpectedEOF)?;
+        self.input = self.input.get(1..).ok_or_else(|| self.eof())?;
         Ok(())
     }
 
     fn skip(&mut self, n: i64) -> Result<()> {
-        self.input = &self
-            .input
-            .get(n as usize..)
-            .ok_or(DeserializeError::UnexpectedEOF)?;
+        self.input = self.input.get(n as usize..).ok_or_else(|| self.eof())?;
         Ok(())
     }
 
+    /// Digits until `stop`, which is `:` for the length of the strings
     fn read_integer(&mut self, stop: u8) -> Result<i64> {
         let mut n: i64 = 0;
 
         loop {
+            let offset = self.offset();
+            let invalid = || match stop {
+                b':' => BencodeError::InvalidLength { offset },
+                _ => BencodeError::InvalidInteger { offset },
+            };
+
             match self.next()? {
-                c @ b'0'..=b'9' => n = (n * 10) + (c - b'0') as i64,
+                c @ b'0'..=b'9' => {
+                    n = n
+                        .checked_mul(10)
+                        .and_then(|n| n.checked_add((c - b'0') as i64))
+                        .ok_or_else(invalid)?
+                }
                 c if c == stop => break,
-                c => return Err(DeserializeError::WrongCharacter(c)),
+                _ => return Err(invalid()),
             }
         }
 
         Ok(n)
     }
 
+    /// Split the string at the start of `input` from the rest
+    fn split_string(&self, input: &'de [u8]) -> Result<(&'de [u8], &'de [u8])> {
+        let start = self.offset_of(input);
+        let colon = memchr::memchr(b':', input).ok_or_else(|| self.eof())?;
+        let mut len: usize = 0;
+
+        for (index, &c) in input[..colon].iter().enumerate() {
+            let invalid = BencodeError::InvalidLength {
+                offset: start + index,
+            };
+
+            match c {
+                b'0'..=b'9' => {
+                    len = len
+                        .checked_mul(10)
+                        .and_then(|n| n.checked_add((c - b'0') as usize))
+                        .ok_or(invalid)?
+                }
+                _ => return Err(invalid),
+            }
+        }
+
+        let rest = &input[colon + 1..];
+        if rest.len() < len {
+            return Err(BencodeError::InvalidLength { offset: start });
+        }
+
+        Ok(rest.split_at(len))
+    }
+
+    /// Returns the input after the value at its start, without decoding it
+    fn skip_value(&self, input: &'de [u8], depth: u16) -> Result<&'de [u8]> {
+        match input.first() {
+            Some(b'i') => {
+                let end = memchr::memchr(b'e', input).ok_or_else(|| self.eof())?;
+                Ok(&input[end + 1..])
+            }
+            Some(b'l') | Some(b'd') => {
+                if depth > 100 {
+                    return Err(BencodeError::TooDeep);
+                }
+
+                let mut rest = &input[1..];
+                loop {
+                    match rest.first() {
+                        Some(b'e') => return Ok(&rest[1..]),
+                        Some(_) => rest = self.skip_value(rest, depth + 1)?,
+                        None => return Err(self.eof()),
+                    }
+                }
+            }
+            Some(b'0'..=b'9') => self.split_string(input).map(|(_, rest)| rest),
+            Some(&found) => Err(BencodeError::ExpectedDelimiter {
+                offset: self.offset_of(input),
+                found,
+            }),
+            None => Err(self.eof()),
+        }
+    }
+
+    /// Offset of the last entry of each key, in the dictionary at `input`
+    fn last_entries(&self, mut input: &'de [u8]) -> Result<HashMap<&'de [u8], usize>> {
+        let mut last = HashMap::new();
+
+        while input.first() != Some(&b'e') {
+            let offset = self.offset_of(input);
+            let (key, rest) = self.split_string(input)?;
+            last.insert(key, offset);
+            input = self.skip_value(rest, 0)?;
+        }
+
+        Ok(last)
+    }
+
     fn read_number(&mut self) -> Result<i64> {
         self.consume()?; // 'i'
 
@@ -179,12 +359,13 @@ impl<'de> Deserializer<'de> {
     }
 
     fn read_string(&mut self) -> Result<&'de [u8]> {
+        let start = self.offset();
         let len = self.read_integer(b':')?;
 
         let s = self
             .input
             .get(..len as usize)
-            .ok_or(DeserializeError::UnexpectedEOF)?;
+            .ok_or(BencodeError::InvalidLength { offset: start })?;
 
         self.skip(len)?;
 
@@ -202,14 +383,14 @@ impl<'de> Deserializer<'de> {
 
 #[doc(hidden)]
 impl<'a, 'de> serde::de::Deserializer<'de> for &'a mut Deserializer<'de> {
-    type Error = DeserializeError;
+    type Error = BencodeError;
 
     fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
         // println!("NEXT: {:?}", self.peek());
-        match self.peek().ok_or(DeserializeError::UnexpectedEOF)? {
+        match self.peek().ok_or_else(|| self.eof())? {
             b'i' => {
                 // println!("FOUND NUMBER", );
                 visitor.visit_i64(self.read_number()?)
@@ -222,7 +403,7 @@ impl<'a, 'de> serde::de::Deserializer<'de> for &'a mut Deserializer<'de> {
             b'd' => {
                 let depth = self.depth.get();
                 if depth > 100 {
-                    return Err(DeserializeError::TooDeep);
+                    return Err(BencodeError::TooDeep);
                 }
                 self.depth.set(depth + 1);
                 // println!("FOUND DICT {}", self.depth.get());
@@ -233,7 +414,10 @@ impl<'a, 'de> serde::de::Deserializer<'de> for &'a mut Deserializer<'de> {
                 // println!("FOUND STRING", );
                 visitor.visit_borrowed_bytes(self.read_string()?)
             }
-            c => Err(DeserializeError::WrongCharacter(c)),
+            found => Err(BencodeError::ExpectedDelimiter {
+                offset: self.offset(),
+                found,
+            }),
         }
     }
 
@@ -244,8 +428,19 @@ impl<'a, 'de> serde::de::Deserializer<'de> for &'a mut Deserializer<'de> {
         visitor.visit_some(self)
     }
 
+    /// Booleans are serialized as the integers 0 and 1
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.peek() {
+            Some(b'i') => visitor.visit_bool(self.read_number()? != 0),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
     forward_to_deserialize_any! {
-        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string
+        i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string
         unit unit_struct seq tuple tuple_struct map struct identifier
         newtype_struct ignored_any enum bytes byte_buf
     }
@@ -253,6 +448,11 @@ impl<'a, 'de> serde::de::Deserializer<'de> for &'a mut Deserializer<'de> {
 
 struct BencAccess<'a, 'de> {
     de: &'a mut Deserializer<'de>,
+    /// Keys of the dictionary read so far
+    keys: HashSet<&'de [u8]>,
+    /// Offset of the last entry of each key, with `DuplicateKeyPolicy::Last`.
+    /// The dictionary is read once, on its first entry
+    last: Option<HashMap<&'de [u8], usize>>,
 }
 
 impl<'a, 'de> BencAccess<'a, 'de> {
@@ -262,17 +462,62 @@ impl<'a, 'de> BencAccess<'a, 'de> {
             let _s = de.input;
             //println!("DEPTH[NEW]={:?} {:?}", de.info_depth, String::from_utf8((&s[0..std::cmp::min(50, s.len())]).to_vec()));
         }
-        BencAccess { de }
+        BencAccess {
+            de,
+            keys: HashSet::new(),
+            last: None,
+        }
+    }
+
+    /// Whether the entry at the input is ignored, because of its
+    /// duplicate key
+    fn is_ignored_entry(&mut self) -> Result<bool> {
+        let policy = match self.de.duplicate_key {
+            Some(policy) => policy,
+            None => return Ok(false),
+        };
+
+        let (key, rest) = match self.de.split_string(self.de.input) {
+            Ok(entry) => entry,
+            // Not a string, the error is reported by the deserializer
+            Err(_) => return Ok(false),
+        };
+
+        let ignored = match policy {
+            DuplicateKeyPolicy::Error if self.keys.contains(&key) => {
+                let key = String::from_utf8_lossy(key).into_owned();
+                return Err(BencodeError::DuplicateKey(key));
+            }
+            DuplicateKeyPolicy::Error => false,
+            DuplicateKeyPolicy::First => self.keys.contains(&key),
+            DuplicateKeyPolicy::Last => {
+                if self.last.is_none() {
+                    self.last = Some(self.de.last_entries(self.de.input)?);
+                }
+                let offset = self.de.offset();
+                self.last.as_ref().and_then(|last| last.get(key)) != Some(&offset)
+            }
+        };
+
+        if ignored {
+            self.de.input = self.de.skip_value(rest, 0)?;
+        } else {
+            self.keys.insert(key);
+        }
+
+        Ok(ignored)
     }
 }
 
 impl<'a, 'de> MapAccess<'de> for BencAccess<'a, 'de> {
-    type Error = DeserializeError;
+    type Error = BencodeError;
 
     fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
     where
         K: DeserializeSeed<'de>,
     {
+        while self.de.peek() != Some(b'e') && self.is_ignored_entry()? {}
+
         if self.de.peek() == Some(b'e') {
             let _ = self.de.consume();
             self.de.info_depth -= 1;
@@ -297,7 +542,7 @@ impl<'a, 'de> MapAccess<'de> for BencAccess<'a, 'de> {
 }
 
 impl<'a, 'de> SeqAccess<'de> for BencAccess<'a, 'de> {
-    type Error = DeserializeError;
+    type Error = BencodeError;
 
     fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
     where
@@ -324,9 +569,15 @@ impl<'a, 'de> SeqAccess<'de> for BencAccess<'a, 'de> {
 #[cfg(test)]
 mod tests {
 
-    use super::{from_bytes, DeserializeError, Result};
+    use super::{
+        from_bytes, read_meta, read_meta_with_options, BencodeError, DecodeOptions,
+        DuplicateKeyPolicy, Result,
+    };
     use serde::Deserialize;
 
+    const INFO: &[u8] =
+        b"4:infod6:lengthi10e4:name1:a12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+
     #[test]
     fn test_dict() {
         #[derive(Deserialize, PartialEq, Debug)]
@@ -361,7 +612,13 @@ mod tests {
         let res: Result<Dict> = from_bytes(b"d1:ai1e1:be");
 
         println!("{:?}", res);
-        assert_eq!(res, Err(DeserializeError::WrongCharacter(101)));
+        assert_eq!(
+            res,
+            Err(BencodeError::ExpectedDelimiter {
+                offset: 10,
+                found: b'e'
+            })
+        );
     }
 
     #[test]
@@ -378,6 +635,127 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn duplicate_keys() {
+        let mut data = b"d8:announce13:http://a.test8:announce13:http://b.test".to_vec();
+        data.extend_from_slice(INFO);
+        data.push(b'e');
+
+        let announce = |duplicate_key| {
+            let options = DecodeOptions {
+                duplicate_key,
+                ..Default::default()
+            };
+            read_meta_with_options(&data, options).map(|t| t.meta.announce.unwrap())
+        };
+
+        assert_eq!(
+            announce(DuplicateKeyPolicy::First).unwrap(),
+            "http://a.test"
+        );
+        assert_eq!(announce(DuplicateKeyPolicy::Last).unwrap(), "http://b.test");
+        assert_eq!(
+            announce(DuplicateKeyPolicy::Error).unwrap_err(),
+            BencodeError::DuplicateKey("announce".to_string())
+        );
+        assert!(read_meta(&data).is_err());
+
+        // Other messages are lenient, the map keeps the last value
+        let map: std::collections::BTreeMap<String, i64> = from_bytes(b"d1:ai1e1:ai2ee").unwrap();
+        assert_eq!(map["a"], 2);
+
+        // The skipped entry doesn't change the info hash
+        let mut twice = b"d".to_vec();
+        twice.extend_from_slice(INFO);
+        twice.extend_from_slice(INFO);
+        twice.push(b'e');
+        let options = DecodeOptions {
+            duplicate_key: DuplicateKeyPolicy::Last,
+            ..Default::default()
+        };
+        let single = read_meta(&[b"d", INFO, b"e"].concat()).unwrap();
+        let torrent = read_meta_with_options(&twice, options).unwrap();
+        assert_eq!(torrent.info_hash, single.info_hash);
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut data = b"d8:announce13:http://a.test".to_vec();
+        data.extend_from_slice(INFO);
+        data.extend_from_slice(b"e\n\0\0garbage");
+
+        assert_eq!(read_meta(&data).unwrap_err(), BencodeError::TrailingBytes);
+
+        let lenient = DecodeOptions {
+            allow_trailing_bytes: true,
+            ..Default::default()
+        };
+        assert!(read_meta_with_options(&data, lenient).is_ok());
+
+        let end = data.len() - 10;
+        assert!(read_meta(&data[..end]).is_ok());
+    }
+
+    #[test]
+    fn error_offsets() {
+        let torrent = [b"d", INFO, b"e"].concat();
+        let position = |pattern: &[u8]| torrent.windows(pattern.len()).position(|w| w == pattern);
+        let prefix = position(b"20:").unwrap();
+
+        // The pieces are truncated: the length prefix is longer than the
+        // remaining bytes
+        assert_eq!(
+            read_meta(&torrent[..torrent.len() - 5]).unwrap_err(),
+            BencodeError::InvalidLength { offset: prefix }
+        );
+
+        // Not a digit in `length`
+        let digit = position(b"lengthi10e").unwrap() + 8;
+        let mut invalid = torrent.clone();
+        invalid[digit] = b'x';
+        assert_eq!(
+            read_meta(&invalid).unwrap_err(),
+            BencodeError::InvalidInteger { offset: digit }
+        );
+
+        // Missing trailing `e`
+        assert_eq!(
+            read_meta(&torrent[..torrent.len() - 1]).unwrap_err(),
+            BencodeError::UnexpectedEof {
+                offset: torrent.len() - 1
+            }
+        );
+
+        let res: Result<(i64, i64)> = from_bytes(b"li1ex2:abe");
+        assert_eq!(
+            res.unwrap_err(),
+            BencodeError::ExpectedDelimiter {
+                offset: 4,
+                found: b'x'
+            }
+        );
+
+        let res: Result<Vec<&[u8]>> = from_bytes(b"l3a:xe");
+        assert_eq!(res.unwrap_err(), BencodeError::InvalidLength { offset: 2 });
+    }
+
     // TODO: Add more tests from
     // https://github.com/arvidn/libtorrent/blob/RC_1_2/test/test_bdecode.cpp
+
+    #[test]
+    fn encode_value() {
+        use crate::bencode::{encode, Value};
+
+        let value: Value = super::from_bytes(b"d1:bli1e2:abe1:ad1:zi-3eee").unwrap();
+        // The keys are sorted
+        assert_eq!(encode(&value), b"d1:ad1:zi-3ee1:bli1e2:abee");
+        assert_eq!(
+            value,
+            Value::dict(vec![
+                ("b", Some(vec![Value::from(1i64), "ab".into()].into())),
+                ("a", Some(Value::dict(vec![("z", Some((-3i64).into()))]))),
+                ("c", None),
+            ])
+        );
+    }
 }
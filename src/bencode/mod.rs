@@ -2,6 +2,9 @@ pub mod de;
 pub mod ser;
 
 use serde::{de as serde_de, de::Visitor, Deserialize, Deserializer};
+use serde_bytes::ByteBuf;
+
+use std::{collections::BTreeMap, io::Write};
 
 #[derive(Debug)]
 pub struct PtrBuf<'a> {
@@ -29,3 +32,133 @@ impl<'a, 'de: 'a> Deserialize<'de> for PtrBuf<'a> {
         deserializer.deserialize_bytes(PtrBufVisitor)
     }
 }
+
+/// Any bencode value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    /// Sorted by their raw bytes, as bencode requires
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Value {
+        Value::Integer(n)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Value {
+        Value::Integer(n as i64)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(bytes: &[u8]) -> Value {
+        Value::Bytes(bytes.to_vec())
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(list: Vec<T>) -> Value {
+        Value::List(list.into_iter().map(Into::into).collect())
+    }
+}
+
+impl Value {
+    /// Dictionary of the entries with a value
+    pub fn dict<'a>(entries: impl IntoIterator<Item = (&'a str, Option<Value>)>) -> Value {
+        Value::Dict(
+            entries
+                .into_iter()
+                .filter_map(|(key, value)| Some((key.as_bytes().to_vec(), value?)))
+                .collect(),
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D>(deserializer: D) -> Result<Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ValueVisitor;
+        impl<'de> Visitor<'de> for ValueVisitor {
+            type Value = Value;
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("Expecting a bencode value")
+            }
+            fn visit_i64<E>(self, n: i64) -> Result<Value, E> {
+                Ok(Value::Integer(n))
+            }
+            fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Value, E> {
+                Ok(Value::Bytes(bytes.to_vec()))
+            }
+            fn visit_seq<A>(self, mut seq: A) -> Result<Value, A::Error>
+            where
+                A: serde_de::SeqAccess<'de>,
+            {
+                let mut list = Vec::new();
+                while let Some(value) = seq.next_element()? {
+                    list.push(value);
+                }
+                Ok(Value::List(list))
+            }
+            fn visit_map<A>(self, mut map: A) -> Result<Value, A::Error>
+            where
+                A: serde_de::MapAccess<'de>,
+            {
+                let mut dict = BTreeMap::new();
+                while let Some((key, value)) = map.next_entry::<ByteBuf, Value>()? {
+                    dict.insert(key.into_vec(), value);
+                }
+                Ok(Value::Dict(dict))
+            }
+        }
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+/// Canonical bencode of `value`
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_to(value, &mut out);
+    out
+}
+
+/// Append the bencode of `value` to `out`
+pub fn encode_to(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Integer(n) => {
+            write!(out, "i{}e", n).unwrap();
+        }
+        Value::Bytes(bytes) => {
+            write!(out, "{}:", bytes.len()).unwrap();
+            out.extend_from_slice(bytes);
+        }
+        Value::List(list) => {
+            out.push(b'l');
+            for value in list {
+                encode_to(value, out);
+            }
+            out.push(b'e');
+        }
+        Value::Dict(dict) => {
+            out.push(b'd');
+            for (key, value) in dict {
+                write!(out, "{}:", key.len()).unwrap();
+                out.extend_from_slice(key);
+                encode_to(value, out);
+            }
+            out.push(b'e');
+        }
+    }
+}
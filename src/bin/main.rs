@@ -5,9 +5,95 @@
     clippy::large_enum_variant
 )]
 
-use std::io::{self, Read};
+use std::{
+    io::{self, BufRead, Read},
+    net::{SocketAddr, ToSocketAddrs},
+};
+
+use rustorrent::{
+    bencode::de::{self, DecodeOptions},
+    metadata::Torrent,
+    session::Session,
+    supervisors::torrent::{ByteStats, PiecesDebug, TorrentOptions},
+};
+use serde::Serialize;
+
+/// Output of `--json`, the field names are stable
+#[derive(Serialize)]
+struct JsonTorrent {
+    info_hash: String,
+    name: String,
+    length: usize,
+    piece_length: u64,
+    num_pieces: usize,
+    files: Vec<JsonFile>,
+    trackers: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct JsonFile {
+    path: String,
+    length: u64,
+}
+
+/// Live status printed with `--json`, on each line read from stdin
+#[derive(Serialize)]
+struct JsonStatus {
+    info_hash: String,
+    num_pieces: usize,
+    verified: usize,
+    checking: usize,
+    downloading: usize,
+    missing: usize,
+    choked_by_all: bool,
+    downloaded: u64,
+    uploaded: u64,
+    total_downloaded: u64,
+    total_uploaded: u64,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl From<&Torrent> for JsonTorrent {
+    fn from(torrent: &Torrent) -> JsonTorrent {
+        JsonTorrent {
+            info_hash: to_hex(&torrent.info_hash),
+            name: torrent.name(),
+            length: torrent.files_total_size(),
+            piece_length: torrent.meta.info.piece_length,
+            num_pieces: torrent.meta.info.pieces.len() / 20,
+            files: torrent
+                .files()
+                .iter()
+                .map(|f| JsonFile {
+                    path: f.path.to_string_lossy().into_owned(),
+                    length: f.length,
+                })
+                .collect(),
+            trackers: torrent.iter_urls().map(|u| u.to_string()).collect(),
+        }
+    }
+}
 
-use rustorrent::{bencode::de, session::Session};
+impl JsonStatus {
+    fn new(info_hash: &[u8], pieces: PiecesDebug, bytes: ByteStats) -> JsonStatus {
+        JsonStatus {
+            info_hash: to_hex(info_hash),
+            num_pieces: pieces.num_pieces,
+            verified: pieces.verified,
+            checking: pieces.checking,
+            downloading: pieces.downloading,
+            missing: pieces.missing,
+            choked_by_all: pieces.choked_by_all,
+            downloaded: bytes.payload_downloaded,
+            uploaded: bytes.payload_uploaded,
+            total_downloaded: bytes.total_downloaded,
+            total_uploaded: bytes.total_uploaded,
+        }
+    }
+}
 
 // use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
@@ -273,28 +359,91 @@ async fn main() {
 
     // handle.read_to_end(&mut buffer).unwrap();
 
-    let file = env!("CARGO_MANIFEST_DIR").to_owned()
-        + "/scripts/Fedora-Workstation-Live-x86_64-33.torrent";
+    let args: Vec<String> = std::env::args().collect();
+    let debug_pieces = args.iter().any(|arg| arg == "--debug-pieces");
+    let json = args.iter().any(|arg| arg == "--json");
+
+    // `--peer host:port` connects directly to the peer, without trackers
+    let peers: Vec<SocketAddr> = args
+        .windows(2)
+        .filter(|w| w[0] == "--peer")
+        .flat_map(|w| w[1].to_socket_addrs().expect("Invalid peer address"))
+        .collect();
+
+    let file = args
+        .windows(2)
+        .find(|w| w[0] == "--torrent")
+        .map(|w| w[1].clone())
+        .unwrap_or_else(|| {
+            env!("CARGO_MANIFEST_DIR").to_owned()
+                + "/scripts/Fedora-Workstation-Live-x86_64-33.torrent"
+        });
 
     // let file = "/home/sebastien/Downloads/Fedora-Workstation-Live-x86_64-33.torrent";
     // let file = "/home/sebastien/Downloads/Fedora-Workstation-Live-x86_64-33_Beta.torrent";
     // let file = "/home/sebastien/Downloads/ubuntu-20.10-desktop-amd64.iso.torrent";
-    let buffer = std::fs::read(file).unwrap();
+    let buffer = std::fs::read(&file).unwrap();
 
     //let (meta, info) = de::from_bytes_with_hash::<MetaTorrent>(&buffer).unwrap();
-    let torrent = de::read_meta(&buffer).unwrap();
-
-    println!("TORRENT={:#?}", torrent);
-
+    // Many torrent files end with a newline
+    let options = DecodeOptions {
+        allow_trailing_bytes: true,
+        ..Default::default()
+    };
+    let torrent = match de::read_meta_with_options(&buffer, options) {
+        Ok(torrent) => torrent,
+        Err(e) => {
+            eprintln!("Invalid torrent {}: {}", file, e);
+            std::process::exit(1);
+        }
+    };
+
+    if json {
+        let description = JsonTorrent::from(&torrent);
+        println!("{}", serde_json::to_string(&description).unwrap());
+    } else {
+        println!("TORRENT={:#?}", torrent);
+    }
+
+    let info_hash = torrent.info_hash.clone();
     let mut session = Session::new();
 
-    session.add_torrent(torrent);
+    if peers.is_empty() {
+        session.add_torrent(torrent).unwrap();
+    } else {
+        let options = TorrentOptions {
+            disable_trackers: true,
+            ..Default::default()
+        };
+        session.add_torrent_with_options(torrent, options).unwrap();
+        session.add_peers(&info_hash, peers);
+    }
 
     let mut buffer = String::new();
     let stdin = io::stdin();
     let mut handle = stdin.lock();
 
-    handle.read_to_string(&mut buffer).unwrap();
+    if json {
+        // Print the status on each line read
+        for _ in handle.lines() {
+            let pieces = session.debug_pieces(&info_hash);
+            let bytes = session.byte_stats(&info_hash);
+
+            if let (Some(pieces), Some(bytes)) = (pieces, bytes) {
+                let status = JsonStatus::new(&info_hash, pieces, bytes);
+                println!("{}", serde_json::to_string(&status).unwrap());
+            }
+        }
+    } else if debug_pieces {
+        // Print the pieces state on each line read
+        for _ in handle.lines() {
+            if let Some(pieces) = session.debug_pieces(&info_hash) {
+                println!("{}", pieces);
+            }
+        }
+    } else {
+        handle.read_to_string(&mut buffer).unwrap();
+    }
     //     task::block_on(async move {
     //         let mut res = surf::get("http://localhost:6969/announce")
     // //        let mut res = surf::get(&meta.announce)
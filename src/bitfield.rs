@@ -19,7 +19,7 @@ impl From<BitField> for BitFieldUpdate {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BitField {
     inner: Box<[u8]>,
     nbits: usize,
@@ -43,11 +43,31 @@ impl<'a> TryFrom<(&'a [u8], usize)> for BitField {
 impl BitField {
     pub fn new(nbits: usize) -> BitField {
         BitField {
-            inner: vec![0; (nbits / 8) + 1].into_boxed_slice(),
+            inner: vec![0; nbits.div_ceil(8)].into_boxed_slice(),
             nbits,
         }
     }
 
+    /// Bitfield of a BITFIELD message, bit 0 is the high bit of the first
+    /// byte (BEP 3). The spare bits of the last byte must be zero
+    pub fn from_bytes(bytes: &[u8], nbits: usize) -> Result<BitField, TorrentError> {
+        if bytes.len() != nbits.div_ceil(8) {
+            return Err(TorrentError::InvalidInput);
+        }
+
+        let spare = bytes.len() * 8 - nbits;
+        if let Some(last) = bytes.last() {
+            if last & ((1 << spare) - 1) != 0 {
+                return Err(TorrentError::InvalidInput);
+            }
+        }
+
+        Ok(BitField {
+            inner: Vec::from_slice(bytes).into_boxed_slice(),
+            nbits,
+        })
+    }
+
     pub fn get_bit<I: Into<usize>>(&self, index: I) -> bool {
         let index: usize = index.into();
 
@@ -72,6 +92,37 @@ impl BitField {
         }
     }
 
+    pub fn clear_bit<I: Into<usize>>(&mut self, index: I) {
+        let index: usize = index.into();
+
+        if index < self.nbits {
+            let slice_index = index / 8;
+            let bit_index = index % 8;
+
+            self.inner[slice_index] &= !(1 << (7 - bit_index));
+        }
+    }
+
+    /// Number of bits set
+    pub fn count_ones(&self) -> usize {
+        (0..self.nbits).filter(|index| self.get_bit(*index)).count()
+    }
+
+    /// All the bits are set
+    pub fn is_complete(&self) -> bool {
+        self.count_ones() == self.nbits
+    }
+
+    /// Indexes of the bits not set
+    pub fn iter_missing(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.nbits).filter(move |index| !self.get_bit(*index))
+    }
+
+    /// Bytes of the bitfield, in the format of the BITFIELD message
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.inner[..self.nbits.div_ceil(8)]
+    }
+
     pub fn update(&mut self, update: BitFieldUpdate) {
         match update {
             BitFieldUpdate::BitField(bitfield) => {
@@ -93,4 +144,62 @@ mod tests {
         let bitfield = BitField::new(12);
         println!("bitfield={:?}", bitfield);
     }
+
+    #[test]
+    fn as_bytes() {
+        let mut bitfield = BitField::new(16);
+        bitfield.set_bit(0usize);
+        bitfield.set_bit(9usize);
+
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+        assert_eq!(bitfield.count_ones(), 2);
+
+        bitfield.clear_bit(0usize);
+        assert_eq!(bitfield.as_bytes(), &[0, 0b0100_0000]);
+    }
+
+    #[test]
+    fn twelve_pieces() {
+        let mut bitfield = BitField::new(12);
+        assert_eq!(bitfield.as_bytes(), &[0, 0]);
+
+        for index in &[0usize, 3, 8, 11] {
+            bitfield.set_bit(*index);
+        }
+        // Out of range, ignored
+        bitfield.set_bit(12usize);
+
+        assert_eq!(bitfield.as_bytes(), &[0b1001_0000, 0b1001_0000]);
+        assert!(bitfield.get_bit(11usize));
+        assert!(!bitfield.get_bit(12usize));
+        assert_eq!(bitfield.count_ones(), 4);
+        assert!(!bitfield.is_complete());
+        assert_eq!(
+            bitfield.iter_missing().collect::<Vec<_>>(),
+            vec![1, 2, 4, 5, 6, 7, 9, 10]
+        );
+
+        let bytes = bitfield.as_bytes();
+        let read = BitField::from_bytes(bytes, 12).unwrap();
+        assert_eq!(read.as_bytes(), bytes);
+        assert_eq!(read.iter_missing().count(), 8);
+
+        let full = BitField::from_bytes(&[0xFF, 0xF0], 12).unwrap();
+        assert!(full.is_complete());
+        assert_eq!(full.iter_missing().next(), None);
+        assert_eq!(full.as_bytes(), &[0xFF, 0xF0]);
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        // A spare bit set
+        assert!(BitField::from_bytes(&[0xFF, 0xF8], 12).is_err());
+        assert!(BitField::from_bytes(&[0x00, 0x01], 12).is_err());
+        // Wrong length
+        assert!(BitField::from_bytes(&[0xFF], 12).is_err());
+        assert!(BitField::from_bytes(&[0xFF, 0xF0, 0x00], 12).is_err());
+
+        assert!(BitField::from_bytes(&[0xFF], 8).is_ok());
+        assert!(BitField::from_bytes(&[], 0).is_ok());
+    }
 }
@@ -0,0 +1,327 @@
+use crossbeam_channel::{unbounded, Sender as SyncSender};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    actors::sha1::Sha1Task,
+    bencode::ser::{to_bytes, SerializeError},
+};
+
+/// Pieces read but not hashed yet, a bound on the memory used
+const MAX_PIECES_IN_FLIGHT: usize = 16;
+
+#[derive(Debug)]
+pub enum CreateError {
+    IO(io::Error),
+    /// The directory has no file
+    NoFile,
+    /// The piece length is not a power of 2
+    InvalidPieceLength,
+    /// The sha1 workers are gone
+    Sha1Workers,
+    Serialization(SerializeError),
+}
+
+impl From<io::Error> for CreateError {
+    fn from(e: io::Error) -> CreateError {
+        CreateError::IO(e)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CreateOptions {
+    /// In bytes, a power of 2 usually between 16 KiB and 16 MiB
+    pub piece_length: u64,
+    /// Tiers of trackers. The first tracker is also the `announce`
+    pub trackers: Vec<Vec<String>>,
+    pub private: bool,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// Seconds since the epoch
+    pub creation_date: Option<u64>,
+}
+
+// The fields are declared in the order of their keys, bencode
+// dictionaries are sorted
+
+#[derive(Serialize)]
+struct NewFile {
+    length: u64,
+    path: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct NewInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<NewFile>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<u64>,
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: u64,
+    pieces: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct NewTorrent<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    announce: Option<&'a str>,
+    #[serde(rename = "announce-list", skip_serializing_if = "Option::is_none")]
+    announce_list: Option<&'a [Vec<String>]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<&'a str>,
+    #[serde(rename = "created by", skip_serializing_if = "Option::is_none")]
+    created_by: Option<&'a str>,
+    #[serde(rename = "creation date", skip_serializing_if = "Option::is_none")]
+    creation_date: Option<u64>,
+    info: NewInfo,
+}
+
+/// Files under `dir`, sorted by path, with their path relative to `dir`
+fn walk(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<(PathBuf, NewFile)>,
+) -> io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let metadata = std::fs::metadata(&path)?;
+
+        prefix.push(entry.file_name().to_string_lossy().into_owned());
+
+        if metadata.is_dir() {
+            walk(&path, prefix, files)?;
+        } else if metadata.is_file() {
+            let file = NewFile {
+                length: metadata.len(),
+                path: prefix.clone(),
+            };
+            files.push((path, file));
+        }
+
+        prefix.pop();
+    }
+
+    Ok(())
+}
+
+/// Hash the files as one stream cut in pieces, with the sha1 workers
+fn hash_pieces(
+    paths: &[PathBuf],
+    piece_length: usize,
+    sha1_workers: &SyncSender<Sha1Task>,
+) -> Result<Vec<u8>, CreateError> {
+    let (respond, results) = unbounded();
+    let mut sums: Vec<[u8; 20]> = Vec::new();
+    let mut received = 0;
+
+    let receive_one = |sums: &mut Vec<[u8; 20]>| -> Result<(), CreateError> {
+        let (index, sum) = results.recv().map_err(|_| CreateError::Sha1Workers)?;
+        sums[index] = sum;
+        Ok(())
+    };
+
+    let send_piece = |piece: &mut Vec<u8>, sums: &mut Vec<[u8; 20]>| {
+        let task = Sha1Task::Hash {
+            piece: std::mem::replace(piece, Vec::with_capacity(piece_length)).into_boxed_slice(),
+            piece_index: sums.len(),
+            respond: respond.clone(),
+        };
+        sums.push([0; 20]);
+        sha1_workers
+            .send(task)
+            .map_err(|_| CreateError::Sha1Workers)
+    };
+
+    let mut piece = Vec::with_capacity(piece_length);
+
+    for path in paths {
+        let mut file = File::open(path)?;
+
+        loop {
+            let start = piece.len();
+            piece.resize(piece_length, 0);
+            let n = file.read(&mut piece[start..])?;
+            piece.truncate(start + n);
+
+            if n == 0 {
+                break;
+            }
+
+            if piece.len() == piece_length {
+                send_piece(&mut piece, &mut sums)?;
+
+                if sums.len() - received >= MAX_PIECES_IN_FLIGHT {
+                    receive_one(&mut sums)?;
+                    received += 1;
+                }
+            }
+        }
+    }
+
+    if !piece.is_empty() {
+        send_piece(&mut piece, &mut sums)?;
+    }
+
+    while received < sums.len() {
+        receive_one(&mut sums)?;
+        received += 1;
+    }
+
+    Ok(sums.concat())
+}
+
+/// Create the bencoded `.torrent` of a file, or of all the files of a
+/// directory. The pieces are hashed by the sha1 workers, created with
+/// `Sha1Workers::new_pool`
+pub fn create_torrent(
+    path: &Path,
+    options: &CreateOptions,
+    sha1_workers: &SyncSender<Sha1Task>,
+) -> Result<Vec<u8>, CreateError> {
+    if !options.piece_length.is_power_of_two() {
+        return Err(CreateError::InvalidPieceLength);
+    }
+
+    let name = path
+        .canonicalize()?
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let (paths, files, length) = if std::fs::metadata(path)?.is_dir() {
+        let mut files = Vec::new();
+        walk(path, &mut Vec::new(), &mut files)?;
+
+        if files.is_empty() {
+            return Err(CreateError::NoFile);
+        }
+
+        let (paths, files): (Vec<_>, Vec<_>) = files.into_iter().unzip();
+        (paths, Some(files), None)
+    } else {
+        let length = std::fs::metadata(path)?.len();
+        (vec![path.to_path_buf()], None, Some(length))
+    };
+
+    let pieces = hash_pieces(&paths, options.piece_length as usize, sha1_workers)?;
+
+    let announce_list = options.trackers.as_slice();
+    let torrent = NewTorrent {
+        announce: announce_list.iter().flatten().next().map(String::as_str),
+        announce_list: Some(announce_list).filter(|l| !l.is_empty()),
+        comment: options.comment.as_deref(),
+        created_by: options.created_by.as_deref(),
+        creation_date: options.creation_date,
+        info: NewInfo {
+            files,
+            length,
+            name,
+            piece_length: options.piece_length,
+            pieces: ByteBuf::from(pieces),
+            private: if options.private { Some(1) } else { None },
+        },
+    };
+
+    to_bytes(&torrent).map_err(CreateError::Serialization)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::runtime::Runtime;
+
+    use super::{create_torrent, CreateOptions};
+    use crate::{actors::sha1::Sha1Workers, bencode::de::read_meta, metadata::InfoFile};
+
+    #[test]
+    fn create_from_directory() {
+        let dir = std::env::temp_dir().join(format!("rustorrent-create-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+
+        let a: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        let b: Vec<u8> = (0..700).map(|i| (i * 7) as u8).collect();
+        std::fs::write(dir.join("b.bin"), &b).unwrap();
+        std::fs::write(dir.join("sub").join("a.bin"), &a).unwrap();
+
+        // Hashed in the order of the paths: b.bin, sub/a.bin
+        let content = [b, a].concat();
+
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let (fs, _fs_recv) = async_channel::unbounded();
+        let sha1_workers = Sha1Workers::new_pool(runtime, fs, 2);
+
+        let trackers = vec![
+            vec!["http://a.test/announce".to_string()],
+            vec!["udp://b.test:6969".to_string()],
+        ];
+        let options = CreateOptions {
+            piece_length: 1024,
+            trackers: trackers.clone(),
+            private: true,
+            ..Default::default()
+        };
+
+        let bytes = create_torrent(&dir, &options, &sha1_workers).unwrap();
+        let torrent = read_meta(&bytes).unwrap();
+
+        let sums: Vec<u8> = content
+            .chunks(1024)
+            .flat_map(|piece| crate::sha1::sha1(piece).to_vec())
+            .collect();
+        assert_eq!(torrent.meta.info.pieces, sums);
+        assert_eq!(torrent.meta.info.piece_length, 1024);
+        assert_eq!(torrent.meta.info.private, Some(1));
+        assert_eq!(
+            torrent.meta.announce.as_deref(),
+            Some("http://a.test/announce")
+        );
+        assert_eq!(torrent.get_urls_tiers().len(), 2);
+
+        match &torrent.meta.info.files {
+            InfoFile::Multiple { files, .. } => {
+                let files: Vec<_> = files.iter().map(|f| (f.path.to_vec(), f.length)).collect();
+                assert_eq!(
+                    files,
+                    vec![
+                        (vec!["b.bin".to_string()], 700),
+                        (vec!["sub".to_string(), "a.bin".to_string()], 2500),
+                    ]
+                );
+            }
+            files => panic!("Expected multiple files {:?}", files),
+        }
+
+        // Single file
+        let options = CreateOptions {
+            piece_length: 1024,
+            ..Default::default()
+        };
+        let bytes = create_torrent(&dir.join("b.bin"), &options, &sha1_workers).unwrap();
+        let torrent = read_meta(&bytes).unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(torrent.meta.info.pieces, crate::sha1::sha1(&content[..700]));
+        assert_eq!(torrent.meta.info.private, None);
+        assert_eq!(torrent.meta.announce, None);
+        match torrent.meta.info.files {
+            InfoFile::Single { name, length, .. } => {
+                assert_eq!((name.as_str(), length), ("b.bin", 700))
+            }
+            files => panic!("Expected a single file {:?}", files),
+        }
+    }
+}
@@ -1,13 +1,42 @@
-use crate::{actors::tracker::http::HttpError, bencode::de::DeserializeError};
+use crate::{
+    actors::tracker::http::HttpError, bencode::de::BencodeError, peer::handshake::HandshakeError,
+    resume::ResumeError,
+};
 
 #[derive(Debug)]
 pub enum TorrentError {
-    Deserialization(DeserializeError),
+    Deserialization(BencodeError),
     InvalidInput,
     Http(HttpError),
     Unresponsive,
+    /// Error message of a UDP tracker
+    Tracker(String),
+    Handshake(HandshakeError),
     IO(std::io::Error),
     IOAsync(tokio::io::Error),
+    /// Write on a torrent added as read-only
+    ReadOnly,
+    /// Every peer of the magnet was asked, none sent its metadata
+    MetadataUnavailable,
+    /// The disk doesn't have room to preallocate the files, see
+    /// `AllocationMode::Preallocate`. In bytes
+    NotEnoughSpace {
+        needed: u64,
+        available: u64,
+    },
+}
+
+/// Failure of `Session::add_torrent` and its variants
+#[derive(Debug)]
+pub enum AddError {
+    /// The torrent has more pieces than `SessionConfig::max_pieces`
+    TooManyPieces { num_pieces: usize, max: usize },
+    /// The torrent is larger than `SessionConfig::max_torrent_size`
+    TooLarge { size: u64, max: u64 },
+    /// A torrent with the same info hash is in the session
+    Duplicate,
+    /// The resume data given doesn't match the torrent, or can't be read
+    Resume(ResumeError),
 }
 
 impl From<HttpError> for TorrentError {
@@ -26,8 +55,17 @@ impl From<tokio::io::Error> for TorrentError {
     }
 }
 
-impl From<DeserializeError> for TorrentError {
-    fn from(e: DeserializeError) -> TorrentError {
+impl From<HandshakeError> for TorrentError {
+    fn from(e: HandshakeError) -> TorrentError {
+        match e {
+            HandshakeError::IO(e) => TorrentError::IO(e),
+            e => TorrentError::Handshake(e),
+        }
+    }
+}
+
+impl From<BencodeError> for TorrentError {
+    fn from(e: BencodeError) -> TorrentError {
         TorrentError::Deserialization(e)
     }
 }
@@ -1,14 +1,20 @@
+use bitflags::bitflags;
 use serde::{self, Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 
 use hashbrown::HashMap;
 
+use std::{collections::BTreeMap, convert::TryFrom};
+
+pub mod ut_metadata;
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ExtendedHandshake {
     /// Dictionary of supported extension messages which maps names of
     /// extensions to an extended message ID
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub m: Option<HashMap<String, i64>>,
+    /// Sorted, the bencode dictionaries are
+    pub m: Option<BTreeMap<String, i64>>,
     /// Client name and version (as a utf-8 string). This is a much
     /// more reliable way of identifying the client than relying on
     /// the peer id encoding.
@@ -103,6 +109,108 @@ impl<'a> From<PEXMessage<'a>> for Vec<SocketAddr> {
     }
 }
 
+bitflags! {
+    /// Capabilities announced in the 8 reserved bytes of the handshake,
+    /// read as a big endian integer
+    pub struct Capabilities: u64 {
+        /// BEP 5, `reserved[7] & 0x01`. Not implemented yet
+        const DHT = 0x01;
+        /// BEP 6, `reserved[7] & 0x04`. Not implemented yet
+        const FAST = 0x04;
+        /// BEP 10, `reserved[5] & 0x10`
+        const EXTENSION = 0x10_0000;
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Capabilities {
+        Capabilities::EXTENSION
+    }
+}
+
+impl Capabilities {
+    /// The reserved bytes of our handshake
+    pub fn reserved(self) -> [u8; 8] {
+        self.bits().to_be_bytes()
+    }
+}
+
+bitflags! {
+    /// Flags of the peers added by PEX, in `added.f` and `added6.f`
+    #[derive(Default)]
+    pub struct PexFlags: u8 {
+        /// Prefers encrypted connections
+        const ENCRYPTION = 0x01;
+        /// Seed or upload only
+        const SEED = 0x02;
+        const UTP = 0x04;
+        const HOLEPUNCH = 0x08;
+        /// The peer is reachable
+        const OUTGOING = 0x10;
+    }
+}
+
+impl<'a> PEXMessage<'a> {
+    /// The peers added with their flags. A peer without flags byte
+    /// has none
+    pub fn added_peers(&self) -> Vec<(SocketAddr, PexFlags)> {
+        let mut peers = Vec::new();
+
+        let added = [
+            (&self.added, &self.added_flags, false),
+            (&self.added6, &self.added6_flags, true),
+        ];
+
+        for (addrs, flags, ipv6) in added.iter() {
+            let slice = match addrs {
+                Some(PtrBuf { slice }) => slice,
+                None => continue,
+            };
+            let flags = flags.as_ref().map(|f| f.slice).unwrap_or(&[]);
+
+            let mut addrs = Vec::new();
+            if *ipv6 {
+                utils::ipv6_from_slice(slice, &mut addrs);
+            } else {
+                utils::ipv4_from_slice(slice, &mut addrs);
+            }
+
+            peers.extend(addrs.into_iter().enumerate().map(|(index, addr)| {
+                let flags = flags.get(index).copied().unwrap_or(0);
+                (addr, PexFlags::from_bits_truncate(flags))
+            }));
+        }
+
+        peers
+    }
+}
+
+/// Whether the connections to the peers should be encrypted.
+///
+/// The encrypted handshake (MSE) is not implemented yet: the policy
+/// only decides which peers advertised by PEX are dialed first
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    #[default]
+    Disabled,
+    /// Peers preferring encryption are dialed first
+    Enabled,
+}
+
+/// Order in which the PEX peers are dialed, following the encryption
+/// policy. The seeds come last, we might be a seed too
+pub fn pex_dial_order(
+    mut peers: Vec<(SocketAddr, PexFlags)>,
+    policy: EncryptionPolicy,
+) -> Vec<SocketAddr> {
+    peers.sort_by_key(|(_, flags)| {
+        let plain = policy == EncryptionPolicy::Enabled && !flags.contains(PexFlags::ENCRYPTION);
+        (flags.contains(PexFlags::SEED), plain)
+    });
+
+    peers.into_iter().map(|(addr, _)| addr).collect()
+}
+
 #[derive(Debug)]
 pub enum ExtendedMessage<'a> {
     Handshake { handshake: Box<ExtendedHandshake> },
@@ -227,13 +335,240 @@ pub enum ExtendedMessage<'a> {
 // 	virtual bool write_request(peer_request const&) { return false; }
 // }
 
-pub trait Extension {
-    /// Name of this extension
-    const NAME: &'static str;
+/// Extension protocol negotiated in the extended handshake (BEP 10)
+pub trait Extension: Send {
+    /// Name of the extension in the `m` dictionary, such as `ut_pex`
+    fn name(&self) -> &str;
 
-    fn handshake() {}
+    /// Payload of an extended message sent to our id of the extension
+    fn on_message(&mut self, payload: &[u8]);
+}
 
-    fn handle_hanshake() {}
+/// Extensions of a connection and the ids of the messages.
+///
+/// Our ids are given in the order the extensions are registered,
+/// starting at 1: the id 0 is the extended handshake. The peer chooses
+/// its own ids, its messages are sent to ours and ours to its ones
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    extensions: Vec<Box<dyn Extension>>,
+    /// Ids of the `m` dictionary of the peer
+    remote_ids: HashMap<String, u8>,
+}
 
-    fn handle_extended_hanshake() {}
+impl std::fmt::Debug for ExtensionRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ExtensionRegistry")
+            .field("local_ids", &self.local_ids())
+            .field("remote_ids", &self.remote_ids)
+            .finish()
+    }
+}
+
+impl ExtensionRegistry {
+    /// Returns our id of the extension
+    pub fn register(&mut self, extension: Box<dyn Extension>) -> u8 {
+        self.extensions.push(extension);
+        self.extensions.len() as u8
+    }
+
+    /// The `m` dictionary of our extended handshake
+    pub fn local_ids(&self) -> BTreeMap<String, i64> {
+        self.extensions
+            .iter()
+            .enumerate()
+            .map(|(index, ext)| (ext.name().to_string(), index as i64 + 1))
+            .collect()
+    }
+
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.extensions
+            .iter()
+            .position(|ext| ext.name() == name)
+            .map(|index| index as u8 + 1)
+    }
+
+    /// Id of the messages to send to the peer, when it supports the
+    /// extension
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote_ids.get(name).copied()
+    }
+
+    /// Our extended handshake, with the extensions registered
+    pub fn handshake(&self) -> ExtendedHandshake {
+        ExtendedHandshake {
+            m: Some(self.local_ids()),
+            ..Default::default()
+        }
+    }
+
+    /// Read the `m` dictionary of the peer. An extension with the id 0,
+    /// or one that doesn't fit a byte, is disabled
+    pub fn read_handshake(&mut self, handshake: &ExtendedHandshake) {
+        for (name, id) in handshake.m.iter().flatten() {
+            match u8::try_from(*id) {
+                Ok(id) if id != 0 => self.remote_ids.insert(name.clone(), id),
+                _ => self.remote_ids.remove(name),
+            };
+        }
+    }
+
+    /// Dispatch an extended message to its extension. Returns false when
+    /// no extension has this id
+    pub fn dispatch(&mut self, message: ExtendedMessage) -> bool {
+        match message {
+            ExtendedMessage::Handshake { handshake } => {
+                self.read_handshake(&handshake);
+                true
+            }
+            ExtendedMessage::Message { id, buffer } => {
+                match (id as usize)
+                    .checked_sub(1)
+                    .and_then(|index| self.extensions.get_mut(index))
+                {
+                    Some(extension) => {
+                        extension.on_message(buffer);
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{
+        pex_dial_order, EncryptionPolicy, ExtendedHandshake, ExtendedMessage, Extension,
+        ExtensionRegistry, PEXMessage, PexFlags,
+    };
+    use crate::bencode::{de::from_bytes, ser::to_bytes};
+
+    /// Keeps the payloads received
+    struct Recorder {
+        name: &'static str,
+        received: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Extension for Recorder {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn on_message(&mut self, payload: &[u8]) {
+            self.received.lock().unwrap().push(payload.to_vec());
+        }
+    }
+
+    #[test]
+    fn extension_ids() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = ExtensionRegistry::default();
+        for name in &["ut_pex", "ut_metadata"] {
+            registry.register(Box::new(Recorder {
+                name,
+                received: received.clone(),
+            }));
+        }
+        assert_eq!(registry.local_id("ut_metadata"), Some(2));
+        assert_eq!(registry.local_id("lt_donthave"), None);
+
+        // The keys are sorted
+        let bytes = to_bytes(&registry.handshake()).unwrap();
+        assert_eq!(bytes, b"d1:md11:ut_metadatai2e6:ut_pexi1eee");
+
+        // Read by the peer, with its own ids
+        let mut remote = ExtensionRegistry::default();
+        remote.register(Box::new(Recorder {
+            name: "ut_metadata",
+            received: received.clone(),
+        }));
+        let handshake: ExtendedHandshake = from_bytes(&bytes).unwrap();
+        assert!(remote.dispatch(ExtendedMessage::Handshake {
+            handshake: Box::new(handshake)
+        }));
+        assert_eq!(remote.remote_id("ut_pex"), Some(1));
+        assert_eq!(remote.remote_id("ut_metadata"), Some(2));
+
+        // The peer disables ut_pex, and answers with its handshake
+        let handshake: ExtendedHandshake = from_bytes(b"d1:md7:lt_unkni300e6:ut_pexi0eee").unwrap();
+        remote.read_handshake(&handshake);
+        assert_eq!(remote.remote_id("ut_pex"), None);
+        assert_eq!(remote.remote_id("lt_unkn"), None);
+
+        let handshake: ExtendedHandshake =
+            from_bytes(&to_bytes(&remote.handshake()).unwrap()).unwrap();
+        registry.read_handshake(&handshake);
+        assert_eq!(registry.remote_id("ut_metadata"), Some(1));
+
+        // Messages to our ids reach the extensions
+        assert!(registry.dispatch(ExtendedMessage::Message {
+            id: 2,
+            buffer: b"metadata"
+        }));
+        assert!(!registry.dispatch(ExtendedMessage::Message {
+            id: 3,
+            buffer: b"unknown"
+        }));
+        assert!(remote.dispatch(ExtendedMessage::Message {
+            id: 1,
+            buffer: b"remote"
+        }));
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![b"metadata".to_vec(), b"remote".to_vec()]
+        );
+    }
+
+    #[test]
+    fn pex_flags() {
+        // 3 IPv4 peers: encryption, seed, none. The last flags are missing
+        let mut msg = b"d5:added18:".to_vec();
+        for port in 1..=3u8 {
+            msg.extend_from_slice(&[127, 0, 0, 1, 0, port]);
+        }
+        msg.extend_from_slice(b"7:added.f2:\x01\x03e");
+
+        let pex: PEXMessage = crate::bencode::de::from_bytes(&msg).unwrap();
+        let peers = pex.added_peers();
+
+        let addr = |port: u16| -> SocketAddr { format!("127.0.0.1:{}", port).parse().unwrap() };
+        assert_eq!(
+            peers,
+            vec![
+                (addr(1), PexFlags::ENCRYPTION),
+                (addr(2), PexFlags::ENCRYPTION | PexFlags::SEED),
+                (addr(3), PexFlags::empty()),
+            ]
+        );
+
+        assert_eq!(
+            pex_dial_order(peers.clone(), EncryptionPolicy::Disabled),
+            vec![addr(1), addr(3), addr(2)]
+        );
+        assert_eq!(
+            pex_dial_order(peers, EncryptionPolicy::Enabled),
+            vec![addr(1), addr(3), addr(2)]
+        );
+
+        // Encrypted peers before the others
+        let peers = vec![
+            (addr(4), PexFlags::empty()),
+            (addr(5), PexFlags::ENCRYPTION | PexFlags::UTP),
+        ];
+        assert_eq!(
+            pex_dial_order(peers.clone(), EncryptionPolicy::Enabled),
+            vec![addr(5), addr(4)]
+        );
+        assert_eq!(
+            pex_dial_order(peers, EncryptionPolicy::Disabled),
+            vec![addr(4), addr(5)]
+        );
+    }
 }
@@ -0,0 +1,1112 @@
+use async_channel::{unbounded, Receiver, Sender};
+use kv_log_macro::warn;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::{TryFrom, TryInto},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    bencode::{
+        de::{from_bytes_with_rest, BencodeError},
+        ser::to_bytes,
+    },
+    errors::TorrentError,
+    extensions::{Capabilities, ExtendedHandshake, ExtendedMessage},
+    metadata::{MagnetLink, Torrent},
+    peer::{handshake::Handshake, message::MessagePeer, writer::BufferWriter},
+    supervisors::torrent::Result,
+    utils::ConnectTimeout,
+};
+
+/// Size of the metadata pieces, except the last one (BEP 9)
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+/// Our id of `ut_metadata` in the extended handshake
+pub const UT_METADATA_ID: u8 = 2;
+
+/// Longer messages close the connection, they can't be a piece of
+/// the metadata. Other messages such as a bitfield are skipped
+const MAX_MESSAGE_LENGTH: usize = 1024 * 1024;
+
+/// A peer silent for longer is dropped
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A peer not accepting the connection by then is skipped
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Peers `fetch_metadata` is connected to at once
+const MAX_CONNECTIONS: usize = 4;
+
+/// Larger `metadata_size` are ignored
+pub const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FetchSettings {
+    /// Rejected or invalid pieces tolerated from a peer before it's
+    /// not asked anymore
+    pub max_attempts_per_peer: usize,
+    /// Pieces requested to the same peer at once
+    pub max_in_flight_per_peer: usize,
+}
+
+impl Default for FetchSettings {
+    fn default() -> Self {
+        FetchSettings {
+            max_attempts_per_peer: 2,
+            max_in_flight_per_peer: 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PieceState {
+    Missing,
+    Requested(SocketAddr),
+    Received { data: Vec<u8>, from: SocketAddr },
+}
+
+#[derive(Debug)]
+struct FetchPeer {
+    addr: SocketAddr,
+    /// `metadata_size` of its extended handshake
+    size: usize,
+    /// Rejects and invalid pieces
+    attempts: usize,
+    in_flight: usize,
+}
+
+/// Fetches the info dictionary of a magnet link with `ut_metadata`.
+///
+/// The pieces are requested to all the capable peers in turn, so they
+/// are downloaded in parallel. A peer rejecting the requests is skipped
+/// after `max_attempts_per_peer`, its pieces go to the other peers.
+/// The fetch fails only when every peer was tried.
+///
+/// Only the peers agreeing on the size are asked. Another size is
+/// taken once they're all gone or tried, or when the pieces don't
+/// match the info hash
+#[derive(Debug)]
+pub struct MetadataFetcher {
+    info_hash: Arc<[u8]>,
+    settings: FetchSettings,
+    /// Size given in the extended handshake of the peers asked
+    size: Option<usize>,
+    pieces: Vec<PieceState>,
+    peers: Vec<FetchPeer>,
+    /// Peer receiving the next request
+    cursor: usize,
+    /// Pieces of several peers didn't match the info hash: they're
+    /// asked to one peer at a time, to find the one sending bad data
+    one_peer: bool,
+}
+
+impl MetadataFetcher {
+    pub fn new(info_hash: Arc<[u8]>, settings: FetchSettings) -> MetadataFetcher {
+        MetadataFetcher {
+            info_hash,
+            settings,
+            size: None,
+            pieces: Vec::new(),
+            peers: Vec::new(),
+            cursor: 0,
+            one_peer: false,
+        }
+    }
+
+    /// Add a peer with `ut_metadata` in its extended handshake.
+    /// Returns false when its `metadata_size` is invalid, the peer is
+    /// not used
+    pub fn add_peer(&mut self, addr: SocketAddr, metadata_size: i64) -> bool {
+        let size = match metadata_size {
+            n if n > 0 && n as usize <= MAX_METADATA_SIZE => n as usize,
+            _ => return false,
+        };
+
+        if !self.peers.iter().any(|p| p.addr == addr) {
+            self.peers.push(FetchPeer {
+                addr,
+                size,
+                attempts: 0,
+                in_flight: 0,
+            });
+        }
+
+        if self.size.is_none() {
+            self.update_size();
+        }
+
+        true
+    }
+
+    /// The peer disconnected, its pending pieces are requested elsewhere
+    pub fn remove_peer(&mut self, addr: SocketAddr) {
+        for piece in &mut self.pieces {
+            if matches!(piece, PieceState::Requested(a) if *a == addr) {
+                *piece = PieceState::Missing;
+            }
+        }
+
+        self.peers.retain(|p| p.addr != addr);
+        self.cursor = 0;
+        self.update_size();
+    }
+
+    /// Requests to send now, as `(peer, piece)`
+    pub fn next_requests(&mut self) -> Vec<(SocketAddr, u32)> {
+        self.update_size();
+
+        // With `one_peer`, the peer the pieces are from
+        let mut only = None;
+        if self.one_peer {
+            only = self.pieces.iter().find_map(|piece| match piece {
+                PieceState::Requested(addr) | PieceState::Received { from: addr, .. } => {
+                    Some(*addr)
+                }
+                PieceState::Missing => None,
+            });
+
+            let max_attempts = self.settings.max_attempts_per_peer;
+            let usable = |addr| {
+                self.peers
+                    .iter()
+                    .any(|p| p.addr == addr && p.attempts < max_attempts)
+            };
+            if only.map(usable) == Some(false) {
+                only = None;
+                self.reset_pieces();
+            }
+        }
+
+        let mut requests = Vec::new();
+        let MetadataFetcher {
+            settings,
+            size,
+            pieces,
+            peers,
+            cursor,
+            one_peer,
+            ..
+        } = self;
+
+        for (index, piece) in pieces.iter_mut().enumerate() {
+            if !matches!(piece, PieceState::Missing) {
+                continue;
+            }
+
+            let npeers = peers.len();
+            let available = (0..npeers).map(|i| (*cursor + i) % npeers).find(|&i| {
+                let peer = &peers[i];
+                Some(peer.size) == *size
+                    && only.is_none_or(|addr| peer.addr == addr)
+                    && peer.attempts < settings.max_attempts_per_peer
+                    && peer.in_flight < settings.max_in_flight_per_peer
+            });
+
+            let peer = match available {
+                Some(i) => {
+                    *cursor = (i + 1) % npeers;
+                    &mut peers[i]
+                }
+                None => break,
+            };
+
+            if *one_peer {
+                only = Some(peer.addr);
+            }
+            peer.in_flight += 1;
+            *piece = PieceState::Requested(peer.addr);
+            requests.push((peer.addr, index as u32));
+        }
+
+        requests
+    }
+
+    /// The peer answered with a `reject`
+    pub fn on_reject(&mut self, addr: SocketAddr, piece: u32) {
+        if self.take_request(addr, piece) {
+            self.add_attempt(addr);
+        }
+    }
+
+    /// The peer answered with a `data`. Returns the info dictionary once
+    /// all the pieces are received and its hash matches. A piece of the
+    /// wrong length, or with a `total_size` other than the size of the
+    /// handshakes, is invalid
+    pub fn on_data(
+        &mut self,
+        addr: SocketAddr,
+        piece: u32,
+        total_size: i64,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        if !self.take_request(addr, piece) {
+            return None;
+        }
+
+        if data.len() != self.piece_length(piece as usize)
+            || Some(total_size) != self.size.map(|size| size as i64)
+        {
+            self.add_attempt(addr);
+            return None;
+        }
+
+        self.pieces[piece as usize] = PieceState::Received {
+            data: data.to_vec(),
+            from: addr,
+        };
+
+        if !self.is_complete() {
+            return None;
+        }
+
+        let metadata: Vec<u8> = self
+            .pieces
+            .iter()
+            .flat_map(|piece| match piece {
+                PieceState::Received { data, .. } => data.as_slice(),
+                _ => &[],
+            })
+            .copied()
+            .collect();
+
+        if crate::sha1::sha1(&metadata)[..] == self.info_hash[..] {
+            return Some(metadata);
+        }
+
+        // We can't know which piece is wrong, or if the size is. With a
+        // single sender, it's the suspect. Otherwise the next pieces are
+        // asked to one peer at a time
+        let mut senders = self.pieces.iter().filter_map(|piece| match piece {
+            PieceState::Received { from, .. } => Some(*from),
+            _ => None,
+        });
+        let first = senders.next();
+        match first {
+            Some(addr) if senders.all(|from| from == addr) => self.add_attempt(addr),
+            _ => self.one_peer = true,
+        }
+        self.size = None;
+        self.update_size();
+
+        None
+    }
+
+    /// All the peers were tried and nothing is pending: the metadata
+    /// can't be fetched until new peers are added
+    pub fn is_exhausted(&self) -> bool {
+        let pending = self
+            .pieces
+            .iter()
+            .any(|p| matches!(p, PieceState::Requested(_)));
+
+        !pending
+            && self
+                .peers
+                .iter()
+                .all(|p| p.attempts >= self.settings.max_attempts_per_peer)
+    }
+
+    /// Pieces requested to the peer and not answered yet
+    pub fn in_flight(&self, addr: SocketAddr) -> usize {
+        self.peers
+            .iter()
+            .find(|p| p.addr == addr)
+            .map(|p| p.in_flight)
+            .unwrap_or(0)
+    }
+
+    /// Keep the size while a peer giving it can be asked. Otherwise take
+    /// the size of the first peer not tried, with all the pieces missing
+    fn update_size(&mut self) {
+        let max_attempts = self.settings.max_attempts_per_peer;
+        let mut usable = self.peers.iter().filter(|p| p.attempts < max_attempts);
+
+        if self.size.is_some() && usable.clone().any(|p| Some(p.size) == self.size) {
+            return;
+        }
+
+        let size = usable.next().map(|p| p.size);
+        if size == self.size && !self.pieces.is_empty() {
+            return;
+        }
+
+        self.size = size;
+        self.reset_pieces();
+    }
+
+    /// All the pieces of `size` missing, the requests pending are not
+    /// waited for
+    fn reset_pieces(&mut self) {
+        for piece in std::mem::take(&mut self.pieces) {
+            if let PieceState::Requested(addr) = piece {
+                if let Some(peer) = self.peers.iter_mut().find(|p| p.addr == addr) {
+                    peer.in_flight -= 1;
+                }
+            }
+        }
+
+        let npieces = self.size.unwrap_or(0).div_ceil(METADATA_PIECE_SIZE);
+        self.pieces = (0..npieces).map(|_| PieceState::Missing).collect();
+    }
+
+    fn is_complete(&self) -> bool {
+        self.pieces
+            .iter()
+            .all(|p| matches!(p, PieceState::Received { .. }))
+    }
+
+    fn piece_length(&self, index: usize) -> usize {
+        let size = self.size.unwrap_or(0);
+        (size - index * METADATA_PIECE_SIZE).min(METADATA_PIECE_SIZE)
+    }
+
+    /// Whether this piece was requested to this peer
+    fn take_request(&mut self, addr: SocketAddr, piece: u32) -> bool {
+        let index = piece as usize;
+
+        match self.pieces.get(index) {
+            Some(PieceState::Requested(a)) if *a == addr => {
+                self.pieces[index] = PieceState::Missing;
+            }
+            _ => return false,
+        }
+
+        if let Some(peer) = self.peers.iter_mut().find(|p| p.addr == addr) {
+            peer.in_flight -= 1;
+        }
+
+        true
+    }
+
+    fn add_attempt(&mut self, addr: SocketAddr) {
+        if let Some(peer) = self.peers.iter_mut().find(|p| p.addr == addr) {
+            peer.attempts += 1;
+        }
+    }
+}
+
+/// Serialized before the data of the pieces
+#[derive(Debug, Serialize, Deserialize)]
+struct MessageHeader {
+    msg_type: i64,
+    piece: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<i64>,
+}
+
+/// Message of the `ut_metadata` extension
+#[derive(Debug, PartialEq, Eq)]
+pub enum MetadataMessage<'a> {
+    Request {
+        piece: u32,
+    },
+    Data {
+        piece: u32,
+        total_size: i64,
+        data: &'a [u8],
+    },
+    Reject {
+        piece: u32,
+    },
+}
+
+impl<'a> MetadataMessage<'a> {
+    /// Payload of the extended message, without its id
+    pub fn from_bytes(bytes: &'a [u8]) -> std::result::Result<MetadataMessage<'a>, BencodeError> {
+        let (header, data): (MessageHeader, _) = from_bytes_with_rest(bytes)?;
+
+        let piece = header
+            .piece
+            .try_into()
+            .map_err(|_| BencodeError::Message(format!("Invalid piece {}", header.piece)))?;
+
+        Ok(match header.msg_type {
+            0 => MetadataMessage::Request { piece },
+            1 => MetadataMessage::Data {
+                piece,
+                total_size: header.total_size.unwrap_or(0),
+                data,
+            },
+            2 => MetadataMessage::Reject { piece },
+            t => return Err(BencodeError::Message(format!("Unknown msg_type {}", t))),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (msg_type, piece, total_size, data) = match *self {
+            MetadataMessage::Request { piece } => (0, piece, None, &[][..]),
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => (1, piece, Some(total_size), data),
+            MetadataMessage::Reject { piece } => (2, piece, None, &[][..]),
+        };
+
+        let header = MessageHeader {
+            msg_type,
+            piece: piece as i64,
+            total_size,
+        };
+
+        let mut bytes = to_bytes(&header).unwrap();
+        bytes.extend_from_slice(data);
+        bytes
+    }
+}
+
+async fn write_message<'a, S, M>(stream: &mut S, msg: M) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+    M: Into<MessagePeer<'a>>,
+{
+    let mut buffer = BufferWriter::new(METADATA_PIECE_SIZE);
+    buffer.write_msg(msg);
+    stream.write_all(buffer.as_ref()).await?;
+    Ok(())
+}
+
+/// Next message of the peer, `None` when it's silent for too long
+async fn read_message<S>(stream: &mut S, buffer: &mut Vec<u8>) -> Result<Option<()>>
+where
+    S: AsyncRead + Unpin,
+{
+    let read = async {
+        let length = stream.read_u32().await? as usize;
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(TorrentError::InvalidInput);
+        }
+
+        buffer.resize(length, 0);
+        stream.read_exact(buffer).await?;
+        Ok(())
+    };
+
+    match tokio::time::timeout(READ_TIMEOUT, read).await {
+        Ok(result) => result.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// What a connection of `fetch_metadata` tells the fetcher
+enum PeerEvent {
+    /// Extended handshake received, the pieces to request are sent
+    /// to `requests`
+    Connected {
+        metadata_size: i64,
+        requests: Sender<u32>,
+    },
+    Data {
+        piece: u32,
+        total_size: i64,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: u32,
+    },
+    Closed,
+}
+
+/// Connection to a peer of `fetch_metadata`. The pieces received from
+/// `requests` are requested, the answers are sent to `events`. It ends
+/// once `requests` is dropped
+async fn fetch_from<S>(
+    stream: S,
+    addr: SocketAddr,
+    peer_id: [u8; 20],
+    info_hash: [u8; 20],
+    events: Sender<(SocketAddr, PeerEvent)>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    Handshake::new(info_hash, peer_id, Capabilities::EXTENSION)
+        .write_to(&mut writer)
+        .await?;
+    let handshake = Handshake::read_from(&mut reader, |h| h == &info_hash).await?;
+
+    if !handshake.capabilities().contains(Capabilities::EXTENSION) {
+        return Ok(());
+    }
+
+    let handshake = ExtendedHandshake {
+        m: Some(std::iter::once(("ut_metadata".to_string(), UT_METADATA_ID as i64)).collect()),
+        v: Some(String::from("Rustorrent 0.1")),
+        ..Default::default()
+    };
+    write_message(&mut writer, handshake).await?;
+
+    let mut buffer = Vec::new();
+
+    // Id of ut_metadata for the peer, and its size of the metadata
+    let (id, metadata_size) = loop {
+        if read_message(&mut reader, &mut buffer).await?.is_none() {
+            return Ok(());
+        }
+
+        let handshake = match MessagePeer::try_from(&buffer[..])? {
+            MessagePeer::Extension(ExtendedMessage::Handshake { handshake }) => handshake,
+            _ => continue,
+        };
+
+        let id = handshake
+            .m
+            .as_ref()
+            .and_then(|m| m.get("ut_metadata"))
+            .and_then(|&id| u8::try_from(id).ok())
+            .filter(|&id| id != 0);
+
+        match id {
+            Some(id) => break (id, handshake.metadata_size.unwrap_or(0)),
+            None => return Ok(()),
+        }
+    };
+
+    let (requests_sender, requests) = unbounded();
+    let connected = PeerEvent::Connected {
+        metadata_size,
+        requests: requests_sender,
+    };
+    if events.send((addr, connected)).await.is_err() {
+        return Ok(());
+    }
+
+    // Pieces the peer asked us, we have nothing to share
+    let (rejects_sender, rejects) = unbounded();
+
+    let read = async {
+        loop {
+            if read_message(&mut reader, &mut buffer).await?.is_none() {
+                return Ok(());
+            }
+
+            let buffer = match MessagePeer::try_from(&buffer[..])? {
+                MessagePeer::Extension(ExtendedMessage::Message { id, buffer })
+                    if id == UT_METADATA_ID =>
+                {
+                    buffer
+                }
+                _ => continue,
+            };
+
+            let event = match MetadataMessage::from_bytes(buffer)? {
+                MetadataMessage::Data {
+                    piece,
+                    total_size,
+                    data,
+                } => PeerEvent::Data {
+                    piece,
+                    total_size,
+                    data: data.to_vec(),
+                },
+                MetadataMessage::Reject { piece } => PeerEvent::Reject { piece },
+                MetadataMessage::Request { piece } => {
+                    rejects_sender.send(piece).await.ok();
+                    continue;
+                }
+            };
+
+            if events.send((addr, event)).await.is_err() {
+                return Ok(());
+            }
+        }
+    };
+
+    let write = async {
+        loop {
+            let msg = tokio::select! {
+                piece = requests.recv() => match piece {
+                    Ok(piece) => MetadataMessage::Request { piece },
+                    Err(_) => return Ok(()),
+                },
+                Ok(piece) = rejects.recv() => MetadataMessage::Reject { piece },
+            };
+
+            let msg = msg.to_bytes();
+            let msg = ExtendedMessage::Message { id, buffer: &msg };
+            write_message(&mut writer, MessagePeer::Extension(msg)).await?;
+        }
+    };
+
+    tokio::select! {
+        result = read => result,
+        result = write => result,
+    }
+}
+
+/// Connect to the peer and run `fetch_from`, then tell the fetcher the
+/// connection is closed
+async fn connect(
+    addr: SocketAddr,
+    peer_id: [u8; 20],
+    info_hash: [u8; 20],
+    events: Sender<(SocketAddr, PeerEvent)>,
+) {
+    let result = match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).await {
+        Ok(stream) => fetch_from(stream, addr, peer_id, info_hash, events.clone()).await,
+        Err(e) => Err(e.into()),
+    };
+
+    if let Err(e) = result {
+        warn!("[{}] Metadata not fetched: {:?}", addr, e);
+    }
+
+    events.send((addr, PeerEvent::Closed)).await.ok();
+}
+
+/// Fetch the info dictionary of a magnet from its peers, up to
+/// `MAX_CONNECTIONS` at once. The `x.pe` peers are dialed right away,
+/// the peers of the trackers and the DHT are received from `discovered`
+/// as they're found. The pieces are requested to all the connected
+/// peers, a peer sending invalid data is dropped
+pub async fn fetch_metadata(
+    magnet: MagnetLink,
+    discovered: Receiver<Vec<SocketAddr>>,
+    peer_id: [u8; 20],
+    settings: FetchSettings,
+) -> Result<Torrent> {
+    let mut fetcher = MetadataFetcher::new(magnet.info_hash.clone(), settings);
+    let mut info_hash = [0; 20];
+    info_hash.copy_from_slice(&magnet.info_hash);
+
+    // Peers given to `connect` once, the `x.pe` peers first
+    let mut known: HashSet<SocketAddr> = HashSet::new();
+    let mut pending: VecDeque<SocketAddr> = magnet
+        .peers
+        .iter()
+        .copied()
+        .filter(|addr| known.insert(*addr))
+        .collect();
+    let mut discovering = true;
+    let (events_sender, events) = unbounded();
+    // Connections opened and not closed yet
+    let mut nconnections = 0;
+    // Peers with ut_metadata, receiving the requests
+    let mut peers: HashMap<SocketAddr, Sender<u32>> = HashMap::new();
+
+    loop {
+        while nconnections < MAX_CONNECTIONS {
+            let addr = match pending.pop_front() {
+                Some(addr) => addr,
+                None => break,
+            };
+            tokio::spawn(connect(addr, peer_id, info_hash, events_sender.clone()));
+            nconnections += 1;
+        }
+
+        if !discovering
+            && pending.is_empty()
+            && peers.len() == nconnections
+            && fetcher.is_exhausted()
+        {
+            return Err(TorrentError::MetadataUnavailable);
+        }
+
+        let (addr, event) = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => event,
+                Err(_) => return Err(TorrentError::MetadataUnavailable),
+            },
+            addrs = discovered.recv(), if discovering => {
+                match addrs {
+                    Ok(addrs) => pending.extend(addrs.into_iter().filter(|a| known.insert(*a))),
+                    Err(_) => discovering = false,
+                }
+                continue;
+            }
+        };
+
+        match event {
+            PeerEvent::Connected {
+                metadata_size,
+                requests,
+            } => {
+                if fetcher.add_peer(addr, metadata_size) {
+                    peers.insert(addr, requests);
+                }
+            }
+            PeerEvent::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                if let Some(info) = fetcher.on_data(addr, piece, total_size, &data) {
+                    return Ok(magnet.into_torrent(&info)?);
+                }
+            }
+            PeerEvent::Reject { piece } => fetcher.on_reject(addr, piece),
+            PeerEvent::Closed => {
+                fetcher.remove_peer(addr);
+                peers.remove(&addr);
+                nconnections -= 1;
+            }
+        }
+
+        for (addr, piece) in fetcher.next_requests() {
+            if let Some(requests) = peers.get(&addr) {
+                requests.send(piece).await.ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::TryFrom, net::SocketAddr};
+
+    use async_channel::{unbounded, Receiver};
+    use tokio::net::TcpListener;
+
+    use super::{
+        fetch_metadata, read_message, write_message, FetchSettings, MetadataFetcher,
+        MetadataMessage, METADATA_PIECE_SIZE,
+    };
+    use crate::{
+        bencode::de::read_meta,
+        extensions::{Capabilities, ExtendedHandshake, ExtendedMessage},
+        metadata::MagnetLink,
+        peer::{handshake::Handshake, message::MessagePeer},
+    };
+
+    #[test]
+    fn reject_then_other_peer() {
+        // 3 pieces
+        let info: Vec<u8> = (0..METADATA_PIECE_SIZE * 2 + 100)
+            .map(|i| i as u8)
+            .collect();
+        let info_hash = crate::sha1::sha1(&info);
+        let piece = |index: u32| {
+            let start = index as usize * METADATA_PIECE_SIZE;
+            &info[start..(start + METADATA_PIECE_SIZE).min(info.len())]
+        };
+
+        let settings = FetchSettings {
+            max_attempts_per_peer: 1,
+            max_in_flight_per_peer: 2,
+        };
+        let mut fetcher = MetadataFetcher::new(info_hash.to_vec().into(), settings);
+
+        let rejecting: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let serving: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let size = info.len() as i64;
+        assert!(fetcher.add_peer(rejecting, size));
+        assert!(fetcher.add_peer(serving, size));
+        // Different size, not asked while the others can be
+        assert!(fetcher.add_peer("127.0.0.1:3".parse().unwrap(), 10));
+
+        // The pieces are spread over both peers
+        let requests = fetcher.next_requests();
+        assert_eq!(requests, vec![(rejecting, 0), (serving, 1), (rejecting, 2)]);
+
+        fetcher.on_reject(rejecting, 0);
+        assert_eq!(fetcher.on_data(serving, 1, size, piece(1)), None);
+        fetcher.on_reject(rejecting, 2);
+        assert!(!fetcher.is_exhausted());
+
+        // The first peer reached its cap, only the second one is asked
+        let requests = fetcher.next_requests();
+        assert_eq!(requests, vec![(serving, 0), (serving, 2)]);
+
+        // Unrequested data is ignored
+        assert_eq!(fetcher.on_data(rejecting, 0, size, piece(0)), None);
+
+        assert_eq!(fetcher.on_data(serving, 0, size, piece(0)), None);
+        assert_eq!(
+            fetcher.on_data(serving, 2, size, piece(2)),
+            Some(info.clone())
+        );
+    }
+
+    #[test]
+    fn all_peers_tried() {
+        let info = vec![1; 100];
+        let info_hash = crate::sha1::sha1(&info);
+        let mut fetcher = MetadataFetcher::new(info_hash.to_vec().into(), FetchSettings::default());
+
+        let peers: Vec<SocketAddr> = vec![
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        ];
+        for peer in &peers {
+            fetcher.add_peer(*peer, 100);
+        }
+
+        // Each peer is asked twice, then the fetch gives up
+        for _ in 0..4 {
+            let requests = fetcher.next_requests();
+            assert_eq!(requests.len(), 1);
+            let (peer, index) = requests[0];
+            fetcher.on_reject(peer, index);
+        }
+        assert!(fetcher.next_requests().is_empty());
+        assert!(fetcher.is_exhausted());
+
+        // A corrupted piece counts as an attempt of its sender
+        let mut fetcher = MetadataFetcher::new(info_hash.to_vec().into(), FetchSettings::default());
+        fetcher.add_peer(peers[0], 100);
+        fetcher.add_peer(peers[1], 100);
+
+        let (peer, index) = fetcher.next_requests()[0];
+        assert_eq!(fetcher.on_data(peer, index, 100, &[2; 100]), None);
+        let (other, index) = fetcher.next_requests()[0];
+        assert_ne!(peer, other);
+        assert_eq!(
+            fetcher.on_data(other, index, 100, &info),
+            Some(info.clone())
+        );
+
+        // So does a piece announcing another size than the handshake
+        let mut fetcher = MetadataFetcher::new(info_hash.to_vec().into(), FetchSettings::default());
+        fetcher.add_peer(peers[0], 100);
+
+        assert_eq!(fetcher.next_requests(), vec![(peers[0], 0)]);
+        assert_eq!(fetcher.on_data(peers[0], 0, 200, &info), None);
+        assert_eq!(fetcher.next_requests(), vec![(peers[0], 0)]);
+        assert_eq!(fetcher.on_data(peers[0], 0, 100, &info), Some(info));
+    }
+
+    #[test]
+    fn other_size() {
+        let info = vec![1; 100];
+        let info_hash = crate::sha1::sha1(&info);
+        let settings = FetchSettings {
+            max_attempts_per_peer: 1,
+            max_in_flight_per_peer: 2,
+        };
+        let mut fetcher = MetadataFetcher::new(info_hash.to_vec().into(), settings);
+
+        let wrong: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let right: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        assert!(fetcher.add_peer(wrong, 200));
+        assert!(fetcher.add_peer(right, 100));
+
+        // The size of the first peer is taken, the other peer waits
+        assert_eq!(fetcher.next_requests(), vec![(wrong, 0)]);
+        assert_eq!(fetcher.on_data(wrong, 0, 200, &[1; 200]), None);
+
+        // It doesn't match the info hash, the size of the other is tried
+        assert_eq!(fetcher.next_requests(), vec![(right, 0)]);
+        assert_eq!(fetcher.on_data(right, 0, 100, &info), Some(info.clone()));
+
+        // Or once the peers giving the size are gone
+        let mut fetcher = MetadataFetcher::new(info_hash.to_vec().into(), settings);
+        assert!(fetcher.add_peer(wrong, 200));
+        assert!(fetcher.add_peer(right, 100));
+        assert_eq!(fetcher.next_requests(), vec![(wrong, 0)]);
+
+        fetcher.remove_peer(wrong);
+        assert_eq!(fetcher.next_requests(), vec![(right, 0)]);
+        assert_eq!(fetcher.on_data(right, 0, 100, &info), Some(info));
+    }
+
+    /// Info dictionary of 2 metadata pieces
+    fn info_dict() -> Vec<u8> {
+        let npieces = 1000;
+        let pieces: Vec<u8> = (0..npieces * 20).map(|i| i as u8).collect();
+
+        let mut info = format!(
+            "d6:lengthi{}e4:name4:test12:piece lengthi16384e6:pieces{}:",
+            npieces * 16384,
+            pieces.len()
+        )
+        .into_bytes();
+        info.extend_from_slice(&pieces);
+        info.push(b'e');
+        info
+    }
+
+    /// Channel of the peers found by the trackers, with `peers`
+    fn discovered(peers: &[SocketAddr]) -> Receiver<Vec<SocketAddr>> {
+        let (sender, receiver) = unbounded();
+        sender.try_send(peers.to_vec()).unwrap();
+        receiver
+    }
+
+    /// Peer of one connection, serving the `pieces` of `info` to the
+    /// `ut_metadata` requests and rejecting the others. Returns the
+    /// number of pieces sent
+    async fn serve_metadata(listener: TcpListener, info: Vec<u8>, pieces: Vec<u32>) -> usize {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let handshake = Handshake::read_from(&mut stream, |_| true).await.unwrap();
+        Handshake::new(handshake.info_hash, [9; 20], Capabilities::EXTENSION)
+            .write_to(&mut stream)
+            .await
+            .unwrap();
+
+        let handshake = ExtendedHandshake {
+            m: Some(std::iter::once(("ut_metadata".to_string(), 5)).collect()),
+            metadata_size: Some(info.len() as i64),
+            ..Default::default()
+        };
+        write_message(&mut stream, handshake).await.unwrap();
+
+        let mut client_id = 0;
+        let mut buffer = Vec::new();
+        let mut served = 0;
+
+        // Until the client disconnects
+        while let Ok(Some(())) = read_message(&mut stream, &mut buffer).await {
+            let piece = match MessagePeer::try_from(&buffer[..]).unwrap() {
+                MessagePeer::Extension(ExtendedMessage::Handshake { handshake }) => {
+                    client_id = handshake.m.unwrap()["ut_metadata"] as u8;
+                    continue;
+                }
+                MessagePeer::Extension(ExtendedMessage::Message { id: 5, buffer }) => {
+                    match MetadataMessage::from_bytes(buffer).unwrap() {
+                        MetadataMessage::Request { piece } => piece,
+                        msg => panic!("Unexpected message {:?}", msg),
+                    }
+                }
+                msg => panic!("Unexpected message {:?}", msg),
+            };
+
+            let start = piece as usize * METADATA_PIECE_SIZE;
+            let data = if pieces.contains(&piece) {
+                served += 1;
+                MetadataMessage::Data {
+                    piece,
+                    total_size: info.len() as i64,
+                    data: &info[start..(start + METADATA_PIECE_SIZE).min(info.len())],
+                }
+            } else {
+                MetadataMessage::Reject { piece }
+            }
+            .to_bytes();
+
+            let msg = ExtendedMessage::Message {
+                id: client_id,
+                buffer: &data,
+            };
+            write_message(&mut stream, MessagePeer::Extension(msg))
+                .await
+                .unwrap();
+        }
+
+        served
+    }
+
+    #[tokio::test]
+    async fn fetch_from_mock_peers() {
+        let msg = MetadataMessage::Data {
+            piece: 1,
+            total_size: 100,
+            data: b"abc",
+        };
+        let bytes = msg.to_bytes();
+        assert_eq!(bytes, b"d8:msg_typei1e5:piecei1e10:total_sizei100eeabc");
+        assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), msg);
+
+        let info = info_dict();
+        let info_hash: String = crate::sha1::sha1(&info)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        // The first peer sends data of the right size but not matching
+        // the info hash, the second one the metadata
+        let corrupted: Vec<u8> = info.iter().map(|b| !b).collect();
+        let bad = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let good = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peers = [bad.local_addr().unwrap(), good.local_addr().unwrap()];
+        tokio::spawn(serve_metadata(bad, corrupted, vec![0, 1]));
+        tokio::spawn(serve_metadata(good, info.clone(), vec![0, 1]));
+
+        let magnet = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:btih:{}&dn=magnet&tr=http%3A%2F%2Fa.test%2Fannounce",
+            info_hash
+        ))
+        .unwrap();
+
+        let torrent = fetch_metadata(
+            magnet,
+            discovered(&peers),
+            [1; 20],
+            FetchSettings::default(),
+        )
+        .await
+        .unwrap();
+
+        let file = [&b"d4:info"[..], &info, b"e"].concat();
+        let original = read_meta(&file).unwrap();
+
+        assert_eq!(torrent.info_hash, original.info_hash);
+        assert_eq!(torrent.meta.info.pieces, original.meta.info.pieces);
+        assert_eq!(torrent.meta.info.piece_length, 16384);
+        assert_eq!(torrent.name(), original.name());
+        assert_eq!(torrent.files_total_size(), original.files_total_size());
+        assert_eq!(torrent.trackers(), vec!["http://a.test/announce"]);
+    }
+
+    #[tokio::test]
+    async fn pieces_from_two_peers() {
+        let info = info_dict();
+        let info_hash: String = crate::sha1::sha1(&info)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        // Each peer has a piece of the metadata
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peers = [first.local_addr().unwrap(), second.local_addr().unwrap()];
+        let first = tokio::spawn(serve_metadata(first, info.clone(), vec![0]));
+        let second = tokio::spawn(serve_metadata(second, info.clone(), vec![1]));
+
+        let magnet = MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", info_hash)).unwrap();
+
+        let torrent = fetch_metadata(
+            magnet,
+            discovered(&peers),
+            [1; 20],
+            FetchSettings::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(&torrent.info_hash[..], &crate::sha1::sha1(&info)[..]);
+        assert_eq!(first.await.unwrap(), 1);
+        assert_eq!(second.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn x_pe_peer_first() {
+        let info = info_dict();
+        let info_hash: String = crate::sha1::sha1(&info)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = peer.local_addr().unwrap();
+        let peer = tokio::spawn(serve_metadata(peer, info.clone(), vec![0, 1]));
+
+        let magnet = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:btih:{}&x.pe={}",
+            info_hash,
+            addr.to_string().replace(':', "%3A")
+        ))
+        .unwrap();
+
+        // The trackers didn't answer yet
+        let (_trackers, discovered) = unbounded();
+
+        let torrent = fetch_metadata(magnet, discovered, [1; 20], FetchSettings::default())
+            .await
+            .unwrap();
+
+        assert_eq!(&torrent.info_hash[..], &crate::sha1::sha1(&info)[..]);
+        assert_eq!(peer.await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,484 @@
+use std::sync::Arc;
+
+use async_channel::{Receiver, RecvError, Sender};
+use kv_log_macro::{error, info};
+use tokio::runtime::Runtime;
+
+use crate::{
+    fs::FSMessage,
+    metadata::Torrent,
+    peer::peer::PeerCommand,
+    piece_picker::{BlockIndex, PieceIndex},
+    pieces::Pieces,
+    supervisors::torrent::{TorrentId, TorrentNotification},
+    utils::Map,
+};
+
+use super::{new_read_buffer, send_to_peer, send_to_supervisor, WriteThrottle};
+
+/// Storage of the torrents data, to keep it elsewhere than in files
+/// (object storage, database, memory).
+///
+/// The data of a torrent is addressed as a single range of bytes, from
+/// 0 to `Pieces::files_size`: the offset of a block is
+/// `piece * piece_length + block`. Splitting it in files is up to the
+/// backend.
+///
+/// All the calls are made from a dedicated thread, they can block
+pub trait StorageBackend: Send + 'static {
+    /// A torrent is added to the session, prepare its storage.
+    /// Called before any other operation on the torrent.
+    ///
+    /// With `read_only`, the existing data is seeded and no write
+    /// is made
+    fn allocate(
+        &mut self,
+        id: TorrentId,
+        torrent: &Torrent,
+        pieces: &Pieces,
+        read_only: bool,
+    ) -> std::io::Result<()>;
+
+    /// Fill `buf` with the data at `offset`.
+    /// Returns the number of bytes read, less than the length of `buf`
+    /// when the data isn't stored (yet)
+    fn read(&mut self, id: TorrentId, offset: u64, buf: &mut [u8]) -> std::io::Result<usize>;
+
+    /// Store a piece, its sha1 was checked
+    fn write(&mut self, id: TorrentId, offset: u64, data: &[u8]) -> std::io::Result<()>;
+
+    /// Make the written data durable.
+    /// Called before the torrent is removed
+    fn flush(&mut self, id: TorrentId) -> std::io::Result<()>;
+
+    /// The torrent is removed from the session, its storage is released.
+    /// No other call is made for this torrent afterward
+    fn delete(&mut self, id: TorrentId) -> std::io::Result<()>;
+}
+
+struct BackendTorrent {
+    pieces_infos: Arc<Pieces>,
+    read_only: bool,
+}
+
+/// Receives the same messages as the file systems, and stores the
+/// data with a `StorageBackend`
+pub struct BackendFS<B: StorageBackend> {
+    runtime: Arc<Runtime>,
+    recv: Receiver<FSMessage>,
+    backend: B,
+    torrents: Map<TorrentId, BackendTorrent>,
+    throttle: WriteThrottle,
+}
+
+impl<B: StorageBackend> BackendFS<B> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(runtime: Arc<Runtime>, backend: B) -> Sender<FSMessage> {
+        let (sender, recv) = async_channel::bounded(1000);
+
+        let vfs = BackendFS {
+            runtime,
+            recv,
+            backend,
+            torrents: Map::default(),
+            throttle: WriteThrottle::default(),
+        };
+
+        std::thread::Builder::new()
+            .name("rustorrent-vfs".into())
+            .spawn(move || vfs.start())
+            .unwrap();
+
+        sender
+    }
+
+    fn wait_for_message(&mut self) -> Result<FSMessage, RecvError> {
+        self.throttle.next(&self.runtime, &self.recv)
+    }
+
+    fn start(mut self) {
+        while let Ok(msg) = self.wait_for_message() {
+            self.process_msg(msg);
+        }
+    }
+
+    fn process_msg(&mut self, msg: FSMessage) {
+        match msg {
+            FSMessage::AddTorrent {
+                id,
+                meta,
+                pieces_infos,
+                read_only,
+                ..
+            } => {
+                if let Err(e) = self.backend.allocate(id, &meta, &pieces_infos, read_only) {
+                    error!("[vfs] {:?} Allocation failed {:?}", id, e);
+                    return;
+                }
+
+                let torrent = BackendTorrent {
+                    pieces_infos,
+                    read_only,
+                };
+                self.torrents.insert(id, torrent);
+
+                info!("[vfs] {:?} Add torrent", id);
+            }
+            FSMessage::RemoveTorrent { id } => {
+                if self.torrents.remove(&id).is_none() {
+                    return;
+                }
+
+                let result = self.backend.flush(id).and_then(|_| self.backend.delete(id));
+
+                if let Err(e) = result {
+                    error!("[vfs] {:?} Remove failed {:?}", id, e);
+                }
+            }
+            FSMessage::Read {
+                id,
+                piece,
+                block,
+                length,
+                peer,
+            } => {
+                self.read(id, piece, block, length, peer);
+            }
+            FSMessage::Write { id, piece, data } => {
+                self.write(id, piece, 0.into(), &data);
+            }
+            // The backends have no files, the writes are made in order
+            FSMessage::WriteBatch { id, writes } => {
+                for write in writes {
+                    self.write(id, write.piece, write.block, &write.data);
+                }
+            }
+            FSMessage::SetWriteRate { bytes_per_sec } => {
+                self.throttle.set_rate(bytes_per_sec);
+            }
+            // The space of the backend isn't known, writes are never rejected
+            FSMessage::SetMinFreeSpace { .. } | FSMessage::CheckFreeSpace { .. } => {}
+            // The backend allocates its storage in `StorageBackend::allocate`
+            FSMessage::SetAllocation { .. } | FSMessage::Allocate { .. } => {}
+            FSMessage::ReadPiece {
+                id,
+                piece,
+                supervisor,
+            } => {
+                self.read_piece(id, piece, supervisor);
+            }
+            FSMessage::ReadBlock {
+                id,
+                file_offset,
+                length,
+                respond,
+            } => {
+                respond.send(self.read_block(id, file_offset, length)).ok();
+            }
+            FSMessage::Flush { id, done } => {
+                if self.torrents.contains_key(&id) {
+                    if let Err(e) = self.backend.flush(id) {
+                        error!("[vfs] {:?} Flush failed {:?}", id, e);
+                    }
+                }
+                done.try_send(()).ok();
+            }
+        }
+    }
+
+    fn offset_of(&self, id: TorrentId, piece: PieceIndex, block: BlockIndex) -> Option<u64> {
+        let torrent = self.torrents.get(&id)?;
+        let piece: usize = piece.into();
+        let block: usize = block.into();
+
+        Some((piece * torrent.pieces_infos.piece_length + block) as u64)
+    }
+
+    fn read(
+        &mut self,
+        id: TorrentId,
+        piece: PieceIndex,
+        block: BlockIndex,
+        length: u32,
+        peer: Sender<PeerCommand>,
+    ) {
+        let (data, complete) = match self.read_buffer(id, piece, block, length) {
+            Some(read) => read,
+            None => return,
+        };
+
+        // Never send a block we don't have entirely
+        if !complete {
+            error!("[vfs] {:?} Short read on piece {:?}", id, piece);
+            return;
+        }
+
+        send_to_peer(&self.runtime, peer, piece, block, data);
+    }
+
+    fn read_piece(
+        &mut self,
+        id: TorrentId,
+        piece: PieceIndex,
+        supervisor: Sender<TorrentNotification>,
+    ) {
+        let length = match self.torrents.get(&id) {
+            Some(torrent) => torrent.pieces_infos.piece_size_of(piece),
+            None => return,
+        };
+
+        // The missing data is zeroed, the piece then fails its hash
+        if let Some((data, _)) = self.read_buffer(id, piece, 0.into(), length) {
+            send_to_supervisor(&self.runtime, supervisor, piece, data);
+        }
+    }
+
+    /// Read `length` bytes at this offset of the torrent. The range
+    /// must be inside the torrent, a short read is an `UnexpectedEof`
+    fn read_block(&mut self, id: TorrentId, offset: u64, length: u32) -> std::io::Result<Vec<u8>> {
+        let torrent = self.torrents.get(&id).ok_or(std::io::ErrorKind::NotFound)?;
+
+        if torrent
+            .pieces_infos
+            .block_at(offset, length as usize)
+            .is_none()
+        {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+
+        let mut data = vec![0; length as usize];
+        match self.backend.read(id, offset, &mut data)? {
+            n if n >= data.len() => Ok(data),
+            _ => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    /// Returns the data and whether it was read entirely, the missing
+    /// bytes are zeroed
+    fn read_buffer(
+        &mut self,
+        id: TorrentId,
+        piece: PieceIndex,
+        block: BlockIndex,
+        length: u32,
+    ) -> Option<(Box<[u8]>, bool)> {
+        let offset = self.offset_of(id, piece, block)?;
+        let mut data = new_read_buffer(length as usize);
+
+        let nread = match self.backend.read(id, offset, &mut data) {
+            Ok(nread) => nread.min(data.len()),
+            Err(e) => {
+                error!("[vfs] {:?} Read failed {:?}", id, e);
+                0
+            }
+        };
+
+        let complete = nread == data.len();
+        data[nread..].iter_mut().for_each(|b| *b = 0);
+
+        Some((data, complete))
+    }
+
+    fn write(&mut self, id: TorrentId, piece: PieceIndex, block: BlockIndex, data: &[u8]) {
+        let read_only = match self.torrents.get(&id) {
+            Some(torrent) => torrent.read_only,
+            None => return,
+        };
+
+        if read_only {
+            error!("[vfs] {:?} Write rejected, the torrent is read-only", id);
+            return;
+        }
+
+        let offset = self.offset_of(id, piece, block).unwrap();
+
+        if let Err(e) = self.backend.write(id, offset, data) {
+            error!("[vfs] {:?} Write failed on piece {:?} {:?}", id, piece, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use hashbrown::HashMap;
+    use tokio::runtime::Runtime;
+
+    use super::{BackendFS, StorageBackend};
+    use crate::{
+        fs::FSMessage::{AddTorrent, Read, ReadPiece, RemoveTorrent, Write},
+        metadata::{TestTorrent, Torrent},
+        peer::peer::PeerCommand,
+        pieces::Pieces,
+        supervisors::torrent::{TorrentId, TorrentNotification},
+    };
+
+    #[derive(Default)]
+    struct Memory {
+        torrents: HashMap<TorrentId, Vec<u8>>,
+        flushed: Vec<TorrentId>,
+        deleted: Vec<TorrentId>,
+    }
+
+    /// Backend keeping the data in memory, shared with the test
+    #[derive(Clone, Default)]
+    struct MemoryBackend(Arc<Mutex<Memory>>);
+
+    impl StorageBackend for MemoryBackend {
+        fn allocate(
+            &mut self,
+            id: TorrentId,
+            _torrent: &Torrent,
+            pieces: &Pieces,
+            _read_only: bool,
+        ) -> std::io::Result<()> {
+            let mut memory = self.0.lock().unwrap();
+            memory
+                .torrents
+                .insert(id, Vec::with_capacity(pieces.files_size));
+            Ok(())
+        }
+
+        fn read(&mut self, id: TorrentId, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+            let memory = self.0.lock().unwrap();
+            let data = &memory.torrents[&id];
+            let data = data.get(offset as usize..).unwrap_or(&[]);
+            let n = data.len().min(buf.len());
+
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+
+        fn write(&mut self, id: TorrentId, offset: u64, data: &[u8]) -> std::io::Result<()> {
+            let mut memory = self.0.lock().unwrap();
+            let stored = memory.torrents.get_mut(&id).unwrap();
+            let end = offset as usize + data.len();
+
+            if stored.len() < end {
+                stored.resize(end, 0);
+            }
+            stored[offset as usize..end].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn flush(&mut self, id: TorrentId) -> std::io::Result<()> {
+            self.0.lock().unwrap().flushed.push(id);
+            Ok(())
+        }
+
+        fn delete(&mut self, id: TorrentId) -> std::io::Result<()> {
+            let mut memory = self.0.lock().unwrap();
+            memory.torrents.remove(&id);
+            memory.deleted.push(id);
+            Ok(())
+        }
+    }
+
+    fn torrent() -> Torrent {
+        TestTorrent::new()
+            .name("memory")
+            .length(2500)
+            .info_hash(9)
+            .build()
+    }
+
+    #[test]
+    fn memory_backend() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let backend = MemoryBackend::default();
+        let fs = BackendFS::new(runtime.clone(), backend.clone());
+
+        let torrent = torrent();
+        let pieces = Pieces::from(&torrent);
+        let id = TorrentId::new();
+        let data: Vec<u8> = (0..pieces.files_size).map(|_| fastrand::u8(..)).collect();
+
+        fs.try_send(AddTorrent {
+            id,
+            meta: Arc::new(torrent),
+            pieces_infos: Arc::new(pieces.clone()),
+            read_only: false,
+            supervisor: async_channel::unbounded().0,
+        })
+        .unwrap();
+
+        // The last piece is downloaded first
+        for index in (0..pieces.num_pieces).rev() {
+            let start = index * pieces.piece_length;
+            let end = (start + pieces.piece_length).min(data.len());
+
+            fs.try_send(Write {
+                id,
+                piece: (index as u32).into(),
+                data: data[start..end].to_vec().into_boxed_slice(),
+            })
+            .unwrap();
+        }
+
+        // Seed a block to a peer
+        let (peer, blocks) = async_channel::unbounded();
+        fs.try_send(Read {
+            id,
+            piece: 1.into(),
+            block: 500.into(),
+            length: 500,
+            peer,
+        })
+        .unwrap();
+
+        match runtime.block_on(blocks.recv()) {
+            Ok(PeerCommand::BlockData {
+                piece,
+                block,
+                data: read,
+            }) => {
+                assert_eq!((piece, block), (1.into(), 500.into()));
+                assert_eq!(&*read, &data[1500..2000]);
+            }
+            _ => panic!("No block read"),
+        }
+
+        // Full piece, to recheck it
+        let (supervisor, notifications) = async_channel::unbounded();
+        fs.try_send(ReadPiece {
+            id,
+            piece: 2.into(),
+            supervisor,
+        })
+        .unwrap();
+
+        match runtime.block_on(notifications.recv()) {
+            Ok(TorrentNotification::PieceRead {
+                piece_index,
+                data: read,
+            }) => {
+                assert_eq!(piece_index, 2.into());
+                assert_eq!(&*read, &data[2000..]);
+            }
+            _ => panic!("No piece read"),
+        }
+
+        assert_eq!(backend.0.lock().unwrap().torrents[&id], data);
+
+        fs.try_send(RemoveTorrent { id }).unwrap();
+
+        // The messages are processed in order
+        let (peer, blocks) = async_channel::unbounded();
+        fs.try_send(Read {
+            id,
+            piece: 0.into(),
+            block: 0.into(),
+            length: 10,
+            peer,
+        })
+        .unwrap();
+        drop(fs);
+        assert!(runtime.block_on(blocks.recv()).is_err());
+
+        let memory = backend.0.lock().unwrap();
+        assert_eq!(memory.flushed, vec![id]);
+        assert_eq!(memory.deleted, vec![id]);
+        assert!(memory.torrents.is_empty());
+    }
+}
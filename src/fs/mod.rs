@@ -1,27 +1,56 @@
 use std::{
+    collections::VecDeque,
     fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
     path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use async_channel::{Sender, TrySendError};
+use async_channel::{Receiver, RecvError, Sender, TrySendError};
 use hashbrown::HashMap;
-use kv_log_macro::debug;
-use tokio::runtime::Runtime;
+use kv_log_macro::{debug, error};
+use tokio::{runtime::Runtime, sync::oneshot};
 
 use crate::{
+    bitfield::BitField,
+    errors::TorrentError,
     metadata::{Torrent, TorrentFile},
     peer::peer::PeerCommand,
     piece_picker::{BlockIndex, PieceIndex},
     pieces::Pieces,
-    supervisors::torrent::TorrentId,
+    rate_limit::TokenBucket,
+    sha1::sha1,
+    supervisors::torrent::{TorrentId, TorrentNotification},
 };
 
+pub mod backend;
 pub mod standard_fs;
 pub mod uring_fs;
 
+/// Bytes of zeros written at once when a file is preallocated
+const ZERO_CHUNK: usize = 1 << 20;
+
+/// How the files are allocated on the disk, see `SessionConfig::allocation`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum AllocationMode {
+    /// The files grow with the writes, the parts not downloaded yet
+    /// take no space
+    #[default]
+    Sparse,
+    /// The files take their full size when the torrent is added, before
+    /// any write. This avoids the fragmentation, and running out of
+    /// space in the middle of the download
+    Preallocate,
+}
+
+/// Actor storing the data of the torrents, it receives the `FSMessage`.
+/// See `backend::StorageBackend` to store it elsewhere than in files
 pub trait FileSystem {
-    fn init(runtime: Arc<Runtime>) -> Option<Sender<FSMessage>>;
+    type Error: std::fmt::Debug;
+
+    fn init(runtime: Arc<Runtime>) -> Result<Sender<FSMessage>, Self::Error>;
 }
 
 pub enum FSMessage {
@@ -29,6 +58,10 @@ pub enum FSMessage {
         id: TorrentId,
         meta: Arc<Torrent>,
         pieces_infos: Arc<Pieces>,
+        /// The files are opened read-only and writes are rejected
+        read_only: bool,
+        /// Told about the writes rejected, see `SetMinFreeSpace`
+        supervisor: Sender<TorrentNotification>,
     },
     RemoveTorrent {
         id: TorrentId,
@@ -45,9 +78,175 @@ pub enum FSMessage {
         piece: PieceIndex,
         data: Box<[u8]>,
     },
+    /// Writes of the same torrent, the contiguous ones are coalesced
+    /// in a single write per file
+    WriteBatch {
+        id: TorrentId,
+        writes: Vec<WriteRequest>,
+    },
+    /// Limit the writes of all torrents, in bytes per second.
+    /// 0 means unlimited
+    SetWriteRate {
+        bytes_per_sec: u64,
+    },
+    /// Reject the writes of a torrent when the free space of its disk
+    /// would drop below this, in bytes. The piece is given back to its
+    /// supervisor with `TorrentNotification::WriteRejected`.
+    /// 0 means no limit
+    SetMinFreeSpace {
+        bytes: u64,
+    },
+    /// Allocation of the files of the torrents added afterward. With
+    /// `Preallocate`, the supervisor is told with
+    /// `TorrentNotification::AllocationFailed` when the disk doesn't
+    /// have room for them
+    SetAllocation {
+        mode: AllocationMode,
+    },
+    /// Allocate the files of the torrent again, after a
+    /// `TorrentNotification::AllocationFailed`
+    Allocate {
+        id: TorrentId,
+    },
+    /// Send `TorrentNotification::DiskSpaceAvailable` to the supervisor
+    /// once a piece can be written again
+    CheckFreeSpace {
+        id: TorrentId,
+    },
+    /// Read a full piece, the data is sent back to the supervisor
+    /// with `TorrentNotification::PieceRead`
+    ReadPiece {
+        id: TorrentId,
+        piece: PieceIndex,
+        supervisor: Sender<TorrentNotification>,
+    },
+    /// Read `length` bytes at this offset of the torrent, possibly
+    /// over many files. A short read is an `UnexpectedEof` error
+    ReadBlock {
+        id: TorrentId,
+        file_offset: u64,
+        length: u32,
+        respond: oneshot::Sender<io::Result<Vec<u8>>>,
+    },
+    /// Make the writes received before this message durable, `done` is
+    /// notified once they are
+    Flush {
+        id: TorrentId,
+        done: Sender<()>,
+    },
+}
+
+impl FSMessage {
+    /// The torrent of the message, `None` for the settings of the FS
+    fn torrent_id(&self) -> Option<TorrentId> {
+        match self {
+            FSMessage::AddTorrent { id, .. }
+            | FSMessage::RemoveTorrent { id }
+            | FSMessage::Read { id, .. }
+            | FSMessage::Write { id, .. }
+            | FSMessage::WriteBatch { id, .. }
+            | FSMessage::Allocate { id }
+            | FSMessage::CheckFreeSpace { id }
+            | FSMessage::ReadPiece { id, .. }
+            | FSMessage::ReadBlock { id, .. }
+            | FSMessage::Flush { id, .. } => Some(*id),
+            FSMessage::SetWriteRate { .. }
+            | FSMessage::SetMinFreeSpace { .. }
+            | FSMessage::SetAllocation { .. } => None,
+        }
+    }
+
+    /// Bytes written by the message, `None` when it's not a write
+    fn write_length(&self) -> Option<usize> {
+        match self {
+            FSMessage::Write { data, .. } => Some(data.len()),
+            FSMessage::WriteBatch { writes, .. } => Some(writes.iter().map(|w| w.data.len()).sum()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct WriteRequest {
+    pub piece: PieceIndex,
+    /// Offset in the piece
+    pub block: BlockIndex,
+    pub data: Box<[u8]>,
+}
+
+/// Contiguous data to write in one file, made of parts of the
+/// `WriteRequest`s of a batch
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct FileWrite {
+    /// Index in `TorrentCache::files`
+    pub file: usize,
+    /// Offset in the file
+    pub offset: usize,
+    /// Index of the request and range of its data, in order
+    pub parts: Vec<(usize, Range<usize>)>,
 }
 
-fn open_file(path: &Path) -> File {
+impl FileWrite {
+    pub fn length(&self) -> usize {
+        self.parts.iter().map(|(_, range)| range.len()).sum()
+    }
+}
+
+/// Reserve the blocks of the file up to `length`, its data is kept.
+/// Returns `false` when the file system doesn't support it
+#[cfg(target_os = "linux")]
+fn fallocate_file(fd: &File, length: u64) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    if length == 0 {
+        return Ok(true);
+    }
+
+    if unsafe { libc::fallocate(fd.as_raw_fd(), 0, 0, length as libc::off_t) } == 0 {
+        return Ok(true);
+    }
+
+    match io::Error::last_os_error() {
+        e if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(false),
+        e => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fallocate_file(_fd: &File, _length: u64) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Extend the file to `length`, the bytes after its current end are
+/// written with zeros so they take their space on the disk
+fn fill_with_zeros(fd: &mut File, length: u64) -> io::Result<()> {
+    use std::io::Write;
+
+    let current = fd.metadata()?.len();
+    if current >= length {
+        return Ok(());
+    }
+
+    fd.set_len(length)?;
+    fd.seek(SeekFrom::Start(current))?;
+
+    let zeros = vec![0; ZERO_CHUNK];
+    let mut remaining = length - current;
+
+    while remaining > 0 {
+        let chunk = remaining.min(ZERO_CHUNK as u64) as usize;
+        fd.write_all(&zeros[..chunk])?;
+        remaining -= chunk as u64;
+    }
+
+    Ok(())
+}
+
+fn open_file(path: &Path, read_only: bool) -> io::Result<File> {
+    if read_only {
+        return File::open(path);
+    }
+
     if !path.exists() {
         let create_dir = if path.is_dir() {
             Some(path)
@@ -57,7 +256,7 @@ fn open_file(path: &Path) -> File {
 
         if let Some(dir) = create_dir {
             debug!("Creating directory {:?}", dir);
-            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::create_dir_all(&dir)?;
         };
     }
 
@@ -66,7 +265,355 @@ fn open_file(path: &Path) -> File {
         .create(true)
         .read(true)
         .open(path)
-        .unwrap()
+}
+
+/// The files of a torrent read in place, without the FS actor: a file
+/// is only opened when it exists, nothing is created
+struct ExistingFiles {
+    ranges: Vec<Range<usize>>,
+    fds: Vec<Option<File>>,
+}
+
+impl ExistingFiles {
+    /// `keep` filters the files opened, with their range in the torrent
+    fn open(
+        files: &[TorrentFile],
+        keep: impl Fn(&TorrentFile, &Range<usize>, &File) -> bool,
+    ) -> ExistingFiles {
+        let mut file_start = 0;
+        let ranges: Vec<Range<usize>> = files
+            .iter()
+            .map(|file| {
+                let start = file_start;
+                file_start += file.length as usize;
+                start..file_start
+            })
+            .collect();
+
+        let fds = files
+            .iter()
+            .zip(&ranges)
+            .map(|(file, range)| match File::open(&file.path) {
+                Ok(fd) if keep(file, range, &fd) => Some(fd),
+                _ => None,
+            })
+            .collect();
+
+        ExistingFiles { ranges, fds }
+    }
+
+    /// The files of the piece, a piece can span several of them
+    fn spanned(&self, piece_range: &Range<usize>) -> Vec<usize> {
+        self.ranges
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| {
+                !r.is_empty() && r.start < piece_range.end && piece_range.start < r.end
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Read the piece from its files, `false` when one of them isn't
+    /// opened or is too short
+    fn read(&mut self, spanned: &[usize], piece_range: &Range<usize>, piece: &mut [u8]) -> bool {
+        let offset = piece_range.start;
+
+        spanned
+            .iter()
+            .try_for_each(|i| {
+                let range = &self.ranges[*i];
+                let start = range.start.max(piece_range.start);
+                let end = range.end.min(piece_range.end);
+                let fd = match self.fds[*i].as_mut() {
+                    Some(fd) => fd,
+                    None => return Err(io::ErrorKind::NotFound.into()),
+                };
+
+                fd.seek(SeekFrom::Start((start - range.start) as u64))?;
+                fd.read_exact(&mut piece[start - offset..end - offset])
+            })
+            .is_ok()
+    }
+}
+
+fn piece_range(pieces: &Pieces, index: usize) -> Range<usize> {
+    let offset = index * pieces.piece_length;
+    let length = if index + 1 == pieces.num_pieces {
+        pieces.last_piece_length
+    } else {
+        pieces.piece_length
+    };
+    offset..offset + length
+}
+
+/// Existing files with the size of the torrent file, but a different
+/// content: one of the pieces they contain fails its sha1.
+/// The pieces with an empty block are ignored, they are holes not
+/// downloaded yet, or pieces interrupted while they were written.
+/// `ours` are the pieces of the resume data, the files containing one
+/// of them are our own download and are never reported
+pub fn find_conflicts(files: &[TorrentFile], pieces: &Pieces, ours: &BitField) -> Vec<PathBuf> {
+    let piece_length = pieces.piece_length;
+
+    // The files which can conflict
+    let mut existing = ExistingFiles::open(files, |file, range, fd| {
+        let ours = !range.is_empty()
+            && (range.start / piece_length..range.end.div_ceil(piece_length))
+                .any(|index| ours.get_bit(index));
+
+        !ours && fd.metadata().map(|m| m.len()).ok() == Some(file.length)
+    });
+
+    let mut conflicts = vec![false; files.len()];
+    let mut buffer = vec![0; piece_length];
+
+    for index in 0..pieces.num_pieces {
+        let piece_range = piece_range(pieces, index);
+        let spanned = existing.spanned(&piece_range);
+
+        if spanned.iter().all(|i| conflicts[*i]) {
+            continue;
+        }
+
+        let piece = &mut buffer[..piece_range.len()];
+        if !existing.read(&spanned, &piece_range, piece) {
+            continue;
+        }
+
+        let block_size = pieces.block_size as usize;
+        if piece
+            .chunks(block_size)
+            .any(|block| block.iter().all(|b| *b == 0))
+        {
+            continue;
+        }
+
+        if sha1(piece) != *pieces.sha1_pieces[index] {
+            for i in spanned {
+                conflicts[i] = true;
+            }
+        }
+    }
+
+    files
+        .iter()
+        .zip(conflicts)
+        .filter(|(_, conflict)| *conflict)
+        .map(|(file, _)| file.path.clone())
+        .collect()
+}
+
+/// The pieces already on the disk with their sha1, a copy placed there
+/// before downloading for example. A piece over a missing or short
+/// file is not valid, the files are never created
+pub fn scan_existing(files: &[TorrentFile], pieces: &Pieces) -> BitField {
+    let mut existing = ExistingFiles::open(files, |_, _, _| true);
+    let mut bitfield = BitField::new(pieces.num_pieces);
+    let mut buffer = vec![0; pieces.piece_length];
+
+    for index in 0..pieces.num_pieces {
+        let piece_range = piece_range(pieces, index);
+        let spanned = existing.spanned(&piece_range);
+        let piece = &mut buffer[..piece_range.len()];
+
+        if existing.read(&spanned, &piece_range, piece) && sha1(piece) == *pieces.sha1_pieces[index]
+        {
+            bitfield.set_bit(index);
+        }
+    }
+
+    bitfield
+}
+
+/// Free space of the file system containing `path`, in bytes.
+/// `path` doesn't have to exist, its closest existing parent is used
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let c_path = CString::new(existing.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Messages delayed by `FSMessage::SetWriteRate`. A write waits for its
+/// tokens without blocking the thread: the messages of the other
+/// torrents are processed meanwhile, those of its torrent wait behind
+/// it so they are still processed in order
+pub(super) struct WriteThrottle {
+    limit: TokenBucket,
+    /// In the order they were received, with the instant they can be
+    /// processed
+    delayed: VecDeque<(Instant, FSMessage)>,
+}
+
+impl Default for WriteThrottle {
+    fn default() -> WriteThrottle {
+        WriteThrottle {
+            limit: TokenBucket::new(0),
+            delayed: VecDeque::new(),
+        }
+    }
+}
+
+impl WriteThrottle {
+    pub(super) fn set_rate(&mut self, bytes_per_sec: u64) {
+        self.limit = TokenBucket::new(bytes_per_sec);
+    }
+
+    /// The next message to process: a delayed one once its time has
+    /// come, or one received on `recv`. Waits until there is one.
+    /// The delayed messages are still processed after `recv` is closed
+    pub(super) fn next(
+        &mut self,
+        runtime: &Runtime,
+        recv: &Receiver<FSMessage>,
+    ) -> Result<FSMessage, RecvError> {
+        loop {
+            if let Some(msg) = self.try_next(recv) {
+                return Ok(msg);
+            }
+
+            let deadline = self.delayed.iter().map(|(at, _)| *at).min();
+
+            let received = runtime.block_on(async {
+                match deadline {
+                    Some(deadline) => {
+                        let deadline = tokio::time::Instant::from_std(deadline);
+                        tokio::time::timeout_at(deadline, recv.recv()).await.ok()
+                    }
+                    None => Some(recv.recv().await),
+                }
+            });
+
+            match received {
+                Some(Ok(msg)) => {
+                    if let Some(msg) = self.admit(msg) {
+                        return Ok(msg);
+                    }
+                }
+                Some(Err(e)) if self.delayed.is_empty() => return Err(e),
+                // Closed, the last delayed messages are awaited
+                Some(Err(_)) => {
+                    runtime.block_on(tokio::time::sleep_until(tokio::time::Instant::from_std(
+                        deadline.unwrap(),
+                    )));
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Like `next`, without waiting
+    pub(super) fn try_next(&mut self, recv: &Receiver<FSMessage>) -> Option<FSMessage> {
+        let now = Instant::now();
+
+        if let Some(index) = self.delayed.iter().position(|(at, _)| *at <= now) {
+            return self.delayed.remove(index).map(|(_, msg)| msg);
+        }
+
+        while let Ok(msg) = recv.try_recv() {
+            if let Some(msg) = self.admit(msg) {
+                return Some(msg);
+            }
+        }
+
+        None
+    }
+
+    /// Returns the message when it can be processed now, it's delayed
+    /// otherwise
+    fn admit(&mut self, msg: FSMessage) -> Option<FSMessage> {
+        let now = Instant::now();
+        let id = msg.torrent_id();
+
+        let wait = match msg.write_length() {
+            Some(length) => self.limit.take_at(length, now),
+            None => Duration::from_secs(0),
+        };
+
+        // Behind the messages of its torrent
+        let previous = self
+            .delayed
+            .iter()
+            .rev()
+            .find(|(_, delayed)| id.is_some() && delayed.torrent_id() == id)
+            .map(|(at, _)| *at);
+
+        if previous.is_none() && wait == Duration::from_secs(0) {
+            return Some(msg);
+        }
+
+        let at = previous.unwrap_or(now).max(now + wait);
+        self.delayed.push_back((at, msg));
+        None
+    }
+}
+
+/// Free space required on the disk of the torrents before writing
+pub(crate) struct DiskSpace {
+    min_free: u64,
+    free_space: fn(&Path) -> io::Result<u64>,
+}
+
+impl Default for DiskSpace {
+    fn default() -> DiskSpace {
+        DiskSpace {
+            min_free: 0,
+            free_space,
+        }
+    }
+}
+
+impl DiskSpace {
+    #[cfg(test)]
+    pub(crate) fn with_free_space(free_space: fn(&Path) -> io::Result<u64>) -> DiskSpace {
+        DiskSpace {
+            min_free: 0,
+            free_space,
+        }
+    }
+
+    pub(crate) fn set_min_free(&mut self, bytes: u64) {
+        self.min_free = bytes;
+    }
+
+    /// Free space of the disk of the torrent, `None` when it can't
+    /// be read
+    fn available(&self, cache: &TorrentCache) -> Option<u64> {
+        let path = &cache.files.first()?.path;
+        (self.free_space)(path).ok()
+    }
+
+    /// Whether `length` bytes can be written for this torrent. The
+    /// free space is assumed large enough when it can't be read
+    fn has_room(&self, cache: &TorrentCache, length: u64) -> bool {
+        if self.min_free == 0 {
+            return true;
+        }
+
+        match self.available(cache) {
+            Some(free) => free >= self.min_free.saturating_add(length),
+            None => true,
+        }
+    }
 }
 
 pub struct TorrentCache {
@@ -74,9 +621,162 @@ pub struct TorrentCache {
     pub pieces_infos: Arc<Pieces>,
     pub files: Vec<TorrentFile>,
     pub fds: HashMap<PathBuf, File>,
+    pub read_only: bool,
+    pub supervisor: Sender<TorrentNotification>,
 }
 
 impl TorrentCache {
+    pub fn new(
+        meta: Arc<Torrent>,
+        pieces_infos: Arc<Pieces>,
+        read_only: bool,
+        supervisor: Sender<TorrentNotification>,
+    ) -> TorrentCache {
+        TorrentCache {
+            files: meta.files(),
+            torrent: meta,
+            pieces_infos,
+            fds: HashMap::default(),
+            read_only,
+            supervisor,
+        }
+    }
+
+    /// Returns `false` when the disk is too full to write this piece,
+    /// the supervisor is told it's not written
+    pub(crate) fn check_space(
+        &self,
+        runtime: &Runtime,
+        id: TorrentId,
+        piece: PieceIndex,
+        length: usize,
+        space: &DiskSpace,
+    ) -> bool {
+        if space.has_room(self, length as u64) {
+            return true;
+        }
+
+        error!(
+            "[vfs] {:?} Not enough free space, piece {:?} rejected",
+            id, piece
+        );
+        let msg = TorrentNotification::WriteRejected { piece_index: piece };
+        send_notification(runtime, &self.supervisor, msg);
+        false
+    }
+
+    /// Answer to `FSMessage::CheckFreeSpace`
+    pub(crate) fn check_free_space(&self, runtime: &Runtime, space: &DiskSpace) {
+        let piece_length = self.pieces_infos.piece_length;
+
+        if space.has_room(self, piece_length as u64) {
+            send_notification(
+                runtime,
+                &self.supervisor,
+                TorrentNotification::DiskSpaceAvailable,
+            );
+        }
+    }
+
+    /// Allocate the files when the torrent is added, or with
+    /// `FSMessage::Allocate`. On failure, the files are left sparse and
+    /// the supervisor is told when the disk is too small
+    pub(crate) fn allocate(
+        &mut self,
+        runtime: &Runtime,
+        id: TorrentId,
+        mode: AllocationMode,
+        space: &DiskSpace,
+    ) {
+        if mode == AllocationMode::Sparse {
+            return;
+        }
+
+        match self.preallocate(space) {
+            Ok(()) => {}
+            Err(TorrentError::NotEnoughSpace { needed, available }) => {
+                error!(
+                    "[vfs] {:?} Not enough free space to preallocate {} bytes ({} available)",
+                    id, needed, available
+                );
+                let msg = TorrentNotification::AllocationFailed { needed, available };
+                send_notification(runtime, &self.supervisor, msg);
+            }
+            Err(e) => error!("[vfs] {:?} Preallocation failed {:?}", id, e),
+        }
+    }
+
+    /// Give the files their full size on the disk. Nothing is allocated
+    /// when the disk doesn't have room for all of them. The blocks are
+    /// reserved with fallocate(2), without being written. They are
+    /// written with zeros only when the file system doesn't support it
+    pub(crate) fn preallocate(&mut self, space: &DiskSpace) -> Result<(), TorrentError> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        let needed: u64 = self
+            .files
+            .iter()
+            .map(|file| {
+                let current = std::fs::metadata(&file.path).map(|m| m.len());
+                file.length.saturating_sub(current.unwrap_or(0))
+            })
+            .sum();
+
+        let available = space.available(self);
+        if let Some(available) = available {
+            if available < space.min_free.saturating_add(needed) {
+                return Err(TorrentError::NotEnoughSpace { needed, available });
+            }
+        }
+
+        let to_error = |e: io::Error| match e.raw_os_error() {
+            Some(libc::ENOSPC) => TorrentError::NotEnoughSpace {
+                needed,
+                available: available.unwrap_or(0),
+            },
+            _ => TorrentError::IO(e),
+        };
+
+        for index in 0..self.files.len() {
+            let length = self.files[index].length;
+            let fd = self.file(index).map_err(TorrentError::IO)?;
+
+            let allocated = fallocate_file(fd, length).map_err(to_error)?;
+            if !allocated {
+                fill_with_zeros(fd, length).map_err(to_error)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Flush the files opened to the disk
+    pub fn sync(&self, id: TorrentId) {
+        for fd in self.fds.values() {
+            if let Err(e) = fd.sync_all() {
+                error!("[vfs] {:?} Flush failed {:?}", id, e);
+            }
+        }
+    }
+
+    /// Piece and block of a `FSMessage::ReadBlock`
+    pub fn block_at(&self, offset: u64, length: u32) -> io::Result<(PieceIndex, BlockIndex)> {
+        self.pieces_infos
+            .block_at(offset, length as usize)
+            .ok_or_else(|| io::ErrorKind::InvalidInput.into())
+    }
+
+    /// Returns an error when the torrent doesn't accept writes
+    pub fn check_writable(&self, id: TorrentId) -> Result<(), TorrentError> {
+        if self.read_only {
+            error!("[vfs] {:?} Write rejected, the torrent is read-only", id);
+            return Err(TorrentError::ReadOnly);
+        }
+        Ok(())
+    }
+
     fn file_offset_at(&self, piece: PieceIndex, block: BlockIndex) -> Option<(usize, usize)> {
         let piece_index: usize = piece.into();
         let block_index: usize = block.into();
@@ -94,35 +794,102 @@ impl TorrentCache {
         None
     }
 
+    /// Stops at the first file which can't be opened, with its error
     pub fn iter_files_on_piece(
         &mut self,
         piece: PieceIndex,
         block: BlockIndex,
         mut fun: impl FnMut(&mut File, usize, usize) -> bool,
-    ) {
+    ) -> io::Result<()> {
         let (start, mut offset) = self.file_offset_at(piece, block).unwrap();
 
-        for torrent_file in &self.files[start..] {
-            let file_length = torrent_file.length as usize;
-            let path = torrent_file.path.as_path();
+        for index in start..self.files.len() {
+            let max = self.files[index].length as usize - offset;
 
-            let file = match self.fds.get_mut(path) {
-                Some(file) => file,
-                None => {
-                    let file = open_file(path);
-                    self.fds.insert(path.to_owned(), file);
-                    self.fds.get_mut(path).unwrap()
-                }
-            };
-
-            let max = file_length - offset;
-
-            if !fun(file, offset, max) {
-                return;
+            if !fun(self.file(index)?, offset, max) {
+                return Ok(());
             }
 
             offset = 0;
         }
+
+        Ok(())
+    }
+
+    /// Open the files containing these `length` bytes
+    pub fn open_files(
+        &mut self,
+        piece: PieceIndex,
+        block: BlockIndex,
+        length: usize,
+    ) -> io::Result<()> {
+        let mut remaining = length;
+
+        self.iter_files_on_piece(piece, block, |_, _, max| {
+            remaining = remaining.saturating_sub(max);
+            remaining > 0
+        })
+    }
+
+    /// The file at this index of `files`, opened on its first use.
+    /// A file of a read-only torrent might be missing
+    pub fn file(&mut self, index: usize) -> io::Result<&mut File> {
+        let path = self.files[index].path.as_path();
+
+        if !self.fds.contains_key(path) {
+            let file = open_file(path, self.read_only)
+                .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))?;
+            self.fds.insert(path.to_owned(), file);
+        }
+
+        Ok(self.fds.get_mut(path).unwrap())
+    }
+
+    /// Tell the supervisor that a file of the torrent can't be opened
+    /// or written
+    pub(crate) fn report_file_error(&self, runtime: &Runtime, id: TorrentId, error: io::Error) {
+        error!("[vfs] {:?} {}", id, error);
+        let msg = TorrentNotification::FileError { error };
+        send_notification(runtime, &self.supervisor, msg);
+    }
+
+    /// Split the writes at the file boundaries, and merge the parts
+    /// following each other in the same file
+    pub(crate) fn coalesce_writes(&self, writes: &[WriteRequest]) -> Vec<FileWrite> {
+        let mut sorted: Vec<_> = writes
+            .iter()
+            .enumerate()
+            .filter_map(|(index, w)| Some((self.file_offset_at(w.piece, w.block)?, index)))
+            .collect();
+        sorted.sort();
+
+        let mut file_writes: Vec<FileWrite> = Vec::new();
+
+        for ((mut file, mut offset), index) in sorted {
+            let length = writes[index].data.len();
+            let mut start = 0;
+
+            while start < length && file < self.files.len() {
+                let end = length.min(start + self.files[file].length as usize - offset);
+
+                match file_writes.last_mut() {
+                    Some(last) if last.file == file && last.offset + last.length() == offset => {
+                        last.parts.push((index, start..end));
+                    }
+                    _ => file_writes.push(FileWrite {
+                        file,
+                        offset,
+                        parts: vec![(index, start..end)],
+                    }),
+                }
+
+                start = end;
+                file += 1;
+                offset = 0;
+            }
+        }
+
+        file_writes
     }
 }
 
@@ -132,83 +899,80 @@ pub(super) fn new_read_buffer(length: usize) -> Box<[u8]> {
     data.into_boxed_slice()
 }
 
-pub(super) fn send_to_peer(
+pub(super) fn send_to_supervisor(
     runtime: &Runtime,
-    peer: Sender<PeerCommand>,
-    piece: PieceIndex,
-    block: BlockIndex,
+    supervisor: Sender<TorrentNotification>,
+    piece_index: PieceIndex,
     data: Box<[u8]>,
 ) {
-    let msg = PeerCommand::BlockData { piece, block, data };
+    let msg = TorrentNotification::PieceRead { piece_index, data };
 
-    if let Err(TrySendError::Full(msg)) = peer.try_send(msg) {
-        runtime.spawn(async move { peer.send(msg).await });
+    if let Err(TrySendError::Full(msg)) = supervisor.try_send(msg) {
+        runtime.spawn(async move { supervisor.send(msg).await });
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::sync::Arc;
-
+fn send_notification(
+    runtime: &Runtime,
+    supervisor: &Sender<TorrentNotification>,
+    msg: TorrentNotification,
+) {
+    if let Err(TrySendError::Full(msg)) = supervisor.try_send(msg) {
+        let supervisor = supervisor.clone();
+        runtime.spawn(async move { supervisor.send(msg).await });
+    }
+}
+
+pub(super) fn send_to_peer(
+    runtime: &Runtime,
+    peer: Sender<PeerCommand>,
+    piece: PieceIndex,
+    block: BlockIndex,
+    data: Box<[u8]>,
+) {
+    let msg = PeerCommand::BlockData { piece, block, data };
+
+    if let Err(TrySendError::Full(msg)) = peer.try_send(msg) {
+        runtime.spawn(async move { peer.send(msg).await });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
     use async_channel::Sender;
-    use smallvec::smallvec;
-    use tokio::runtime::Runtime;
+    use tokio::{runtime::Runtime, sync::oneshot};
 
     use crate::{
-        fs::FSMessage::{AddTorrent, Read, RemoveTorrent, Write},
-        metadata::{InfoFile::Multiple, MetaFile, MetaInfo, MetaTorrent, Torrent},
+        bitfield::BitField,
+        errors::TorrentError,
+        fs::FSMessage::{
+            AddTorrent, CheckFreeSpace, Flush, Read, ReadBlock, ReadPiece, RemoveTorrent,
+            SetAllocation, SetMinFreeSpace, SetWriteRate, Write, WriteBatch,
+        },
+        metadata::{InfoFile::Multiple, TestTorrent, Torrent},
         peer::peer::PeerCommand,
         pieces::Pieces,
-        supervisors::torrent::TorrentId,
+        supervisors::torrent::{TorrentId, TorrentNotification},
     };
 
-    use super::{standard_fs::StandardFS, uring_fs::UringFS, FSMessage, FileSystem};
+    use super::{
+        standard_fs::StandardFS, uring_fs::UringFS, AllocationMode, DiskSpace, FSMessage,
+        FileSystem, FileWrite, TorrentCache, WriteRequest,
+    };
+
+    fn torrent(dir_name: &str) -> Torrent {
+        TestTorrent::new()
+            .name(dir_name)
+            .files(&[("a", 98080), ("b", 11111), ("c", 198), ("d", 5)])
+            .build()
+    }
 
     fn read_write(fs: Sender<FSMessage>, dir_name: &str) {
         crate::logger::start();
 
-        let torrent = Torrent {
-            meta: MetaTorrent {
-                announce: None,
-                info: MetaInfo {
-                    pieces: vec![1; 20 * 110],
-                    piece_length: 1000,
-                    private: None,
-                    files: Multiple {
-                        name: dir_name.to_string(),
-                        files: vec![
-                            MetaFile {
-                                length: 98080,
-                                md5sum: None,
-                                path: smallvec!["a".to_string()],
-                            },
-                            MetaFile {
-                                length: 11111,
-                                md5sum: None,
-                                path: smallvec!["b".to_string()],
-                            },
-                            MetaFile {
-                                length: 198,
-                                md5sum: None,
-                                path: smallvec!["c".to_string()],
-                            },
-                            MetaFile {
-                                length: 5,
-                                md5sum: None,
-                                path: smallvec!["d".to_string()],
-                            },
-                        ],
-                    },
-                },
-                announce_list: None,
-                creation_date: None,
-                comment: None,
-                created_by: None,
-                encoding: None,
-                url_list: None,
-            },
-            info_hash: Arc::new([]),
-        };
+        let torrent = torrent(dir_name);
 
         let pieces = Pieces::from(&torrent);
         let pieces_clone = pieces.clone();
@@ -222,6 +986,8 @@ mod tests {
             id: torrent_id,
             meta: Arc::new(torrent),
             pieces_infos: Arc::new(pieces_clone),
+            read_only: false,
+            supervisor: async_channel::unbounded().0,
         })
         .unwrap();
 
@@ -239,7 +1005,29 @@ mod tests {
             .unwrap();
         }
 
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        // Read back right after the writes, it sees the written data
+        let (supervisor, pieces_read) = async_channel::unbounded();
+        for piece in 0..pieces.num_pieces {
+            fs.try_send(ReadPiece {
+                id: torrent_id,
+                piece: (piece as u32).into(),
+                supervisor: supervisor.clone(),
+            })
+            .unwrap();
+        }
+        let runtime = Runtime::new().unwrap();
+        for _ in 0..pieces.num_pieces {
+            match runtime.block_on(pieces_read.recv()) {
+                Ok(TorrentNotification::PieceRead {
+                    piece_index,
+                    data: read,
+                }) => {
+                    let offset = usize::from(piece_index) * piece_length;
+                    assert_eq!(&read[..], &data[offset..offset + read.len()]);
+                }
+                _ => panic!("Missing PieceRead"),
+            }
+        }
 
         let (sender, recv) = async_channel::unbounded();
 
@@ -322,6 +1110,205 @@ mod tests {
         std::thread::sleep(std::time::Duration::from_millis(100));
     }
 
+    fn read_only(fs: Sender<FSMessage>, dir_name: &str) {
+        crate::logger::start();
+
+        let torrent = torrent(dir_name);
+        let files = torrent.files();
+        let pieces = Pieces::from(&torrent);
+        let torrent_id = TorrentId::new();
+
+        let mut data = Vec::with_capacity(pieces.files_size);
+        for _ in 0..data.capacity() {
+            data.push(fastrand::u8(..));
+        }
+
+        // The last file is missing
+        let mut cursor = 0;
+        for file in &files[..files.len() - 1] {
+            let length = file.length as usize;
+            std::fs::create_dir_all(file.path.parent().unwrap()).unwrap();
+            std::fs::write(&file.path, &data[cursor..cursor + length]).unwrap();
+            cursor += length;
+        }
+
+        let (supervisor, supervisor_recv) = async_channel::unbounded();
+
+        fs.try_send(AddTorrent {
+            id: torrent_id,
+            meta: Arc::new(torrent),
+            pieces_infos: Arc::new(pieces.clone()),
+            read_only: true,
+            supervisor: supervisor.clone(),
+        })
+        .unwrap();
+
+        // Any write is rejected
+        fs.try_send(Write {
+            id: torrent_id,
+            piece: 0.into(),
+            data: vec![0; pieces.piece_length].into_boxed_slice(),
+        })
+        .unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        // Seeding still works
+        let (sender, recv) = async_channel::unbounded();
+
+        fs.try_send(Read {
+            id: torrent_id,
+            piece: 0.into(),
+            block: 0.into(),
+            length: pieces.piece_length as u32,
+            peer: sender,
+        })
+        .unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        match recv.try_recv() {
+            Ok(PeerCommand::BlockData {
+                piece,
+                block,
+                data: read,
+            }) => {
+                assert_eq!(piece, 0.into());
+                assert_eq!(block, 0.into());
+                assert_eq!(&*read, &data[..pieces.piece_length]);
+            }
+            _ => panic!("No block read"),
+        }
+
+        assert_eq!(std::fs::read(&files[0].path).unwrap(), &data[..98080]);
+
+        // The FS keeps running, the supervisor is told and the piece
+        // fails its hash
+        let last_piece = (pieces.num_pieces as u32 - 1).into();
+        fs.try_send(ReadPiece {
+            id: torrent_id,
+            piece: last_piece,
+            supervisor,
+        })
+        .unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        assert!(matches!(
+            supervisor_recv.try_recv(),
+            Ok(TorrentNotification::FileError { .. })
+        ));
+        match supervisor_recv.try_recv() {
+            Ok(TorrentNotification::PieceRead { piece_index, data }) => {
+                assert_eq!(piece_index, last_piece);
+                assert_eq!(data.len(), 394);
+                assert!(data.ends_with(&[0; 5]));
+            }
+            _ => panic!("No piece read"),
+        }
+        assert!(!files.last().unwrap().path.exists());
+
+        fs.try_send(RemoveTorrent { id: torrent_id }).unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn low_disk_space() {
+        use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+        static FREE_SPACE: AtomicU64 = AtomicU64::new(1500);
+
+        std::fs::remove_dir_all("low_space").ok();
+
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let disk_space = DiskSpace::with_free_space(|_| Ok(FREE_SPACE.load(Relaxed)));
+        let fs = StandardFS::spawn(runtime, disk_space);
+
+        let torrent = torrent("low_space");
+        let files = torrent.files();
+        let pieces = Pieces::from(&torrent);
+        let torrent_id = TorrentId::new();
+        let (supervisor, notifications) = async_channel::unbounded();
+        let recv = || {
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+            loop {
+                match notifications.try_recv() {
+                    Ok(msg) => return msg,
+                    Err(_) if std::time::Instant::now() < deadline => {
+                        std::thread::sleep(std::time::Duration::from_millis(10))
+                    }
+                    Err(e) => panic!("No notification {:?}", e),
+                }
+            }
+        };
+
+        fs.try_send(SetMinFreeSpace { bytes: 1000 }).unwrap();
+        fs.try_send(AddTorrent {
+            id: torrent_id,
+            meta: Arc::new(torrent),
+            pieces_infos: Arc::new(pieces.clone()),
+            read_only: false,
+            supervisor,
+        })
+        .unwrap();
+
+        // 1500 bytes free, a piece of 1000 would leave less than 1000
+        let data = vec![1; pieces.piece_length].into_boxed_slice();
+        fs.try_send(Write {
+            id: torrent_id,
+            piece: 3.into(),
+            data: data.clone(),
+        })
+        .unwrap();
+
+        assert!(matches!(
+            recv(),
+            TorrentNotification::WriteRejected { piece_index } if piece_index == 3.into()
+        ));
+        assert!(!files[0].path.exists());
+
+        fs.try_send(CheckFreeSpace { id: torrent_id }).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(notifications.is_empty());
+
+        FREE_SPACE.store(1 << 30, Relaxed);
+        fs.try_send(CheckFreeSpace { id: torrent_id }).unwrap();
+        assert!(matches!(recv(), TorrentNotification::DiskSpaceAvailable));
+
+        fs.try_send(Write {
+            id: torrent_id,
+            piece: 3.into(),
+            data,
+        })
+        .unwrap();
+        fs.try_send(RemoveTorrent { id: torrent_id }).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        let written = std::fs::read(&files[0].path).unwrap();
+        assert_eq!(&written[3000..4000], &[1; 1000][..]);
+
+        std::fs::remove_dir_all("low_space").ok();
+    }
+
+    #[test]
+    fn read_only_rejects_writes() {
+        let torrent = Arc::new(torrent("ro"));
+        let pieces = Arc::new(Pieces::from(&*torrent));
+
+        let (supervisor, _) = async_channel::unbounded();
+
+        let cache = TorrentCache::new(torrent.clone(), pieces.clone(), true, supervisor.clone());
+        assert!(matches!(
+            cache.check_writable(TorrentId::new()),
+            Err(TorrentError::ReadOnly)
+        ));
+
+        let cache = TorrentCache::new(torrent, pieces, false, supervisor);
+        assert!(cache.check_writable(TorrentId::new()).is_ok());
+    }
+
     #[test]
     #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
     fn standard_fs() {
@@ -336,6 +1323,390 @@ mod tests {
         std::fs::remove_dir_all("abc").ok();
     }
 
+    /// Blocks of pieces 97 and 98, piece 98 starts in `a` and ends in `b`
+    fn contiguous_blocks(data: &[u8]) -> Vec<WriteRequest> {
+        let block = |piece: u32, block: u32, range: std::ops::Range<usize>| WriteRequest {
+            piece: piece.into(),
+            block: block.into(),
+            data: data[range].into(),
+        };
+
+        // Out of order
+        vec![
+            block(98, 500, 1000..1500),
+            block(97, 500, 0..500),
+            block(98, 0, 500..1000),
+        ]
+    }
+
+    fn write_batch(fs: Sender<FSMessage>, dir_name: &str) {
+        std::fs::remove_dir_all(dir_name).ok();
+
+        let torrent = torrent(dir_name);
+        let pieces = Pieces::from(&torrent);
+        let torrent_id = TorrentId::new();
+
+        let data: Vec<u8> = (0..1500).map(|_| fastrand::u8(..)).collect();
+
+        fs.try_send(AddTorrent {
+            id: torrent_id,
+            meta: Arc::new(torrent),
+            pieces_infos: Arc::new(pieces),
+            read_only: false,
+            supervisor: async_channel::unbounded().0,
+        })
+        .unwrap();
+        fs.try_send(WriteBatch {
+            id: torrent_id,
+            writes: contiguous_blocks(&data),
+        })
+        .unwrap();
+
+        let (done, flushed) = async_channel::bounded(1);
+        fs.try_send(Flush {
+            id: torrent_id,
+            done,
+        })
+        .unwrap();
+        Runtime::new().unwrap().block_on(flushed.recv()).unwrap();
+
+        // `a` ends at 98080
+        let a = std::fs::read(format!("{}/a", dir_name)).unwrap();
+        let b = std::fs::read(format!("{}/b", dir_name)).unwrap();
+        std::fs::remove_dir_all(dir_name).ok();
+
+        assert_eq!(a.len(), 98080);
+        assert_eq!(&a[97500..], &data[..580]);
+        assert_eq!(b, &data[580..]);
+    }
+
+    #[test]
+    fn coalesce_writes() {
+        let torrent = torrent("coalesce");
+        let pieces = Pieces::from(&torrent);
+        let cache = TorrentCache::new(
+            Arc::new(torrent),
+            Arc::new(pieces),
+            true,
+            async_channel::unbounded().0,
+        );
+
+        let writes = contiguous_blocks(&[0; 1500]);
+        assert_eq!(
+            cache.coalesce_writes(&writes),
+            vec![
+                FileWrite {
+                    file: 0,
+                    offset: 97500,
+                    // The second block is split at the end of `a`
+                    parts: vec![(1, 0..500), (2, 0..80)],
+                },
+                FileWrite {
+                    file: 1,
+                    offset: 0,
+                    parts: vec![(2, 80..500), (0, 0..500)],
+                },
+            ]
+        );
+
+        // Not contiguous: a gap between the blocks
+        let writes = vec![writes.into_iter().nth(1).unwrap(), {
+            WriteRequest {
+                piece: 1.into(),
+                block: 0.into(),
+                data: vec![0; 10].into(),
+            }
+        }];
+        assert_eq!(cache.coalesce_writes(&writes).len(), 2);
+    }
+
+    /// The files have their full size once the torrent is added
+    fn preallocate(fs: Sender<FSMessage>, dir_name: &str) {
+        std::fs::remove_dir_all(dir_name).ok();
+
+        let torrent = torrent(dir_name);
+        let files = torrent.files();
+        let pieces = Pieces::from(&torrent);
+        let torrent_id = TorrentId::new();
+
+        // A partial copy of `a` is kept
+        std::fs::create_dir_all(dir_name).unwrap();
+        std::fs::write(&files[0].path, [7; 100]).unwrap();
+
+        fs.try_send(SetAllocation {
+            mode: AllocationMode::Preallocate,
+        })
+        .unwrap();
+        fs.try_send(AddTorrent {
+            id: torrent_id,
+            meta: Arc::new(torrent),
+            pieces_infos: Arc::new(pieces),
+            read_only: false,
+            supervisor: async_channel::unbounded().0,
+        })
+        .unwrap();
+
+        let (done, flushed) = async_channel::bounded(1);
+        fs.try_send(Flush {
+            id: torrent_id,
+            done,
+        })
+        .unwrap();
+        Runtime::new().unwrap().block_on(flushed.recv()).unwrap();
+
+        for file in &files {
+            let metadata = std::fs::metadata(&file.path).unwrap();
+            assert_eq!(metadata.len(), file.length, "{:?}", file.path);
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                assert!(
+                    metadata.blocks() * 512 >= file.length,
+                    "{:?} is sparse",
+                    file.path
+                );
+            }
+        }
+
+        let a = std::fs::read(&files[0].path).unwrap();
+        assert_eq!(&a[..100], &[7; 100][..]);
+        assert!(a[100..].iter().all(|b| *b == 0));
+
+        std::fs::remove_dir_all(dir_name).ok();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn standard_fs_preallocate() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        preallocate(StandardFS::new(runtime), "preallocate_standard");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support io_uring
+    fn uring_fs_preallocate() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let fs = match UringFS::init(runtime) {
+            Ok(fs) => fs,
+            _ => return, // io_uring not supported
+        };
+
+        preallocate(fs, "preallocate_uring");
+    }
+
+    /// The fallback of fallocate(2)
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support files
+    fn fill_with_zeros() {
+        let path = std::env::temp_dir().join(format!("fill_zeros_{}", fastrand::u64(..)));
+        std::fs::write(&path, [7; 100]).unwrap();
+
+        let mut fd = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        super::fill_with_zeros(&mut fd, 3 * super::ZERO_CHUNK as u64 / 2).unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(data.len(), 3 * super::ZERO_CHUNK / 2);
+        assert_eq!(&data[..100], &[7; 100][..]);
+        assert!(data[100..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn preallocate_without_space() {
+        std::fs::remove_dir_all("preallocate_full").ok();
+
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let disk_space = DiskSpace::with_free_space(|_| Ok(50_000));
+        let fs = StandardFS::spawn(Arc::clone(&runtime), disk_space);
+
+        let torrent = torrent("preallocate_full");
+        let files = torrent.files();
+        let pieces = Pieces::from(&torrent);
+        let (supervisor, notifications) = async_channel::unbounded();
+
+        fs.try_send(SetAllocation {
+            mode: AllocationMode::Preallocate,
+        })
+        .unwrap();
+        fs.try_send(AddTorrent {
+            id: TorrentId::new(),
+            meta: Arc::new(torrent),
+            pieces_infos: Arc::new(pieces),
+            read_only: false,
+            supervisor,
+        })
+        .unwrap();
+
+        match runtime.block_on(notifications.recv()) {
+            Ok(TorrentNotification::AllocationFailed { needed, available }) => {
+                assert_eq!((needed, available), (98080 + 11111 + 198 + 5, 50_000));
+            }
+            msg => panic!("Unexpected notification {:?}", msg),
+        }
+        // Failed before creating anything
+        assert!(files.iter().all(|file| !file.path.exists()));
+    }
+
+    fn read_block(fs: Sender<FSMessage>, dir_name: &str) {
+        std::fs::remove_dir_all(dir_name).ok();
+
+        let torrent = torrent(dir_name);
+        let pieces = Pieces::from(&torrent);
+        let torrent_id = TorrentId::new();
+
+        let data: Vec<u8> = (0..1500).map(|_| fastrand::u8(..)).collect();
+
+        fs.try_send(AddTorrent {
+            id: torrent_id,
+            meta: Arc::new(torrent),
+            pieces_infos: Arc::new(pieces),
+            read_only: false,
+            supervisor: async_channel::unbounded().0,
+        })
+        .unwrap();
+        fs.try_send(WriteBatch {
+            id: torrent_id,
+            writes: contiguous_blocks(&data),
+        })
+        .unwrap();
+
+        // The ring doesn't order the read after the write
+        let runtime = Runtime::new().unwrap();
+        let (done, flushed) = async_channel::bounded(1);
+        fs.try_send(Flush {
+            id: torrent_id,
+            done,
+        })
+        .unwrap();
+        runtime.block_on(flushed.recv()).unwrap();
+
+        let read = |file_offset: u64, length: u32| {
+            let (respond, result) = oneshot::channel();
+            fs.try_send(ReadBlock {
+                id: torrent_id,
+                file_offset,
+                length,
+                respond,
+            })
+            .unwrap();
+            runtime.block_on(result).unwrap()
+        };
+
+        // Over the end of `a` and the start of `b`
+        assert_eq!(read(97900, 400).unwrap(), &data[400..800]);
+        assert_eq!(read(97500, 1500).unwrap(), data);
+
+        // `b` ends at 109191
+        assert_eq!(
+            read(109000, 500).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+
+        std::fs::remove_dir_all(dir_name).ok();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn standard_fs_read_block() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        read_block(StandardFS::new(runtime), "read_block_standard");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support io_uring
+    fn uring_fs_read_block() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let fs = match UringFS::init(runtime) {
+            Ok(fs) => fs,
+            _ => return, // io_uring not supported
+        };
+
+        read_block(fs, "read_block_uring");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn standard_fs_write_batch() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        write_batch(StandardFS::new(runtime), "batch_standard");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn standard_fs_write_batch_error() {
+        let dir_name = "batch_error";
+        std::fs::remove_dir_all(dir_name).ok();
+        std::fs::create_dir(dir_name).unwrap();
+        // Writes to /dev/full fail with ENOSPC
+        std::os::unix::fs::symlink("/dev/full", format!("{}/a", dir_name)).unwrap();
+
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let fs = StandardFS::new(Arc::clone(&runtime));
+
+        let torrent = torrent(dir_name);
+        let pieces = Pieces::from(&torrent);
+        let torrent_id = TorrentId::new();
+        let (supervisor, notifications) = async_channel::unbounded();
+
+        // Batch of a torrent removed already
+        fs.try_send(WriteBatch {
+            id: TorrentId::new(),
+            writes: contiguous_blocks(&[0; 1500]),
+        })
+        .unwrap();
+        fs.try_send(AddTorrent {
+            id: torrent_id,
+            meta: Arc::new(torrent),
+            pieces_infos: Arc::new(pieces),
+            read_only: false,
+            supervisor,
+        })
+        .unwrap();
+        fs.try_send(WriteBatch {
+            id: torrent_id,
+            writes: contiguous_blocks(&[1; 1500]),
+        })
+        .unwrap();
+
+        match runtime.block_on(notifications.recv()) {
+            Ok(TorrentNotification::FileError { error }) => {
+                assert_eq!(error.raw_os_error(), Some(28)); // ENOSPC
+            }
+            msg => panic!("Unexpected notification {:?}", msg),
+        }
+
+        // The rest of the batch is skipped, and the thread still runs
+        let (done, flushed) = async_channel::bounded(1);
+        fs.try_send(Flush {
+            id: torrent_id,
+            done,
+        })
+        .unwrap();
+        runtime.block_on(flushed.recv()).unwrap();
+        assert!(!std::path::Path::new(&format!("{}/b", dir_name)).exists());
+
+        std::fs::remove_dir_all(dir_name).ok();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support io_uring
+    fn uring_fs_write_batch() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let fs = match UringFS::init(runtime) {
+            Ok(fs) => fs,
+            _ => return, // io_uring not supported
+        };
+
+        write_batch(fs, "batch_uring");
+    }
+
     #[test]
     #[cfg_attr(miri, ignore)] // Miri doesn't support io_uring
     fn uring_fs() {
@@ -345,11 +1716,240 @@ mod tests {
 
         let runtime = Arc::new(Runtime::new().unwrap());
         let fs = match UringFS::init(runtime) {
-            Some(fs) => fs,
+            Ok(fs) => fs,
             _ => return, // io_uring not supported
         };
 
         read_write(fs, "aaa");
         std::fs::remove_dir_all("aaa").ok();
     }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn standard_fs_read_only() {
+        std::fs::remove_dir_all("ro_standard").ok();
+
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let fs = StandardFS::new(runtime);
+
+        read_only(fs, "ro_standard");
+        std::fs::remove_dir_all("ro_standard").ok();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn write_rate_limit() {
+        std::fs::remove_dir_all("rate_standard").ok();
+        std::fs::remove_dir_all("rate_other").ok();
+
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let fs = StandardFS::new(runtime.clone());
+
+        let other = torrent("rate_other");
+        let torrent = torrent("rate_standard");
+        let pieces = Pieces::from(&torrent);
+        let torrent_id = TorrentId::new();
+
+        fs.try_send(SetWriteRate {
+            bytes_per_sec: 100_000,
+        })
+        .unwrap();
+        fs.try_send(AddTorrent {
+            id: torrent_id,
+            meta: Arc::new(torrent),
+            pieces_infos: Arc::new(pieces.clone()),
+            read_only: false,
+            supervisor: async_channel::unbounded().0,
+        })
+        .unwrap();
+
+        let start = std::time::Instant::now();
+
+        for piece in 0..pieces.num_pieces {
+            let length = pieces.piece_size_of((piece as u32).into()) as usize;
+            fs.try_send(Write {
+                id: torrent_id,
+                piece: (piece as u32).into(),
+                data: vec![1; length].into_boxed_slice(),
+            })
+            .unwrap();
+        }
+
+        // The writes are delayed without blocking the other torrents
+        let other_id = TorrentId::new();
+        let other_pieces = Pieces::from(&other);
+        fs.try_send(AddTorrent {
+            id: other_id,
+            meta: Arc::new(other),
+            pieces_infos: Arc::new(other_pieces),
+            read_only: false,
+            supervisor: async_channel::unbounded().0,
+        })
+        .unwrap();
+        let (respond, result) = oneshot::channel();
+        fs.try_send(ReadBlock {
+            id: other_id,
+            file_offset: 0,
+            length: 10,
+            respond,
+        })
+        .unwrap();
+        // Nothing written yet
+        assert!(runtime.block_on(result).unwrap().is_err());
+        assert!(start.elapsed() < std::time::Duration::from_millis(500));
+
+        // The messages of the torrent are processed in order, the read
+        // is done after all writes
+        let (sender, recv) = async_channel::unbounded();
+        fs.try_send(Read {
+            id: torrent_id,
+            piece: 0.into(),
+            block: 0.into(),
+            length: 10,
+            peer: sender,
+        })
+        .unwrap();
+        runtime.block_on(recv.recv()).unwrap();
+
+        // ~110KB written at 100KB/s, with a burst of 25KB
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= std::time::Duration::from_millis(700),
+            "{:?}",
+            elapsed
+        );
+
+        std::fs::remove_dir_all("rate_standard").ok();
+        std::fs::remove_dir_all("rate_other").ok();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support io_uring
+    fn uring_fs_read_only() {
+        std::fs::remove_dir_all("ro_uring").ok();
+
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let fs = match UringFS::init(runtime) {
+            Ok(fs) => fs,
+            _ => return, // io_uring not supported
+        };
+
+        read_only(fs, "ro_uring");
+        std::fs::remove_dir_all("ro_uring").ok();
+    }
+
+    #[test]
+    fn find_conflicts() {
+        let dir = std::env::temp_dir().join(format!("rustorrent-conflicts-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // 3 pieces, the 2nd one spans both files
+        let content: Vec<u8> = (0..3000).map(|i| (i % 251) as u8 + 1).collect();
+        let mut torrent = torrent("conflicts");
+        if let Multiple { files, .. } = &mut torrent.meta.info.files {
+            files.truncate(2);
+            files[0].length = 1500;
+            files[1].length = 1500;
+        }
+        torrent.meta.info.pieces = content
+            .chunks(1000)
+            .flat_map(|piece| crate::sha1::sha1(piece).to_vec())
+            .collect();
+
+        let mut pieces = Pieces::from(&torrent);
+        pieces.block_size = 500;
+        let mut files = torrent.files();
+        files[0].path = dir.join("a");
+        files[1].path = dir.join("b");
+
+        let check = |a: &[u8], b: &[u8], ours: &BitField| {
+            std::fs::write(&files[0].path, a).unwrap();
+            std::fs::write(&files[1].path, b).unwrap();
+            let conflicts = super::find_conflicts(&files, &pieces, ours);
+            conflicts
+                .iter()
+                .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let none = BitField::new(3);
+        let (a, b) = content.split_at(1500);
+
+        assert!(check(a, b, &none).is_empty());
+
+        // Another content in the last piece
+        let mut other = b.to_vec();
+        other[1000] ^= 1;
+        assert_eq!(check(a, &other, &none), vec!["b"]);
+
+        // Another content in the piece spanning the files
+        let mut other = a.to_vec();
+        other[1200] ^= 1;
+        assert_eq!(check(&other, b, &none), vec!["a", "b"]);
+
+        // A piece with an empty block was interrupted, it's not a conflict
+        let mut partial = b.to_vec();
+        partial[500] ^= 1;
+        for byte in &mut partial[1000..] {
+            *byte = 0;
+        }
+        assert!(check(a, &partial, &none).is_empty());
+
+        // Our own half-written piece, in a file of the resume data
+        let mut ours = BitField::new(3);
+        ours.set_bit(0usize);
+        let mut half_written = a.to_vec();
+        half_written[1400] ^= 1;
+        assert!(check(&half_written, b, &ours).is_empty());
+
+        // Not the size of the torrent file
+        assert!(check(&other, &b[..1000], &none).is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scan_existing() {
+        let dir = std::env::temp_dir().join(format!("rustorrent-scan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // 3 pieces of 1000 bytes, the last one is short and in "b"
+        let a: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        let b = vec![0xAB; 500];
+        let mut torrent = torrent("scan");
+        if let Multiple { files, .. } = &mut torrent.meta.info.files {
+            files.truncate(2);
+            files[0].length = 2000;
+            files[1].length = 500;
+        }
+        torrent.meta.info.pieces = [&a[..1000], &a[1000..], &b]
+            .iter()
+            .flat_map(|piece| crate::sha1::sha1(piece).to_vec())
+            .collect();
+
+        let pieces = Pieces::from(&torrent);
+        let mut files = torrent.files();
+        files[0].path = dir.join("a");
+        files[1].path = dir.join("b");
+
+        let scan = || {
+            let bitfield = super::scan_existing(&files, &pieces);
+            (0..3usize).map(|i| bitfield.get_bit(i)).collect::<Vec<_>>()
+        };
+
+        // The second piece got corrupted, "b" is missing and not created
+        let mut corrupt = a.clone();
+        corrupt[1500] ^= 0xFF;
+        std::fs::write(&files[0].path, &corrupt).unwrap();
+        assert_eq!(scan(), vec![true, false, false]);
+        assert!(!files[1].path.exists());
+
+        std::fs::write(&files[1].path, &b).unwrap();
+        assert_eq!(scan(), vec![true, false, true]);
+
+        // A short file
+        std::fs::write(&files[0].path, &a[..1500]).unwrap();
+        assert_eq!(scan(), vec![true, false, true]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
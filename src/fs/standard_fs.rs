@@ -1,32 +1,61 @@
-use std::{fs::File, sync::Arc};
+use std::{fs::File, io, sync::Arc};
 
 use async_channel::{Receiver, RecvError, Sender};
-use hashbrown::HashMap;
-use kv_log_macro::info;
+use kv_log_macro::{error, info};
 use tokio::runtime::Runtime;
 
 use crate::{
-    fs::{FSMessage, TorrentCache},
+    fs::{AllocationMode, DiskSpace, FSMessage, TorrentCache, WriteRequest},
     peer::peer::PeerCommand,
     piece_picker::{BlockIndex, PieceIndex},
-    supervisors::torrent::TorrentId,
+    supervisors::torrent::{TorrentId, TorrentNotification},
     utils::Map,
 };
 
-use super::{new_read_buffer, send_to_peer};
+use super::{new_read_buffer, send_to_peer, send_to_supervisor, WriteThrottle};
 
 trait FileOffset {
-    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> std::io::Result<()>;
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> std::io::Result<usize>;
     fn write_all_at(&mut self, buf: &[u8], offset: u64) -> std::io::Result<()>;
+
+    /// Write the buffers one after the other, starting at `offset`
+    fn write_all_vectored_at(&mut self, bufs: &[&[u8]], mut offset: u64) -> std::io::Result<()> {
+        for buf in bufs {
+            self.write_all_at(buf, offset)?;
+            offset += buf.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Maximum number of buffers given to pwritev(2)
+#[cfg(unix)]
+const MAX_IOVECS: usize = 1024;
+
+/// Read until `buf` is full or the end of file is reached.
+/// Returns the number of bytes read
+fn read_full_at<F: FileOffset>(fd: &mut F, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    let mut nread = 0;
+
+    while nread < buf.len() {
+        match fd.read_at(&mut buf[nread..], offset + nread as u64) {
+            Ok(0) => break,
+            Ok(n) => nread += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(nread)
 }
 
 #[cfg(not(unix))]
 impl FileOffset for File {
-    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
         use std::io::{Read, Seek, SeekFrom};
 
         self.seek(SeekFrom::Start(offset))?;
-        self.read_exact(buf)
+        self.read(buf)
     }
     fn write_all_at(&mut self, buf: &[u8], offset: u64) -> std::io::Result<()> {
         use std::io::{Seek, SeekFrom, Write};
@@ -39,16 +68,77 @@ impl FileOffset for File {
 // Use pread(2) and pwrite(2)
 #[cfg(unix)]
 impl FileOffset for File {
-    fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
         use std::os::unix::fs::FileExt;
 
-        FileExt::read_exact_at(self, buf, offset)
+        FileExt::read_at(self, buf, offset)
     }
     fn write_all_at(&mut self, buf: &[u8], offset: u64) -> std::io::Result<()> {
         use std::os::unix::fs::FileExt;
 
         FileExt::write_all_at(self, buf, offset)
     }
+
+    /// With pwritev(2), a single syscall unless the write is short
+    fn write_all_vectored_at(
+        &mut self,
+        mut bufs: &[&[u8]],
+        mut offset: u64,
+    ) -> std::io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        // Bytes of `bufs[0]` already written
+        let mut skip = 0;
+
+        while !bufs.is_empty() {
+            let iovecs: Vec<_> = bufs
+                .iter()
+                .take(MAX_IOVECS)
+                .enumerate()
+                .map(|(index, buf)| {
+                    let buf = if index == 0 { &buf[skip..] } else { buf };
+                    libc::iovec {
+                        iov_base: buf.as_ptr() as *mut libc::c_void,
+                        iov_len: buf.len(),
+                    }
+                })
+                .collect();
+
+            let written = unsafe {
+                libc::pwritev(
+                    self.as_raw_fd(),
+                    iovecs.as_ptr(),
+                    iovecs.len() as libc::c_int,
+                    offset as libc::off_t,
+                )
+            };
+
+            match written {
+                n if n < 0 => {
+                    let e = std::io::Error::last_os_error();
+                    if e.kind() != std::io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+                0 => return Err(std::io::ErrorKind::WriteZero.into()),
+                n => {
+                    offset += n as u64;
+
+                    let mut consumed = skip + n as usize;
+                    while let Some(first) = bufs.first() {
+                        if consumed < first.len() {
+                            break;
+                        }
+                        consumed -= first.len();
+                        bufs = &bufs[1..];
+                    }
+                    skip = consumed;
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 /// File system implementation based on the standard library
@@ -57,17 +147,27 @@ pub struct StandardFS {
     runtime: Arc<Runtime>,
     recv: Receiver<FSMessage>,
     torrents: Map<TorrentId, TorrentCache>,
+    throttle: WriteThrottle,
+    disk_space: DiskSpace,
+    allocation: AllocationMode,
 }
 
 impl StandardFS {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(runtime: Arc<Runtime>) -> Sender<FSMessage> {
+        Self::spawn(runtime, DiskSpace::default())
+    }
+
+    pub(super) fn spawn(runtime: Arc<Runtime>, disk_space: DiskSpace) -> Sender<FSMessage> {
         let (sender, recv) = async_channel::bounded(1000);
 
         let vfs = StandardFS {
             recv,
             runtime,
             torrents: Map::default(),
+            throttle: WriteThrottle::default(),
+            disk_space,
+            allocation: AllocationMode::default(),
         };
 
         std::thread::Builder::new()
@@ -78,12 +178,8 @@ impl StandardFS {
         sender
     }
 
-    fn wait_for_message(&self) -> Result<FSMessage, RecvError> {
-        if let Ok(msg) = self.recv.try_recv() {
-            return Ok(msg);
-        };
-
-        self.runtime.block_on(async { self.recv.recv().await })
+    fn wait_for_message(&mut self) -> Result<FSMessage, RecvError> {
+        self.throttle.next(&self.runtime, &self.recv)
     }
 
     fn start(mut self) {
@@ -98,13 +194,11 @@ impl StandardFS {
                 id,
                 meta,
                 pieces_infos,
+                read_only,
+                supervisor,
             } => {
-                let cache = TorrentCache {
-                    files: meta.files(),
-                    torrent: meta,
-                    pieces_infos,
-                    fds: HashMap::default(),
-                };
+                let mut cache = TorrentCache::new(meta, pieces_infos, read_only, supervisor);
+                cache.allocate(&self.runtime, id, self.allocation, &self.disk_space);
                 self.torrents.insert(id, cache);
 
                 info!("[vfs] {:?} Add torrent", id);
@@ -124,6 +218,49 @@ impl StandardFS {
             FSMessage::Write { id, piece, data } => {
                 self.write(id, piece, &data);
             }
+            FSMessage::WriteBatch { id, writes } => {
+                self.write_batch(id, writes);
+            }
+            FSMessage::SetWriteRate { bytes_per_sec } => {
+                self.throttle.set_rate(bytes_per_sec);
+            }
+            FSMessage::SetMinFreeSpace { bytes } => {
+                self.disk_space.set_min_free(bytes);
+            }
+            FSMessage::SetAllocation { mode } => {
+                self.allocation = mode;
+            }
+            FSMessage::Allocate { id } => {
+                if let Some(cache) = self.torrents.get_mut(&id) {
+                    cache.allocate(&self.runtime, id, self.allocation, &self.disk_space);
+                }
+            }
+            FSMessage::CheckFreeSpace { id } => {
+                if let Some(cache) = self.torrents.get(&id) {
+                    cache.check_free_space(&self.runtime, &self.disk_space);
+                }
+            }
+            FSMessage::ReadPiece {
+                id,
+                piece,
+                supervisor,
+            } => {
+                self.read_piece(id, piece, supervisor);
+            }
+            FSMessage::ReadBlock {
+                id,
+                file_offset,
+                length,
+                respond,
+            } => {
+                respond.send(self.read_block(id, file_offset, length)).ok();
+            }
+            FSMessage::Flush { id, done } => {
+                if let Some(cache) = self.torrents.get(&id) {
+                    cache.sync(id);
+                }
+                done.try_send(()).ok();
+            }
         }
     }
 
@@ -135,35 +272,119 @@ impl StandardFS {
         length: u32,
         peer: Sender<PeerCommand>,
     ) {
+        let (data, complete) = match self.read_buffer(id, piece, block, length) {
+            Ok(read) => read,
+            Err(e) => {
+                self.torrents[&id].report_file_error(&self.runtime, id, e);
+                return;
+            }
+        };
+
+        // Never send a block we don't have entirely
+        if !complete {
+            error!("[vfs] {:?} Short read on piece {:?}", id, piece);
+            return;
+        }
+
+        send_to_peer(&self.runtime, peer, piece, block, data);
+    }
+
+    fn read_piece(
+        &mut self,
+        id: TorrentId,
+        piece: PieceIndex,
+        supervisor: Sender<TorrentNotification>,
+    ) {
+        let length = match self.torrents.get(&id) {
+            Some(cache) => cache.pieces_infos.piece_size_of(piece),
+            None => return,
+        };
+
+        // The missing data is zeroed, the piece then fails its hash
+        let data = match self.read_buffer(id, piece, 0.into(), length) {
+            Ok((data, _)) => data,
+            Err(e) => {
+                self.torrents[&id].report_file_error(&self.runtime, id, e);
+                vec![0; length as usize].into_boxed_slice()
+            }
+        };
+
+        send_to_supervisor(&self.runtime, supervisor, piece, data);
+    }
+
+    fn read_block(&mut self, id: TorrentId, offset: u64, length: u32) -> io::Result<Vec<u8>> {
+        let cache = self.torrents.get(&id).ok_or(io::ErrorKind::NotFound)?;
+        let (piece, block) = cache.block_at(offset, length)?;
+
+        match self.read_buffer(id, piece, block, length)? {
+            (data, true) => Ok(data.into_vec()),
+            _ => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    /// Returns the data and whether it was read entirely.
+    /// On a short read (a file shorter than expected), the missing bytes
+    /// are zeroed. A file failing to open or to read is an error
+    fn read_buffer(
+        &mut self,
+        id: TorrentId,
+        piece: PieceIndex,
+        block: BlockIndex,
+        length: u32,
+    ) -> io::Result<(Box<[u8]>, bool)> {
         let cache = self.torrents.get_mut(&id).unwrap();
         let length = length as usize;
 
         let mut data = new_read_buffer(length);
         let slice = &mut data[..];
         let mut cursor = 0;
+        let mut complete = true;
+        let mut error = None;
 
         cache.iter_files_on_piece(piece, block, |fd, offset, max| {
             let remaining = length - cursor;
             let to_read = remaining.min(max);
-
-            fd.read_exact_at(&mut slice[cursor..cursor + to_read], offset as u64)
-                .unwrap();
+            let chunk = &mut slice[cursor..cursor + to_read];
+
+            let nread = match read_full_at(fd, chunk, offset as u64) {
+                Ok(nread) => nread,
+                Err(e) => {
+                    error = Some(e);
+                    return false;
+                }
+            };
+            if nread < to_read {
+                chunk[nread..].iter_mut().for_each(|b| *b = 0);
+                complete = false;
+            }
 
             cursor += to_read;
             cursor < length
-        });
+        })?;
+
+        if let Some(e) = error {
+            return Err(e);
+        }
 
         assert_eq!(cursor, length);
 
-        send_to_peer(&self.runtime, peer, piece, block, data);
+        Ok((data, complete))
     }
 
     fn write(&mut self, id: TorrentId, piece: PieceIndex, data: &[u8]) {
         let cache = self.torrents.get_mut(&id).unwrap();
 
+        if cache.check_writable(id).is_err() {
+            return;
+        }
+
+        if !cache.check_space(&self.runtime, id, piece, data.len(), &self.disk_space) {
+            return;
+        }
+
         let mut data = &data[..];
 
-        cache.iter_files_on_piece(piece, 0.into(), |ref mut fd, offset, max| {
+        let result = cache.iter_files_on_piece(piece, 0.into(), |ref mut fd, offset, max| {
             let chunk = &data[..max.min(data.len())];
 
             fd.write_all_at(chunk, offset as u64).unwrap();
@@ -172,6 +393,97 @@ impl StandardFS {
             !data.is_empty()
         });
 
+        if let Err(e) = result {
+            cache.report_file_error(&self.runtime, id, e);
+            return;
+        }
+
         assert!(data.is_empty());
     }
+
+    fn write_batch(&mut self, id: TorrentId, mut writes: Vec<WriteRequest>) {
+        // The torrent was removed after the batch was sent
+        let cache = match self.torrents.get_mut(&id) {
+            Some(cache) => cache,
+            None => return,
+        };
+
+        if cache.check_writable(id).is_err() {
+            return;
+        }
+
+        let (runtime, disk_space) = (&self.runtime, &self.disk_space);
+        writes.retain(|w| cache.check_space(runtime, id, w.piece, w.data.len(), disk_space));
+
+        for file_write in cache.coalesce_writes(&writes) {
+            let bufs: Vec<&[u8]> = file_write
+                .parts
+                .iter()
+                .map(|(index, range)| &writes[*index].data[range.clone()])
+                .collect();
+
+            let fd = match cache.file(file_write.file) {
+                Ok(fd) => fd,
+                Err(e) => {
+                    cache.report_file_error(&self.runtime, id, e);
+                    continue;
+                }
+            };
+
+            // The disk is full or failing, the next writes would fail too
+            if let Err(e) = fd.write_all_vectored_at(&bufs, file_write.offset as u64) {
+                cache.report_file_error(&self.runtime, id, e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_full_at, FileOffset};
+
+    /// File returning at most 3 bytes on each read
+    struct ShortReads(Vec<u8>);
+
+    impl FileOffset for ShortReads {
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+            let data = self.0.get(offset as usize..).unwrap_or(&[]);
+            let n = data.len().min(buf.len()).min(3);
+
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+        fn write_all_at(&mut self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+            let offset = offset as usize;
+            let end = offset + buf.len();
+
+            if self.0.len() < end {
+                self.0.resize(end, 0);
+            }
+            self.0[offset..end].copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn short_reads() {
+        let mut file = ShortReads((0..20).collect());
+
+        let mut buf = [0; 10];
+        assert_eq!(read_full_at(&mut file, &mut buf, 5).unwrap(), 10);
+        assert_eq!(buf, [5, 6, 7, 8, 9, 10, 11, 12, 13, 14]);
+
+        // The end of file is reached
+        let mut buf = [0xFF; 10];
+        assert_eq!(read_full_at(&mut file, &mut buf, 15).unwrap(), 5);
+        assert_eq!(&buf[..5], &[15, 16, 17, 18, 19]);
+
+        // Written past the end, then read back in pieces
+        file.write_all_vectored_at(&[&[1, 2], &[3, 4, 5, 6]], 18)
+            .unwrap();
+        let mut buf = [0; 6];
+        assert_eq!(read_full_at(&mut file, &mut buf, 18).unwrap(), 6);
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6]);
+    }
 }
@@ -1,29 +1,61 @@
 use std::{cell::RefCell, convert::TryInto, ptr::NonNull, sync::Arc};
 
 use async_channel::{Receiver, RecvError, Sender};
-use hashbrown::HashMap;
-use kv_log_macro::info;
-use tokio::runtime::Runtime;
+use kv_log_macro::{error, info};
+use tokio::{runtime::Runtime, sync::oneshot};
 
 use crate::{
-    fs::TorrentCache,
+    fs::{AllocationMode, DiskSpace, TorrentCache, WriteRequest},
     io_uring::file::FilesUring,
     peer::peer::PeerCommand,
     piece_picker::{BlockIndex, PieceIndex},
-    supervisors::torrent::TorrentId,
+    supervisors::torrent::{TorrentId, TorrentNotification},
     utils::{Map, NoHash},
 };
 
-use super::{new_read_buffer, send_to_peer, FSMessage, FileSystem};
+use super::{
+    new_read_buffer, send_to_peer, send_to_supervisor, FSMessage, FileSystem, WriteThrottle,
+};
+
+/// Why io_uring can't be used, the session falls back to `StandardFS`
+#[derive(Debug)]
+pub enum UringInitError {
+    /// No io_uring, or without the operations we use
+    KernelTooOld,
+    /// The syscalls are denied, usually by seccomp in a container
+    /// or by the `kernel.io_uring_disabled` sysctl
+    Disabled,
+    /// The ring couldn't be created or mapped
+    Setup(std::io::Error),
+}
+
+impl From<std::io::Error> for UringInitError {
+    fn from(e: std::io::Error) -> UringInitError {
+        match e.raw_os_error() {
+            Some(libc::ENOSYS) => UringInitError::KernelTooOld,
+            Some(libc::EPERM) => UringInitError::Disabled,
+            _ if e.kind() == std::io::ErrorKind::Unsupported => UringInitError::KernelTooOld,
+            _ => UringInitError::Setup(e),
+        }
+    }
+}
 
 /// FileSystem implementation based on io_uring
 pub struct UringFS {
     runtime: Arc<Runtime>,
     recv: Receiver<FSMessage>,
     torrents: Map<TorrentId, TorrentCache>,
+    throttle: WriteThrottle,
+    disk_space: DiskSpace,
+    allocation: AllocationMode,
     files_ring: RefCell<Box<FilesUring<NonNull<u8>>>>,
     pending_buffers: Map<NonNull<u8>, Pending>,
     to_remove: Vec<TorrentId>,
+    /// Flushed once their writes in flight complete
+    to_flush: Vec<(TorrentId, Sender<()>)>,
+    /// Pieces read once the writes in flight complete, the kernel
+    /// doesn't order the operations of a submission
+    to_read: Vec<(TorrentId, PieceIndex, Sender<TorrentNotification>)>,
 }
 
 unsafe impl Send for UringFS {}
@@ -35,11 +67,26 @@ enum Pending {
     },
     Read {
         nrequests: u32,
+        /// A request failed or was short (end of file)
+        failed: bool,
         piece: PieceIndex,
         block: BlockIndex,
         buffer: Box<[u8]>,
         peer: Sender<PeerCommand>,
     },
+    ReadPiece {
+        nrequests: u32,
+        failed: bool,
+        piece: PieceIndex,
+        buffer: Box<[u8]>,
+        supervisor: Sender<TorrentNotification>,
+    },
+    ReadBlock {
+        nrequests: u32,
+        failed: bool,
+        buffer: Box<[u8]>,
+        respond: oneshot::Sender<std::io::Result<Vec<u8>>>,
+    },
 }
 
 impl Pending {
@@ -47,27 +94,38 @@ impl Pending {
         match self {
             Pending::Read {
                 nrequests: _,
+                failed: _,
                 piece,
                 block,
                 buffer,
                 peer,
             } => (piece, block, buffer, peer),
-            Pending::Write { .. } => panic!(),
+            Pending::Write { .. } | Pending::ReadPiece { .. } | Pending::ReadBlock { .. } => {
+                panic!()
+            }
         }
     }
 }
 
 impl FileSystem for UringFS {
-    fn init(runtime: Arc<Runtime>) -> Option<Sender<FSMessage>> {
+    type Error = UringInitError;
+
+    fn init(runtime: Arc<Runtime>) -> Result<Sender<FSMessage>, UringInitError> {
+        let files_ring = FilesUring::new(256)?;
         let (sender, recv) = async_channel::bounded(1000);
 
         let vfs = UringFS {
             recv,
             runtime,
             torrents: Map::default(),
-            files_ring: RefCell::new(Box::new(FilesUring::new(256).ok()?)),
+            throttle: WriteThrottle::default(),
+            disk_space: DiskSpace::default(),
+            allocation: AllocationMode::default(),
+            files_ring: RefCell::new(Box::new(files_ring)),
             pending_buffers: Map::with_capacity_and_hasher(16, NoHash::default()),
             to_remove: Vec::new(),
+            to_flush: Vec::new(),
+            to_read: Vec::new(),
         };
 
         std::thread::Builder::new()
@@ -75,7 +133,7 @@ impl FileSystem for UringFS {
             .spawn(move || vfs.start())
             .unwrap();
 
-        Some(sender)
+        Ok(sender)
     }
 }
 
@@ -88,12 +146,8 @@ fn drop_box_from_ptr(ptr: NonNull<u8>, buffer_length: u32) {
 }
 
 impl UringFS {
-    fn wait_for_message(&self) -> Result<FSMessage, RecvError> {
-        if let Ok(msg) = self.recv.try_recv() {
-            return Ok(msg);
-        };
-
-        self.runtime.block_on(async { self.recv.recv().await })
+    fn wait_for_message(&mut self) -> Result<FSMessage, RecvError> {
+        self.throttle.next(&self.runtime, &self.recv)
     }
 
     fn start(mut self) {
@@ -102,7 +156,7 @@ impl UringFS {
             self.process_msg(msg);
 
             // Process all available messages before notifying the kernel
-            while let Some(msg) = self.recv.try_recv().ok() {
+            while let Some(msg) = self.throttle.try_next(&self.recv) {
                 self.process_msg(msg);
             }
 
@@ -111,7 +165,7 @@ impl UringFS {
 
             loop {
                 while let Some(completed) = ring.get_completed() {
-                    let (ptr, _result) = match completed {
+                    let (ptr, result) = match completed {
                         (Some(ptr), result) => (ptr, result),
                         _ => continue,
                     };
@@ -130,20 +184,77 @@ impl UringFS {
                             drop_box_from_ptr(ptr, *buffer_length);
                             self.pending_buffers.remove(&ptr).unwrap();
                         }
-                        Pending::Read { nrequests, .. } => {
+                        Pending::Read {
+                            nrequests, failed, ..
+                        }
+                        | Pending::ReadPiece {
+                            nrequests, failed, ..
+                        }
+                        | Pending::ReadBlock {
+                            nrequests, failed, ..
+                        } => {
+                            *failed |= result.is_err();
+
                             if *nrequests != 1 {
                                 *nrequests -= 1;
                                 continue;
                             }
 
-                            let pending = self.pending_buffers.remove(&ptr).unwrap();
-                            let (piece, block, buffer, peer) = pending.extract_read();
-
-                            send_to_peer(&self.runtime, peer, piece, block, buffer);
+                            match self.pending_buffers.remove(&ptr).unwrap() {
+                                Pending::ReadPiece {
+                                    piece,
+                                    mut buffer,
+                                    supervisor,
+                                    failed,
+                                    ..
+                                } => {
+                                    // We don't know which part is missing, the
+                                    // zeroed piece fails its hash
+                                    if failed {
+                                        buffer.iter_mut().for_each(|b| *b = 0);
+                                    }
+                                    send_to_supervisor(&self.runtime, supervisor, piece, buffer);
+                                }
+                                Pending::ReadBlock {
+                                    buffer,
+                                    respond,
+                                    failed,
+                                    ..
+                                } => {
+                                    let result = match failed {
+                                        true => Err(std::io::ErrorKind::UnexpectedEof.into()),
+                                        false => Ok(buffer.into_vec()),
+                                    };
+                                    respond.send(result).ok();
+                                }
+                                Pending::Read {
+                                    failed: true,
+                                    piece,
+                                    ..
+                                } => {
+                                    // Never send a block we don't have entirely
+                                    error!("[vfs] Short read on piece {:?}", piece);
+                                }
+                                pending => {
+                                    let (piece, block, buffer, peer) = pending.extract_read();
+
+                                    send_to_peer(&self.runtime, peer, piece, block, buffer);
+                                }
+                            }
                         }
                     }
                 }
 
+                if ring.in_flight() == 0 && !self.to_read.is_empty() {
+                    drop(ring);
+                    for (id, piece, supervisor) in std::mem::take(&mut self.to_read) {
+                        self.read_piece(id, piece, supervisor);
+                    }
+                    ring = self.files_ring.borrow_mut();
+                    ring.submit();
+                    continue;
+                }
+
                 // The kernel processed all operations, or we got new
                 // operations available
                 if ring.in_flight() == 0 || !self.recv.is_empty() {
@@ -155,6 +266,15 @@ impl UringFS {
                 ring.block();
             }
 
+            if !self.to_flush.is_empty() && ring.in_flight() == 0 {
+                for (id, done) in self.to_flush.drain(..) {
+                    if let Some(cache) = self.torrents.get(&id) {
+                        cache.sync(id);
+                    }
+                    done.try_send(()).ok();
+                }
+            }
+
             // Remove torrents data when there is nothing more in flight
             if !self.to_remove.is_empty() && ring.in_flight() == 0 {
                 for id in &self.to_remove {
@@ -173,13 +293,11 @@ impl UringFS {
                 id,
                 meta,
                 pieces_infos,
+                read_only,
+                supervisor,
             } => {
-                let cache = TorrentCache {
-                    files: meta.files(),
-                    torrent: meta,
-                    pieces_infos,
-                    fds: HashMap::default(),
-                };
+                let mut cache = TorrentCache::new(meta, pieces_infos, read_only, supervisor);
+                cache.allocate(&self.runtime, id, self.allocation, &self.disk_space);
                 self.torrents.insert(id, cache);
 
                 info!("[vfs] {:?} Add torrent", id);
@@ -199,6 +317,55 @@ impl UringFS {
             FSMessage::Write { id, piece, data } => {
                 self.write(id, piece, data);
             }
+            FSMessage::WriteBatch { id, writes } => {
+                self.write_batch(id, writes);
+            }
+            FSMessage::SetWriteRate { bytes_per_sec } => {
+                self.throttle.set_rate(bytes_per_sec);
+            }
+            FSMessage::SetMinFreeSpace { bytes } => {
+                self.disk_space.set_min_free(bytes);
+            }
+            FSMessage::SetAllocation { mode } => {
+                self.allocation = mode;
+            }
+            FSMessage::Allocate { id } => {
+                if let Some(cache) = self.torrents.get_mut(&id) {
+                    cache.allocate(&self.runtime, id, self.allocation, &self.disk_space);
+                }
+            }
+            FSMessage::CheckFreeSpace { id } => {
+                if let Some(cache) = self.torrents.get(&id) {
+                    cache.check_free_space(&self.runtime, &self.disk_space);
+                }
+            }
+            FSMessage::ReadPiece {
+                id,
+                piece,
+                supervisor,
+            } => {
+                let writing = self
+                    .pending_buffers
+                    .values()
+                    .any(|pending| matches!(pending, Pending::Write { .. }));
+
+                if writing {
+                    self.to_read.push((id, piece, supervisor));
+                } else {
+                    self.read_piece(id, piece, supervisor);
+                }
+            }
+            FSMessage::ReadBlock {
+                id,
+                file_offset,
+                length,
+                respond,
+            } => {
+                self.read_block(id, file_offset, length, respond);
+            }
+            FSMessage::Flush { id, done } => {
+                self.to_flush.push((id, done));
+            }
         }
     }
 
@@ -210,10 +377,124 @@ impl UringFS {
         length: u32,
         peer: Sender<PeerCommand>,
     ) {
+        // Never send a block we don't have entirely
+        let (data, user_data, nrequests) = match self.submit_read(id, piece, block, length) {
+            Ok(read) => read,
+            Err(_) => return,
+        };
+
+        self.pending_buffers.insert(
+            user_data,
+            Pending::Read {
+                nrequests,
+                failed: false,
+                peer,
+                piece,
+                block,
+                buffer: data,
+            },
+        );
+    }
+
+    fn read_piece(
+        &mut self,
+        id: TorrentId,
+        piece: PieceIndex,
+        supervisor: Sender<TorrentNotification>,
+    ) {
+        let length = match self.torrents.get(&id) {
+            Some(cache) => cache.pieces_infos.piece_size_of(piece),
+            None => return,
+        };
+
+        let (data, user_data, nrequests) = match self.submit_read(id, piece, 0.into(), length) {
+            Ok(read) => read,
+            Err(_) => {
+                // The zeroed piece fails its hash
+                let data = vec![0; length as usize].into_boxed_slice();
+                send_to_supervisor(&self.runtime, supervisor, piece, data);
+                return;
+            }
+        };
+
+        self.pending_buffers.insert(
+            user_data,
+            Pending::ReadPiece {
+                nrequests,
+                failed: false,
+                piece,
+                buffer: data,
+                supervisor,
+            },
+        );
+    }
+
+    fn read_block(
+        &mut self,
+        id: TorrentId,
+        offset: u64,
+        length: u32,
+        respond: oneshot::Sender<std::io::Result<Vec<u8>>>,
+    ) {
+        let block = match self.torrents.get(&id) {
+            Some(cache) => cache.block_at(offset, length),
+            None => Err(std::io::ErrorKind::NotFound.into()),
+        };
+
+        let (piece, block) = match block {
+            Ok(block) => block,
+            Err(e) => {
+                respond.send(Err(e)).ok();
+                return;
+            }
+        };
+
+        if length == 0 {
+            respond.send(Ok(Vec::new())).ok();
+            return;
+        }
+
+        let (data, user_data, nrequests) = match self.submit_read(id, piece, block, length) {
+            Ok(read) => read,
+            Err(e) => {
+                respond.send(Err(e)).ok();
+                return;
+            }
+        };
+
+        self.pending_buffers.insert(
+            user_data,
+            Pending::ReadBlock {
+                nrequests,
+                failed: false,
+                buffer: data,
+                respond,
+            },
+        );
+    }
+
+    /// Submit the reads to the ring, the buffer must be kept alive
+    /// until all requests completed. Nothing is submitted when one of
+    /// the files can't be opened, the supervisor is told
+    fn submit_read(
+        &mut self,
+        id: TorrentId,
+        piece: PieceIndex,
+        block: BlockIndex,
+        length: u32,
+    ) -> std::io::Result<(Box<[u8]>, NonNull<u8>, u32)> {
         let cache = self.torrents.get_mut(&id).unwrap();
-        let mut ring = self.files_ring.borrow_mut();
         let length = length as usize;
 
+        // Open the files before the first request is submitted
+        if let Err(e) = cache.open_files(piece, block, length) {
+            let kind = e.kind();
+            cache.report_file_error(&self.runtime, id, e);
+            return Err(kind.into());
+        }
+
+        let mut ring = self.files_ring.borrow_mut();
+
         let mut data = new_read_buffer(length);
         let user_data = NonNull::new(data.as_mut_ptr()).unwrap();
 
@@ -221,7 +502,7 @@ impl UringFS {
         let mut cursor = 0;
         let mut nrequest_on_data = 0;
 
-        cache.iter_files_on_piece(piece, block, |fd, offset, max| {
+        let submitted = cache.iter_files_on_piece(piece, block, |fd, offset, max| {
             let remaining = length - cursor;
             let to_read = remaining.min(max);
 
@@ -236,23 +517,31 @@ impl UringFS {
             cursor < length
         });
 
+        // The files are already opened
+        assert!(submitted.is_ok());
         assert_eq!(cursor, length);
         assert!(nrequest_on_data > 0);
 
-        self.pending_buffers.insert(
-            user_data,
-            Pending::Read {
-                nrequests: nrequest_on_data,
-                peer,
-                piece,
-                block,
-                buffer: data,
-            },
-        );
+        Ok((data, user_data, nrequest_on_data))
     }
 
     fn write(&mut self, id: TorrentId, piece: PieceIndex, mut data: Box<[u8]>) {
         let cache = self.torrents.get_mut(&id).unwrap();
+
+        if cache.check_writable(id).is_err() {
+            return;
+        }
+
+        if !cache.check_space(&self.runtime, id, piece, data.len(), &self.disk_space) {
+            return;
+        }
+
+        // Open the files before the first request is submitted
+        if let Err(e) = cache.open_files(piece, 0.into(), data.len()) {
+            cache.report_file_error(&self.runtime, id, e);
+            return;
+        }
+
         let mut ring = self.files_ring.borrow_mut();
 
         let user_data = NonNull::new(data.as_mut_ptr()).unwrap();
@@ -260,7 +549,7 @@ impl UringFS {
         let mut slice = &data[..];
         let mut nrequest_on_data = 0;
 
-        cache.iter_files_on_piece(piece, 0.into(), |fd, offset, max| {
+        let submitted = cache.iter_files_on_piece(piece, 0.into(), |fd, offset, max| {
             let chunk = &slice[..max.min(slice.len())];
 
             // Safety: The buffer is dropped only after all requests completed
@@ -274,6 +563,8 @@ impl UringFS {
             !slice.is_empty()
         });
 
+        // The files are already opened
+        assert!(submitted.is_ok());
         assert!(slice.is_empty());
         assert!(nrequest_on_data > 0);
 
@@ -288,4 +579,56 @@ impl UringFS {
             },
         );
     }
+
+    /// Each run of contiguous writes in a file is copied into one buffer,
+    /// written with a single request
+    fn write_batch(&mut self, id: TorrentId, mut writes: Vec<WriteRequest>) {
+        // The torrent was removed after the batch was sent
+        let cache = match self.torrents.get_mut(&id) {
+            Some(cache) => cache,
+            None => return,
+        };
+
+        if cache.check_writable(id).is_err() {
+            return;
+        }
+
+        let (runtime, disk_space) = (&self.runtime, &self.disk_space);
+        writes.retain(|w| cache.check_space(runtime, id, w.piece, w.data.len(), disk_space));
+
+        let mut ring = self.files_ring.borrow_mut();
+
+        for file_write in cache.coalesce_writes(&writes) {
+            let mut data = Vec::with_capacity(file_write.length());
+            for (index, range) in &file_write.parts {
+                data.extend_from_slice(&writes[*index].data[range.clone()]);
+            }
+            let mut data = data.into_boxed_slice();
+
+            let fd = match cache.file(file_write.file) {
+                Ok(fd) => fd,
+                Err(e) => {
+                    cache.report_file_error(&self.runtime, id, e);
+                    continue;
+                }
+            };
+            let user_data = NonNull::new(data.as_mut_ptr()).unwrap();
+
+            // Safety: The buffer is dropped only after the request completed
+            unsafe {
+                ring.write_with_data(fd, file_write.offset, &data, user_data);
+            }
+
+            let buffer_length: u32 = data.len().try_into().unwrap();
+            Box::leak(data);
+
+            self.pending_buffers.insert(
+                user_data,
+                Pending::Write {
+                    nrequests: 1,
+                    buffer_length,
+                },
+            );
+        }
+    }
 }
@@ -411,10 +411,15 @@ impl<T> FilesUring<T> {
                         // Try twice
                         // https://github.com/facebook/rocksdb/pull/6441#issuecomment-589843435
                         if *neof == 1 {
-                            // EOF reached
+                            // EOF reached before processing all the data
+
+                            let error = match kind {
+                                OpKind::Read => std::io::ErrorKind::UnexpectedEof,
+                                OpKind::Write => std::io::ErrorKind::WriteZero,
+                            };
 
                             let Pending { user_data, .. } = self.pending.remove(&id).unwrap();
-                            return Some((user_data, Ok(())));
+                            return Some((user_data, Err(error.into())));
                         }
                         *neof += 1;
                     }
@@ -244,7 +244,7 @@ impl IoUring {
 
         if !Self::is_ops_supported(io_ring_fd)? {
             warn!("Kernel doesn't support our io_uring operations");
-            return Err(std::io::ErrorKind::Other.into());
+            return Err(std::io::ErrorKind::Unsupported.into());
         }
 
         let features = FeaturesFlags::from_bits_truncate(params.features);
@@ -8,16 +8,20 @@ pub mod actors;
 pub mod bencode;
 pub mod bitfield;
 pub mod cache_line;
+pub mod create;
 pub mod errors;
 pub mod extensions;
 pub mod fs;
 pub mod io_uring;
 pub mod logger;
 pub mod metadata;
+pub mod metrics;
 pub mod peer;
 pub mod piece_collector;
 pub mod piece_picker;
 pub mod pieces;
+pub mod rate_limit;
+pub mod resume;
 pub mod session;
 pub mod sha1;
 pub mod spsc;
@@ -27,6 +31,8 @@ pub mod udp_ext;
 pub mod utils;
 pub mod utp;
 
+pub use spsc::{MpscSender, PopError, PushError, Receiver, RecvError, Sender};
+
 // pub mod memory_pool;
 
 //https://blog.cloudflare.com/how-to-receive-a-million-packets/
@@ -1,104 +1,328 @@
 use ansi_term::{ANSIGenericString, Colour};
-use log::{kv, LevelFilter, Log, Metadata, Record};
-use std::io::{self, StdoutLock, Write};
+use crossbeam_channel::Sender;
+use log::{kv, Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Mutex, Once, RwLock},
+};
 
-/// Start logging.
-//pub(crate) fn start(level: LevelFilter) {
-pub(crate) fn start() {
-    let logger = Box::new(Logger {});
-    log::set_boxed_logger(logger).ok();
-    log::set_max_level(LevelFilter::Trace);
+#[cfg(test)]
+thread_local! {
+    /// Lines logged by the current thread, when captured
+    static CAPTURED: std::cell::RefCell<Option<Vec<u8>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Returns the lines logged by the current thread while running `fun`,
+/// without the colors
+#[cfg(test)]
+pub(crate) fn capture(fun: impl FnOnce()) -> String {
+    start();
+
+    CAPTURED.with(|c| c.replace(Some(Vec::new())));
+    fun();
+    let captured = CAPTURED.with(|c| c.take()).unwrap_or_default();
+
+    let captured = String::from_utf8_lossy(&captured);
+    let mut output = String::with_capacity(captured.len());
+    let mut chars = captured.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the escape sequence, until its final 'm'
+            chars.by_ref().find(|c| *c == 'm');
+        } else {
+            output.push(c);
+        }
+    }
+
+    output
+}
+
+/// A line logged, for `LogTarget::Channel`
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub level: Level,
+    pub module_path: Option<String>,
+    pub message: String,
+    /// Structured values of the line, such as `{ addr: .. }`
+    pub key_values: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub enum LogTarget {
+    Stderr,
+    /// Appended to the file, without colors
+    File(PathBuf),
+    /// Each line is sent as a `LogRecord`, it's dropped when the
+    /// receiver is gone
+    Channel(Sender<LogRecord>),
+}
+
+/// All the levels, to stderr by default: stdout is left to the program
+#[derive(Debug, Clone)]
+pub struct LoggerConfig {
+    pub level: LevelFilter,
+    pub target: LogTarget,
+}
+
+impl Default for LoggerConfig {
+    fn default() -> LoggerConfig {
+        LoggerConfig {
+            level: LevelFilter::Trace,
+            target: LogTarget::Stderr,
+        }
+    }
+}
+
+enum Output {
+    Stderr,
+    File(Mutex<File>),
+    Channel(Sender<LogRecord>),
+}
+
+static INIT: Once = Once::new();
+static OUTPUT: RwLock<Output> = RwLock::new(Output::Stderr);
+
+fn install() {
+    INIT.call_once(|| {
+        log::set_logger(&Logger {}).ok();
+        log::set_max_level(LoggerConfig::default().level);
+    });
+}
+
+/// Start logging with the default configuration, unless it's already
+/// started. It's called by each `Session`, call `start_with` before
+/// creating them to change the configuration
+pub fn start() {
+    install();
+}
+
+/// Start logging, or replace the configuration when it's already
+/// started
+pub fn start_with(config: LoggerConfig) -> io::Result<()> {
+    let output = match config.target {
+        LogTarget::Stderr => Output::Stderr,
+        LogTarget::File(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Output::File(Mutex::new(file))
+        }
+        LogTarget::Channel(sender) => Output::Channel(sender),
+    };
+
+    install();
+    *OUTPUT.write().unwrap() = output;
+    log::set_max_level(config.level);
+
+    Ok(())
+}
+
+/// Puts back the output and the level of the logger when it's dropped,
+/// for the tests replacing them. The other tests keep logging to stderr
+/// even when one of them panics
+#[cfg(test)]
+struct RestoreOutput {
+    output: Option<Output>,
+    level: LevelFilter,
+}
+
+#[cfg(test)]
+impl RestoreOutput {
+    fn new() -> RestoreOutput {
+        install();
+        let output = std::mem::replace(&mut *OUTPUT.write().unwrap(), Output::Stderr);
+
+        RestoreOutput {
+            output: Some(output),
+            level: log::max_level(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for RestoreOutput {
+    fn drop(&mut self) {
+        if let Some(output) = self.output.take() {
+            *OUTPUT.write().unwrap_or_else(|e| e.into_inner()) = output;
+        }
+        log::set_max_level(self.level);
+    }
+}
+
+/// Whether the lines of the current thread are captured by a test
+fn is_captured() -> bool {
+    #[cfg(test)]
+    return CAPTURED.with(|c| c.borrow().is_some());
+
+    #[cfg(not(test))]
+    false
 }
 
 #[derive(Debug)]
 pub(crate) struct Logger {}
 
 impl Log for Logger {
-    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
-        // metadata.level() <= log::max_level()
-        true
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= log::max_level()
     }
 
     fn log(&self, record: &Record<'_>) {
-        use chrono::prelude::*;
-
-        if record
+        let is_ours = record
             .module_path()
             .map(|m| m.contains("rustorrent"))
-            .unwrap_or(true)
+            .unwrap_or(true);
+
+        if !is_ours || !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let output = OUTPUT.read().unwrap();
+
+        let file = match &*output {
+            Output::Channel(sender) if !is_captured() => {
+                sender.send(to_record(record)).ok();
+                return;
+            }
+            Output::File(file) => Some(file),
+            _ => None,
+        };
+
+        let mut handle = Vec::with_capacity(256);
+        let level = get_level(record.level(), file.is_none());
+        let time = chrono::Local::now().format("%T");
+        write!(&mut handle, "{} {}: ", time, level).unwrap();
+        write!(&mut handle, "{}", record.args()).unwrap();
+        format_kv_pairs(&mut handle, record, file.is_none());
+        writeln!(&mut handle).unwrap();
+
+        #[cfg(test)]
         {
-            // if self.enabled(record.metadata()) {
-            let stdout = io::stdout();
-            let mut handle = stdout.lock();
-            let level = get_level(record.level());
-            let time = Local::now().format("%T");
-            //let time = time::UNIX_EPOCH.elapsed().unwrap().as_millis();
-            write!(&mut handle, "{} {}: ", time, level).unwrap();
-            // write!(&mut handle, "{{\"level\":{},\"time\":{},\"msg\":", level, time).unwrap();
-            write!(&mut handle, "{}", record.args()).unwrap();
-            // serde_json::to_writer(&mut handle, record.args()).unwrap();
-            format_kv_pairs(&mut handle, &record);
-            // writeln!(&mut handle, " }}").unwrap();
-            writeln!(&mut handle).unwrap();
+            let captured = CAPTURED.with(|c| match c.borrow_mut().as_mut() {
+                Some(captured) => {
+                    captured.extend_from_slice(&handle);
+                    true
+                }
+                None => false,
+            });
+
+            if captured {
+                return;
+            }
         }
+
+        match file {
+            Some(file) => file.lock().unwrap().write_all(&handle).ok(),
+            None => io::stderr().lock().write_all(&handle).ok(),
+        };
     }
 
-    // fn log(&self, record: &Record<'_>) {
-    //     if self.enabled(record.metadata()) {
-    //         let stdout = io::stdout();
-    //         let mut handle = stdout.lock();
-    //         let level = get_level(record.level());
-    //         let time = time::UNIX_EPOCH.elapsed().unwrap().as_millis();
-    //         write!(&mut handle, "{{\"level\":{},\"time\":{},\"msg\":", level, time).unwrap();
-    //         serde_json::to_writer(&mut handle, record.args()).unwrap();
-    //         format_kv_pairs(&mut handle, &record);
-    //         writeln!(&mut handle, "}}").unwrap();
-    //     }
-    // }
     fn flush(&self) {}
 }
 
-fn get_level(level: log::Level) -> ANSIGenericString<'static, str> {
+fn get_level(level: log::Level, colors: bool) -> ANSIGenericString<'static, str> {
     use log::Level::*;
 
-    match level {
-        Trace => Colour::Purple.paint("TRACE"),
-        Debug => Colour::Blue.paint("DEBUG"),
-        Info => Colour::Green.paint("INFO"),
-        Warn => Colour::Yellow.paint("WARN"),
-        Error => Colour::Red.paint("ERROR"),
+    let (colour, level) = match level {
+        Trace => (Colour::Purple, "TRACE"),
+        Debug => (Colour::Blue, "DEBUG"),
+        Info => (Colour::Green, "INFO"),
+        Warn => (Colour::Yellow, "WARN"),
+        Error => (Colour::Red, "ERROR"),
+    };
+
+    if colors {
+        colour.paint(level)
+    } else {
+        level.into()
     }
 }
 
-fn format_kv_pairs<'b>(mut out: &mut StdoutLock<'b>, record: &Record) {
-    struct Visitor<'a, 'b> {
-        string: &'a mut StdoutLock<'b>,
-    }
+/// The key-values of the record, as strings
+fn key_values(record: &Record) -> Vec<(String, String)> {
+    struct Visitor(Vec<(String, String)>);
 
-    impl<'kvs, 'a, 'b> kv::Visitor<'kvs> for Visitor<'a, 'b> {
+    impl<'kvs> kv::Visitor<'kvs> for Visitor {
         fn visit_pair(
             &mut self,
             key: kv::Key<'kvs>,
             val: kv::Value<'kvs>,
         ) -> Result<(), kv::Error> {
-            write!(
-                self.string,
-                " {}: {}",
-                Colour::Yellow.bold().paint(key.to_string()),
-                val
-            )
-            .unwrap();
-            // write!(self.string, ",\"{}\":{}", key, val).unwrap();
+            self.0.push((key.to_string(), val.to_string()));
             Ok(())
         }
     }
 
-    let key_values = record.key_values();
+    let mut visitor = Visitor(Vec::new());
+    record.key_values().visit(&mut visitor).unwrap();
+    visitor.0
+}
+
+fn to_record(record: &Record) -> LogRecord {
+    LogRecord {
+        level: record.level(),
+        module_path: record.module_path().map(str::to_string),
+        message: record.args().to_string(),
+        key_values: key_values(record),
+    }
+}
+
+fn format_kv_pairs(out: &mut Vec<u8>, record: &Record, colors: bool) {
+    let key_values = key_values(record);
+
+    if key_values.is_empty() {
+        return;
+    }
+
+    write!(out, " {{").unwrap();
+    for (key, val) in key_values {
+        if colors {
+            write!(out, " {}: {}", Colour::Yellow.bold().paint(key), val).unwrap();
+        } else {
+            write!(out, " {}: {}", key, val).unwrap();
+        }
+    }
+    write!(out, " }}").unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use kv_log_macro::info;
+    use log::{Level, LevelFilter};
+
+    use super::{start, start_with, LogTarget, LoggerConfig, RestoreOutput};
+
+    #[test]
+    fn log_to_channel() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        let _restore = RestoreOutput::new();
+        start_with(LoggerConfig {
+            level: LevelFilter::Trace,
+            target: LogTarget::Channel(sender),
+        })
+        .unwrap();
+        // Doesn't replace the configuration
+        start();
+
+        info!("Channel test {}", 42, { peer: 7 });
+
+        // Other tests log at the same time
+        let record = std::iter::from_fn(|| receiver.recv_timeout(Duration::from_secs(5)).ok())
+            .find(|r| r.message.starts_with("Channel test"))
+            .unwrap();
 
-    if key_values.count() > 0 {
-        write!(out, " {{").unwrap();
-        let mut visitor = Visitor { string: &mut out };
-        record.key_values().visit(&mut visitor).unwrap();
-        write!(out, " }}").unwrap();
+        assert_eq!(record.message, "Channel test 42");
+        assert_eq!(record.level, Level::Info);
+        assert_eq!(
+            record.key_values,
+            vec![("peer".to_string(), "7".to_string())]
+        );
+        assert!(record
+            .module_path
+            .unwrap()
+            .starts_with("rustorrent::logger"));
     }
 }
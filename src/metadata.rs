@@ -1,12 +1,17 @@
 use itertools::Itertools;
-use serde::{Deserialize, Serialize};
+use kv_log_macro::warn;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bytes::ByteBuf;
 use smallvec::SmallVec;
 use url::Url;
 
+use crate::bencode::{self, de::BencodeError, Value};
+
 use std::{
     convert::TryInto,
     hash::{Hash, Hasher},
     iter::Iterator,
+    net::SocketAddr,
     ops::Deref,
     path::{Path, PathBuf, MAIN_SEPARATOR},
     sync::Arc,
@@ -14,23 +19,93 @@ use std::{
 
 pub(crate) type StackVec<T> = SmallVec<[T; 16]>;
 
+/// Decode the bytes as UTF-8, invalid sequences are replaced
+fn from_utf8_lossy(bytes: ByteBuf) -> String {
+    String::from_utf8(bytes.into_vec()).unwrap_or_else(|e| {
+        let string = String::from_utf8_lossy(e.as_bytes()).into_owned();
+        warn!("Invalid UTF-8 in the torrent: {:?}", string);
+        string
+    })
+}
+
+fn lossy_string<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    ByteBuf::deserialize(deserializer).map(from_utf8_lossy)
+}
+
+fn lossy_option<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<ByteBuf>::deserialize(deserializer).map(|s| s.map(from_utf8_lossy))
+}
+
+fn lossy_path<'de, D>(deserializer: D) -> Result<StackVec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let path = Vec::<ByteBuf>::deserialize(deserializer)?;
+    Ok(path.into_iter().map(from_utf8_lossy).collect())
+}
+
+fn lossy_path_option<'de, D>(deserializer: D) -> Result<Option<StackVec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let path = Option::<Vec<ByteBuf>>::deserialize(deserializer)?;
+    Ok(path.map(|p| p.into_iter().map(from_utf8_lossy).collect()))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MetaFile {
     pub length: u64,
     pub md5sum: Option<String>,
+    #[serde(deserialize_with = "lossy_path")]
     pub path: StackVec<String>,
+    #[serde(
+        rename = "path.utf-8",
+        default,
+        deserialize_with = "lossy_path_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub path_utf8: Option<StackVec<String>>,
+}
+
+impl MetaFile {
+    /// The UTF-8 variant of the path, when present
+    pub fn path(&self) -> &StackVec<String> {
+        self.path_utf8.as_ref().unwrap_or(&self.path)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum InfoFile {
     Single {
+        #[serde(deserialize_with = "lossy_string")]
         name: String,
+        #[serde(
+            rename = "name.utf-8",
+            default,
+            deserialize_with = "lossy_option",
+            skip_serializing_if = "Option::is_none"
+        )]
+        name_utf8: Option<String>,
         length: u64,
         md5sum: Option<String>,
     },
     Multiple {
+        #[serde(deserialize_with = "lossy_string")]
         name: String,
+        #[serde(
+            rename = "name.utf-8",
+            default,
+            deserialize_with = "lossy_option",
+            skip_serializing_if = "Option::is_none"
+        )]
+        name_utf8: Option<String>,
         files: Vec<MetaFile>,
     },
 }
@@ -94,6 +169,9 @@ pub struct MetaTorrent {
 pub struct Torrent {
     pub meta: MetaTorrent,
     pub info_hash: Arc<[u8]>,
+    /// The `info` dictionary as read, kept by `to_bytes` so the info
+    /// hash doesn't change. `None` when the torrent was not read
+    info_bytes: Option<Arc<[u8]>>,
 }
 
 pub struct UrlIterator<'a> {
@@ -159,7 +237,180 @@ impl TrackerUrl {
     }
 }
 
+impl MetaFile {
+    fn to_value(&self) -> Value {
+        let path = |path: &StackVec<String>| {
+            Value::from(path.iter().map(String::as_str).collect::<Vec<_>>())
+        };
+
+        Value::dict(vec![
+            ("length", Some(self.length.into())),
+            ("md5sum", self.md5sum.as_deref().map(Value::from)),
+            ("path", Some(path(&self.path))),
+            ("path.utf-8", self.path_utf8.as_ref().map(path)),
+        ])
+    }
+}
+
+impl MetaInfo {
+    fn to_value(&self) -> Value {
+        let mut entries = vec![
+            ("pieces", Some(Value::from(&self.pieces[..]))),
+            ("piece length", Some(self.piece_length.into())),
+            ("private", self.private.map(Value::from)),
+        ];
+
+        match &self.files {
+            InfoFile::Single {
+                name,
+                name_utf8,
+                length,
+                md5sum,
+            } => entries.extend(vec![
+                ("name", Some(name.as_str().into())),
+                ("name.utf-8", name_utf8.as_deref().map(Value::from)),
+                ("length", Some((*length).into())),
+                ("md5sum", md5sum.as_deref().map(Value::from)),
+            ]),
+            InfoFile::Multiple {
+                name,
+                name_utf8,
+                files,
+            } => entries.extend(vec![
+                ("name", Some(name.as_str().into())),
+                ("name.utf-8", name_utf8.as_deref().map(Value::from)),
+                (
+                    "files",
+                    Some(Value::List(files.iter().map(MetaFile::to_value).collect())),
+                ),
+            ]),
+        }
+
+        Value::dict(entries)
+    }
+}
+
 impl Torrent {
+    /// Torrent read from a file, `info` is its `info` dictionary
+    pub(crate) fn from_meta(meta: MetaTorrent, info: &[u8]) -> Torrent {
+        Torrent {
+            meta,
+            info_hash: crate::sha1::sha1(info).to_vec().into(),
+            info_bytes: Some(info.into()),
+        }
+    }
+
+    /// The `.torrent` file, in canonical bencode. The `info` dictionary
+    /// read is written as is, a torrent read and not modified gives the
+    /// same file when it was canonical, without its unknown keys.
+    /// Once `meta.info` is modified, it's encoded from its fields
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let meta = &self.meta;
+
+        let announce_list = meta.announce_list.as_ref().map(|tiers| {
+            Value::List(
+                tiers
+                    .iter()
+                    .map(|tier| tier.iter().map(String::as_str).collect::<Vec<_>>().into())
+                    .collect(),
+            )
+        });
+        // The invalid url-lists are dropped
+        let url_list = meta.url_list.as_ref().and_then(|urls| match urls {
+            UrlList::Single(url) => Some(url.as_str().into()),
+            UrlList::Multiple(urls) => {
+                Some(urls.iter().map(String::as_str).collect::<Vec<_>>().into())
+            }
+            _ => None,
+        });
+
+        let dict = Value::dict(vec![
+            ("announce", meta.announce.as_deref().map(Value::from)),
+            ("announce-list", announce_list),
+            ("comment", meta.comment.as_deref().map(Value::from)),
+            ("created by", meta.created_by.as_deref().map(Value::from)),
+            ("creation date", meta.creation_date.map(Value::from)),
+            ("encoding", meta.encoding.as_deref().map(Value::from)),
+            ("url-list", url_list),
+        ]);
+        let dict = match dict {
+            Value::Dict(dict) => dict,
+            _ => unreachable!(),
+        };
+
+        // The known keys of the info read must still be the fields
+        let encoded = bencode::encode(&meta.info.to_value());
+        let is_read = |info: &[u8]| {
+            bencode::de::from_bytes::<MetaInfo>(info)
+                .map(|read| bencode::encode(&read.to_value()) == encoded)
+                .unwrap_or(false)
+        };
+        let info = match &self.info_bytes {
+            Some(info) if is_read(info) => info.to_vec(),
+            _ => encoded,
+        };
+        let mut info = Some(info);
+
+        // The info is inserted between the other keys, in their order
+        let mut out = vec![b'd'];
+        for (key, value) in &dict {
+            if key.as_slice() > &b"info"[..] {
+                if let Some(info) = info.take() {
+                    out.extend_from_slice(b"4:info");
+                    out.extend_from_slice(&info);
+                }
+            }
+            bencode::encode_to(&Value::Bytes(key.clone()), &mut out);
+            bencode::encode_to(value, &mut out);
+        }
+        if let Some(info) = info {
+            out.extend_from_slice(b"4:info");
+            out.extend_from_slice(&info);
+        }
+        out.push(b'e');
+
+        out
+    }
+
+    /// Check the fields the serde types can't express: `pieces` is a list
+    /// of 20 bytes hashes, one per piece of the files, the `piece length`
+    /// is a power of 2 and there is at least 1 non-empty file
+    pub fn validate(&self) -> Result<(), BencodeError> {
+        let info = &self.meta.info;
+
+        if !info.pieces.len().is_multiple_of(20) {
+            return Err(BencodeError::UnalignedPieces);
+        }
+
+        if !info.piece_length.is_power_of_two() {
+            return Err(BencodeError::InconsistentMetadata(format!(
+                "piece length {} is not a power of 2",
+                info.piece_length
+            )));
+        }
+
+        match &info.files {
+            InfoFile::Multiple { files, .. } if files.is_empty() => {
+                return Err(BencodeError::NoFile)
+            }
+            InfoFile::Single { length: 0, .. } => return Err(BencodeError::EmptyFile),
+            _ => {}
+        }
+
+        let total_size = self.files_total_size() as u64;
+        let expected = total_size.div_ceil(info.piece_length);
+        let num_pieces = info.pieces.len() / 20;
+
+        if num_pieces as u64 != expected {
+            return Err(BencodeError::InconsistentMetadata(format!(
+                "{} pieces for {} bytes, expected {}",
+                num_pieces, total_size, expected
+            )));
+        }
+
+        Ok(())
+    }
+
     pub fn get_urls_tiers(&self) -> Vec<Arc<TrackerUrl>> {
         let mut vec = self
             .meta
@@ -217,6 +468,37 @@ impl Torrent {
         }
     }
 
+    /// Tiers of trackers of `announce-list` (BEP 12), in priority order.
+    /// When it's absent or empty, the `announce` is the only tier
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .meta
+            .announce_list
+            .iter()
+            .flatten()
+            .filter(|tier| !tier.is_empty())
+            .map(|tier| tier.to_vec())
+            .collect();
+
+        match &self.meta.announce {
+            Some(announce) if tiers.is_empty() => vec![vec![announce.clone()]],
+            _ => tiers,
+        }
+    }
+
+    /// The trackers of all the tiers, in the order they should be tried:
+    /// the trackers are shuffled within their tier, the tiers keep their
+    /// priority
+    pub fn trackers(&self) -> Vec<String> {
+        let mut tiers = self.tiers();
+
+        for tier in &mut tiers {
+            fastrand::shuffle(tier);
+        }
+
+        tiers.into_iter().flatten().collect()
+    }
+
     pub fn files_total_size(&self) -> usize {
         match &self.meta.info.files {
             InfoFile::Single { length, .. } => *length as usize,
@@ -248,31 +530,53 @@ impl Torrent {
         }
     }
 
+    /// Name of the root directory, or of the file when there is a single one.
+    /// `name.utf-8` is preferred when present.
+    /// An empty name, or one with a path separator, is replaced by the info
+    /// hash in hex, so we never write outside the download directory
+    pub fn name(&self) -> String {
+        let (name, name_utf8) = match &self.meta.info.files {
+            InfoFile::Single {
+                name, name_utf8, ..
+            } => (name, name_utf8),
+            InfoFile::Multiple {
+                name, name_utf8, ..
+            } => (name, name_utf8),
+        };
+        let name = name_utf8.as_ref().unwrap_or(name);
+
+        let is_valid =
+            !name.is_empty() && name != "." && name != ".." && !name.contains(&['/', '\\'][..]);
+
+        if is_valid {
+            name.clone()
+        } else {
+            self.info_hash
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect()
+        }
+    }
+
     pub fn files(&self) -> Vec<TorrentFile> {
         match &self.meta.info.files {
-            InfoFile::Single {
-                name,
-                length,
-                md5sum,
-            } => {
-                let name = name
-                    .chars()
-                    .filter(|c| !std::path::is_separator(*c))
-                    .collect::<String>();
+            InfoFile::Single { length, md5sum, .. } => {
+                let name = self.name();
                 vec![TorrentFile {
                     path: PathBuf::from(name),
                     length: *length,
                     md5sum: md5sum.clone(),
                 }]
             }
-            InfoFile::Multiple { files, name } => {
+            InfoFile::Multiple { files, .. } => {
                 let sep = MAIN_SEPARATOR.to_string();
+                let name = self.name();
 
                 files
                     .iter()
                     .map(|ref file| {
                         let name = itertools::Itertools::intersperse(
-                            std::iter::Iterator::chain(std::iter::once(name), file.path.iter())
+                            std::iter::Iterator::chain(std::iter::once(&name), file.path().iter())
                                 .map(|s| {
                                     s.chars()
                                         .filter(|c| !std::path::is_separator(*c))
@@ -294,12 +598,310 @@ impl Torrent {
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum MagnetError {
+    InvalidUri,
+    /// Missing `xt`, or not a `urn:btih:` with a 20 bytes hash
+    InvalidInfoHash,
+}
+
+/// Content of a magnet link (BEP 9)
+#[derive(Debug, Default)]
+pub struct MagnetLink {
+    pub info_hash: Arc<[u8]>,
+    /// `dn`
+    pub display_name: Option<String>,
+    /// `tr`
+    pub trackers: Vec<String>,
+    /// Peers given with `x.pe`
+    pub peers: Vec<SocketAddr>,
+    /// `ws`
+    pub web_seeds: Vec<String>,
+    /// DHT nodes given with `x.dht`, as `host:port`
+    pub dht_nodes: Vec<String>,
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // `from_str_radix` accepts a leading '+'
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// RFC 4648 base32, without padding. Some clients write it lowercase
+fn decode_base32(base32: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(base32.len() * 5 / 8);
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+
+    for c in base32.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<MagnetLink, MagnetError> {
+        let url: Url = uri.parse().map_err(|_| MagnetError::InvalidUri)?;
+
+        if url.scheme() != "magnet" {
+            return Err(MagnetError::InvalidUri);
+        }
+
+        let mut info_hash = None;
+        let mut magnet = MagnetLink::default();
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = match hash.len() {
+                            40 => decode_hex(hash),
+                            32 => decode_base32(hash),
+                            _ => None,
+                        }
+                        .filter(|h| h.len() == 20);
+                    }
+                }
+                "dn" => magnet.display_name = Some(value.into_owned()),
+                "tr" => magnet.trackers.push(value.into_owned()),
+                "ws" => magnet.web_seeds.push(value.into_owned()),
+                "x.dht" => magnet.dht_nodes.push(value.into_owned()),
+                "x.pe" => {
+                    if let Ok(addr) = value.parse() {
+                        magnet.peers.push(addr);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        magnet.info_hash = info_hash.ok_or(MagnetError::InvalidInfoHash)?.into();
+        magnet.web_seeds = magnet.web_seeds.into_iter().unique().collect();
+
+        Ok(magnet)
+    }
+
+    /// Torrent of the magnet with its `info` dictionary, fetched from
+    /// the peers. It's rejected when its hash is not the info hash
+    pub fn into_torrent(self, info: &[u8]) -> Result<Torrent, BencodeError> {
+        if crate::sha1::sha1(info)[..] != self.info_hash[..] {
+            return Err(BencodeError::InconsistentMetadata(
+                "the info dictionary doesn't match the info hash".to_string(),
+            ));
+        }
+
+        let mut torrent = self.into_partial_torrent();
+        torrent.meta.info = bencode::de::from_bytes(info)?;
+        torrent.info_bytes = Some(info.into());
+        torrent.validate()?;
+
+        Ok(torrent)
+    }
+
+    /// Torrent of the magnet before its metadata is fetched: the info
+    /// hash and the trackers, each in its own tier, but no piece nor
+    /// file. It doesn't pass `Torrent::validate`
+    pub fn into_partial_torrent(self) -> Torrent {
+        // Torrent::name falls back to the info hash when it is empty
+        let name = self.display_name.unwrap_or_default();
+
+        let announce_list = self
+            .trackers
+            .iter()
+            .map(|tracker| std::iter::once(tracker.clone()).collect())
+            .collect::<StackVec<StackVec<String>>>();
+
+        let url_list = match self.web_seeds.len() {
+            0 => None,
+            _ => Some(UrlList::Multiple(self.web_seeds)),
+        };
+
+        Torrent {
+            meta: MetaTorrent {
+                announce: self.trackers.into_iter().next(),
+                info: MetaInfo {
+                    pieces: Vec::new(),
+                    piece_length: 0,
+                    private: None,
+                    files: InfoFile::Single {
+                        name,
+                        name_utf8: None,
+                        length: 0,
+                        md5sum: None,
+                    },
+                },
+                announce_list: Some(announce_list).filter(|l| !l.is_empty()),
+                creation_date: None,
+                comment: None,
+                created_by: None,
+                encoding: None,
+                url_list,
+            },
+            info_hash: self.info_hash,
+            info_bytes: None,
+        }
+    }
+}
+
+/// Torrent of the tests, a single file "a" of one piece of 1000 bytes
+/// by default. The sha1 of the pieces are not the ones of a content
+#[cfg(test)]
+pub(crate) struct TestTorrent {
+    announce: Option<String>,
+    name: String,
+    piece_length: u64,
+    length: u64,
+    files: Option<Vec<(String, u64)>>,
+    info_hash: u8,
+}
+
+#[cfg(test)]
+impl TestTorrent {
+    pub(crate) fn new() -> TestTorrent {
+        TestTorrent {
+            announce: None,
+            name: "a".to_string(),
+            piece_length: 1000,
+            length: 1000,
+            files: None,
+            info_hash: 7,
+        }
+    }
+
+    pub(crate) fn announce(mut self, announce: &str) -> TestTorrent {
+        self.announce = Some(announce.to_string());
+        self
+    }
+
+    /// Name of the single file, or of the directory of the files
+    pub(crate) fn name(mut self, name: &str) -> TestTorrent {
+        self.name = name.to_string();
+        self
+    }
+
+    pub(crate) fn piece_length(mut self, piece_length: u64) -> TestTorrent {
+        self.piece_length = piece_length;
+        self
+    }
+
+    /// Length of the single file, the last piece can be short
+    pub(crate) fn length(mut self, length: u64) -> TestTorrent {
+        self.length = length;
+        self
+    }
+
+    /// A single file of `num_pieces` full pieces
+    pub(crate) fn pieces(self, num_pieces: usize) -> TestTorrent {
+        let length = self.piece_length * num_pieces as u64;
+        self.length(length)
+    }
+
+    /// Several files, with their path in the directory and their length
+    pub(crate) fn files(mut self, files: &[(&str, u64)]) -> TestTorrent {
+        let files = files
+            .iter()
+            .map(|(path, length)| (path.to_string(), *length))
+            .collect();
+        self.files = Some(files);
+        self
+    }
+
+    /// The info hash is 20 times this byte
+    pub(crate) fn info_hash(mut self, byte: u8) -> TestTorrent {
+        self.info_hash = byte;
+        self
+    }
+
+    pub(crate) fn build(self) -> Torrent {
+        let (files, length) = match self.files {
+            Some(files) => {
+                let length = files.iter().map(|(_, length)| length).sum();
+                let files = files
+                    .into_iter()
+                    .map(|(path, length)| MetaFile {
+                        length,
+                        md5sum: None,
+                        path: std::iter::once(path).collect(),
+                        path_utf8: None,
+                    })
+                    .collect();
+                let files = InfoFile::Multiple {
+                    name: self.name,
+                    name_utf8: None,
+                    files,
+                };
+                (files, length)
+            }
+            None => {
+                let files = InfoFile::Single {
+                    name: self.name,
+                    name_utf8: None,
+                    length: self.length,
+                    md5sum: None,
+                };
+                (files, self.length)
+            }
+        };
+        let num_pieces = length.div_ceil(self.piece_length) as usize;
+
+        Torrent {
+            meta: MetaTorrent {
+                announce: self.announce,
+                info: MetaInfo {
+                    pieces: vec![1; 20 * num_pieces],
+                    piece_length: self.piece_length,
+                    private: None,
+                    files,
+                },
+                announce_list: None,
+                creation_date: None,
+                comment: None,
+                created_by: None,
+                encoding: None,
+                url_list: None,
+            },
+            info_hash: Arc::new([self.info_hash; 20]),
+            info_bytes: None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::bencode::de;
+    use crate::bencode::de::{self, BencodeError, DecodeOptions};
     use itertools::assert_equal;
     use std::ffi::OsStr;
 
+    use super::Torrent;
+
+    /// Most of the files of `scripts/test_torrents` end with a newline
+    fn read_test_file(buffer: &[u8]) -> Result<Torrent, BencodeError> {
+        let options = DecodeOptions {
+            allow_trailing_bytes: true,
+            ..Default::default()
+        };
+        de::read_meta_with_options(buffer, options)
+    }
+
     #[test]
     // Miri takes all the RAM
     // TODO: Report the bug on miri
@@ -335,12 +937,12 @@ mod tests {
     // TODO: Report the bug on miri
     #[cfg_attr(miri, ignore)]
     fn parse_torrent_fail() {
-        use de::DeserializeError::*;
+        use de::BencodeError::*;
 
         #[derive(Debug)]
         struct TorrentFail {
             filename: &'static str,
-            error: de::DeserializeError,
+            error: de::BencodeError,
         }
 
         macro_rules! declare_torrent_errors (
@@ -397,7 +999,7 @@ mod tests {
                 + "/scripts/test_torrents/"
                 + torrent_error.filename;
             let content = std::fs::read(filename).unwrap();
-            let result = de::read_meta(&content);
+            let result = read_test_file(&content);
 
             assert!(
                 result.is_err() && result.as_ref().err() == Some(&torrent_error.error),
@@ -450,7 +1052,8 @@ mod tests {
             },
             { "slash_path3.torrent", |torrent| {
                 assert_eq!(torrent.nfiles(), 1);
-                assert_equal(torrent.files()[0].path.iter(), ["temp....abc"].iter().map(OsStr::new));
+                let hex: String = torrent.info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+                assert_equal(torrent.files()[0].path.iter(), [hex.as_str()].iter().map(OsStr::new));
             }
             },
             { "backslash_path.torrent", |_| {} },
@@ -589,7 +1192,7 @@ mod tests {
                 + "/scripts/test_torrents/"
                 + torrent_success.filename;
             let content = std::fs::read(filename).unwrap();
-            let result = de::read_meta(&content);
+            let result = read_test_file(&content);
 
             assert!(
                 result.is_ok(),
@@ -617,7 +1220,7 @@ mod tests {
         for item in std::fs::read_dir(dir).unwrap() {
             let path = item.unwrap().path();
             let buffer = std::fs::read(path.as_path()).unwrap();
-            let torrent = de::read_meta(&buffer);
+            let torrent = read_test_file(&buffer);
             println!("{} {:?}", torrent.is_ok(), path.file_name());
             if let Ok(torrent) = torrent {
                 torrent.files_total_size();
@@ -633,6 +1236,238 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn re_encode() {
+        let dir = env!("CARGO_MANIFEST_DIR").to_owned() + "/scripts/test_torrents/";
+        let fedora = env!("CARGO_MANIFEST_DIR").to_owned()
+            + "/scripts/Fedora-Workstation-Live-x86_64-33.torrent";
+
+        let mut paths: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|item| item.unwrap().path())
+            .collect();
+        paths.push(fedora.clone().into());
+
+        let mut nencoded = 0;
+        for path in paths {
+            let buffer = std::fs::read(&path).unwrap();
+            let torrent = match read_test_file(&buffer) {
+                Ok(torrent) => torrent,
+                Err(_) => continue,
+            };
+
+            let bytes = torrent.to_bytes();
+            let decoded = de::read_meta(&bytes).unwrap();
+            assert_eq!(decoded.info_hash, torrent.info_hash, "{:?}", path);
+            assert_eq!(decoded.tiers(), torrent.tiers(), "{:?}", path);
+            assert_eq!(decoded.web_seeds(), torrent.web_seeds(), "{:?}", path);
+            assert_eq!(decoded.name(), torrent.name(), "{:?}", path);
+            // Stable
+            assert_eq!(decoded.to_bytes(), bytes, "{:?}", path);
+            nencoded += 1;
+        }
+        assert!(nencoded > 10);
+
+        // Only known keys, the same file
+        let buffer = std::fs::read(&fedora).unwrap();
+        let mut torrent = de::read_meta(&buffer).unwrap();
+        assert_eq!(torrent.to_bytes(), buffer);
+
+        // A new tracker, the info hash doesn't change
+        torrent.meta.announce_list = Some(
+            vec![vec!["udp://tracker.test:6969".to_string()].into()]
+                .into_iter()
+                .collect(),
+        );
+        let decoded = de::read_meta(&torrent.to_bytes()).unwrap();
+        assert_eq!(decoded.info_hash, torrent.info_hash);
+        assert_eq!(decoded.tiers(), vec![vec!["udp://tracker.test:6969"]]);
+
+        // Without the bytes read, the info is encoded from its fields
+        let bytes = torrent.info_bytes.take();
+        let decoded = de::read_meta(&torrent.to_bytes()).unwrap();
+        assert_eq!(decoded.info_hash, torrent.info_hash);
+
+        // The bytes read are not written once the info is modified
+        torrent.info_bytes = bytes;
+        torrent.meta.info.private = Some(1);
+        let decoded = de::read_meta(&torrent.to_bytes()).unwrap();
+        assert_ne!(decoded.info_hash, torrent.info_hash);
+        assert_eq!(decoded.meta.info.private, Some(1));
+    }
+
+    fn torrent_with_name(name: &str, files: Option<&[&str]>) -> super::Torrent {
+        let torrent = super::TestTorrent::new()
+            .name(name)
+            .piece_length(16384)
+            .length(10)
+            .info_hash(0xAB);
+
+        match files {
+            Some(files) => {
+                let files: Vec<_> = files.iter().map(|path| (*path, 10)).collect();
+                torrent.files(&files).build()
+            }
+            None => torrent.build(),
+        }
+    }
+
+    #[test]
+    fn empty_name() {
+        let hex = "ab".repeat(20);
+
+        let torrent = torrent_with_name("", None);
+        assert_eq!(torrent.name(), hex);
+        assert_equal(
+            torrent.files()[0].path.iter(),
+            [hex.as_str()].iter().map(OsStr::new),
+        );
+
+        let torrent = torrent_with_name("", Some(&["a"]));
+        assert_equal(
+            torrent.files()[0].path.iter(),
+            [hex.as_str(), "a"].iter().map(OsStr::new),
+        );
+    }
+
+    #[test]
+    fn name_with_separator() {
+        let hex = "ab".repeat(20);
+
+        for name in &["../etc", "/etc", "a/b", "a\\b", "..", "."] {
+            let torrent = torrent_with_name(name, Some(&["passwd"]));
+            assert_eq!(torrent.name(), hex);
+            assert_equal(
+                torrent.files()[0].path.iter(),
+                [hex.as_str(), "passwd"].iter().map(OsStr::new),
+            );
+
+            let torrent = torrent_with_name(name, None);
+            assert_equal(
+                torrent.files()[0].path.iter(),
+                [hex.as_str()].iter().map(OsStr::new),
+            );
+        }
+
+        let torrent = torrent_with_name("valid name", Some(&["a"]));
+        assert_eq!(torrent.name(), "valid name");
+    }
+
+    /// Bencoded string
+    fn bstr(s: &[u8]) -> Vec<u8> {
+        [s.len().to_string().as_bytes(), b":", s].concat()
+    }
+
+    fn multi_file_torrent(name: &[u8], path: &[u8], utf8: Option<(&str, &str)>) -> Vec<u8> {
+        let (name_utf8, path_utf8) = match utf8 {
+            Some((name, path)) => (
+                [&bstr(b"name.utf-8")[..], &bstr(name.as_bytes())].concat(),
+                [&bstr(b"path.utf-8")[..], b"l", &bstr(path.as_bytes()), b"e"].concat(),
+            ),
+            None => (vec![], vec![]),
+        };
+
+        [
+            &b"d4:infod5:filesld6:lengthi5e4:pathl"[..],
+            &bstr(path),
+            b"e",
+            &path_utf8,
+            b"ee4:name",
+            &bstr(name),
+            &name_utf8,
+            b"12:piece lengthi16384e6:pieces",
+            &bstr(&[0; 20]),
+            b"ee",
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn utf8_variants() {
+        let buffer = multi_file_torrent(b"dir", b"file", Some(("ディレクトリ", "ファイル")));
+        let torrent = de::read_meta(&buffer).unwrap();
+
+        assert_eq!(torrent.name(), "ディレクトリ");
+        assert_equal(
+            torrent.files()[0].path.iter(),
+            ["ディレクトリ", "ファイル"].iter().map(OsStr::new),
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_is_lossy() {
+        let buffer = multi_file_torrent(b"dir\xff", b"file\xfe", None);
+        let torrent = de::read_meta(&buffer).unwrap();
+
+        assert_eq!(torrent.name(), "dir\u{FFFD}");
+        assert_equal(
+            torrent.files()[0].path.iter(),
+            ["dir\u{FFFD}", "file\u{FFFD}"].iter().map(OsStr::new),
+        );
+    }
+
+    #[test]
+    fn unaligned_pieces() {
+        let buffer = multi_file_torrent(b"dir", b"file", None);
+        let mut torrent = de::read_meta(&buffer).unwrap();
+        assert!(torrent.validate().is_ok());
+
+        torrent.meta.info.pieces = vec![0; 19];
+        assert_eq!(
+            torrent.validate().unwrap_err(),
+            de::BencodeError::UnalignedPieces
+        );
+
+        let buffer = String::from_utf8(buffer).unwrap().replace(
+            &format!("6:pieces20:{}", "\0".repeat(20)),
+            &format!("6:pieces19:{}", "\0".repeat(19)),
+        );
+        assert_eq!(
+            de::read_meta(buffer.as_bytes()).unwrap_err(),
+            de::BencodeError::UnalignedPieces
+        );
+    }
+
+    #[test]
+    fn inconsistent_metadata() {
+        // 5 bytes in a piece of 16 KiB
+        let buffer = multi_file_torrent(b"dir", b"file", None);
+        let read = || de::read_meta(&buffer).unwrap();
+        assert_eq!(read().meta.info.piece_length, 16384);
+
+        let inconsistent = |reason: &str| de::BencodeError::InconsistentMetadata(reason.into());
+
+        for (piece_length, reason) in [
+            (0, "piece length 0 is not a power of 2"),
+            (10000, "piece length 10000 is not a power of 2"),
+        ]
+        .iter()
+        {
+            let mut torrent = read();
+            torrent.meta.info.piece_length = *piece_length;
+            assert_eq!(torrent.validate().unwrap_err(), inconsistent(reason));
+        }
+
+        // A hash too many
+        let more = String::from_utf8(buffer.clone()).unwrap().replace(
+            &format!("6:pieces20:{}", "\0".repeat(20)),
+            &format!("6:pieces40:{}", "\0".repeat(40)),
+        );
+        assert_eq!(
+            de::read_meta(more.as_bytes()).unwrap_err(),
+            inconsistent("2 pieces for 5 bytes, expected 1")
+        );
+
+        // A hash missing
+        let mut torrent = read();
+        torrent.meta.info.piece_length = 4;
+        assert_eq!(
+            torrent.validate().unwrap_err(),
+            inconsistent("1 pieces for 5 bytes, expected 2")
+        );
+    }
+
     #[test]
     fn url_list_debug() {
         // For coverage
@@ -646,4 +1481,180 @@ mod tests {
         let url = super::TrackerUrl::new("http://test.com".parse().unwrap(), 0);
         println!("{:?}", *url);
     }
+
+    #[test]
+    fn magnet_link() {
+        let magnet = super::MagnetLink::parse(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\
+             &dn=Test&tr=udp%3A%2F%2Ftracker.test%3A6969\
+             &ws=http%3A%2F%2Fseed.test%2Ffile&ws=http%3A%2F%2Fseed.test%2Ffile\
+             &x.pe=10.0.0.1%3A6881&x.dht=router.test%3A6881",
+        )
+        .unwrap();
+
+        assert_eq!(magnet.info_hash[..2], [0xc1, 0x2f]);
+        assert_eq!(magnet.display_name.as_deref(), Some("Test"));
+        assert_eq!(magnet.trackers, vec!["udp://tracker.test:6969"]);
+        assert_eq!(magnet.web_seeds, vec!["http://seed.test/file"]);
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(magnet.dht_nodes, vec!["router.test:6881"]);
+
+        assert_eq!(
+            super::MagnetLink::parse("magnet:?dn=Test").unwrap_err(),
+            super::MagnetError::InvalidInfoHash
+        );
+        // Too short
+        assert_eq!(
+            super::MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9").unwrap_err(),
+            super::MagnetError::InvalidInfoHash
+        );
+        // A sign isn't a hex digit
+        assert_eq!(
+            super::MagnetLink::parse(
+                "magnet:?xt=urn:btih:%2Bf2fe1c06bba254a9dc9f519b335aa7c1367a88a"
+            )
+            .unwrap_err(),
+            super::MagnetError::InvalidInfoHash
+        );
+        assert_eq!(super::decode_hex("+f"), None);
+        assert_eq!(super::decode_hex("0f"), Some(vec![0x0f]));
+        assert_eq!(
+            super::MagnetLink::parse("http://test.com").unwrap_err(),
+            super::MagnetError::InvalidUri
+        );
+    }
+
+    #[test]
+    fn magnet_partial_torrent() {
+        let hex = "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+        // The same hash in base32
+        let base32 = "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK\
+                      &dn=Test&tr=udp%3A%2F%2Fa.test%3A6969&tr=http%3A%2F%2Fb.test%2Fannounce";
+
+        let from_hex = super::MagnetLink::parse(hex).unwrap();
+        let from_base32 = super::MagnetLink::parse(base32).unwrap();
+        assert_eq!(from_hex.info_hash, from_base32.info_hash);
+        assert_eq!(
+            super::MagnetLink::parse(&base32.to_lowercase())
+                .unwrap()
+                .info_hash,
+            from_hex.info_hash
+        );
+
+        let torrent = from_base32.into_partial_torrent();
+        assert_eq!(torrent.info_hash, from_hex.info_hash);
+        assert_eq!(torrent.name(), "Test");
+        assert_eq!(
+            torrent.tiers(),
+            vec![
+                vec!["udp://a.test:6969".to_string()],
+                vec!["http://b.test/announce".to_string()]
+            ]
+        );
+        assert!(torrent.meta.info.pieces.is_empty());
+        assert!(torrent.web_seeds().is_empty());
+
+        let torrent = from_hex.into_partial_torrent();
+        assert_eq!(torrent.name(), "c12fe1c06bba254a9dc9f519b335aa7c1367a88a");
+        assert!(torrent.tiers().is_empty());
+
+        // Invalid base32
+        assert_eq!(
+            super::MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE1")
+                .unwrap_err(),
+            super::MagnetError::InvalidInfoHash
+        );
+    }
+
+    #[test]
+    fn multiple_files() {
+        let data = b"d8:announce15:http://test.com4:infod5:filesld6:lengthi100e4:pathl3:dir5:a.txteed6:lengthi50e4:pathl5:b.txteee4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+
+        let torrent = de::read_meta(data).unwrap();
+
+        let files = match &torrent.meta.info.files {
+            super::InfoFile::Multiple { files, .. } => files,
+            f => panic!("Not a multi-file torrent {:?}", f),
+        };
+        let entries: Vec<_> = files
+            .iter()
+            .map(|f| (f.path().to_vec(), f.length))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (vec!["dir".to_string(), "a.txt".to_string()], 100),
+                (vec!["b.txt".to_string()], 50)
+            ]
+        );
+
+        let paths: Vec<_> = torrent.files().into_iter().map(|f| f.path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                std::path::PathBuf::from("test/dir/a.txt"),
+                std::path::PathBuf::from("test/b.txt")
+            ]
+        );
+        assert_eq!(torrent.files_total_size(), 150);
+    }
+
+    #[test]
+    fn announce_list() {
+        let info =
+            "4:infod6:lengthi10e4:name1:a12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let data = format!(
+            "d8:announce8:http://x13:announce-listll8:http://a8:http://b8:http://cel8:http://dee{}e",
+            info
+        );
+
+        let torrent = de::read_meta(data.as_bytes()).unwrap();
+        assert_eq!(
+            torrent.tiers(),
+            vec![vec!["http://a", "http://b", "http://c"], vec!["http://d"]]
+        );
+
+        // The announce is ignored with an announce-list, the tiers are
+        // shuffled but keep their priority
+        for _ in 0..10 {
+            let trackers = torrent.trackers();
+            assert_eq!(trackers.len(), 4);
+            let mut first_tier = trackers[..3].to_vec();
+            first_tier.sort();
+            assert_eq!(first_tier, vec!["http://a", "http://b", "http://c"]);
+            assert_eq!(trackers[3], "http://d");
+        }
+
+        // Without announce-list
+        let data = format!("d8:announce8:http://x{}e", info);
+        let torrent = de::read_meta(data.as_bytes()).unwrap();
+        assert_eq!(torrent.tiers(), vec![vec!["http://x"]]);
+        assert_eq!(torrent.trackers(), vec!["http://x"]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn info_hash() {
+        let file = env!("CARGO_MANIFEST_DIR").to_owned()
+            + "/scripts/Fedora-Workstation-Live-x86_64-33.torrent";
+        let torrent = de::read_meta(&std::fs::read(file).unwrap()).unwrap();
+
+        let hex: String = torrent
+            .info_hash
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(hex, "7707056a138a00dd9b9eff4fab29f46233e2bad9");
+
+        // The keys of the info dictionary are not sorted: the hash is
+        // computed on the bytes as received, not on the dictionary
+        // serialized again
+        let info = b"d4:name1:a6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi16e6:lengthi10ee";
+        let mut data = b"d8:announce15:http://test.com4:info".to_vec();
+        data.extend_from_slice(info);
+        data.extend_from_slice(b"e");
+
+        let torrent = de::read_meta(&data).unwrap();
+        assert_eq!(torrent.info_hash[..], crate::sha1::sha1(info)[..]);
+    }
 }
@@ -0,0 +1,99 @@
+use std::fmt::Write;
+
+use crate::supervisors::torrent::ByteStats;
+
+/// Snapshot of the session, see `Session::metrics_text`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    pub torrents: usize,
+    /// Downloading or seeding, not waiting in the queue
+    pub active: usize,
+    pub queued: usize,
+    /// Peers connected, of all torrents
+    pub peers: usize,
+    /// Sum of the stats of all torrents
+    pub bytes: ByteStats,
+    pub pieces_verified: usize,
+    /// Messages waiting to be processed by the disk actor
+    pub disk_queue: usize,
+}
+
+impl Metrics {
+    pub(crate) fn add_bytes(&mut self, stats: ByteStats) {
+        self.bytes.payload_downloaded += stats.payload_downloaded;
+        self.bytes.payload_uploaded += stats.payload_uploaded;
+        self.bytes.total_downloaded += stats.total_downloaded;
+        self.bytes.total_uploaded += stats.total_uploaded;
+        self.bytes.wasted += stats.wasted;
+    }
+
+    /// Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let metrics: &[(&str, &str, &str, u64)] = &[
+            (
+                "rustorrent_torrents",
+                "gauge",
+                "Torrents in the session",
+                self.torrents as u64,
+            ),
+            (
+                "rustorrent_torrents_active",
+                "gauge",
+                "Torrents downloading or seeding",
+                self.active as u64,
+            ),
+            (
+                "rustorrent_torrents_queued",
+                "gauge",
+                "Torrents waiting for an active slot",
+                self.queued as u64,
+            ),
+            (
+                "rustorrent_peers",
+                "gauge",
+                "Peers connected",
+                self.peers as u64,
+            ),
+            (
+                "rustorrent_downloaded_bytes_total",
+                "counter",
+                "Payload downloaded",
+                self.bytes.payload_downloaded,
+            ),
+            (
+                "rustorrent_uploaded_bytes_total",
+                "counter",
+                "Payload uploaded",
+                self.bytes.payload_uploaded,
+            ),
+            (
+                "rustorrent_wasted_bytes_total",
+                "counter",
+                "Payload received without being requested",
+                self.bytes.wasted,
+            ),
+            (
+                "rustorrent_pieces_verified",
+                "gauge",
+                "Pieces downloaded with a valid sha1",
+                self.pieces_verified as u64,
+            ),
+            (
+                "rustorrent_disk_queue",
+                "gauge",
+                "Messages waiting for the disk",
+                self.disk_queue as u64,
+            ),
+        ];
+
+        let mut text = String::with_capacity(metrics.len() * 128);
+
+        for (name, kind, help, value) in metrics {
+            writeln!(text, "# HELP {} {}", name, help).unwrap();
+            writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+            writeln!(text, "{} {}", name, value).unwrap();
+        }
+
+        text
+    }
+}
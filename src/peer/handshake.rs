@@ -0,0 +1,171 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::extensions::Capabilities;
+
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// Length of the handshake, with the 19 bytes protocol
+pub const HANDSHAKE_LENGTH: usize = 1 + 19 + 8 + 20 + 20;
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    IO(io::Error),
+    /// Not the BitTorrent protocol
+    InvalidProtocol,
+    /// The peer asked for a torrent we're not serving
+    UnknownInfoHash {
+        info_hash: [u8; 20],
+    },
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> HandshakeError {
+        HandshakeError::IO(e)
+    }
+}
+
+/// First message of a connection, sent by both peers (BEP 3)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub reserved: [u8; 8],
+}
+
+impl Handshake {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20], capabilities: Capabilities) -> Handshake {
+        Handshake {
+            info_hash,
+            peer_id,
+            reserved: capabilities.reserved(),
+        }
+    }
+
+    /// Capabilities announced in the reserved bytes, the unknown
+    /// bits are ignored
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_bits_truncate(u64::from_be_bytes(self.reserved))
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LENGTH] {
+        let mut bytes = [0; HANDSHAKE_LENGTH];
+
+        bytes[0] = PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..].copy_from_slice(&self.peer_id);
+
+        bytes
+    }
+
+    /// `bytes` starts with the handshake, the bytes following it
+    /// are ignored
+    pub fn from_bytes(bytes: &[u8]) -> Result<Handshake, HandshakeError> {
+        if bytes.len() < HANDSHAKE_LENGTH || bytes[0] != 19 || &bytes[1..20] != PROTOCOL {
+            return Err(HandshakeError::InvalidProtocol);
+        }
+
+        let mut handshake = Handshake {
+            info_hash: [0; 20],
+            peer_id: [0; 20],
+            reserved: [0; 8],
+        };
+        handshake.reserved.copy_from_slice(&bytes[20..28]);
+        handshake.info_hash.copy_from_slice(&bytes[28..48]);
+        handshake
+            .peer_id
+            .copy_from_slice(&bytes[48..HANDSHAKE_LENGTH]);
+
+        Ok(handshake)
+    }
+
+    pub async fn write_to<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        writer.write_all(&self.to_bytes()).await
+    }
+
+    /// Read the handshake of a peer. It's rejected when `is_served`
+    /// returns false for its info hash
+    pub async fn read_from<R, F>(reader: &mut R, is_served: F) -> Result<Handshake, HandshakeError>
+    where
+        R: AsyncRead + Unpin,
+        F: Fn(&[u8; 20]) -> bool,
+    {
+        let mut bytes = [0; HANDSHAKE_LENGTH];
+
+        // Check the protocol before waiting for the rest
+        reader.read_exact(&mut bytes[..20]).await?;
+        if bytes[0] != 19 || &bytes[1..20] != PROTOCOL {
+            return Err(HandshakeError::InvalidProtocol);
+        }
+        reader.read_exact(&mut bytes[20..]).await?;
+
+        let handshake = Handshake::from_bytes(&bytes)?;
+
+        if !is_served(&handshake.info_hash) {
+            return Err(HandshakeError::UnknownInfoHash {
+                info_hash: handshake.info_hash,
+            });
+        }
+
+        Ok(handshake)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handshake, HandshakeError, HANDSHAKE_LENGTH};
+    use crate::extensions::Capabilities;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let handshake = Handshake::new([1; 20], *b"-RR0001-123456789012", Capabilities::default());
+
+        let mut bytes = Vec::new();
+        handshake.write_to(&mut bytes).await.unwrap();
+        assert_eq!(bytes.len(), HANDSHAKE_LENGTH);
+        assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+        assert_eq!(bytes[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0]);
+
+        // Followed by a message
+        bytes.extend_from_slice(&[0, 0, 0, 1, 2]);
+
+        let mut reader = &bytes[..];
+        let read = Handshake::read_from(&mut reader, |h| h == &[1; 20])
+            .await
+            .unwrap();
+        assert_eq!(read, handshake);
+        assert_eq!(read.capabilities(), Capabilities::EXTENSION);
+        assert_eq!(reader, &[0, 0, 0, 1, 2]);
+
+        assert_eq!(Handshake::from_bytes(&bytes).unwrap(), handshake);
+    }
+
+    #[tokio::test]
+    async fn reject_handshake() {
+        let bytes = Handshake::new([2; 20], [3; 20], Capabilities::empty()).to_bytes();
+
+        match Handshake::read_from(&mut &bytes[..], |h| h == &[1; 20]).await {
+            Err(HandshakeError::UnknownInfoHash { info_hash }) => assert_eq!(info_hash, [2; 20]),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let mut other = bytes;
+        other[1..20].copy_from_slice(b"BitTorrent_protoco1");
+        assert!(matches!(
+            Handshake::read_from(&mut &other[..], |_| true).await,
+            Err(HandshakeError::InvalidProtocol)
+        ));
+
+        // Truncated
+        assert!(matches!(
+            Handshake::read_from(&mut &bytes[..50], |_| true).await,
+            Err(HandshakeError::IO(_))
+        ));
+    }
+}
@@ -0,0 +1,90 @@
+use crate::pieces::Pieces;
+
+/// Maximum payload of an extended message (handshake, pex, metadata).
+/// A metadata piece is 16 KiB plus its bencoded header
+pub const EXTENDED_MESSAGE_LENGTH: usize = 64 * 1024;
+
+/// Size of a metadata piece, BEP 9
+pub const METADATA_PIECE_LENGTH: usize = 16 * 1024;
+
+/// Maximum size of the info dictionary, when it isn't known yet
+pub const METADATA_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Maximum data of a PIECE message, when the torrent isn't known.
+/// Clients request 16 KiB, some accept up to 128 KiB
+pub const BLOCK_LENGTH: usize = 128 * 1024;
+
+/// Bounds of the lengths announced by a peer, derived from the torrent.
+///
+/// They are checked before reading or allocating anything, a peer
+/// exceeding them is disconnected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Length of a message, without its length prefix
+    pub message: usize,
+    /// Exact length of the BITFIELD payload
+    pub bitfield: usize,
+    /// Payload of the extended messages
+    pub extended: usize,
+    /// Data of a metadata piece
+    pub metadata_piece: usize,
+    /// `metadata_size` of the extended handshake
+    pub metadata: usize,
+}
+
+impl Limits {
+    pub fn new(pieces: &Pieces) -> Limits {
+        let bitfield = pieces.num_pieces.div_ceil(8);
+        // The info dictionary holds 20 bytes per piece, the rest is
+        // the list of files
+        let metadata = (pieces.num_pieces * 20 + 1024 * 1024).min(METADATA_LENGTH);
+
+        let message = (9 + pieces.block_size as usize)
+            .max(1 + bitfield)
+            .max(2 + EXTENDED_MESSAGE_LENGTH);
+
+        Limits {
+            message,
+            bitfield,
+            extended: EXTENDED_MESSAGE_LENGTH,
+            metadata_piece: METADATA_PIECE_LENGTH,
+            metadata,
+        }
+    }
+
+    /// Whether the `metadata_size` announced by a peer can be allocated
+    pub fn accept_metadata_size(&self, size: i64) -> bool {
+        size > 0 && size as u64 <= self.metadata as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limits, EXTENDED_MESSAGE_LENGTH};
+    use crate::{metadata::TestTorrent, pieces::Pieces};
+
+    fn pieces(num_pieces: usize) -> Pieces {
+        let torrent = TestTorrent::new()
+            .piece_length(1 << 20)
+            .pieces(num_pieces)
+            .build();
+
+        Pieces::from(&torrent)
+    }
+
+    #[test]
+    fn derived_from_torrent() {
+        let limits = Limits::new(&pieces(10));
+
+        assert_eq!(limits.bitfield, 2);
+        assert_eq!(limits.message, 2 + EXTENDED_MESSAGE_LENGTH);
+        assert!(limits.accept_metadata_size(10 * 20 + 100));
+        assert!(!limits.accept_metadata_size(-1));
+        assert!(!limits.accept_metadata_size(i64::MAX));
+
+        // A large bitfield raises the message limit
+        let limits = Limits::new(&pieces(1_000_000));
+        assert_eq!(limits.bitfield, 125_000);
+        assert_eq!(limits.message, 125_001);
+    }
+}
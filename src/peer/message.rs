@@ -4,8 +4,8 @@ use byteorder::{BigEndian, ReadBytesExt};
 
 use crate::{
     errors::TorrentError,
-    extensions::{ExtendedHandshake, ExtendedMessage},
-    peer::peer::PeerExternId,
+    extensions::{Capabilities, ExtendedHandshake, ExtendedMessage},
+    peer::{limits::BLOCK_LENGTH, peer::PeerExternId},
     piece_picker::{BlockIndex, PieceIndex},
     supervisors::torrent::Result,
 };
@@ -41,6 +41,7 @@ pub enum MessagePeer<'a> {
     Handshake {
         info_hash: &'a [u8],
         extern_id: &'a PeerExternId,
+        capabilities: Capabilities,
     },
     Unknown {
         id: u8,
@@ -64,8 +65,12 @@ impl<'a> TryFrom<&'a [u8]> for MessagePeer<'a> {
             return Ok(MessagePeer::KeepAlive);
         }
         let id = buffer[0];
-        let buffer = &buffer[1..];
 
+        if !check_length(id, buffer.len()) {
+            return Err(TorrentError::InvalidInput);
+        }
+
+        let buffer = &buffer[1..];
         let mut cursor = Cursor::new(buffer);
 
         Ok(match id {
@@ -124,7 +129,7 @@ impl<'a> TryFrom<&'a [u8]> for MessagePeer<'a> {
                         handshake: Box::new(handshake),
                     })
                 }
-                _ => MessagePeer::Extension(ExtendedMessage::Message {
+                id => MessagePeer::Extension(ExtendedMessage::Message {
                     id,
                     buffer: &buffer[1..],
                 }),
@@ -133,3 +138,58 @@ impl<'a> TryFrom<&'a [u8]> for MessagePeer<'a> {
         })
     }
 }
+
+/// Check the length of a message of BEP 3 from its id, `length`
+/// includes the id. A PIECE is bounded by the largest block of any
+/// torrent. The BITFIELD and the extensions are bounded by the torrent,
+/// with `Limits::message` when they are read
+fn check_length(id: u8, length: usize) -> bool {
+    match id {
+        0..=3 => length == 1,
+        4 => length == 5,
+        6 | 8 => length == 13,
+        7 => length >= 9 && length - 9 <= BLOCK_LENGTH,
+        9 => length == 3,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::MessagePeer;
+    use crate::{errors::TorrentError, peer::limits::BLOCK_LENGTH};
+
+    #[test]
+    fn message_lengths() {
+        let have = MessagePeer::try_from(&[4, 0, 0, 1, 2][..]).unwrap();
+        assert!(matches!(have, MessagePeer::Have { piece_index } if piece_index == 258.into()));
+
+        let piece = MessagePeer::try_from(&[7, 0, 0, 0, 1, 0, 0, 0, 2][..]).unwrap();
+        assert!(matches!(piece, MessagePeer::Piece { data, .. } if data.is_empty()));
+
+        // Larger than the blocks of any torrent
+        let mut huge_piece = vec![0; 9 + BLOCK_LENGTH + 1];
+        huge_piece[0] = 7;
+
+        for invalid in &[
+            &[1, 0][..],
+            &[4, 0, 0, 1][..],
+            &[4, 0, 0, 1, 2, 3][..],
+            &[6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0][..],
+            &[7, 0, 0, 0, 1, 0, 0, 0][..],
+            &[9, 0x1A][..],
+            &huge_piece[..],
+        ] {
+            assert!(
+                matches!(
+                    MessagePeer::try_from(*invalid),
+                    Err(TorrentError::InvalidInput)
+                ),
+                "{:?}",
+                &invalid[..invalid.len().min(16)]
+            );
+        }
+    }
+}
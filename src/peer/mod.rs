@@ -1,3 +1,5 @@
+pub mod handshake;
+pub mod limits;
 pub(crate) mod message;
 #[allow(clippy::clippy::module_inception)]
 pub(crate) mod peer;
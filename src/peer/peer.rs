@@ -5,7 +5,8 @@ use kv_log_macro::{debug, error, info, warn};
 use tokio::net::TcpStream;
 
 use std::{
-    convert::{TryFrom, TryInto},
+    collections::BTreeMap,
+    convert::TryInto,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::{
         atomic::{AtomicUsize, Ordering},
@@ -14,15 +15,22 @@ use std::{
 };
 
 use crate::{
-    extensions::{ExtendedHandshake, ExtendedMessage, PEXMessage},
+    errors::TorrentError,
+    extensions::{
+        pex_dial_order, Capabilities, EncryptionPolicy, ExtendedHandshake, ExtendedMessage,
+        Extension, ExtensionRegistry, PEXMessage,
+    },
     fs::FSMessage,
-    peer::{message::MessagePeer, stream::StreamBuffers},
+    peer::{
+        handshake::HandshakeError, limits::Limits, message::MessagePeer, stream::StreamBuffers,
+    },
     piece_collector::Block,
     piece_picker::{BlockIndex, PieceIndex},
     pieces::{BlockToDownload, IterTaskDownload, Pieces, TaskDownload},
+    rate_limit::BandwidthLimits,
     spsc::{Consumer, Producer},
     supervisors::torrent::{
-        NewPeer, Result, Shared, TorrentId,
+        ByteCounters, NewPeer, PeerOrigin, Result, Shared, TorrentId,
         TorrentNotification::{self, *},
     },
     utils::{send_to, SaturatingDuration},
@@ -30,7 +38,7 @@ use crate::{
 
 static PEER_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
-#[derive(Eq, PartialEq, Copy, Clone, Debug, Hash)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Debug, Hash)]
 pub struct PeerId(usize);
 
 impl std::fmt::Display for PeerId {
@@ -57,6 +65,11 @@ pub enum PeerCommand {
     TasksAvailables,
     Die,
     TasksIncreased,
+    /// Send INTERESTED again if the peer chokes us
+    Interested,
+    /// Stop uploading to the peer, see `ChokeManager`
+    Choke,
+    UnChoke,
     BlockData {
         piece: PieceIndex,
         block: BlockIndex,
@@ -68,7 +81,7 @@ use hashbrown::{HashMap, HashSet};
 
 #[derive(Debug)]
 struct PeerDetail {
-    extension_ids: HashMap<String, i64>,
+    extension_ids: BTreeMap<String, i64>,
     // Number of requests the peer supports without dropping
     max_requests: usize,
     client_name: Option<String>,
@@ -81,7 +94,7 @@ impl Default for PeerDetail {
     fn default() -> Self {
         Self {
             max_requests: Peer::MAX_REQUEST_IN_FLIGHT_DEFAULT,
-            extension_ids: HashMap::default(),
+            extension_ids: BTreeMap::default(),
             client_name: None,
             my_ip: None,
             ipv4: None,
@@ -154,6 +167,67 @@ impl PartialEq for PeerExternId {
 
 impl Eq for PeerExternId {}
 
+/// Timeout of our block requests, scaled by the round trip time
+/// of the peer (time between a REQUEST and its PIECE), so slow
+/// peers are not timed out while they're still working
+#[derive(Debug)]
+struct RequestTimeout {
+    /// Smoothed round trip time, `None` until the first block is received
+    srtt: Option<coarsetime::Duration>,
+    multiplier: u32,
+    floor: coarsetime::Duration,
+    ceiling: coarsetime::Duration,
+}
+
+impl Default for RequestTimeout {
+    fn default() -> Self {
+        Self::new(
+            4,
+            coarsetime::Duration::from_secs(2),
+            coarsetime::Duration::from_secs(60),
+        )
+    }
+}
+
+impl RequestTimeout {
+    fn new(
+        multiplier: u32,
+        floor: coarsetime::Duration,
+        ceiling: coarsetime::Duration,
+    ) -> RequestTimeout {
+        RequestTimeout {
+            srtt: None,
+            multiplier,
+            floor,
+            ceiling,
+        }
+    }
+
+    fn add_sample(&mut self, rtt: coarsetime::Duration) {
+        // Same smoothing than TCP (RFC 6298)
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+
+    fn timeout(&self) -> coarsetime::Duration {
+        match self.srtt {
+            Some(srtt) => (srtt * self.multiplier).max(self.floor).min(self.ceiling),
+            None => self.ceiling,
+        }
+    }
+
+    fn is_expired(&self, elapsed: coarsetime::Duration) -> bool {
+        elapsed > self.timeout()
+    }
+
+    fn set_ceiling(&mut self, ceiling: coarsetime::Duration) {
+        self.ceiling = ceiling;
+        self.floor = self.floor.min(ceiling);
+    }
+}
+
 pub struct Peer {
     id: PeerId,
     torrent_id: TorrentId,
@@ -172,23 +246,85 @@ pub struct Peer {
     fs: Sender<FSMessage>,
 
     pieces_infos: Arc<Pieces>,
+    /// Lengths accepted from the peer
+    limits: Limits,
 
     peer_detail: PeerDetail,
 
     extern_id: Arc<PeerExternId>,
 
     shared: Arc<Shared>,
+    counters: Arc<ByteCounters>,
 
     requested_by_peer: HashSet<BlockToDownload>,
-    requested_by_us: HashSet<BlockToDownload>,
+    /// Our requests with the time they were sent
+    requested_by_us: HashMap<BlockToDownload, coarsetime::Instant>,
+    request_timeout: RequestTimeout,
+    /// The peer is dropped when nothing is received for this long
+    idle_timeout: coarsetime::Duration,
+    /// A keep-alive is sent when nothing was sent for this long
+    keep_alive_interval: coarsetime::Duration,
+    /// Maximum number of our requests outstanding, see
+    /// `TorrentOptions::pipeline_depth`
+    pipeline_depth: usize,
+    bandwidth: BandwidthLimits,
+    /// Payload of the last message read, taken from the download limit
+    /// before reading the next one
+    received_payload: usize,
 
     last_task_timestamp: Option<coarsetime::Instant>,
+
+    /// Do we choke the peer
+    am_choking: bool,
+    /// Never upload to this peer, see `TorrentOptions::no_upload`
+    no_upload: bool,
+    /// Order of the peers received with PEX
+    encryption: EncryptionPolicy,
+    /// Id of an inbound peer, its handshake was read when it connected
+    inbound_id: Option<Arc<PeerExternId>>,
+    /// Announced in our handshake
+    capabilities: Capabilities,
+    extensions: ExtensionRegistry,
+}
+
+/// `ut_pex` (BEP 11), the peers received are given to the supervisor
+struct Pex {
+    id: PeerId,
+    supervisor: Sender<TorrentNotification>,
+    encryption: EncryptionPolicy,
+}
+
+impl Extension for Pex {
+    fn name(&self) -> &str {
+        "ut_pex"
+    }
+
+    fn on_message(&mut self, payload: &[u8]) {
+        let pex = match crate::bencode::de::from_bytes::<PEXMessage>(payload) {
+            Ok(pex) => pex,
+            Err(_) => return,
+        };
+
+        let addrs = pex_dial_order(pex.added_peers(), self.encryption);
+        info!("[{}] new peers from pex {:?}", self.id, addrs);
+
+        send_to(
+            &self.supervisor,
+            PeerDiscovered {
+                addrs: addrs.into_boxed_slice(),
+                origin: PeerOrigin::Pex,
+            },
+        );
+    }
 }
 
 impl Peer {
-    const MIN_REQUEST_IN_FLIGHT: usize = 10;
+    const DEFAULT_PIPELINE_DEPTH: usize = 16;
     const MAX_REQUEST_IN_FLIGHT_DEFAULT: usize = 250;
+    const IDLE_TIMEOUT_SECS: u64 = 120;
+    const KEEP_ALIVE_INTERVAL_SECS: u64 = 90;
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         torrent_id: TorrentId,
         socket: SocketAddr,
@@ -197,16 +333,11 @@ impl Peer {
         extern_id: Arc<PeerExternId>,
         consumer: Consumer<TaskDownload>,
         fs: Sender<FSMessage>,
+        counters: Arc<ByteCounters>,
     ) -> Result<Peer> {
         // TODO [2001:df0:a280:1001::3:1]:59632
         //      [2001:df0:a280:1001::3:1]:59632
 
-        let id = PEER_COUNTER.fetch_add(1, Ordering::SeqCst);
-
-        // if id > 0 {
-        //     return Err(crate::errors::TorrentError::InvalidInput);
-        // }
-
         // if socket == "[2001:df0:a280:1001::3:1]:59632".parse::<SocketAddr>().unwrap() {
         //     return Err(crate::errors::TorrentError::InvalidInput);
         // }
@@ -214,7 +345,65 @@ impl Peer {
         // let socket = "[2001:df0:a280:1001::3:1]:59632".parse::<SocketAddr>().unwrap();
 
         let stream = TcpStream::connect(&socket).await?;
+
+        Ok(Peer::with_stream(
+            torrent_id,
+            socket,
+            stream,
+            pieces_infos,
+            supervisor,
+            extern_id,
+            consumer,
+            fs,
+            counters,
+        ))
+    }
+
+    /// A peer which connected to us. Its handshake, with `remote_id`,
+    /// was already read to find its torrent
+    #[allow(clippy::too_many_arguments)]
+    pub fn accepted(
+        torrent_id: TorrentId,
+        socket: SocketAddr,
+        stream: TcpStream,
+        remote_id: PeerExternId,
+        pieces_infos: Arc<Pieces>,
+        supervisor: Sender<TorrentNotification>,
+        extern_id: Arc<PeerExternId>,
+        consumer: Consumer<TaskDownload>,
+        fs: Sender<FSMessage>,
+        counters: Arc<ByteCounters>,
+    ) -> Peer {
+        let mut peer = Peer::with_stream(
+            torrent_id,
+            socket,
+            stream,
+            pieces_infos,
+            supervisor,
+            extern_id,
+            consumer,
+            fs,
+            counters,
+        );
+        peer.inbound_id = Some(Arc::new(remote_id));
+        peer
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn with_stream(
+        torrent_id: TorrentId,
+        socket: SocketAddr,
+        stream: TcpStream,
+        pieces_infos: Arc<Pieces>,
+        supervisor: Sender<TorrentNotification>,
+        extern_id: Arc<PeerExternId>,
+        consumer: Consumer<TaskDownload>,
+        fs: Sender<FSMessage>,
+        counters: Arc<ByteCounters>,
+    ) -> Peer {
+        let id = PEER_COUNTER.fetch_add(1, Ordering::SeqCst);
         let piece_length = pieces_infos.piece_length;
+        let limits = Limits::new(&pieces_infos);
 
         let shared = Arc::new(Shared::new(socket));
 
@@ -222,37 +411,106 @@ impl Peer {
 
         let (cmd_sender, cmd_recv) = bounded(1000);
 
-        Ok(Peer {
+        Peer {
             id: PeerId(id),
             cmd_sender,
             cmd_recv,
             torrent_id,
             supervisor,
-            stream: StreamBuffers::new(stream, piece_length, 32 * 1024),
+            stream: StreamBuffers::new(stream, limits.message, 32 * 1024, counters.clone()),
             choked: Choke::Choked,
             tasks: consumer,
             local_tasks: None,
             fs,
             pieces_infos,
+            limits,
             peer_detail: Default::default(),
             extern_id,
             shared,
+            counters,
             requested_by_peer: HashSet::default(),
-            requested_by_us: HashSet::default(),
+            requested_by_us: HashMap::default(),
+            request_timeout: RequestTimeout::default(),
+            idle_timeout: coarsetime::Duration::from_secs(Self::IDLE_TIMEOUT_SECS),
+            keep_alive_interval: coarsetime::Duration::from_secs(Self::KEEP_ALIVE_INTERVAL_SECS),
+            pipeline_depth: Self::DEFAULT_PIPELINE_DEPTH,
+            bandwidth: BandwidthLimits::default(),
+            received_payload: 0,
             last_task_timestamp: None,
-        })
+            am_choking: true,
+            no_upload: false,
+            capabilities: Capabilities::default(),
+            encryption: EncryptionPolicy::default(),
+            inbound_id: None,
+            extensions: ExtensionRegistry::default(),
+        }
     }
 
     pub(crate) fn internal_id(&self) -> PeerId {
         self.id
     }
 
-    pub async fn start(&mut self, producer: Producer<TaskDownload>) -> Result<()> {
+    /// Keep the peer choked and ignore its requests
+    pub(crate) fn set_no_upload(&mut self, no_upload: bool) {
+        self.no_upload = no_upload;
+    }
+
+    /// See `TorrentOptions::encryption`
+    pub(crate) fn set_encryption(&mut self, encryption: EncryptionPolicy) {
+        self.encryption = encryption;
+    }
+
+    /// See `TorrentOptions::disable_extensions`
+    pub(crate) fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    /// See `TorrentOptions::pipeline_depth`, 0 for the default
+    pub(crate) fn set_pipeline_depth(&mut self, depth: usize) {
+        self.pipeline_depth = match depth {
+            0 => Self::DEFAULT_PIPELINE_DEPTH,
+            depth => depth,
+        };
+    }
+
+    /// See `TorrentOptions::request_timeout`, 0 for the default
+    pub(crate) fn set_request_timeout(&mut self, timeout: std::time::Duration) {
+        if !timeout.is_zero() {
+            self.request_timeout.set_ceiling(timeout.into());
+        }
+    }
+
+    /// See `SessionConfig::max_download_bps` and `max_upload_bps`
+    pub(crate) fn set_bandwidth_limits(&mut self, bandwidth: BandwidthLimits) {
+        self.bandwidth = bandwidth;
+    }
+
+    /// `bitfield` is our pieces, sent right after the handshake.
+    /// It must be `None` when we don't have any piece
+    pub async fn start(
+        &mut self,
+        producer: Producer<TaskDownload>,
+        bitfield: Option<Box<[u8]>>,
+    ) -> Result<()> {
         // let (addr, cmds) = bounded(1000);
         // let mut cmds = Box::pin(cmds);
 
+        if self.capabilities.contains(Capabilities::EXTENSION) {
+            self.extensions.register(Box::new(Pex {
+                id: self.id,
+                supervisor: self.supervisor.clone(),
+                encryption: self.encryption,
+            }));
+        }
+
         let extern_id = self.do_handshake().await?;
 
+        // The BITFIELD must be the first message after the handshake
+        if let Some(bitfield) = bitfield {
+            self.stream
+                .write_message(MessagePeer::BitField(&bitfield))?;
+        }
+
         send_to(
             &self.supervisor,
             AddPeer {
@@ -262,19 +520,38 @@ impl Peer {
                     addr: self.cmd_sender.clone(),
                     extern_id,
                     shared: Arc::clone(&self.shared),
+                    outbound: self.inbound_id.is_none(),
                 }),
             },
         );
 
         let mut recv = self.cmd_recv.clone().fuse();
+        let mut timeout_check = tokio::time::interval(std::time::Duration::from_secs(1));
+        // Not reading the socket until the download tokens are available
+        // slows down the peer. The commands are still handled meanwhile
+        let throttle = tokio::time::sleep(std::time::Duration::from_secs(0));
+        tokio::pin!(throttle);
+        let mut throttled = false;
 
         loop {
             tokio::select! {
-                msg = self.stream.read_message() => {
+                msg = self.stream.read_message(), if !throttled => {
                     msg?;
 
                     self.dispatch()?;
                     self.stream.consume_read();
+
+                    if let Some(limit) = self.bandwidth.download.as_ref() {
+                        let nbytes = std::mem::take(&mut self.received_payload);
+                        if nbytes > 0 {
+                            let wait = limit.take(nbytes);
+                            throttle.as_mut().reset(tokio::time::Instant::now() + wait);
+                            throttled = true;
+                        }
+                    }
+                }
+                _ = &mut throttle, if throttled => {
+                    throttled = false;
                 }
                 cmd = recv.select_next_some() => {
                     use PeerCommand::*;
@@ -284,14 +561,36 @@ impl Peer {
                             self.handle_new_tasks()?;
                         }
                         TasksIncreased => {}
+                        Interested => {
+                            if self.am_choked() {
+                                self.stream.write_message(MessagePeer::Interested)?;
+                            }
+                        }
+                        Choke => {
+                            // The requests pending are discarded
+                            self.am_choking = true;
+                            self.requested_by_peer.clear();
+                            self.stream.write_message(MessagePeer::Choke)?;
+                        }
+                        UnChoke => {
+                            self.am_choking = false;
+                            self.stream.write_message(MessagePeer::UnChoke)?;
+                        }
                         Die => {
                             return Ok(());
                         }
                         BlockData { piece, block, data } => {
+                            if let Some(limit) = self.bandwidth.upload.as_ref() {
+                                limit.acquire(data.len()).await;
+                            }
                             self.send_block(piece, block, data)?;
                         }
                     }
                 }
+                _ = timeout_check.tick() => {
+                    self.check_idle()?;
+                    self.check_requests_timeout()?;
+                }
             }
         }
     }
@@ -314,7 +613,10 @@ impl Peer {
     }
 
     fn pop_task(&mut self) -> Option<BlockToDownload> {
-        if self.requested_by_us.len() >= self.peer_detail.max_requests {
+        // The peer may drop the requests over its `reqq`
+        let depth = self.pipeline_depth.min(self.peer_detail.max_requests);
+
+        if self.requested_by_us.len() >= depth {
             return None;
         }
 
@@ -346,16 +648,13 @@ impl Peer {
 
         while let Some(task) = self.pop_task() {
             self.stream.write_message(task.clone())?;
-            self.requested_by_us.insert(task);
+            self.requested_by_us
+                .insert(task, coarsetime::Instant::now());
 
             if self.is_empty_task() {
                 send_to(&self.supervisor, IncreaseTasksPeer { id: self.id });
                 break;
             }
-
-            if self.requested_by_us.len() >= Self::MIN_REQUEST_IN_FLIGHT {
-                break;
-            }
         }
 
         // TODO [2001:df0:a280:1001::3:1]:59632
@@ -363,6 +662,86 @@ impl Peer {
         Ok(())
     }
 
+    /// Drop a silent peer, and keep the connection alive on our side
+    fn check_idle(&mut self) -> Result<()> {
+        let read_idle = self.stream.read_idle();
+
+        if read_idle >= self.idle_timeout {
+            warn!(
+                "[{}] Nothing received for {}s, dropping the peer",
+                self.id,
+                read_idle.as_secs()
+            );
+            return Err(TorrentError::Unresponsive);
+        }
+
+        if self.stream.write_idle() >= self.keep_alive_interval {
+            self.stream.write_message(MessagePeer::KeepAlive)?;
+        }
+
+        Ok(())
+    }
+
+    /// Cancel the blocks we didn't receive in time, the torrent gives
+    /// their pieces to other peers
+    fn check_requests_timeout(&mut self) -> Result<()> {
+        let now = coarsetime::Instant::now();
+        let request_timeout = &self.request_timeout;
+
+        let expired: Vec<_> = self
+            .requested_by_us
+            .iter()
+            .filter(|(_, requested_at)| {
+                request_timeout.is_expired(now.saturating_duration_since(**requested_at))
+            })
+            .map(|(block, _)| block.clone())
+            .collect();
+
+        let mut timed_out = Vec::new();
+
+        for block in expired {
+            let requested_at = self.requested_by_us.remove(&block).unwrap();
+
+            warn!(
+                "[{}] Request timeout {:?} after {}ms (timeout={}ms)",
+                self.id,
+                block,
+                now.saturating_duration_since(requested_at).as_millis(),
+                self.request_timeout.timeout().as_millis()
+            );
+
+            self.stream.write_message(MessagePeer::Cancel {
+                piece: block.piece,
+                block: block.start,
+                length: block.length,
+            })?;
+            self.shared
+                .nbytes_on_tasks
+                .fetch_sub(block.length as usize, Ordering::Release);
+
+            if !timed_out.contains(&block.piece) {
+                timed_out.push(block.piece);
+            }
+        }
+
+        if timed_out.is_empty() {
+            return Ok(());
+        }
+
+        for piece_index in timed_out {
+            send_to(
+                &self.supervisor,
+                PieceTimeout {
+                    id: self.id,
+                    piece_index,
+                },
+            );
+        }
+
+        // Our pipeline has room for the other tasks
+        self.maybe_request_block("request_timeout")
+    }
+
     fn send_block(&mut self, piece: PieceIndex, block: BlockIndex, data: Box<[u8]>) -> Result<()> {
         let requested = BlockToDownload {
             piece,
@@ -380,6 +759,9 @@ impl Peer {
             block,
             data: &data,
         })?;
+        self.shared
+            .uploaded
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
 
         Ok(())
     }
@@ -396,23 +778,26 @@ impl Peer {
         match msg {
             Choke => {
                 self.choked = self::Choke::Choked;
+                self.shared.am_choked.store(true, Ordering::Relaxed);
                 info!("[{}] Choke", self.id);
             }
             UnChoke => {
                 // If the peer has piece we're interested in
                 // Send a Request
                 self.choked = self::Choke::UnChoked;
+                self.shared.am_choked.store(false, Ordering::Relaxed);
 
                 info!("[{}] Unchoke", self.id);
 
                 self.maybe_request_block("unchoke")?;
             }
             Interested => {
-                // Unshoke this peer
+                // Unchoked or not by the supervisor, on its next tick
+                self.shared.peer_interested.store(true, Ordering::Relaxed);
                 info!("[{}] Interested", self.id);
             }
             NotInterested => {
-                // Shoke this peer
+                self.shared.peer_interested.store(false, Ordering::Relaxed);
                 info!("[{}] Not interested", self.id);
             }
             Have { piece_index } => {
@@ -432,10 +817,12 @@ impl Peer {
 
                 let num_pieces = self.pieces_infos.num_pieces;
 
-                let bitfield = match BitField::try_from((bitfield, num_pieces)) {
-                    Ok(bitfield) => bitfield,
-                    _ => return Ok(()),
-                };
+                if bitfield.len() != self.limits.bitfield {
+                    warn!("[{}] Invalid bitfield length {}", self.id, bitfield.len());
+                    return Err(TorrentError::InvalidInput);
+                }
+
+                let bitfield = BitField::from_bytes(bitfield, num_pieces)?;
 
                 send_to(
                     &self.supervisor,
@@ -463,6 +850,17 @@ impl Peer {
 
                 info!("[{}] Requested by Peer {:?}", self.id, requested);
 
+                if self.no_upload {
+                    // The peer didn't get our CHOKE yet, or ignores it
+                    self.stream.write_message(MessagePeer::Choke)?;
+                    return Ok(());
+                }
+
+                if self.am_choking {
+                    // Sent before our CHOKE reached the peer
+                    return Ok(());
+                }
+
                 if self.requested_by_peer.contains(&requested) {
                     return Ok(());
                 }
@@ -490,9 +888,24 @@ impl Peer {
                     start: block,
                     length: data.len().try_into().unwrap(),
                 };
-
-                if !self.requested_by_us.remove(&recv) {
-                    warn!("[{}] Received but not requested {:?}", self.id, recv);
+                self.received_payload = data.len();
+
+                // Only the blocks we requested from this peer are kept.
+                // In endgame (`PiecePicker::is_endgame`) the other peers
+                // on the piece request them too, the supervisor keeps the
+                // last one received. Anything else (never requested, or
+                // received after a timeout and its answer) is dropped
+                match self.requested_by_us.remove(&recv) {
+                    Some(requested_at) => {
+                        let now = coarsetime::Instant::now();
+                        self.request_timeout
+                            .add_sample(now.saturating_duration_since(requested_at));
+                    }
+                    None => {
+                        warn!("[{}] Received but not requested {:?}", self.id, recv);
+                        self.counters.add_wasted(data.len());
+                        return Ok(());
+                    }
                 }
 
                 self.shared
@@ -532,22 +945,34 @@ impl Peer {
                 info!("[{}] Keep alive", self.id);
             }
             Extension(ExtendedMessage::Handshake { handshake }) => {
+                if let Some(size) = handshake.metadata_size {
+                    if !self.limits.accept_metadata_size(size) {
+                        warn!("[{}] Invalid metadata size {}", self.id, size);
+                        return Err(TorrentError::InvalidInput);
+                    }
+                }
                 self.read_extended_handshake(&handshake);
-                self.send_extended_handshake()?;
+                self.extensions.read_handshake(&handshake);
+                if self.capabilities.contains(Capabilities::EXTENSION) {
+                    self.send_extended_handshake()?;
+                }
+            }
+            Extension(ExtendedMessage::Message { buffer, .. })
+                if buffer.len() > self.limits.extended =>
+            {
+                warn!(
+                    "[{}] Extended message too long ({} bytes)",
+                    self.id,
+                    buffer.len()
+                );
+                return Err(TorrentError::InvalidInput);
             }
             Extension(ExtendedMessage::Message { id, buffer }) => {
-                if id == 1 {
-                    if let Ok(addrs) = crate::bencode::de::from_bytes::<PEXMessage>(buffer) {
-                        let addrs: Vec<SocketAddr> = addrs.into();
-                        info!("[{}] new peers from pex {:?}", self.id, addrs);
-
-                        send_to(
-                            &self.supervisor,
-                            PeerDiscovered {
-                                addrs: addrs.into_boxed_slice(),
-                            },
-                        );
-                    };
+                if !self
+                    .extensions
+                    .dispatch(ExtendedMessage::Message { id, buffer })
+                {
+                    debug!("[{}] Unsupported extended message {}", self.id, id);
                 }
             }
             Handshake { .. } => {
@@ -618,38 +1043,11 @@ impl Peer {
         error!("[{}] {:#?}", self.id, self.peer_detail);
     }
 
-    fn read_extended_message(&self, id: u8, buffer: &[u8]) {
-        match id {
-            1 => {
-                let addrs = match crate::bencode::de::from_bytes::<PEXMessage>(buffer) {
-                    Ok(addrs) => addrs,
-                    Err(_) => return,
-                };
-
-                let addrs: Vec<SocketAddr> = addrs.into();
-                info!("[{}] new peers from pex {:?}", self.id, addrs);
-
-                send_to(
-                    &self.supervisor,
-                    PeerDiscovered {
-                        addrs: addrs.into_boxed_slice(),
-                    },
-                );
-            }
-            id => {
-                debug!("[{}] Unsupported extended message {}", self.id, id);
-            }
-        }
-    }
-
     fn send_extended_handshake(&mut self) -> Result<()> {
-        let mut extensions = HashMap::new();
-        extensions.insert("ut_pex".to_string(), 1);
         let handshake = ExtendedHandshake {
-            m: Some(extensions),
             v: Some(String::from("Rustorrent 0.1")),
             p: Some(6801),
-            ..Default::default()
+            ..self.extensions.handshake()
         };
         self.stream.write_message(handshake)?;
 
@@ -660,22 +1058,580 @@ impl Peer {
         self.stream.write_message(MessagePeer::Handshake {
             info_hash: &self.pieces_infos.info_hash,
             extern_id: &self.extern_id,
+            capabilities: self.capabilities,
         })?;
 
-        let peer_id = self.stream.read_handshake().await?;
+        let peer_id = match self.inbound_id.clone() {
+            Some(peer_id) => peer_id,
+            None => {
+                let handshake = self.stream.read_handshake().await?;
+
+                if handshake.info_hash[..] != self.pieces_infos.info_hash[..] {
+                    return Err(HandshakeError::UnknownInfoHash {
+                        info_hash: handshake.info_hash,
+                    }
+                    .into());
+                }
+
+                Arc::new(PeerExternId(handshake.peer_id))
+            }
+        };
 
-        // TODO: Check the info hash and send to other TorrentSupervisor if necessary
         info!("[{}] Handshake done", self.id);
 
-        Ok(Arc::new(peer_id))
+        Ok(peer_id)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::MessagePeer;
+    use coarsetime::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        task::JoinHandle,
+    };
+
+    use std::sync::Arc;
+
+    use super::{MessagePeer, Peer, PeerCommand, PeerExternId, RequestTimeout};
+    use crate::{
+        errors::TorrentError,
+        fs::FSMessage,
+        metadata::{TestTorrent, Torrent},
+        peer::limits::EXTENDED_MESSAGE_LENGTH,
+        pieces::{Pieces, TaskDownload},
+        rate_limit::BandwidthLimits,
+        spsc::{self, Producer},
+        supervisors::torrent::{ByteCounters, Result, TorrentId, TorrentNotification},
+    };
+
+    fn torrent() -> Torrent {
+        torrent_with(16384, 16384)
+    }
+
+    fn torrent_with(piece_length: u64, length: u64) -> Torrent {
+        TestTorrent::new()
+            .piece_length(piece_length)
+            .length(length)
+            .info_hash(1)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn no_upload() {
+        let Connected {
+            mut remote,
+            notifications,
+            fs,
+            ..
+        } = connected_with("127.0.0.1:0", torrent(), |peer, _| peer.set_no_upload(true)).await;
+
+        let peer_addr = match notifications.recv().await {
+            Ok(TorrentNotification::AddPeer { peer }) => peer.addr,
+            _ => panic!("Missing AddPeer"),
+        };
+
+        // REQUEST piece 0, block 0, 16384 bytes
+        remote
+            .write_all(&[0, 0, 0, 13, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 0])
+            .await
+            .unwrap();
+
+        // The peer answers with a CHOKE, the block isn't read
+        let mut choke = [0; 5];
+        remote.read_exact(&mut choke).await.unwrap();
+        assert_eq!(choke, [0, 0, 0, 1, 0]);
+        assert!(fs.try_recv().is_err());
+
+        // Even when the data is available, no PIECE is sent
+        peer_addr
+            .send(PeerCommand::BlockData {
+                piece: 0.into(),
+                block: 0.into(),
+                data: vec![0; 16384].into_boxed_slice(),
+            })
+            .await
+            .unwrap();
+
+        let mut buffer = [0; 16];
+        let read = tokio::time::timeout(
+            std::time::Duration::from_millis(200),
+            remote.read(&mut buffer),
+        )
+        .await;
+        assert!(read.is_err(), "Received {:?}", read);
+    }
+
+    /// Peer connected to a fake remote, after the handshake, with the
+    /// channels of its torrent
+    struct Connected {
+        remote: TcpStream,
+        handle: JoinHandle<Result<()>>,
+        notifications: async_channel::Receiver<TorrentNotification>,
+        fs: async_channel::Receiver<FSMessage>,
+        counters: Arc<ByteCounters>,
+    }
+
+    /// Peer of `torrent()` connected to a fake remote
+    async fn connected() -> (TcpStream, JoinHandle<Result<()>>) {
+        let connected = connected_with("127.0.0.1:0", torrent(), |_, _| {}).await;
+        (connected.remote, connected.handle)
+    }
+
+    /// The fake remote listens on `bind`, `configure` is called on the
+    /// peer and its queue of tasks before it starts
+    async fn connected_with<F>(bind: &str, torrent: Torrent, configure: F) -> Connected
+    where
+        F: FnOnce(&mut Peer, &mut Producer<TaskDownload>),
+    {
+        let listener = TcpListener::bind(bind).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (supervisor, notifications) = async_channel::unbounded();
+        let (fs_addr, fs) = async_channel::unbounded();
+        let (mut producer, consumer) = spsc::bounded(16);
+
+        let pieces = Arc::new(Pieces::from(&torrent));
+        let extern_id = Arc::new(PeerExternId::generate());
+        let counters = Arc::new(ByteCounters::default());
+
+        let (peer, remote) = tokio::join!(
+            Peer::new(
+                TorrentId::new(),
+                addr,
+                pieces,
+                supervisor,
+                extern_id,
+                consumer,
+                fs_addr,
+                Arc::clone(&counters)
+            ),
+            listener.accept()
+        );
+        let mut peer = peer.unwrap();
+        let mut remote = remote.unwrap().0;
+        configure(&mut peer, &mut producer);
+
+        // The channels stay open when the test doesn't keep them
+        let channels = (notifications.clone(), fs.clone());
+        let handle = tokio::spawn(async move {
+            let _channels = channels;
+            peer.start(producer, None).await
+        });
+
+        let mut handshake = [0; 68];
+        remote.read_exact(&mut handshake).await.unwrap();
+        remote.write_all(&handshake).await.unwrap();
+
+        Connected {
+            remote,
+            handle,
+            notifications,
+            fs,
+            counters,
+        }
+    }
+
+    /// The peer must drop the connection without waiting for more data
+    async fn assert_dropped(handle: JoinHandle<Result<()>>) -> TorrentError {
+        let result = tokio::time::timeout(std::time::Duration::from_secs(1), handle).await;
+
+        match result {
+            Ok(Ok(Err(e))) => e,
+            r => panic!("Connection not dropped {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_bitfield() {
+        // Only the header of a 2 GiB BITFIELD is sent
+        let (mut remote, handle) = connected().await;
+        remote
+            .write_all(&[0x7F, 0xFF, 0xFF, 0xFF, 5])
+            .await
+            .unwrap();
+
+        match assert_dropped(handle).await {
+            TorrentError::IOAsync(e) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        // The torrent has 1 piece, its bitfield is 1 byte
+        let (mut remote, handle) = connected().await;
+        remote
+            .write_all(&[0, 0, 0, 5, 5, 0xFF, 0xFF, 0xFF, 0xFF])
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            assert_dropped(handle).await,
+            TorrentError::InvalidInput
+        ));
+    }
+
+    #[tokio::test]
+    async fn ipv6_peer() {
+        // The handshake is exchanged by `connected_with`
+        let Connected { remote, handle, .. } =
+            connected_with("[::1]:0", torrent(), |_, _| {}).await;
+        assert!(remote.peer_addr().unwrap().is_ipv6());
+
+        drop(remote);
+        match tokio::time::timeout(std::time::Duration::from_secs(1), handle).await {
+            Ok(Ok(Err(TorrentError::IOAsync(_)))) | Ok(Ok(Err(TorrentError::IO(_)))) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_peer() {
+        use futures::FutureExt;
+
+        let Connected {
+            mut remote,
+            mut handle,
+            ..
+        } = connected_with("127.0.0.1:0", torrent(), |peer, _| {
+            peer.idle_timeout = Duration::from_secs(1);
+            peer.keep_alive_interval = Duration::from_millis(500);
+        })
+        .await;
+
+        // Our keep-alives keep the connection open past the timeout
+        for _ in 0..5 {
+            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+            remote.write_all(&[0, 0, 0, 0]).await.unwrap();
+        }
+        assert!((&mut handle).now_or_never().is_none());
+
+        // Silent now, we received the keep-alives of the peer before
+        // the connection is closed
+        let mut received = Vec::new();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(3),
+            remote.read_to_end(&mut received),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert!(received.ends_with(&[0, 0, 0, 0]));
+        assert!(matches!(
+            assert_dropped(handle).await,
+            TorrentError::Unresponsive
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_extended_message() {
+        let length = (2 + EXTENDED_MESSAGE_LENGTH as u32 + 1).to_be_bytes();
+        let (mut remote, handle) = connected().await;
+        remote.write_all(&length).await.unwrap();
+        remote.write_all(&[20, 1]).await.unwrap();
+
+        match assert_dropped(handle).await {
+            TorrentError::IOAsync(e) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        // Extended handshake announcing a 1 TiB info dictionary
+        let handshake = b"d13:metadata_sizei1099511627776ee";
+        let length = (2 + handshake.len() as u32).to_be_bytes();
+        let (mut remote, handle) = connected().await;
+        remote.write_all(&length).await.unwrap();
+        remote.write_all(&[20, 0]).await.unwrap();
+        remote.write_all(handshake).await.unwrap();
+
+        assert!(matches!(
+            assert_dropped(handle).await,
+            TorrentError::InvalidInput
+        ));
+    }
+
+    #[tokio::test]
+    async fn unrequested_piece() {
+        let Connected {
+            mut remote,
+            notifications,
+            counters,
+            ..
+        } = connected_with("127.0.0.1:0", torrent(), |_, _| {}).await;
+
+        // PIECE piece 0, begin 0, 16 bytes, never requested
+        remote
+            .write_all(&[0, 0, 0, 25, 7, 0, 0, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        remote.write_all(&[0xAB; 16]).await.unwrap();
+
+        let wasted = tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while counters.stats().wasted == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(wasted.is_ok(), "The block isn't counted as wasted");
+        assert_eq!(counters.stats().wasted, 16);
+
+        // The block isn't given to the torrent
+        assert!(!std::iter::from_fn(|| notifications.try_recv().ok())
+            .any(|msg| matches!(msg, TorrentNotification::AddBlock { .. })));
+    }
+
+    #[tokio::test]
+    async fn download_limit() {
+        let Connected {
+            mut remote,
+            notifications,
+            counters,
+            ..
+        } = connected_with("127.0.0.1:0", torrent(), |peer, _| {
+            peer.set_bandwidth_limits(BandwidthLimits::new(Some(16384), None));
+        })
+        .await;
+
+        let peer_addr = match notifications.recv().await {
+            Ok(TorrentNotification::AddPeer { peer }) => peer.addr,
+            _ => panic!("Missing AddPeer"),
+        };
+        let wasted = |nbytes| {
+            let counters = Arc::clone(&counters);
+            async move {
+                while counters.stats().wasted < nbytes {
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+            }
+        };
+
+        // 2 PIECE of 16384 bytes, the burst is a quarter of it
+        let start = std::time::Instant::now();
+        for _ in 0..2 {
+            remote
+                .write_all(&[0, 0, 0x40, 9, 7, 0, 0, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            remote.write_all(&[0xAB; 16384]).await.unwrap();
+        }
+        wasted(16384).await;
+
+        // The peer waits for the tokens of the first one, it still
+        // answers to the torrent
+        peer_addr.send(PeerCommand::Choke).await.unwrap();
+        let mut choke = [0; 5];
+        tokio::time::timeout(
+            std::time::Duration::from_millis(300),
+            remote.read_exact(&mut choke),
+        )
+        .await
+        .expect("The command waits for the limiter")
+        .unwrap();
+        assert_eq!(choke, [0, 0, 0, 1, 0]);
+        assert_eq!(counters.stats().wasted, 16384);
+
+        // The second one is read once the first one is paid
+        tokio::time::timeout(std::time::Duration::from_secs(3), wasted(32768))
+            .await
+            .unwrap();
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= std::time::Duration::from_millis(700),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests() {
+        // 2 pieces of 4 blocks, the last one has a block of 100 bytes
+        let length = 5 * 16384 + 100;
+        let data: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+
+        let torrent = torrent_with(4 * 16384, length as u64);
+        let Connected {
+            remote,
+            notifications,
+            ..
+        } = connected_with("127.0.0.1:0", torrent, |peer, tasks| {
+            peer.set_pipeline_depth(3);
+            for piece_index in 0..2 {
+                let task = TaskDownload::Piece {
+                    piece_index: piece_index.into(),
+                };
+                tasks.push(task).unwrap();
+            }
+        })
+        .await;
+
+        // The messages are read on their own task, a timeout can't
+        // cut one in the middle
+        let (mut reader, mut writer) = remote.into_split();
+        let (messages, mut received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let length = match reader.read_u32().await {
+                    Ok(length) => length as usize,
+                    Err(_) => return,
+                };
+                let mut message = vec![0; length];
+                if reader.read_exact(&mut message).await.is_err() {
+                    return;
+                }
+                if messages.send(message).is_err() {
+                    return;
+                }
+            }
+        });
+
+        // UNCHOKE
+        writer.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+
+        let mut outstanding = std::collections::VecDeque::new();
+        let mut requested = Vec::new();
+        let mut max_outstanding = 0;
+
+        for _ in 0..6 {
+            // Take the requests until the peer waits for a block
+            while let Ok(Some(message)) =
+                tokio::time::timeout(std::time::Duration::from_millis(100), received.recv()).await
+            {
+                if message.first() != Some(&6) {
+                    continue;
+                }
+                let field = |i: usize| {
+                    let mut bytes = [0; 4];
+                    bytes.copy_from_slice(&message[1 + i * 4..5 + i * 4]);
+                    u32::from_be_bytes(bytes)
+                };
+                outstanding.push_back((field(0), field(1), field(2)));
+                requested.push((field(0), field(1), field(2)));
+            }
+
+            assert!(outstanding.len() <= 3, "{:?} outstanding", outstanding);
+            max_outstanding = max_outstanding.max(outstanding.len());
+
+            let (piece, begin, length) = outstanding.pop_front().expect("No request");
+            let offset = (piece * 4 * 16384 + begin) as usize;
+
+            let mut message = (9 + length).to_be_bytes().to_vec();
+            message.push(7);
+            message.extend_from_slice(&piece.to_be_bytes());
+            message.extend_from_slice(&begin.to_be_bytes());
+            message.extend_from_slice(&data[offset..offset + length as usize]);
+            writer.write_all(&message).await.unwrap();
+        }
+
+        assert_eq!(max_outstanding, 3);
+        assert_eq!(
+            requested,
+            vec![
+                (0, 0, 16384),
+                (0, 16384, 16384),
+                (0, 32768, 16384),
+                (0, 49152, 16384),
+                (1, 0, 16384),
+                (1, 16384, 100),
+            ]
+        );
+
+        // The blocks are given to the torrent in order
+        let mut assembled = Vec::new();
+        while assembled.len() < length {
+            let notification =
+                tokio::time::timeout(std::time::Duration::from_secs(1), notifications.recv())
+                    .await
+                    .expect("Missing blocks")
+                    .unwrap();
+
+            if let TorrentNotification::AddBlock { block, .. } = notification {
+                let offset =
+                    usize::from(block.piece_index) * 4 * 16384 + u32::from(block.index) as usize;
+                assert_eq!(offset, assembled.len());
+                assembled.extend_from_slice(&block.block);
+            }
+        }
+        assert_eq!(assembled, data);
+    }
+
+    #[tokio::test]
+    async fn unanswered_request() {
+        let Connected {
+            mut remote,
+            notifications,
+            ..
+        } = connected_with("127.0.0.1:0", torrent(), |peer, tasks| {
+            peer.set_request_timeout(std::time::Duration::from_millis(100));
+            let task = TaskDownload::Piece {
+                piece_index: 0.into(),
+            };
+            tasks.push(task).unwrap();
+        })
+        .await;
+
+        // UNCHOKE
+        remote.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+
+        // The block is requested, and never sent
+        let mut request = Vec::new();
+        let read = async {
+            loop {
+                let length = remote.read_u32().await.unwrap() as usize;
+                let mut body = vec![0; length];
+                remote.read_exact(&mut body).await.unwrap();
+                if body.first() == Some(&8) {
+                    return body;
+                }
+                if body.first() == Some(&6) {
+                    request = body;
+                }
+            }
+        };
+        let cancel = tokio::time::timeout(std::time::Duration::from_secs(3), read)
+            .await
+            .expect("The request isn't canceled");
+
+        // CANCEL of the REQUEST piece 0, block 0, 16384 bytes
+        assert_eq!(request, &[6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 0]);
+        assert_eq!(cancel, &[8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 0]);
+
+        // The torrent is told, to give the piece to another peer
+        let timed_out = std::iter::from_fn(|| notifications.try_recv().ok()).any(|msg| {
+            matches!(msg, TorrentNotification::PieceTimeout { piece_index, .. } if piece_index == 0.into())
+        });
+        assert!(timed_out);
+    }
 
     fn assert_message_size() {
         assert_eq!(std::mem::size_of::<MessagePeer>(), 24);
     }
+
+    #[test]
+    fn request_timeout_high_rtt() {
+        let mut timeout = RequestTimeout::default();
+
+        // No sample yet, use the ceiling
+        assert!(!timeout.is_expired(Duration::from_secs(30)));
+
+        // Slow but working peer: blocks arrive after 5s
+        for _ in 0..10 {
+            timeout.add_sample(Duration::from_secs(5));
+        }
+
+        // A block requested 15s ago is not re-requested
+        assert!(!timeout.is_expired(Duration::from_secs(15)));
+        assert!(timeout.is_expired(Duration::from_secs(21)));
+    }
+
+    #[test]
+    fn request_timeout_bounds() {
+        let mut timeout = RequestTimeout::new(4, Duration::from_secs(2), Duration::from_secs(60));
+
+        timeout.add_sample(Duration::from_millis(10));
+        assert_eq!(timeout.timeout(), Duration::from_secs(2));
+
+        let mut timeout = RequestTimeout::new(4, Duration::from_secs(2), Duration::from_secs(60));
+
+        timeout.add_sample(Duration::from_secs(30));
+        assert_eq!(timeout.timeout(), Duration::from_secs(60));
+    }
 }
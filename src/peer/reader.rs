@@ -3,12 +3,12 @@ use futures::ready;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use std::{
-    io::{Cursor, Result},
+    io::{Cursor, Error, ErrorKind, Result},
     pin::Pin,
     task::{Context, Poll},
 };
 
-use super::writer::TryWrite;
+use super::{handshake::HANDSHAKE_LENGTH, writer::TryWrite};
 
 pub trait AsyncReadWrite: AsyncRead + AsyncWrite + TryWrite + Send + Sync {}
 impl<T: AsyncRead + AsyncWrite + TryWrite + Send + Sync> AsyncReadWrite for T {}
@@ -19,19 +19,24 @@ pub struct PeerReadBuffer {
     pos: usize,
     msg_len: usize,
     pre_data: usize,
+    /// Longest message accepted, without its length prefix
+    max_message_length: usize,
 }
 
 impl PeerReadBuffer {
-    pub fn new<T>(stream: T, piece_length: usize) -> PeerReadBuffer
+    /// The buffer is allocated once, large enough for the longest
+    /// message accepted
+    pub fn new<T>(stream: T, max_message_length: usize) -> PeerReadBuffer
     where
         T: AsyncReadWrite + 'static,
     {
         PeerReadBuffer {
             reader: Box::pin(stream),
-            buffer: vec![0; piece_length].into_boxed_slice(),
+            buffer: vec![0; max_message_length + 4].into_boxed_slice(),
             pos: 0,
             msg_len: 0,
             pre_data: 0,
+            max_message_length,
         }
     }
 
@@ -44,6 +49,17 @@ impl PeerReadBuffer {
         &self.buffer[self.pre_data..self.msg_len]
     }
 
+    /// Current message, with its header
+    pub fn message(&self) -> &[u8] {
+        assert_ne!(self.msg_len, 0);
+        &self.buffer[..self.msg_len]
+    }
+
+    /// Length of the current message, with its header
+    pub fn message_length(&self) -> usize {
+        self.msg_len
+    }
+
     pub fn consume(&mut self) {
         let pos = self.pos;
         let msg_len = self.msg_len;
@@ -70,14 +86,13 @@ impl PeerReadBuffer {
     }
 
     fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        ready!(self.read_at_least(1, cx))?;
-
-        let length = self.buffer[0] as usize;
-
-        ready!(self.read_at_least(length + 48, cx))?;
+        // The bytes following the handshake are kept in the buffer,
+        // a peer can send its first message in the same segment.
+        // The protocol is checked by `Handshake::from_bytes`
+        ready!(self.read_at_least(HANDSHAKE_LENGTH, cx))?;
 
         self.pre_data = 1;
-        self.msg_len = length + 48 + 1;
+        self.msg_len = HANDSHAKE_LENGTH;
         Poll::Ready(Ok(()))
     }
 
@@ -89,7 +104,13 @@ impl PeerReadBuffer {
             cursor.read_u32::<BigEndian>().unwrap() as usize
         };
 
-        assert!(length + 4 < self.buffer.len());
+        if length > self.max_message_length {
+            // Hostile or broken peer, the connection is dropped
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Message too long ({} bytes)", length),
+            )));
+        }
 
         ready!(self.read_at_least(length + 4, cx))?;
 
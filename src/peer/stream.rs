@@ -1,12 +1,14 @@
 use std::{
     convert::TryFrom,
     io::Result,
+    sync::Arc,
     task::{Context, Poll},
 };
 
-use crate::peer::peer::PeerExternId;
+use crate::{supervisors::torrent::ByteCounters, utils::SaturatingDuration};
 
 use super::{
+    handshake::Handshake,
     message::MessagePeer,
     reader::{AsyncReadWrite, PeerReadBuffer},
     writer::BufferWriter,
@@ -15,19 +17,40 @@ use super::{
 pub struct StreamBuffers {
     reader: PeerReadBuffer,
     buffer_writer: BufferWriter,
+    counters: Arc<ByteCounters>,
+    last_read: coarsetime::Instant,
+    last_write: coarsetime::Instant,
 }
 
 impl StreamBuffers {
-    pub fn new<T>(stream: T, read_buffer_length: usize, write_buffer_length: usize) -> Self
+    pub fn new<T>(
+        stream: T,
+        max_message_length: usize,
+        write_buffer_length: usize,
+        counters: Arc<ByteCounters>,
+    ) -> Self
     where
         T: AsyncReadWrite + 'static,
     {
         Self {
-            reader: PeerReadBuffer::new(stream, read_buffer_length),
+            reader: PeerReadBuffer::new(stream, max_message_length),
             buffer_writer: BufferWriter::new(write_buffer_length),
+            counters,
+            last_read: coarsetime::Instant::now(),
+            last_write: coarsetime::Instant::now(),
         }
     }
 
+    /// Time since the last message received, keep-alives included
+    pub fn read_idle(&self) -> coarsetime::Duration {
+        coarsetime::Instant::now().saturating_duration_since(self.last_read)
+    }
+
+    /// Time since the last message sent
+    pub fn write_idle(&self) -> coarsetime::Duration {
+        coarsetime::Instant::now().saturating_duration_since(self.last_write)
+    }
+
     fn write_to_socket(&mut self) -> Result<()> {
         let writer = self.reader.as_writer();
 
@@ -50,10 +73,36 @@ impl StreamBuffers {
     where
         M: Into<MessagePeer<'a>>,
     {
+        let msg = msg.into();
+        let payload = match &msg {
+            MessagePeer::Piece { data, .. } => data.len(),
+            _ => 0,
+        };
+
+        let before = self.buffer_writer.len();
         self.buffer_writer.write_msg(msg);
+        self.counters
+            .add_uploaded(payload, self.buffer_writer.len() - before);
+        self.last_write = coarsetime::Instant::now();
+
         self.write_to_socket()
     }
 
+    /// Account the bytes of the message in the read buffer
+    fn count_read(&mut self) {
+        // Header, including its length
+        let total = self.reader.message_length();
+
+        let payload = match self.reader.buffer() {
+            // PIECE: id, index and begin precede the data
+            [7, ..] => total.saturating_sub(4 + 9),
+            _ => 0,
+        };
+
+        self.counters.add_downloaded(payload, total);
+        self.last_read = coarsetime::Instant::now();
+    }
+
     pub async fn read_message(&mut self) -> Result<()> {
         enum State {
             Write(Result<()>),
@@ -62,7 +111,9 @@ impl StreamBuffers {
 
         loop {
             if self.buffer_writer.is_empty() {
-                return self.reader.read_message().await;
+                self.reader.read_message().await?;
+                self.count_read();
+                return Ok(());
             }
 
             let fun = |cx: &mut Context<'_>| {
@@ -74,7 +125,11 @@ impl StreamBuffers {
             };
 
             match futures::future::poll_fn(fun).await {
-                State::Read(v) => return v,
+                State::Read(v) => {
+                    v?;
+                    self.count_read();
+                    return Ok(());
+                }
                 State::Write(v) => {
                     v?;
                     self.write_to_socket()?;
@@ -83,15 +138,16 @@ impl StreamBuffers {
         }
     }
 
-    pub async fn read_handshake(&mut self) -> Result<PeerExternId> {
+    pub async fn read_handshake(&mut self) -> crate::supervisors::torrent::Result<Handshake> {
         self.reader.read_handshake().await?;
-        let buffer = self.reader.buffer();
-        let length = buffer.len();
+        self.counters
+            .add_downloaded(0, self.reader.message_length());
+        self.last_read = coarsetime::Instant::now();
 
-        let peer_id = PeerExternId::new(&buffer[length - 20..]);
+        let handshake = Handshake::from_bytes(self.reader.message());
         self.reader.consume();
 
-        Ok(peer_id)
+        Ok(handshake?)
     }
 
     pub fn get_message(&self) -> crate::supervisors::torrent::Result<MessagePeer> {
@@ -102,3 +158,107 @@ impl StreamBuffers {
         self.reader.consume();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::{peer::message::MessagePeer, supervisors::torrent::ByteCounters};
+
+    use super::StreamBuffers;
+
+    #[tokio::test]
+    async fn byte_counters() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (sender, receiver) = tokio::join!(TcpStream::connect(addr), listener.accept());
+
+        let sender_counters = Arc::new(ByteCounters::default());
+        let receiver_counters = Arc::new(ByteCounters::default());
+
+        let mut sender = StreamBuffers::new(sender.unwrap(), 1024, 1024, sender_counters.clone());
+        let mut receiver =
+            StreamBuffers::new(receiver.unwrap().0, 1024, 1024, receiver_counters.clone());
+
+        let block = [1; 100];
+
+        sender.write_message(MessagePeer::Interested).unwrap();
+        sender
+            .write_message(MessagePeer::Piece {
+                piece: 0.into(),
+                block: 0.into(),
+                data: &block,
+            })
+            .unwrap();
+
+        receiver.read_message().await.unwrap();
+        receiver.consume_read();
+        receiver.read_message().await.unwrap();
+        receiver.consume_read();
+
+        for stats in &[sender_counters.stats(), receiver_counters.stats()] {
+            let (payload, total) = if stats.payload_uploaded > 0 {
+                (stats.payload_uploaded, stats.total_uploaded)
+            } else {
+                (stats.payload_downloaded, stats.total_downloaded)
+            };
+
+            assert_eq!(payload, 100);
+            // Interested (5 bytes) and the PIECE header (13 bytes)
+            assert_eq!(total, 100 + 13 + 5);
+        }
+
+        assert_eq!(receiver_counters.stats().payload_downloaded, 100);
+        assert_eq!(sender_counters.stats().payload_uploaded, 100);
+    }
+
+    #[tokio::test]
+    async fn handshake_with_first_message() {
+        use tokio::io::AsyncWriteExt;
+
+        let mut handshake = vec![19];
+        handshake.extend_from_slice(b"BitTorrent protocol");
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(&[1; 20]);
+        handshake.extend_from_slice(b"-RR0001-123456789012");
+
+        let mut data = handshake.clone();
+        // BITFIELD
+        data.extend_from_slice(&[0, 0, 0, 3, 5, 0xFF, 0x80]);
+
+        // In a single write, then split 1 byte before the end of
+        // the handshake
+        for split in &[data.len(), handshake.len() - 1] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let (remote, receiver) = tokio::join!(TcpStream::connect(addr), listener.accept());
+            let mut remote = remote.unwrap();
+            let counters = Arc::new(ByteCounters::default());
+            let mut receiver = StreamBuffers::new(receiver.unwrap().0, 1024, 1024, counters);
+
+            remote.write_all(&data[..*split]).await.unwrap();
+            remote.flush().await.unwrap();
+
+            let rest = data[*split..].to_vec();
+            tokio::spawn(async move {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                remote.write_all(&rest).await.unwrap();
+                remote
+            });
+
+            let handshake = receiver.read_handshake().await.unwrap();
+            assert_eq!(&handshake.peer_id, b"-RR0001-123456789012");
+            assert_eq!(handshake.info_hash, [1; 20]);
+
+            receiver.read_message().await.unwrap();
+            match receiver.get_message().unwrap() {
+                MessagePeer::BitField(bitfield) => assert_eq!(bitfield, &[0xFF, 0x80]),
+                m => panic!("Unexpected message {:?}", m),
+            }
+        }
+    }
+}
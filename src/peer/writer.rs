@@ -8,7 +8,7 @@ use tokio::net::TcpStream;
 
 use crate::extensions::ExtendedMessage;
 
-use super::message::MessagePeer;
+use super::{handshake::Handshake, message::MessagePeer};
 
 pub trait TryWrite {
     fn try_write(&self, _: &[u8]) -> Result<usize>;
@@ -161,16 +161,12 @@ impl BufferWriter {
             MessagePeer::Handshake {
                 info_hash,
                 extern_id,
+                capabilities,
             } => {
-                let mut reserved: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
-
-                reserved[5] |= 0x10; // Support Extension Protocol
-
-                cursor.write_all(&[19]).unwrap();
-                cursor.write_all(b"BitTorrent protocol").unwrap();
-                cursor.write_all(&reserved[..]).unwrap();
-                cursor.write_all(info_hash.as_ref()).unwrap();
-                cursor.write_all(&**extern_id).unwrap();
+                let mut hash = [0; 20];
+                hash.copy_from_slice(info_hash);
+                let handshake = Handshake::new(hash, **extern_id, capabilities);
+                cursor.write_all(&handshake.to_bytes()).unwrap();
             }
             MessagePeer::Unknown { .. } => unreachable!(),
         }
@@ -182,7 +178,7 @@ impl BufferWriter {
 #[cfg(test)]
 mod tests {
     use crate::{
-        extensions::ExtendedHandshake,
+        extensions::{Capabilities, ExtendedHandshake},
         peer::{message::MessagePeer, peer::PeerExternId},
     };
 
@@ -312,6 +308,7 @@ mod tests {
         buffer.write_msg(MessagePeer::Handshake {
             info_hash: &info_hash,
             extern_id: &peer_id,
+            capabilities: Capabilities::default(),
         });
         assert_eq!(
             buffer.as_ref(),
@@ -324,4 +321,34 @@ mod tests {
         );
         buffer.consume(buffer.len());
     }
+
+    #[test]
+    fn handshake_reserved() {
+        let info_hash = [1; 20];
+        let peer_id = PeerExternId::new(&[2; 20]);
+        let mut buffer = BufferWriter::new(128);
+
+        for bits in 0..8 {
+            let mut capabilities = Capabilities::empty();
+            capabilities.set(Capabilities::EXTENSION, bits & 1 != 0);
+            capabilities.set(Capabilities::DHT, bits & 2 != 0);
+            capabilities.set(Capabilities::FAST, bits & 4 != 0);
+
+            buffer.write_msg(MessagePeer::Handshake {
+                info_hash: &info_hash,
+                extern_id: &peer_id,
+                capabilities,
+            });
+
+            let reserved = &buffer.as_ref()[20..28];
+            assert_eq!(reserved[..5], [0; 5]);
+            assert_eq!(reserved[5] == 0x10, bits & 1 != 0, "{:?}", capabilities);
+            assert_eq!(reserved[6], 0);
+            assert_eq!(reserved[7] & 0x01 != 0, bits & 2 != 0, "{:?}", capabilities);
+            assert_eq!(reserved[7] & 0x04 != 0, bits & 4 != 0, "{:?}", capabilities);
+            assert_eq!(reserved[7] & !0x05, 0);
+
+            buffer.consume(buffer.len());
+        }
+    }
 }
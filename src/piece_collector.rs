@@ -296,6 +296,38 @@ impl PieceCollector {
         }
     }
 
+    /// Bytes of the blocks received for the pieces not completed
+    pub fn nbytes(&self) -> u64 {
+        self.pieces
+            .values()
+            .flat_map(|m| m.blocks_completed.ranges.iter())
+            .map(|range| (range.end - range.start) as u64)
+            .sum()
+    }
+
+    /// The received parts of the pieces not completed, with
+    /// contiguous blocks merged
+    pub fn blocks(&self) -> Vec<Block> {
+        let mut blocks: Vec<_> = self
+            .pieces
+            .iter()
+            .flat_map(|(piece_index, metadata)| {
+                metadata
+                    .blocks_completed
+                    .ranges
+                    .iter()
+                    .map(move |range| Block {
+                        piece_index: *piece_index,
+                        index: range.start.into(),
+                        block: metadata.piece[range.start as usize..range.end as usize].into(),
+                    })
+            })
+            .collect();
+
+        blocks.sort_by_key(|b| (b.piece_index, b.index));
+        blocks
+    }
+
     /// Return the piece if completed
     pub fn add_block(&mut self, block: &Block) -> Option<Box<[u8]>> {
         let piece_index = block.piece_index;
@@ -140,6 +140,17 @@ impl PieceState {
     }
 }
 
+/// Number of pieces in each state
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PieceStateCount {
+    /// Pieces not downloaded and without any peer on it
+    pub missing: usize,
+    /// Pieces being downloaded by at least 1 peer
+    pub downloading: usize,
+    /// Pieces fully downloaded. Their sha1 might not be checked yet
+    pub downloaded: usize,
+}
+
 #[derive(Debug)]
 pub struct PiecePicker {
     pieces_infos: Arc<Pieces>,
@@ -157,8 +168,31 @@ pub struct PiecePicker {
 
     haves: Vec<PieceIndex>,
     rng: Rng,
+
+    /// Ranges of pieces `[start, end)` picked in order, before
+    /// the rarest first pieces
+    sequential: Vec<(PieceIndex, PieceIndex)>,
+
+    /// Number of pieces not yet downloaded picked in priority,
+    /// from `playback`. 0 to disable
+    read_ahead: usize,
+    /// Priority position, the read-ahead window starts here
+    playback: PieceIndex,
+
+    /// Pieces failing to be assembled, picked first by any peer
+    /// having them, even when other workers are on them
+    suspects: Vec<PieceIndex>,
+
+    /// Below that many pieces remaining, the pieces other workers are
+    /// on are picked too
+    endgame_pieces: usize,
+    /// Number of pieces in `states` downloaded
+    num_downloaded: usize,
 }
 
+/// Default of `PiecePicker::set_endgame_pieces`
+pub const ENDGAME_PIECES: usize = 4;
+
 enum Picked {
     Full(PieceIndex),
     Partial(PieceIndex),
@@ -191,17 +225,95 @@ impl PiecePicker {
             to_download: Vec::with_capacity(256),
             rng: Rng::new(),
             haves: Vec::with_capacity(256),
+            sequential: Vec::new(),
+            read_ahead: 0,
+            playback: PieceIndex(0),
+            suspects: Vec::new(),
+            endgame_pieces: ENDGAME_PIECES,
+            num_downloaded: 0,
+        }
+    }
+
+    /// Pick the pieces `[start, end)` in ascending order, before any other
+    pub fn set_sequential(&mut self, start: PieceIndex, end: PieceIndex) {
+        self.sequential.push((start, end));
+    }
+
+    /// Pick in priority the `read_ahead` pieces not yet downloaded following
+    /// the playback position, so they're always in flight
+    pub fn set_read_ahead(&mut self, read_ahead: usize) {
+        self.read_ahead = read_ahead;
+    }
+
+    pub fn set_playback_position(&mut self, piece: PieceIndex) {
+        self.playback = piece;
+    }
+
+    /// Endgame starts once fewer than `pieces` are not downloaded, 0
+    /// to disable it
+    pub fn set_endgame_pieces(&mut self, pieces: usize) {
+        self.endgame_pieces = pieces;
+    }
+
+    pub fn is_endgame(&self) -> bool {
+        let remaining = self.states.len() - self.num_downloaded;
+
+        remaining > 0 && remaining < self.endgame_pieces
+    }
+
+    /// Widen the peer set of a piece: it's picked before any other,
+    /// by all the peers having it
+    pub fn set_suspect(&mut self, piece: PieceIndex) {
+        if !self.suspects.contains(&piece) {
+            self.suspects.push(piece);
         }
     }
 
+    pub fn clear_suspect(&mut self, piece: PieceIndex) {
+        self.suspects.retain(|p| *p != piece);
+    }
+
+    pub fn is_suspect(&self, piece: PieceIndex) -> bool {
+        self.suspects.contains(&piece)
+    }
+
     pub fn set_as_downloaded(&mut self, piece: PieceIndex, valid: bool) {
         let index: usize = piece.into();
         if valid != self.states[index].downloaded {
             self.states[index].downloaded = valid;
+            if valid {
+                self.num_downloaded += 1;
+            } else {
+                self.num_downloaded -= 1;
+            }
             self.sort_indexed();
         }
     }
 
+    pub fn set_all_as_downloaded(&mut self) {
+        for state in &mut *self.states {
+            state.downloaded = true;
+        }
+        self.num_downloaded = self.states.len();
+        self.sort_indexed();
+    }
+
+    pub fn state_count(&self) -> PieceStateCount {
+        let mut count = PieceStateCount::default();
+
+        for state in &*self.states {
+            if state.downloaded {
+                count.downloaded += 1;
+            } else if !state.workers.is_empty() {
+                count.downloading += 1;
+            } else {
+                count.missing += 1;
+            }
+        }
+
+        count
+    }
+
     fn add_piece_to_download(&mut self, piece_index: PieceIndex, no_push: bool) -> bool {
         // We're only interested with the last added piece, because
         // previous pieces could have a higher priority
@@ -242,6 +354,85 @@ impl PiecePicker {
             return;
         }
 
+        let endgame = self.is_endgame();
+
+        // Suspect pieces are picked first, whether other workers are on them or not
+        for index in 0..self.suspects.len() {
+            let piece_index = self.suspects[index];
+            let state = &self.states[usize::from(piece_index)];
+
+            if state.downloaded
+                || state.workers.contains(&peer_id)
+                || !bitfield.get_bit(piece_index)
+            {
+                continue;
+            }
+
+            let mode = if collector.is_empty(piece_index) {
+                fun(self, Picked::Full(piece_index))
+            } else {
+                fun(self, Picked::Partial(piece_index))
+            };
+
+            if let PickMode::Stop = mode {
+                return;
+            }
+        }
+
+        // Then the read-ahead window, the pieces following the playback position
+        let mut in_window = 0;
+        let mut piece_index = self.playback;
+
+        while in_window < self.read_ahead && usize::from(piece_index) < self.states.len() {
+            let state = &self.states[usize::from(piece_index)];
+            let current = piece_index;
+            piece_index = piece_index.next_piece();
+
+            if state.downloaded {
+                continue;
+            }
+
+            in_window += 1;
+
+            if !state.workers.is_empty() || !bitfield.get_bit(current) {
+                continue;
+            }
+
+            let mode = if collector.is_empty(current) {
+                fun(self, Picked::Full(current))
+            } else {
+                fun(self, Picked::Partial(current))
+            };
+
+            if let PickMode::Stop = mode {
+                return;
+            }
+        }
+
+        // Pieces of the sequential ranges are picked first, in order
+        for index in 0..self.sequential.len() {
+            let (start, end) = self.sequential[index];
+
+            for piece_index in start.0..end.0 {
+                let piece_index = PieceIndex(piece_index);
+                let state = &self.states[usize::from(piece_index)];
+
+                if state.downloaded || !state.workers.is_empty() || !bitfield.get_bit(piece_index) {
+                    continue;
+                }
+
+                let mode = if collector.is_empty(piece_index) {
+                    fun(self, Picked::Full(piece_index))
+                } else {
+                    fun(self, Picked::Partial(piece_index))
+                };
+
+                if let PickMode::Stop = mode {
+                    return;
+                }
+            }
+        }
+
         // Number of peers having the piece at the current index
         let mut npeers_current = self.sorted_index[self.start_at].npeers;
 
@@ -268,8 +459,8 @@ impl PiecePicker {
             // Since we prioritize rarest piece first, we need to process
             // pieces from previous iteration of the loop.
             // For thoses pieces, there are already other workers requesting
-            // them but we want to download them before passing to pieces with more
-            // peers
+            // them (in endgame) but we want to download them before passing
+            // to pieces with more peers
             if npeers != npeers_current {
                 if !self.haves.is_empty() {
                     // Do not use randomness during tests, for assertions in tests
@@ -315,8 +506,8 @@ impl PiecePicker {
             }
 
             // The peer has this piece, but another worker is on it
-            // Save the piece for next iterations
-            if have {
+            // In endgame, save the piece for next iterations
+            if have && endgame {
                 self.haves.push(piece_index);
             }
         }
@@ -451,12 +642,29 @@ impl PiecePicker {
         found
     }
 
+    /// The peer gave up on the piece, the others can pick its
+    /// missing blocks
+    pub fn remove_worker(&mut self, piece: PieceIndex, peer_id: PeerId) {
+        self.states[usize::from(piece)].workers.remove(&peer_id);
+    }
+
     pub fn remove_peer(&mut self, peer_id: PeerId) {
         for state in &mut *self.states {
             state.workers.remove(&peer_id);
         }
     }
 
+    /// A peer disconnected, its pieces are rarer
+    pub fn remove_bitfield(&mut self, bitfield: &BitField) {
+        for peers_per_piece in &mut *self.sorted_index {
+            if bitfield.get_bit(peers_per_piece.piece_index) {
+                peers_per_piece.npeers = peers_per_piece.npeers.saturating_sub(1);
+            }
+        }
+
+        self.sort_indexed();
+    }
+
     pub fn update(&mut self, update: &BitFieldUpdate) {
         match update {
             BitFieldUpdate::BitField(bitfield) => {
@@ -655,6 +863,218 @@ mod tests {
         );
     }
 
+    #[test]
+    fn picker_sequential() {
+        let pieces_info = Arc::new(Pieces {
+            info_hash: Arc::new([]),
+            num_pieces: 10,
+            sha1_pieces: Arc::new([]),
+            block_size: 100,
+            last_block_size: 100,
+            nblocks_piece: 10,
+            nblocks_last_piece: 10,
+            piece_length: 1000,
+            last_piece_length: 1000,
+            files_size: 10000,
+        });
+
+        let mut picker = PiecePicker::new(&pieces_info);
+        let collector = PieceCollector::new(&pieces_info);
+
+        // The 1st file, pieces [0, 5), is sequential
+        picker.set_sequential(0.into(), 5.into());
+
+        // The last pieces are the rarest
+        for piece in 0..10u32 {
+            for _ in 0..(10 - piece) {
+                picker.update(&BitFieldUpdate::Piece(piece.into()));
+            }
+        }
+
+        let bitfield = BitField::try_from((&[0b11111111, 0b11000000][..], 10)).unwrap();
+
+        let peer = PeerId::new(1);
+        let mut picked = Vec::new();
+
+        while let Some((_, tasks)) = picker.pick_piece(peer, 1000, 1, &bitfield, &collector) {
+            match tasks {
+                [TaskDownload::Piece { piece_index }] => picked.push(u32::from(*piece_index)),
+                tasks => panic!("Unexpected tasks {:?}", tasks),
+            }
+        }
+
+        assert_eq!(picked, &[0, 1, 2, 3, 4, 9, 8, 7, 6, 5]);
+    }
+
+    #[test]
+    fn pick_rarest() {
+        let pieces_info = Arc::new(Pieces {
+            info_hash: Arc::new([]),
+            num_pieces: 8,
+            sha1_pieces: Arc::new([]),
+            block_size: 100,
+            last_block_size: 100,
+            nblocks_piece: 10,
+            nblocks_last_piece: 10,
+            piece_length: 1000,
+            last_piece_length: 1000,
+            files_size: 8000,
+        });
+
+        let mut picker = PiecePicker::new(&pieces_info);
+        let collector = PieceCollector::new(&pieces_info);
+
+        // Piece 5 is on a peer, 1 and 6 on 2 peers, the others on 3
+        let bytes = [0b11111111, 0b11111011, 0b10111001];
+        let bitfield = |byte: u8| BitField::from_bytes(&[byte], 8).unwrap();
+        let peers: Vec<_> = bytes.iter().map(|b| bitfield(*b)).collect();
+        for byte in &bytes {
+            picker.update(&BitFieldUpdate::BitField(bitfield(*byte)));
+        }
+
+        let pick = |picker: &mut PiecePicker, peer: usize| {
+            let id = PeerId::new(peer);
+            match picker.pick_piece(id, 1000, 1, &peers[peer], &collector) {
+                Some((_, [TaskDownload::Piece { piece_index }])) => Some(u32::from(*piece_index)),
+                Some((_, tasks)) => panic!("Unexpected tasks {:?}", tasks),
+                None => None,
+            }
+        };
+
+        assert_eq!(pick(&mut picker, 0), Some(5));
+        assert_eq!(pick(&mut picker, 1), Some(1));
+
+        // Rarer once a peer is gone: 2 has 2 peers now, like 6.
+        // Downloaded pieces are skipped
+        picker.remove_bitfield(&peers[2]);
+        picker.set_as_downloaded(0.into(), true);
+        assert_eq!(pick(&mut picker, 1), Some(2));
+    }
+
+    #[test]
+    fn pick_endgame() {
+        let pieces_info = Arc::new(Pieces {
+            info_hash: Arc::new([]),
+            num_pieces: 4,
+            sha1_pieces: Arc::new([]),
+            block_size: 100,
+            last_block_size: 100,
+            nblocks_piece: 10,
+            nblocks_last_piece: 10,
+            piece_length: 1000,
+            last_piece_length: 1000,
+            files_size: 4000,
+        });
+
+        let mut picker = PiecePicker::new(&pieces_info);
+        let mut collector = PieceCollector::new(&pieces_info);
+        picker.set_endgame_pieces(2);
+
+        let bitfield = BitField::from_bytes(&[0b11110000], 4).unwrap();
+        picker.update(&BitFieldUpdate::BitField(bitfield.clone()));
+        picker.update(&BitFieldUpdate::BitField(bitfield.clone()));
+
+        let (first, second) = (PeerId::new(1), PeerId::new(2));
+
+        // Each peer is on its own pieces
+        for peer in &[first, second, first, second] {
+            assert!(picker
+                .pick_piece(*peer, 1000, 1, &bitfield, &collector)
+                .is_some());
+        }
+        assert!(!picker.is_endgame());
+        assert!(picker
+            .pick_piece(first, 1000, 1, &bitfield, &collector)
+            .is_none());
+
+        for piece in 0..3 {
+            picker.set_as_downloaded(piece.into(), true);
+        }
+        assert!(picker.is_endgame());
+
+        // The last piece is requested by both peers, its missing blocks only
+        collector.add_block(&Block {
+            piece_index: 3.into(),
+            index: 0.into(),
+            block: vec![0; 100].into_boxed_slice(),
+        });
+
+        let picked = picker.pick_piece(first, 1000, 1, &bitfield, &collector);
+        assert_eq!(
+            picked.map(|(_, tasks)| tasks),
+            Some(
+                &[TaskDownload::BlockRange {
+                    piece_index: 3.into(),
+                    start: 100.into(),
+                    end: 1000.into(),
+                }][..]
+            )
+        );
+        assert_eq!(picker.state_count().downloading, 1);
+
+        // Not twice by the same peer
+        assert!(picker
+            .pick_piece(first, 1000, 1, &bitfield, &collector)
+            .is_none());
+    }
+
+    #[test]
+    fn picker_read_ahead() {
+        let pieces_info = Arc::new(Pieces {
+            info_hash: Arc::new([]),
+            num_pieces: 10,
+            sha1_pieces: Arc::new([]),
+            block_size: 100,
+            last_block_size: 100,
+            nblocks_piece: 10,
+            nblocks_last_piece: 10,
+            piece_length: 1000,
+            last_piece_length: 1000,
+            files_size: 10000,
+        });
+
+        let mut picker = PiecePicker::new(&pieces_info);
+        let collector = PieceCollector::new(&pieces_info);
+
+        picker.set_sequential(0.into(), 10.into());
+        picker.set_read_ahead(3);
+        picker.set_playback_position(4.into());
+
+        for piece in 0..10u32 {
+            picker.update(&BitFieldUpdate::Piece(piece.into()));
+        }
+
+        let bitfield = BitField::try_from((&[0b11111111, 0b11000000][..], 10)).unwrap();
+        let pick = |picker: &mut PiecePicker, peer| match picker.pick_piece(
+            PeerId::new(peer),
+            1000,
+            1,
+            &bitfield,
+            &collector,
+        ) {
+            Some((_, [TaskDownload::Piece { piece_index }])) => u32::from(*piece_index),
+            tasks => panic!("Unexpected tasks {:?}", tasks),
+        };
+
+        // The 3 pieces after the playback position are requested first,
+        // before the start of the sequential range
+        assert_eq!(pick(&mut picker, 1), 4);
+        assert_eq!(pick(&mut picker, 2), 5);
+        assert_eq!(pick(&mut picker, 3), 6);
+        assert_eq!(pick(&mut picker, 4), 0);
+
+        // The window moves forward as the pieces are downloaded
+        picker.set_as_downloaded(4.into(), true);
+        assert_eq!(pick(&mut picker, 5), 7);
+        assert_eq!(pick(&mut picker, 6), 1);
+
+        // Seeking moves the window
+        picker.set_playback_position(8.into());
+        assert_eq!(pick(&mut picker, 7), 8);
+        assert_eq!(pick(&mut picker, 8), 9);
+        assert_eq!(pick(&mut picker, 9), 2);
+    }
+
     #[test]
     fn peers_per_piece_order() {
         let ordered = [
@@ -924,6 +1344,9 @@ mod tests {
         let mut picker = PiecePicker::new(&pieces_info);
         let mut collector = PieceCollector::new(&pieces_info);
 
+        // All the pieces are in endgame, the peers share them
+        picker.set_endgame_pieces(10);
+
         picker.update(&BitFieldUpdate::Piece(1.into()));
         picker.update(&BitFieldUpdate::Piece(3.into()));
         picker.update(&BitFieldUpdate::Piece(4.into()));
@@ -4,7 +4,7 @@ use crate::{
     piece_picker::{BlockIndex, PieceIndex},
 };
 
-use std::{fmt::Debug, sync::Arc};
+use std::{convert::TryFrom, fmt::Debug, sync::Arc};
 
 #[derive(Clone)]
 pub struct Pieces {
@@ -106,6 +106,33 @@ impl From<&Torrent> for Pieces {
 // https://stackoverflow.com/questions/42938907/is-it-possible-to-use-simd-instructions-in-rust
 
 impl Pieces {
+    /// Range of pieces `[start, end)` overlapping the bytes
+    /// `[offset, offset + length)` of the torrent
+    pub fn pieces_of_range(&self, offset: usize, length: usize) -> (PieceIndex, PieceIndex) {
+        let start = offset / self.piece_length;
+        let end = (offset + length).div_ceil(self.piece_length);
+
+        (
+            (start.min(self.num_pieces) as u32).into(),
+            (end.min(self.num_pieces) as u32).into(),
+        )
+    }
+
+    /// Piece and offset in the piece of the byte `offset` of the torrent.
+    /// `None` when `[offset, offset + length)` goes past the end
+    pub fn block_at(&self, offset: u64, length: usize) -> Option<(PieceIndex, BlockIndex)> {
+        let offset = usize::try_from(offset).ok()?;
+
+        if offset.checked_add(length)? > self.files_size {
+            return None;
+        }
+
+        let piece = (offset / self.piece_length) as u32;
+        let block = (offset % self.piece_length) as u32;
+
+        Some((piece.into(), block.into()))
+    }
+
     pub fn block_length_of(&self, piece_index: PieceIndex, block_index: BlockIndex) -> u32 {
         let block_index: u32 = block_index.into();
         let piece_length = self.piece_size_of(piece_index);
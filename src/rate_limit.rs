@@ -0,0 +1,170 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Token bucket limiting a number of bytes per second.
+///
+/// The bucket can go in debt: a request larger than the capacity
+/// is accepted, the following ones wait until the debt is paid
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Bytes per second, 0 for unlimited
+    rate: u64,
+    /// Maximum burst, in bytes
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// `rate` in bytes per second, 0 means unlimited.
+    /// Bursts up to a quarter of second are allowed
+    pub fn new(rate: u64) -> TokenBucket {
+        let capacity = rate as f64 / 4.0;
+
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.rate == 0
+    }
+
+    /// Take `nbytes` from the bucket, returns the duration to wait
+    /// before using them
+    pub fn take_at(&mut self, nbytes: usize, now: Instant) -> Duration {
+        if self.is_unlimited() {
+            return Duration::from_secs(0);
+        }
+
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.capacity);
+        self.tokens -= nbytes as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+/// Token bucket shared by all the connections of a session
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// `rate` in bytes per second
+    pub fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            bucket: Mutex::new(TokenBucket::new(rate)),
+        }
+    }
+
+    /// Bytes per second
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Take `nbytes` from the bucket, returns the duration to wait
+    /// before using them.
+    /// The bucket refills with the time elapsed since the last call
+    pub fn take(&self, nbytes: usize) -> Duration {
+        self.bucket.lock().unwrap().take_at(nbytes, Instant::now())
+    }
+
+    /// Take `nbytes` from the bucket, waiting until they are available
+    pub async fn acquire(&self, nbytes: usize) {
+        let wait = self.take(nbytes);
+
+        if wait > Duration::from_secs(0) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Bandwidth of the peers of a session, `None` when it's unlimited
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthLimits {
+    pub download: Option<Arc<RateLimiter>>,
+    pub upload: Option<Arc<RateLimiter>>,
+}
+
+impl BandwidthLimits {
+    /// Limits in bytes per second. 0 is unlimited, like `None`
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> BandwidthLimits {
+        let limiter = |rate: Option<u64>| {
+            rate.filter(|rate| *rate > 0)
+                .map(|rate| Arc::new(RateLimiter::new(rate)))
+        };
+
+        BandwidthLimits {
+            download: limiter(download),
+            upload: limiter(upload),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use super::{BandwidthLimits, TokenBucket};
+
+    #[test]
+    fn token_bucket() {
+        let mut bucket = TokenBucket::new(1000);
+        let now = Instant::now();
+
+        // The burst is available immediately
+        assert_eq!(bucket.take_at(250, now), Duration::from_secs(0));
+        assert_eq!(bucket.take_at(500, now), Duration::from_millis(500));
+
+        // The debt is paid after half a second
+        let now = now + Duration::from_millis(500);
+        assert_eq!(bucket.take_at(100, now), Duration::from_millis(100));
+
+        let mut unlimited = TokenBucket::new(0);
+        assert_eq!(unlimited.take_at(1 << 30, now), Duration::from_secs(0));
+    }
+
+    #[tokio::test]
+    async fn shared_limit() {
+        let limits = BandwidthLimits::new(Some(1024 * 1024), None);
+        assert!(limits.upload.is_none());
+        let download = limits.download.unwrap();
+
+        // 4 connections receiving 1 MiB each, in blocks of 16 KiB
+        let start = Instant::now();
+        let connections: Vec<_> = (0..4)
+            .map(|_| {
+                let download = Arc::clone(&download);
+                tokio::spawn(async move {
+                    for _ in 0..64 {
+                        download.acquire(16 * 1024).await;
+                    }
+                })
+            })
+            .collect();
+        for connection in connections {
+            connection.await.unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        // A quarter of second of burst is given at the start
+        assert!(elapsed >= Duration::from_millis(3700), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(6), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,262 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bencode::{de::from_bytes, ser::to_bytes},
+    metadata::Torrent,
+};
+
+/// Start of the persisted files
+const MAGIC: &[u8; 4] = b"RTRS";
+
+const PLAIN: u8 = 0;
+const ENCRYPTED: u8 = 1;
+
+/// Symmetric encryption of the resume files, implemented by the
+/// embedder. The key never leaves it: rustorrent only gives the bytes
+/// to encrypt and to decrypt.
+///
+/// The cipher must be authenticated, an AEAD such as AES-GCM or
+/// ChaCha20-Poly1305: rustorrent doesn't check the content decrypted,
+/// a wrong key or a modified file is only detected by `decrypt`
+pub trait ResumeCipher: Send + Sync + std::fmt::Debug {
+    fn encrypt(&self, data: &[u8]) -> Vec<u8>;
+    /// `None` when `data` wasn't encrypted with this key, or was modified
+    fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>>;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResumeError {
+    /// Not a file written by `seal`
+    InvalidFormat,
+    /// The file is encrypted, it can't be loaded without a cipher
+    MissingKey,
+    /// The file doesn't match the cipher given
+    WrongKey,
+    /// The resume data was saved for another torrent, or for other
+    /// metadata (piece length, number of pieces)
+    Mismatch,
+}
+
+/// State of a download, to restart it without reading back all of
+/// its pieces, and its stats.
+///
+/// It's saved as a bencoded dictionary, `to_bytes` then `seal` give the
+/// content of the file
+// The fields are declared in the order of their keys, bencode
+// dictionaries are sorted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeData {
+    /// Verified pieces, in the format of the BITFIELD message
+    #[serde(with = "serde_bytes")]
+    pub bitfield: Vec<u8>,
+    /// The download completed and `Completed` was announced, it's not
+    /// announced again after a restart
+    #[serde(default)]
+    pub completed: bool,
+    /// Payload downloaded in all the sessions, in bytes
+    #[serde(default)]
+    pub downloaded: u64,
+    #[serde(with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+    /// Blocks received of the pieces not completed, they're not on
+    /// the disk until their piece is verified
+    #[serde(default)]
+    pub partial: Vec<ResumeBlock>,
+    pub piece_length: u64,
+    /// Payload uploaded in all the sessions, in bytes
+    #[serde(default)]
+    pub uploaded: u64,
+}
+
+/// Data received of a piece, at `offset` in the piece
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeBlock {
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+    pub offset: u32,
+    pub piece: u32,
+}
+
+impl ResumeData {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        to_bytes(self).expect("ResumeData can't fail to serialize")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ResumeData, ResumeError> {
+        from_bytes(bytes).map_err(|_| ResumeError::InvalidFormat)
+    }
+
+    /// Whether the data was saved for this torrent and its current
+    /// metadata. Trusting a bitfield of other metadata would mark as
+    /// verified pieces never checked
+    pub fn check(&self, torrent: &Torrent) -> Result<(), ResumeError> {
+        let info = &torrent.meta.info;
+        let num_pieces = info.pieces.len() / 20;
+
+        if self.info_hash[..] != torrent.info_hash[..]
+            || self.piece_length != info.piece_length
+            || self.bitfield.len() != num_pieces.div_ceil(8)
+        {
+            return Err(ResumeError::Mismatch);
+        }
+
+        let total_size = torrent.files_total_size() as u64;
+        let outside = self.partial.iter().any(|block| {
+            let start = block.piece as u64 * info.piece_length;
+            let piece_end = (start + info.piece_length).min(total_size);
+            let end = start + block.offset as u64 + block.data.len() as u64;
+
+            block.piece as usize >= num_pieces || end > piece_end
+        });
+
+        if outside {
+            return Err(ResumeError::Mismatch);
+        }
+
+        Ok(())
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.bitfield
+            .get(index / 8)
+            .map(|byte| byte & (0x80 >> (index % 8)) != 0)
+            .unwrap_or(false)
+    }
+}
+
+/// Prepare `data` to be written to the disk, encrypted when a cipher
+/// is given
+pub fn seal(cipher: Option<&dyn ResumeCipher>, data: &[u8]) -> Vec<u8> {
+    let mut file = MAGIC.to_vec();
+
+    match cipher {
+        Some(cipher) => {
+            file.push(ENCRYPTED);
+            file.extend_from_slice(&cipher.encrypt(data));
+        }
+        None => {
+            file.push(PLAIN);
+            file.extend_from_slice(data);
+        }
+    }
+
+    file
+}
+
+/// Returns the data of a file written by `seal`.
+///
+/// With a cipher, a plain file is rejected like a file encrypted with
+/// another key: it could have been replaced by someone without the key
+pub fn open(cipher: Option<&dyn ResumeCipher>, file: &[u8]) -> Result<Vec<u8>, ResumeError> {
+    if file.len() < MAGIC.len() + 1 || &file[..MAGIC.len()] != MAGIC {
+        return Err(ResumeError::InvalidFormat);
+    }
+
+    let content = &file[MAGIC.len() + 1..];
+
+    match (file[MAGIC.len()], cipher) {
+        (PLAIN, None) => Ok(content.to_vec()),
+        (PLAIN, Some(_)) => Err(ResumeError::WrongKey),
+        (ENCRYPTED, None) => Err(ResumeError::MissingKey),
+        (ENCRYPTED, Some(cipher)) => cipher.decrypt(content).ok_or(ResumeError::WrongKey),
+        _ => Err(ResumeError::InvalidFormat),
+    }
+}
+
+/// Stand-in for a real AEAD, the tests only need the key to matter:
+/// the key is appended as the tag
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct XorCipher(pub u8);
+
+#[cfg(test)]
+impl ResumeCipher for XorCipher {
+    fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        let mut encrypted: Vec<u8> = data.iter().map(|b| b ^ self.0).collect();
+        encrypted.push(self.0);
+        encrypted
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+        match data.split_last() {
+            Some((&tag, data)) if tag == self.0 => Some(data.iter().map(|b| b ^ self.0).collect()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open, seal, ResumeBlock, ResumeData, ResumeError, XorCipher};
+    use crate::metadata::TestTorrent;
+
+    #[test]
+    fn encrypted_round_trip() {
+        let data = b"d8:bitfield2:\xff\x0fe";
+        let key = XorCipher(0x5A);
+
+        let file = seal(Some(&key), data);
+        assert!(!file.windows(data.len()).any(|w| w == data));
+
+        assert_eq!(open(Some(&key), &file).unwrap(), data);
+
+        let plain = seal(None, data);
+        assert_eq!(open(None, &plain).unwrap(), data);
+        assert_eq!(open(Some(&key), &plain), Err(ResumeError::WrongKey));
+    }
+
+    #[test]
+    fn wrong_key() {
+        let file = seal(Some(&XorCipher(0x5A)), b"d8:bitfield2:\xff\x0fe");
+
+        assert_eq!(
+            open(Some(&XorCipher(0x33)), &file),
+            Err(ResumeError::WrongKey)
+        );
+        assert_eq!(open(None, &file), Err(ResumeError::MissingKey));
+        assert_eq!(open(None, b"d8:bitfield"), Err(ResumeError::InvalidFormat));
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        // 3 pieces, the last one of 500 bytes
+        let torrent = TestTorrent::new().length(2500).build();
+
+        let resume = ResumeData {
+            bitfield: vec![0b1100_0000],
+            completed: false,
+            downloaded: 3000,
+            info_hash: vec![7; 20],
+            partial: vec![ResumeBlock {
+                data: vec![3; 200],
+                offset: 300,
+                piece: 2,
+            }],
+            piece_length: 1000,
+            uploaded: 500,
+        };
+
+        let bytes = resume.to_bytes();
+        let read = ResumeData::from_bytes(&bytes).unwrap();
+        assert_eq!(read, resume);
+        assert_eq!(read.check(&torrent), Ok(()));
+        assert!(read.has_piece(1) && !read.has_piece(2));
+
+        assert_eq!(
+            ResumeData::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ResumeError::InvalidFormat)
+        );
+
+        // A bitfield of 9 pieces
+        let other = ResumeData {
+            bitfield: vec![0b1100_0000, 0],
+            ..resume.clone()
+        };
+        assert_eq!(other.check(&torrent), Err(ResumeError::Mismatch));
+
+        // Past the end of the last piece
+        let mut other = resume;
+        other.partial[0].offset = 400;
+        assert_eq!(other.check(&torrent), Err(ResumeError::Mismatch));
+    }
+}
@@ -1,16 +1,31 @@
-use std::sync::Arc;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    fs::{standard_fs::StandardFS, uring_fs::UringFS, FSMessage, FileSystem},
+    errors::AddError,
+    fs::{
+        backend::{BackendFS, StorageBackend},
+        standard_fs::StandardFS,
+        uring_fs::{UringFS, UringInitError},
+        AllocationMode, FSMessage, FileSystem,
+    },
     logger,
     metadata::Torrent,
+    metrics::Metrics,
+    peer::peer::PeerExternId,
 };
 //use crate::http_client::{self, AnnounceQuery, AnnounceResponse};
 
 //use crate::http_client::HttpError;
-use async_channel::Sender;
-use crossbeam_channel::{unbounded, Receiver as SyncReceiver, Sender as SyncSender};
+use async_channel::{Receiver, Sender, TrySendError};
+use crossbeam_channel::{bounded, unbounded, Receiver as SyncReceiver, Sender as SyncSender};
+use hashbrown::HashMap;
+use std::collections::VecDeque;
 
+use kv_log_macro::{debug, error, warn};
 use tokio::runtime::Runtime;
 // enum MessageActor {
 //     AddPeer(PeerAddr),
@@ -18,55 +33,737 @@ use tokio::runtime::Runtime;
 // }
 
 // type PeerAddr = Sender<MessageActor>;
-use crate::supervisors::torrent::TorrentSupervisor;
+use crate::{
+    rate_limit::BandwidthLimits,
+    resume::{self, ResumeCipher, ResumeData},
+    supervisors::torrent::{
+        ByteCounters, ByteStats, FileProgress, PeerOrigin, PieceEvent, PiecesDebug, TorrentEvent,
+        TorrentGauges, TorrentNotification, TorrentOptions, TorrentStatus, TorrentSupervisor,
+    },
+    utils::send_to,
+};
+
+use crate::actors::{
+    listener::{IncomingPeer, ListenerActor, ServedTorrents},
+    peer_source::{PeerSource, PeerSourceActor},
+    sha1::{Sha1Task, Sha1Workers},
+    tracker::batch::AnnounceBatcher,
+};
+
+/// Actor storing the data of the torrents
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FsBackend {
+    IoUring,
+    /// Blocking reads and writes, when io_uring is unavailable
+    Standard,
+    /// A `StorageBackend` given to `Session::with_storage`
+    Custom,
+}
+
+/// Configuration of the session
+#[derive(Debug, Default, Clone)]
+pub struct SessionConfig {
+    /// Maximum number of torrents downloading at the same time,
+    /// the others are queued. `None` for no limit
+    pub max_active_downloads: Option<usize>,
+    /// Maximum number of torrents seeding at the same time.
+    /// This only applies to torrents added complete (read-only),
+    /// a download finishing is never stopped
+    pub max_active_seeds: Option<usize>,
+    /// Maximum disk write rate of all torrents, in bytes per second.
+    /// 0 means unlimited
+    pub max_disk_write_rate: u64,
+    /// Announce the torrents sharing an HTTP tracker in a single request,
+    /// when the tracker supports it
+    pub batch_announces: bool,
+    /// Never upload, for all torrents. See `TorrentOptions::no_upload`
+    pub no_upload: bool,
+    /// Labels added to all torrents of the session, after their own
+    pub labels: Vec<String>,
+    /// Torrents with more pieces are rejected when added, the memory of
+    /// the piece picker and of the bitfields grows with it.
+    /// `None` for `DEFAULT_MAX_PIECES`
+    pub max_pieces: Option<usize>,
+    /// Torrents larger than this, in bytes, are rejected when added.
+    /// `None` for `DEFAULT_MAX_TORRENT_SIZE`
+    pub max_torrent_size: Option<u64>,
+    /// Torrents starting during this window after the session creation
+    /// have their first announce and peer connections spread over it,
+    /// instead of all at once. Zero to disable
+    pub startup_ramp: Duration,
+    /// Addresses accepting the peers of all torrents, for example an
+    /// IPv4 and an IPv6 address. The port 0 binds any available port,
+    /// see `Session::listen_addrs`. Empty to accept no connection
+    pub listen_addrs: Vec<SocketAddr>,
+    /// Port forwarded to the listen port by the router (UPnP, ..),
+    /// announced to the trackers instead of the listen port. `None`
+    /// when unknown. There is no DHT, it isn't announced to any node
+    pub external_port: Option<u16>,
+    /// Torrents are paused when a write would leave less free space
+    /// on their disk, in bytes, and resumed once there is room.
+    /// 0 means no limit
+    pub min_free_disk_space: u64,
+    /// Threads hashing the pieces. 0 for `default_sha1_workers()`
+    pub sha1_workers: usize,
+    /// `Standard` to never use io_uring. With `None` or `IoUring`,
+    /// io_uring is used when it's available. `Custom` is ignored,
+    /// see `Session::with_storage`
+    pub fs_backend: Option<FsBackend>,
+    /// Maximum payload received from all the peers, in bytes per second.
+    /// `None` for no limit
+    pub max_download_bps: Option<u64>,
+    /// Maximum payload sent to all the peers, in bytes per second.
+    /// `None` for no limit
+    pub max_upload_bps: Option<u64>,
+    /// `Preallocate` gives the files their full size when a torrent is
+    /// added, a torrent without room on the disk is paused with
+    /// `TorrentEvent::AllocationFailed`. Ignored by `Session::with_storage`
+    pub allocation: AllocationMode,
+    /// Encryption of the files of `Session::export_resume_file`. `None`
+    /// to write them in plain.
+    /// rustorrent ships no cipher: the embedder implements `ResumeCipher`
+    /// with an AEAD of its crypto library
+    pub resume_cipher: Option<Arc<dyn ResumeCipher>>,
+}
+
+/// One thread per core, up to 4
+pub fn default_sha1_workers() -> usize {
+    num_cpus::get().min(4)
+}
+
+/// 4 Mi pieces, a 512 KiB bitfield per peer
+pub const DEFAULT_MAX_PIECES: usize = 1 << 22;
+
+/// 16 TiB
+pub const DEFAULT_MAX_TORRENT_SIZE: u64 = 1 << 44;
+
+/// Maximum duration of the shutdown of all torrents
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of steps of the startup ramp, torrents starting on
+/// the same step announce together
+const RAMP_STEPS: u32 = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum QueueState {
+    /// Waiting for an active slot
+    Queued,
+    Downloading,
+    /// Downloading but without any progress, it doesn't count
+    /// as an active download
+    Stalled,
+    Seeding,
+    /// The torrent didn't start, its files conflict with existing ones
+    Conflict,
+}
+
+/// A torrent in the session
+struct TorrentHandle {
+    addr: Sender<TorrentNotification>,
+    counters: Arc<ByteCounters>,
+    gauges: Arc<TorrentGauges>,
+    state: QueueState,
+    /// Whether the torrent starts as a seed
+    seed: bool,
+    /// The supervisor, until the torrent first leaves the queue. A
+    /// torrent queued again once started is paused instead
+    supervisor: Option<TorrentSupervisor>,
+    /// Labels of the torrent and of the session
+    labels: Vec<String>,
+}
 
-use crate::actors::sha1::{Sha1Task, Sha1Workers};
+impl TorrentHandle {
+    fn status(&self, info_hash: &Arc<[u8]>) -> TorrentStatus {
+        TorrentStatus {
+            labels: self.labels.clone(),
+            queued: self.state == QueueState::Queued,
+            ..self
+                .gauges
+                .status(Arc::clone(info_hash), self.counters.stats())
+        }
+    }
+}
 
 struct SessionInner {
     cmds: SyncReceiver<SessionCommand>,
-    actors: Vec<TorrentSupervisor>,
+    config: SessionConfig,
+    /// Torrents by info hash
+    torrents: HashMap<Arc<[u8]>, TorrentHandle>,
+    /// Queued torrents, in the order they were added
+    queue: VecDeque<Arc<[u8]>>,
+    events: SyncReceiver<TorrentEvent>,
+    events_sender: SyncSender<TorrentEvent>,
+    /// Peers accepted on the listen addresses
+    incoming: SyncReceiver<IncomingPeer>,
     sha1_workers: SyncSender<Sha1Task>,
     fs: Sender<FSMessage>,
+    announce_batcher: Option<AnnounceBatcher>,
+    /// Shared by the peers of all torrents
+    bandwidth: BandwidthLimits,
     runtime: Arc<Runtime>,
+    created: Instant,
+    /// Number of torrents started during the startup ramp
+    ramped: u32,
+    /// Paused with `Session::pause_all`, no torrent leaves the queue
+    paused: bool,
+    /// Our peer id, the same for all torrents
+    peer_id: Arc<PeerExternId>,
+    /// Port announced to the trackers: `SessionConfig::external_port`
+    /// or the first listen port. `None` without listen address
+    listen_port: Option<u16>,
+    /// Info hashes of `torrents`, the listener rejects the handshakes
+    /// of the others
+    served: ServedTorrents,
 }
 
 impl SessionInner {
-    fn start(&self) {
+    fn new(
+        cmds: SyncReceiver<SessionCommand>,
+        config: SessionConfig,
+        sha1_workers: SyncSender<Sha1Task>,
+        fs: Sender<FSMessage>,
+        runtime: Arc<Runtime>,
+        peer_id: Arc<PeerExternId>,
+    ) -> SessionInner {
+        let (events_sender, events) = unbounded();
+
+        let announce_batcher = if config.batch_announces {
+            let (batcher, actor) = AnnounceBatcher::new();
+            runtime.spawn(actor.start());
+            Some(batcher)
+        } else {
+            None
+        };
+
+        let bandwidth = BandwidthLimits::new(config.max_download_bps, config.max_upload_bps);
+
+        SessionInner {
+            cmds,
+            config,
+            torrents: HashMap::default(),
+            queue: VecDeque::new(),
+            events,
+            events_sender,
+            incoming: crossbeam_channel::never(),
+            sha1_workers,
+            fs,
+            announce_batcher,
+            bandwidth,
+            runtime,
+            created: Instant::now(),
+            ramped: 0,
+            paused: false,
+            peer_id,
+            listen_port: None,
+            served: ServedTorrents::default(),
+        }
+    }
+
+    /// Route the peers received from the `ListenerActor`
+    fn set_incoming(&mut self, incoming: SyncReceiver<IncomingPeer>) {
+        self.incoming = incoming;
+    }
+
+    fn start(&mut self) {
         // self.runtime.enter();
-        self.runtime.block_on(async { self.start_session() })
+        let runtime = self.runtime.clone();
+        runtime.block_on(async { self.start_session() })
     }
 
-    fn start_session(&self) {
-        for cmd in self.cmds.iter() {
-            self.dispatch(cmd);
+    fn start_session(&mut self) {
+        loop {
+            crossbeam_channel::select! {
+                recv(self.cmds) -> cmd => match cmd {
+                    Ok(SessionCommand::Shutdown) => {
+                        self.shutdown();
+                        return;
+                    }
+                    Ok(cmd) => self.dispatch(cmd),
+                    Err(_) => return,
+                },
+                recv(self.events) -> event => {
+                    if let Ok(event) = event {
+                        self.on_event(event);
+                    }
+                }
+                recv(self.incoming) -> peer => match peer {
+                    Ok(peer) => self.route_incoming(peer),
+                    // No listener, don't wake up on the closed channel
+                    Err(_) => self.incoming = crossbeam_channel::never(),
+                },
+            }
         }
     }
 
-    fn dispatch(&self, cmd: SessionCommand) {
-        use SessionCommand::*;
+    /// Shut down the torrents out of the queue and wait for them
+    fn shutdown(&mut self) {
+        let (done, finished) = bounded(self.torrents.len());
+        let mut started = 0;
+
+        for torrent in self.torrents.values() {
+            if torrent.supervisor.is_none() {
+                send_to(
+                    &torrent.addr,
+                    TorrentNotification::Shutdown { done: done.clone() },
+                );
+                started += 1;
+            }
+        }
+
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        for _ in 0..started {
+            if finished.recv_deadline(deadline).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn count_state(&self, state: QueueState) -> usize {
+        self.torrents.values().filter(|t| t.state == state).count()
+    }
+
+    fn has_slot(&self, seed: bool) -> bool {
+        let (max, state) = if seed {
+            (self.config.max_active_seeds, QueueState::Seeding)
+        } else {
+            (self.config.max_active_downloads, QueueState::Downloading)
+        };
+
+        max.map(|max| self.count_state(state) < max).unwrap_or(true)
+    }
+
+    /// Start the queued torrents while there are slots available
+    fn promote_queued(&mut self) {
+        if self.paused {
+            return;
+        }
+
+        let mut index = 0;
+
+        while let Some(info_hash) = self.queue.get(index) {
+            let seed = match self.torrents.get(info_hash) {
+                Some(torrent) => torrent.seed,
+                None => {
+                    self.queue.remove(index);
+                    continue;
+                }
+            };
+
+            if self.has_slot(seed) {
+                let info_hash = self.queue.remove(index).unwrap();
+                self.start_torrent(&info_hash);
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    fn start_torrent(&mut self, info_hash: &[u8]) {
+        let torrent = match self.torrents.get_mut(info_hash) {
+            Some(torrent) => torrent,
+            None => return,
+        };
+
+        torrent.state = if torrent.seed {
+            QueueState::Seeding
+        } else {
+            QueueState::Downloading
+        };
+
+        match torrent.supervisor.take() {
+            Some(mut supervisor) => {
+                supervisor.set_start_delay(self.ramp_delay());
 
-        match cmd {
-            AddTorrent(torrent) => {
-                let sha1_workers = self.sha1_workers.clone();
-                let vfs = self.fs.clone();
                 tokio::spawn(async move {
-                    TorrentSupervisor::new(torrent, sha1_workers, vfs)
-                        .start()
-                        .await;
+                    supervisor.start().await;
                 });
             }
+            // Paused when it was queued again
+            None => send_to(&torrent.addr, TorrentNotification::Resume),
+        }
+    }
+
+    /// Delay of the torrent starting now, during the startup ramp
+    fn ramp_delay(&mut self) -> Duration {
+        let ramp = self.config.startup_ramp;
+
+        if self.created.elapsed() >= ramp {
+            return Duration::from_secs(0);
+        }
+
+        // After the last step, the torrents go around the steps again
+        let delay = ramp / RAMP_STEPS * (self.ramped % RAMP_STEPS);
+        self.ramped += 1;
+        delay
+    }
+
+    fn on_event(&mut self, event: TorrentEvent) {
+        let (info_hash, state) = match &event {
+            TorrentEvent::Completed { info_hash } => (info_hash, QueueState::Seeding),
+            TorrentEvent::Stalled { info_hash } => (info_hash, QueueState::Stalled),
+            TorrentEvent::Resumed { info_hash } => (info_hash, QueueState::Downloading),
+            TorrentEvent::VerificationFailed { info_hash, .. } => {
+                (info_hash, QueueState::Downloading)
+            }
+            TorrentEvent::FileConflict { info_hash, .. } => (info_hash, QueueState::Conflict),
+            // Paused, it leaves its active slot to the others
+            TorrentEvent::AllocationFailed { info_hash, .. } => (info_hash, QueueState::Stalled),
+        };
+
+        // A stalled torrent making progress again needs an active slot,
+        // it waits in the queue like the others when there is none
+        let requeue = state == QueueState::Downloading
+            && !self.paused
+            && !self.has_slot(false)
+            && self
+                .torrents
+                .get(info_hash)
+                .map(|torrent| torrent.state == QueueState::Stalled)
+                .unwrap_or(false);
+
+        if let Some(torrent) = self.torrents.get_mut(info_hash) {
+            if requeue {
+                send_to(&torrent.addr, TorrentNotification::Pause);
+                torrent.state = QueueState::Queued;
+                self.queue.push_back(Arc::clone(info_hash));
+            } else if torrent.state != QueueState::Queued {
+                torrent.state = state;
+            }
+        }
+
+        self.promote_queued();
+    }
+
+    /// Send the peer to the torrent of its handshake. It's dropped, and
+    /// disconnected, when the torrent is unknown or still queued
+    fn route_incoming(&self, peer: IncomingPeer) {
+        let torrent = match self.torrents.get(&peer.info_hash[..]) {
+            Some(torrent) if torrent.supervisor.is_none() => torrent,
+            _ => {
+                debug!("Incoming peer for an unknown torrent", { addr: peer.addr.to_string() });
+                return;
+            }
+        };
+
+        send_to(
+            &torrent.addr,
+            TorrentNotification::Accepted {
+                peer: Box::new(peer),
+            },
+        );
+    }
+
+    /// Send a message to the torrents out of the queue
+    fn notify_started<F>(&self, msg: F)
+    where
+        F: Fn() -> TorrentNotification,
+    {
+        for torrent in self.torrents.values() {
+            if torrent.supervisor.is_none() && torrent.state != QueueState::Queued {
+                send_to(&torrent.addr, msg());
+            }
+        }
+    }
+
+    fn dispatch(&mut self, cmd: SessionCommand) {
+        use SessionCommand::*;
+
+        match cmd {
+            AddTorrent {
+                torrent,
+                mut options,
+                respond,
+            } => {
+                options.no_upload |= self.config.no_upload;
+
+                let mut labels = options.labels.clone();
+                for label in &self.config.labels {
+                    if !labels.contains(label) {
+                        labels.push(label.clone());
+                    }
+                }
+
+                let info_hash = Arc::clone(&torrent.info_hash);
+                if self.torrents.contains_key(&info_hash) {
+                    respond.try_send(Err(AddError::Duplicate)).ok();
+                    return;
+                }
+
+                let seed = options.read_only;
+                let mut supervisor = TorrentSupervisor::new(
+                    *torrent,
+                    options,
+                    self.sha1_workers.clone(),
+                    self.fs.clone(),
+                );
+                supervisor.set_events(self.events_sender.clone());
+                supervisor.set_extern_id(Arc::clone(&self.peer_id));
+                if let Some(port) = self.listen_port {
+                    supervisor.set_listen_port(port);
+                }
+                if let Some(batcher) = self.announce_batcher.clone() {
+                    supervisor.set_announce_batcher(batcher);
+                }
+                supervisor.set_bandwidth_limits(self.bandwidth.clone());
+
+                self.torrents.insert(
+                    Arc::clone(&info_hash),
+                    TorrentHandle {
+                        addr: supervisor.addr(),
+                        counters: supervisor.counters(),
+                        gauges: supervisor.gauges(),
+                        state: QueueState::Queued,
+                        seed,
+                        supervisor: Some(supervisor),
+                        labels,
+                    },
+                );
+
+                self.served.write().insert(Arc::clone(&info_hash));
+                self.queue.push_back(info_hash);
+                self.promote_queued();
+
+                respond.try_send(Ok(())).ok();
+            }
+            RemoveTorrent { info_hash, respond } => {
+                let torrent = match self.torrents.remove(&info_hash) {
+                    Some(torrent) => torrent,
+                    None => {
+                        respond.try_send(false).ok();
+                        return;
+                    }
+                };
+
+                self.served.write().remove(&info_hash);
+
+                self.queue.retain(|queued| *queued != info_hash);
+
+                // A queued supervisor is only dropped, otherwise it
+                // disconnects its peers and flushes the disk first
+                if torrent.supervisor.is_none() {
+                    let (done, _) = bounded(1);
+                    send_to(&torrent.addr, TorrentNotification::Shutdown { done });
+                }
+
+                self.promote_queued();
+
+                respond.try_send(true).ok();
+            }
+            AddPeers { info_hash, addrs } => {
+                if let Some(torrent) = self.torrents.get(&info_hash) {
+                    send_to(
+                        &torrent.addr,
+                        TorrentNotification::PeerDiscovered {
+                            addrs,
+                            origin: PeerOrigin::Manual,
+                        },
+                    );
+                }
+            }
+            AddPeerSource { info_hash, source } => {
+                if let Some(torrent) = self.torrents.get(&info_hash) {
+                    let supervisor = torrent.addr.clone();
+                    tokio::spawn(async move {
+                        PeerSourceActor::new(source, supervisor).start().await;
+                    });
+                }
+            }
+            DebugPieces { info_hash, respond } => {
+                // When the torrent doesn't exist, `respond` is dropped.
+                // A queued supervisor isn't running, it's asked here
+                match self.torrents.get(&info_hash) {
+                    Some(TorrentHandle {
+                        supervisor: Some(supervisor),
+                        ..
+                    }) => {
+                        respond.try_send(supervisor.pieces_debug()).ok();
+                    }
+                    Some(torrent) => {
+                        send_to(&torrent.addr, TorrentNotification::DebugPieces { respond });
+                    }
+                    None => {}
+                }
+            }
+            SetPlaybackPosition { info_hash, offset } => {
+                if let Some(torrent) = self.torrents.get(&info_hash) {
+                    send_to(
+                        &torrent.addr,
+                        TorrentNotification::SetPlaybackPosition { offset },
+                    );
+                }
+            }
+            TorrentFiles { info_hash, respond } => match self.torrents.get(&info_hash) {
+                Some(TorrentHandle {
+                    supervisor: Some(supervisor),
+                    ..
+                }) => {
+                    respond.try_send(supervisor.files_progress()).ok();
+                }
+                Some(torrent) => {
+                    send_to(&torrent.addr, TorrentNotification::TorrentFiles { respond });
+                }
+                None => {}
+            },
+            ExportResume { info_hash, respond } => match self.torrents.get(&info_hash) {
+                // The resume data given to a queued torrent is kept
+                Some(TorrentHandle {
+                    supervisor: Some(supervisor),
+                    ..
+                }) => {
+                    respond.try_send(supervisor.export_resume()).ok();
+                }
+                Some(torrent) => {
+                    send_to(&torrent.addr, TorrentNotification::ExportResume { respond });
+                }
+                None => {}
+            },
+            Status { info_hash, respond } => {
+                if let Some((info_hash, torrent)) = self.torrents.get_key_value(&info_hash) {
+                    respond.try_send(torrent.status(info_hash)).ok();
+                }
+            }
+            ByteStats { info_hash, respond } => {
+                if let Some(torrent) = self.torrents.get(&info_hash) {
+                    respond.try_send(torrent.counters.stats()).ok();
+                }
+            }
+            SubscribePieces { info_hash, sender } => {
+                if let Some(torrent) = self.torrents.get(&info_hash) {
+                    send_to(
+                        &torrent.addr,
+                        TorrentNotification::SubscribePieces { sender },
+                    );
+                }
+            }
+            PauseAll => {
+                self.paused = true;
+                self.notify_started(|| TorrentNotification::Pause);
+            }
+            ResumeAll => {
+                self.paused = false;
+                self.notify_started(|| TorrentNotification::Resume);
+                self.promote_queued();
+            }
+            Recheck { info_hash } => {
+                if let Some(torrent) = self.torrents.get(&info_hash) {
+                    send_to(&torrent.addr, TorrentNotification::Recheck);
+                }
+            }
+            TorrentsByLabel { label, respond } => {
+                let statuses = self
+                    .torrents
+                    .iter()
+                    .filter(|(_, torrent)| torrent.labels.contains(&label))
+                    .map(|(info_hash, torrent)| torrent.status(info_hash))
+                    .collect();
+
+                respond.try_send(statuses).ok();
+            }
+            Metrics { respond } => {
+                respond.try_send(self.metrics()).ok();
+            }
+            // Handled in `start_session`, it stops the loop
+            Shutdown => {}
+        }
+    }
+
+    fn metrics(&self) -> Metrics {
+        let mut metrics = Metrics {
+            torrents: self.torrents.len(),
+            disk_queue: self.fs.len(),
+            ..Default::default()
+        };
+
+        for torrent in self.torrents.values() {
+            match torrent.state {
+                QueueState::Queued => metrics.queued += 1,
+                QueueState::Conflict => {}
+                _ => metrics.active += 1,
+            }
+            metrics.peers += torrent.gauges.peers();
+            metrics.pieces_verified += torrent.gauges.pieces_verified();
+            metrics.add_bytes(torrent.counters.stats());
         }
+
+        metrics
     }
 }
 
 enum SessionCommand {
-    AddTorrent(Torrent),
+    AddTorrent {
+        torrent: Box<Torrent>,
+        options: TorrentOptions,
+        respond: SyncSender<Result<(), AddError>>,
+    },
+    RemoveTorrent {
+        info_hash: Arc<[u8]>,
+        respond: SyncSender<bool>,
+    },
+    AddPeers {
+        info_hash: Arc<[u8]>,
+        addrs: Box<[SocketAddr]>,
+    },
+    AddPeerSource {
+        info_hash: Arc<[u8]>,
+        source: Box<dyn PeerSource>,
+    },
+    DebugPieces {
+        info_hash: Arc<[u8]>,
+        respond: SyncSender<PiecesDebug>,
+    },
+    SetPlaybackPosition {
+        info_hash: Arc<[u8]>,
+        offset: u64,
+    },
+    TorrentFiles {
+        info_hash: Arc<[u8]>,
+        respond: SyncSender<Vec<FileProgress>>,
+    },
+    ExportResume {
+        info_hash: Arc<[u8]>,
+        respond: SyncSender<ResumeData>,
+    },
+    ByteStats {
+        info_hash: Arc<[u8]>,
+        respond: SyncSender<ByteStats>,
+    },
+    Status {
+        info_hash: Arc<[u8]>,
+        respond: SyncSender<TorrentStatus>,
+    },
+    SubscribePieces {
+        info_hash: Arc<[u8]>,
+        sender: Sender<PieceEvent>,
+    },
+    Recheck {
+        info_hash: Arc<[u8]>,
+    },
+    PauseAll,
+    ResumeAll,
+    TorrentsByLabel {
+        label: String,
+        respond: SyncSender<Vec<TorrentStatus>>,
+    },
+    Metrics {
+        respond: SyncSender<Metrics>,
+    },
+    Shutdown,
 }
 
 pub struct Session {
-    handle: std::thread::JoinHandle<()>,
+    /// `None` once the session is shut down
+    handle: Option<std::thread::JoinHandle<()>>,
     actor: SyncSender<SessionCommand>,
-    runtime: Arc<Runtime>,
+    /// Bound addresses of `SessionConfig::listen_addrs`
+    listen_addrs: Vec<SocketAddr>,
+    max_pieces: usize,
+    max_torrent_size: u64,
+    peer_id: Arc<PeerExternId>,
+    fs_backend: FsBackend,
+    resume_cipher: Option<Arc<dyn ResumeCipher>>,
+    /// The pool of the session thread, the tests check its size
+    #[cfg(test)]
+    sha1_workers: SyncSender<Sha1Task>,
 }
 
 impl Default for Session {
@@ -75,40 +772,1478 @@ impl Default for Session {
     }
 }
 
+/// Give the settings of the config to the file system, before any
+/// torrent is added. The messages are kept in order when its queue is
+/// full; a custom file system might have stopped already
+fn send_fs_settings(runtime: &Runtime, fs: &Sender<FSMessage>, config: &SessionConfig) {
+    let mut settings = vec![
+        FSMessage::SetWriteRate {
+            bytes_per_sec: config.max_disk_write_rate,
+        },
+        FSMessage::SetMinFreeSpace {
+            bytes: config.min_free_disk_space,
+        },
+        FSMessage::SetAllocation {
+            mode: config.allocation,
+        },
+    ]
+    .into_iter();
+
+    while let Some(msg) = settings.next() {
+        match fs.try_send(msg) {
+            Ok(()) => {}
+            Err(TrySendError::Full(msg)) => {
+                let fs = fs.clone();
+                runtime.spawn(async move {
+                    for msg in std::iter::once(msg).chain(settings) {
+                        if fs.send(msg).await.is_err() {
+                            error!("File system stopped, its settings are not applied");
+                            return;
+                        }
+                    }
+                });
+                return;
+            }
+            Err(TrySendError::Closed(_)) => {
+                error!("File system stopped, its settings are not applied");
+                return;
+            }
+        }
+    }
+}
+
+/// Start the io_uring actor with `init`, or `StandardFS` when it fails
+fn init_fs(
+    runtime: &Arc<Runtime>,
+    init: impl FnOnce(Arc<Runtime>) -> Result<Sender<FSMessage>, UringInitError>,
+) -> (Sender<FSMessage>, FsBackend) {
+    logger::start();
+
+    match init(runtime.clone()) {
+        Ok(fs) => (fs, FsBackend::IoUring),
+        Err(e) => {
+            warn!(
+                "io_uring unavailable, using the standard file system: {:?}",
+                e
+            );
+            (StandardFS::new(runtime.clone()), FsBackend::Standard)
+        }
+    }
+}
+
 impl Session {
     pub fn new() -> Session {
+        Session::with_config(SessionConfig::default())
+    }
+
+    pub fn with_config(config: SessionConfig) -> Session {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let (fs, fs_backend) = match config.fs_backend {
+            Some(FsBackend::Standard) => (StandardFS::new(runtime.clone()), FsBackend::Standard),
+            _ => init_fs(&runtime, UringFS::init),
+        };
+
+        Session::start(config, runtime, fs, fs_backend)
+    }
+
+    /// Store the data of the torrents with `backend`, instead of files
+    pub fn with_storage<B: StorageBackend>(config: SessionConfig, backend: B) -> Session {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let fs = BackendFS::new(runtime.clone(), backend);
+
+        Session::start(config, runtime, fs, FsBackend::Custom)
+    }
+
+    fn start(
+        config: SessionConfig,
+        runtime: Arc<Runtime>,
+        fs: Sender<FSMessage>,
+        fs_backend: FsBackend,
+    ) -> Session {
         logger::start();
 
         let (sender, receiver) = unbounded();
-        let runtime = Arc::new(Runtime::new().unwrap());
-        let fs = match UringFS::init(runtime.clone()) {
-            Some(fs) => fs,
-            _ => StandardFS::new(runtime.clone()),
+        send_fs_settings(&runtime, &fs, &config);
+        let nworkers = match config.sha1_workers {
+            0 => default_sha1_workers(),
+            n => n,
         };
-        let sha1_workers = Sha1Workers::new_pool(runtime.clone(), fs.clone());
-        let runtime_clone = runtime.clone();
+        let sha1_workers = Sha1Workers::new_pool(runtime.clone(), fs.clone(), nworkers);
+        #[cfg(test)]
+        let test_sha1_workers = sha1_workers.clone();
+        let max_pieces = config.max_pieces.unwrap_or(DEFAULT_MAX_PIECES);
+        let max_torrent_size = config.max_torrent_size.unwrap_or(DEFAULT_MAX_TORRENT_SIZE);
+        let peer_id = Arc::new(PeerExternId::generate());
+
+        let listeners: Vec<_> = config
+            .listen_addrs
+            .iter()
+            .filter_map(|addr| match std::net::TcpListener::bind(addr) {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    warn!("Can't listen on {}: {:?}", addr, e);
+                    None
+                }
+            })
+            .collect();
+        let listen_addrs: Vec<SocketAddr> = listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect();
+        let external_port = config.external_port;
+        let resume_cipher = config.resume_cipher.clone();
+        let listen_port = external_port.or_else(|| listen_addrs.first().map(SocketAddr::port));
 
+        let served = ServedTorrents::default();
+        let (incoming_sender, incoming) = unbounded();
+        if !listeners.is_empty() {
+            runtime
+                .spawn(ListenerActor::new(listeners, Arc::clone(&served), incoming_sender).start());
+        }
+
+        // The runtime is dropped by the session thread: it can't be
+        // dropped in the async context of the caller
+        let peer_id_clone = Arc::clone(&peer_id);
         let handle = std::thread::spawn(move || {
-            let session = SessionInner {
-                cmds: receiver,
-                actors: vec![],
-                sha1_workers,
-                runtime: runtime_clone,
-                fs,
-            };
+            let mut session =
+                SessionInner::new(receiver, config, sha1_workers, fs, runtime, peer_id_clone);
+            session.set_incoming(incoming);
+            session.listen_port = listen_port;
+            session.served = served;
             session.start();
         });
 
         Session {
-            handle,
+            handle: Some(handle),
             actor: sender,
-            runtime,
+            listen_addrs,
+            max_pieces,
+            max_torrent_size,
+            peer_id,
+            fs_backend,
+            resume_cipher,
+            #[cfg(test)]
+            sha1_workers: test_sha1_workers,
+        }
+    }
+
+    /// Addresses accepting the peers, with the ports bound when
+    /// `SessionConfig::listen_addrs` has the port 0
+    pub fn listen_addrs(&self) -> &[SocketAddr] {
+        &self.listen_addrs
+    }
+
+    /// Our peer id, sent to the trackers and the peers of all torrents.
+    /// Azureus-style: `-RR0001-` followed by 12 random characters
+    pub fn peer_id(&self) -> [u8; 20] {
+        **self.peer_id
+    }
+
+    /// Which actor stores the data, `Standard` when io_uring failed to
+    /// start. The reason is logged
+    pub fn fs_backend(&self) -> FsBackend {
+        self.fs_backend
+    }
+
+    pub fn add_torrent(&mut self, torrent: Torrent) -> Result<(), AddError> {
+        self.add_torrent_with_options(torrent, TorrentOptions::default())
+    }
+
+    /// Add a torrent downloaded in a previous session, its pieces in
+    /// `resume` are not downloaded again. Fails like
+    /// `add_torrent_with_options`, or when `resume` was saved for
+    /// another torrent or for other metadata
+    pub fn add_torrent_with_resume(
+        &mut self,
+        torrent: Torrent,
+        resume: ResumeData,
+    ) -> Result<(), AddError> {
+        resume.check(&torrent).map_err(AddError::Resume)?;
+
+        let options = TorrentOptions {
+            resume: Some(resume),
+            ..Default::default()
+        };
+        self.add_torrent_with_options(torrent, options)
+    }
+
+    /// Fails when the torrent exceeds `SessionConfig::max_pieces` or
+    /// `SessionConfig::max_torrent_size`, or when it's already in the
+    /// session
+    pub fn add_torrent_with_options(
+        &mut self,
+        torrent: Torrent,
+        options: TorrentOptions,
+    ) -> Result<(), AddError> {
+        let num_pieces = torrent.meta.info.pieces.len() / 20;
+        if num_pieces > self.max_pieces {
+            return Err(AddError::TooManyPieces {
+                num_pieces,
+                max: self.max_pieces,
+            });
+        }
+
+        let size = torrent.files_total_size() as u64;
+        if size > self.max_torrent_size {
+            return Err(AddError::TooLarge {
+                size,
+                max: self.max_torrent_size,
+            });
         }
+
+        let (respond, receiver) = bounded(1);
+
+        // The session thread checks for duplicates, it has all the torrents
+        self.actor
+            .send(SessionCommand::AddTorrent {
+                torrent: Box::new(torrent),
+                options,
+                respond,
+            })
+            .expect("Error contacting session");
+
+        receiver.recv().expect("Error contacting session")
+    }
+
+    /// Stop the torrent and remove it from the session: its peers are
+    /// disconnected and its data flushed to the disk, the files are kept.
+    /// Returns false if the torrent is not in the session
+    pub fn remove_torrent(&mut self, info_hash: &[u8]) -> bool {
+        let (respond, receiver) = bounded(1);
+
+        self.actor
+            .send(SessionCommand::RemoveTorrent {
+                info_hash: info_hash.into(),
+                respond,
+            })
+            .expect("Error contacting session");
+
+        receiver.recv().unwrap_or(false)
+    }
+
+    /// Connect the torrent to those peers, in addition to the ones
+    /// found by the trackers
+    pub fn add_peers(&self, info_hash: &[u8], addrs: Vec<SocketAddr>) {
+        self.actor
+            .send(SessionCommand::AddPeers {
+                info_hash: info_hash.into(),
+                addrs: addrs.into_boxed_slice(),
+            })
+            .expect("Error contacting session");
+    }
+
+    /// Register a custom source of peers for the torrent, used alongside
+    /// the trackers and PEX
+    pub fn add_peer_source(&self, info_hash: &[u8], source: Box<dyn PeerSource>) {
+        self.actor
+            .send(SessionCommand::AddPeerSource {
+                info_hash: info_hash.into(),
+                source,
+            })
+            .expect("Error contacting session");
+    }
+
+    /// Returns the bitfield of the verified pieces and the number
+    /// of pieces in each state.
+    /// `None` if the torrent is not in the session
+    pub fn debug_pieces(&self, info_hash: &[u8]) -> Option<PiecesDebug> {
+        let (respond, receiver) = bounded(1);
+
+        self.actor
+            .send(SessionCommand::DebugPieces {
+                info_hash: info_hash.into(),
+                respond,
+            })
+            .expect("Error contacting session");
+
+        receiver.recv().ok()
+    }
+
+    /// Move the read-ahead window of the torrent to this byte offset,
+    /// when streaming seeks. See `TorrentOptions::read_ahead`
+    pub fn set_playback_position(&self, info_hash: &[u8], offset: u64) {
+        self.actor
+            .send(SessionCommand::SetPlaybackPosition {
+                info_hash: info_hash.into(),
+                offset,
+            })
+            .expect("Error contacting session");
+    }
+
+    /// Returns the state of the download, to give to
+    /// `add_torrent_with_resume` in a later session.
+    /// `None` if the torrent is not in the session
+    pub fn export_resume(&self, info_hash: &[u8]) -> Option<ResumeData> {
+        let (respond, receiver) = bounded(1);
+
+        self.actor
+            .send(SessionCommand::ExportResume {
+                info_hash: info_hash.into(),
+                respond,
+            })
+            .expect("Error contacting session");
+
+        receiver.recv().ok()
+    }
+
+    /// `export_resume` as the content of a file, encrypted with
+    /// `SessionConfig::resume_cipher`.
+    /// `None` if the torrent is not in the session
+    pub fn export_resume_file(&self, info_hash: &[u8]) -> Option<Vec<u8>> {
+        let data = self.export_resume(info_hash)?.to_bytes();
+
+        Some(resume::seal(self.resume_cipher.as_deref(), &data))
+    }
+
+    /// `add_torrent_with_resume` with a file of `export_resume_file`.
+    /// Fails with `ResumeError::MissingKey` or `ResumeError::WrongKey`
+    /// when `SessionConfig::resume_cipher` is not the key of the file
+    pub fn add_torrent_with_resume_file(
+        &mut self,
+        torrent: Torrent,
+        file: &[u8],
+    ) -> Result<(), AddError> {
+        let data = resume::open(self.resume_cipher.as_deref(), file).map_err(AddError::Resume)?;
+        let resume = ResumeData::from_bytes(&data).map_err(AddError::Resume)?;
+
+        self.add_torrent_with_resume(torrent, resume)
+    }
+
+    /// Returns the files of the torrent, their sizes and the number
+    /// of bytes verified in each of them.
+    /// A single-file torrent has 1 entry.
+    /// `None` if the torrent is not in the session
+    pub fn torrent_files(&self, info_hash: &[u8]) -> Option<Vec<FileProgress>> {
+        let (respond, receiver) = bounded(1);
+
+        self.actor
+            .send(SessionCommand::TorrentFiles {
+                info_hash: info_hash.into(),
+                respond,
+            })
+            .expect("Error contacting session");
+
+        receiver.recv().ok()
+    }
+
+    /// Returns the bytes exchanged with the peers of the torrent,
+    /// with and without the protocol overhead
+    pub fn byte_stats(&self, info_hash: &[u8]) -> Option<ByteStats> {
+        let (respond, receiver) = bounded(1);
+
+        self.actor
+            .send(SessionCommand::ByteStats {
+                info_hash: info_hash.into(),
+                respond,
+            })
+            .expect("Error contacting session");
+
+        receiver.recv().ok()
+    }
+
+    /// Returns the progress of the torrent, for a UI polling it.
+    /// The bytes downloaded and the rate are updated every second.
+    /// `None` if the torrent is not in the session
+    pub fn status(&self, info_hash: &[u8]) -> Option<TorrentStatus> {
+        let (respond, receiver) = bounded(1);
+
+        self.actor
+            .send(SessionCommand::Status {
+                info_hash: info_hash.into(),
+                respond,
+            })
+            .expect("Error contacting session");
+
+        receiver.recv().ok()
+    }
+
+    /// Returns a stream of the pieces of the torrent passing or failing
+    /// their sha1 check, as they are checked.
+    /// The receiver is closed when the torrent is not in the session
+    pub fn subscribe_pieces(&self, info_hash: &[u8]) -> Receiver<PieceEvent> {
+        let (sender, receiver) = async_channel::unbounded();
+
+        self.actor
+            .send(SessionCommand::SubscribePieces {
+                info_hash: info_hash.into(),
+                sender,
+            })
+            .expect("Error contacting session");
+
+        receiver
+    }
+
+    /// Read the data of the torrent from the disk and check all its
+    /// pieces again. The download is paused during the check, the
+    /// corrupted pieces are then downloaded again
+    pub fn recheck(&self, info_hash: &[u8]) {
+        self.actor
+            .send(SessionCommand::Recheck {
+                info_hash: info_hash.into(),
+            })
+            .expect("Error contacting session");
+    }
+
+    /// Pause all torrents, before the computer goes to sleep: they are
+    /// disconnected from their peers and stop announcing, but stay in
+    /// the session with their state
+    pub fn pause_all(&self) {
+        self.actor
+            .send(SessionCommand::PauseAll)
+            .expect("Error contacting session");
+    }
+
+    /// Resume the torrents paused with `pause_all`
+    pub fn resume_all(&self) {
+        self.actor
+            .send(SessionCommand::ResumeAll)
+            .expect("Error contacting session");
+    }
+
+    /// Returns the torrents having this label, in no particular order
+    pub fn torrents_by_label(&self, label: &str) -> Vec<TorrentStatus> {
+        let (respond, receiver) = bounded(1);
+
+        self.actor
+            .send(SessionCommand::TorrentsByLabel {
+                label: label.to_string(),
+                respond,
+            })
+            .expect("Error contacting session");
+
+        receiver.recv().unwrap_or_default()
     }
 
-    pub fn add_torrent(&mut self, torrent: Torrent) {
+    /// Counters and gauges of the session, in the Prometheus text format
+    pub fn metrics_text(&self) -> String {
+        let (respond, receiver) = bounded(1);
+
         self.actor
-            .send(SessionCommand::AddTorrent(torrent))
+            .send(SessionCommand::Metrics { respond })
             .expect("Error contacting session");
+
+        receiver.recv().unwrap_or_default().to_prometheus()
+    }
+
+    /// Stop all the torrents and the session. For each torrent:
+    ///
+    /// 1. The peers are disconnected, nothing more is downloaded
+    /// 2. The trackers announce `Stopped`
+    /// 3. The data written is flushed to the disk
+    ///
+    /// This returns once all the torrents are stopped and the session
+    /// thread is joined, or after a timeout. Dropping the session does
+    /// the same
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    /// The other threads and the runtime stop once the session thread
+    /// dropped their channels and its runtime
+    fn stop(&mut self) {
+        let handle = match self.handle.take() {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        // The session thread is gone when it panicked
+        self.actor.send(SessionCommand::Shutdown).ok();
+
+        handle.join().ok();
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpListener},
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use async_trait::async_trait;
+    use tokio::runtime::Runtime;
+
+    use crate::{
+        actors::{peer_source::PeerSource, sha1::Sha1Task},
+        errors::AddError,
+        fs::FSMessage,
+        metadata::{TestTorrent, Torrent},
+        peer::peer::PeerExternId,
+        resume::{ResumeCipher, ResumeData, ResumeError, XorCipher},
+        supervisors::torrent::{TorrentEvent, TorrentOptions, TorrentStatus},
+    };
+
+    use super::{
+        init_fs, FsBackend, QueueState, Session, SessionCommand, SessionConfig, SessionInner,
+        UringInitError,
+    };
+
+    struct CustomSource(Option<Vec<SocketAddr>>);
+
+    #[async_trait]
+    impl PeerSource for CustomSource {
+        async fn next_peers(&mut self) -> Option<Vec<SocketAddr>> {
+            self.0.take()
+        }
+    }
+
+    fn torrent(info_hash: u8) -> Torrent {
+        TestTorrent::new()
+            .name("session_test")
+            .pieces(4)
+            .info_hash(info_hash)
+            .build()
+    }
+
+    fn wait_connection(listener: &TcpListener) -> bool {
+        listener.set_nonblocking(true).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while Instant::now() < deadline {
+            if listener.accept().is_ok() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        false
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn custom_peer_source() {
+        let listeners: Vec<_> = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let addrs = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+
+        let torrent = torrent(3);
+        let info_hash = Arc::clone(&torrent.info_hash);
+
+        let mut session = Session::new();
+        let options = TorrentOptions {
+            disable_trackers: true,
+            ..Default::default()
+        };
+
+        session.add_torrent_with_options(torrent, options).unwrap();
+        session.add_peer_source(&info_hash, Box::new(CustomSource(Some(addrs))));
+
+        for listener in &listeners {
+            assert!(wait_connection(listener), "Peer not dialed");
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn startup_ramp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let announce = format!("http://{}/announce", listener.local_addr().unwrap());
+
+        // Tracker recording when each announce arrives
+        let tracker = std::thread::spawn(move || {
+            let mut arrivals = Vec::new();
+
+            for stream in listener.incoming().take(5) {
+                let mut stream = stream.unwrap();
+                arrivals.push(Instant::now());
+
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..n]);
+                }
+
+                let body = b"d8:intervali1800e5:peers0:e";
+                let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                stream.write_all(header.as_bytes()).unwrap();
+                stream.write_all(body).unwrap();
+            }
+
+            arrivals
+        });
+
+        let mut session = Session::with_config(SessionConfig {
+            startup_ramp: Duration::from_millis(800),
+            ..Default::default()
+        });
+
+        for info_hash in 10..15 {
+            let mut torrent = torrent(info_hash);
+            torrent.meta.announce = Some(announce.clone());
+            session.add_torrent(torrent).unwrap();
+        }
+
+        let arrivals = tracker.join().unwrap();
+
+        // 100ms between each torrent, the announces didn't all arrive at once
+        let first = arrivals[0];
+        let spread = arrivals[4].saturating_duration_since(first);
+        assert!(spread >= Duration::from_millis(300), "{:?}", spread);
+        for pair in arrivals.windows(2) {
+            assert!(pair[1].saturating_duration_since(pair[0]) >= Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn pause_all() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let announce = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (events_sender, events) = crossbeam_channel::unbounded();
+
+        // Tracker sending the event of each announce
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..n]);
+                }
+
+                let request = String::from_utf8_lossy(&request);
+                let event = request
+                    .split(['&', ' '])
+                    .find_map(|param| param.strip_prefix("event="))
+                    .unwrap_or("")
+                    .to_string();
+                events_sender.send(event).ok();
+
+                let body = b"d8:intervali1800e5:peers0:e";
+                let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                stream.write_all(header.as_bytes()).unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+
+        let recv_event = || events.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        let mut session = Session::new();
+        let mut torrent = torrent(20);
+        let info_hash = Arc::clone(&torrent.info_hash);
+        torrent.meta.announce = Some(announce);
+        session.add_torrent(torrent).unwrap();
+
+        assert_eq!(recv_event(), "started");
+
+        session.pause_all();
+        assert_eq!(recv_event(), "stopped");
+
+        // Nothing is announced nor dialed while paused
+        let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+        session.add_peers(&info_hash, vec![peer.local_addr().unwrap()]);
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(events.try_recv().is_err());
+        peer.set_nonblocking(true).unwrap();
+        assert!(peer.accept().is_err());
+
+        session.resume_all();
+        assert_eq!(recv_event(), "started");
+        assert!(wait_connection(&peer), "Peer not dialed after resume");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn max_pieces() {
+        // The test torrents have 4 pieces of 1000 bytes
+        let mut session = Session::with_config(SessionConfig {
+            max_pieces: Some(3),
+            ..Default::default()
+        });
+        assert!(matches!(
+            session.add_torrent(torrent(30)),
+            Err(AddError::TooManyPieces {
+                num_pieces: 4,
+                max: 3
+            })
+        ));
+
+        let mut session = Session::with_config(SessionConfig {
+            max_torrent_size: Some(3999),
+            ..Default::default()
+        });
+        assert!(matches!(
+            session.add_torrent(torrent(31)),
+            Err(AddError::TooLarge {
+                size: 4000,
+                max: 3999
+            })
+        ));
+
+        let mut session = Session::with_config(SessionConfig {
+            max_pieces: Some(4),
+            max_torrent_size: Some(4000),
+            ..Default::default()
+        });
+        assert!(session.add_torrent(torrent(32)).is_ok());
+        assert!(session.torrent_files(&[32; 20]).is_some());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn query_queued_torrent() {
+        let mut session = Session::with_config(SessionConfig {
+            fs_backend: Some(FsBackend::Standard),
+            max_active_downloads: Some(1),
+            ..Default::default()
+        });
+        session.add_torrent(torrent(36)).unwrap();
+        session.add_torrent(torrent(37)).unwrap();
+
+        // None of the queries wait for the queued supervisor to run
+        let status = session.status(&[37; 20]).unwrap();
+        assert!(status.queued);
+        assert_eq!(status.total_pieces, 4);
+
+        let debug = session.debug_pieces(&[37; 20]).unwrap();
+        assert_eq!((debug.num_pieces, debug.missing, debug.verified), (4, 4, 0));
+        assert_eq!(&debug.bitfield[..], &[0]);
+
+        assert_eq!(session.torrent_files(&[37; 20]).unwrap().len(), 1);
+        assert_eq!(session.export_resume(&[37; 20]).unwrap().bitfield, vec![0]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn torrent_files_queued() {
+        let mut session = Session::with_config(SessionConfig {
+            fs_backend: Some(FsBackend::Standard),
+            max_active_downloads: Some(1),
+            ..Default::default()
+        });
+        session.add_torrent(torrent(33)).unwrap();
+        session.add_torrent(torrent(34)).unwrap();
+
+        // The 2nd torrent waits in the queue, its supervisor isn't running
+        let files = session.torrent_files(&[34; 20]).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!((files[0].length, files[0].downloaded), (4000, 0));
+        assert!(session.torrent_files(&[35; 20]).is_none());
+    }
+
+    fn state(session: &SessionInner, info_hash: u8) -> QueueState {
+        session.torrents[&[info_hash; 20][..]].state
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn queue_active_downloads() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let _guard = runtime.enter();
+
+        let (_cmds_sender, cmds) = crossbeam_channel::unbounded();
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+        let config = SessionConfig {
+            max_active_downloads: Some(1),
+            ..Default::default()
+        };
+
+        let mut session = SessionInner::new(
+            cmds,
+            config,
+            sha1_workers,
+            fs,
+            runtime.clone(),
+            Arc::new(PeerExternId::generate()),
+        );
+        let options = TorrentOptions {
+            disable_trackers: true,
+            ..Default::default()
+        };
+
+        for info_hash in 1..=3 {
+            session.dispatch(SessionCommand::AddTorrent {
+                torrent: Box::new(torrent(info_hash)),
+                options: options.clone(),
+                respond: crossbeam_channel::bounded(1).0,
+            });
+        }
+
+        assert_eq!(state(&session, 1), QueueState::Downloading);
+        assert_eq!(state(&session, 2), QueueState::Queued);
+        assert_eq!(state(&session, 3), QueueState::Queued);
+
+        session.on_event(TorrentEvent::Completed {
+            info_hash: Arc::new([1; 20]),
+        });
+
+        assert_eq!(state(&session, 1), QueueState::Seeding);
+        assert_eq!(state(&session, 2), QueueState::Downloading);
+        assert_eq!(state(&session, 3), QueueState::Queued);
+
+        session.on_event(TorrentEvent::Stalled {
+            info_hash: Arc::new([2; 20]),
+        });
+
+        assert_eq!(state(&session, 2), QueueState::Stalled);
+        assert_eq!(state(&session, 3), QueueState::Downloading);
+        assert!(session.queue.is_empty());
+
+        // Progress again, but the slot is taken
+        session.on_event(TorrentEvent::Resumed {
+            info_hash: Arc::new([2; 20]),
+        });
+
+        assert_eq!(state(&session, 2), QueueState::Queued);
+        assert_eq!(state(&session, 3), QueueState::Downloading);
+
+        session.on_event(TorrentEvent::Completed {
+            info_hash: Arc::new([3; 20]),
+        });
+
+        assert_eq!(state(&session, 2), QueueState::Downloading);
+        assert_eq!(state(&session, 3), QueueState::Seeding);
+        assert!(session.queue.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn ramp_steps() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let _guard = runtime.enter();
+
+        let (_cmds_sender, cmds) = crossbeam_channel::unbounded();
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+        let config = SessionConfig {
+            startup_ramp: Duration::from_secs(80),
+            ..Default::default()
+        };
+
+        let mut session = SessionInner::new(
+            cmds,
+            config,
+            sha1_workers,
+            fs,
+            runtime,
+            Arc::new(PeerExternId::generate()),
+        );
+        let delays: Vec<_> = (0..10).map(|_| session.ramp_delay().as_secs()).collect();
+
+        // The 9th and 10th torrents don't all wait until the end
+        assert_eq!(delays, [0, 10, 20, 30, 40, 50, 60, 70, 0, 10]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn remove_torrent() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let _guard = runtime.enter();
+
+        let (_cmds_sender, cmds) = crossbeam_channel::unbounded();
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, fs_recv) = async_channel::unbounded();
+        let config = SessionConfig {
+            max_active_downloads: Some(1),
+            ..Default::default()
+        };
+
+        let mut session = SessionInner::new(
+            cmds,
+            config,
+            sha1_workers,
+            fs,
+            runtime.clone(),
+            Arc::new(PeerExternId::generate()),
+        );
+        let options = TorrentOptions {
+            disable_trackers: true,
+            ..Default::default()
+        };
+
+        for info_hash in 1..=2 {
+            session.dispatch(SessionCommand::AddTorrent {
+                torrent: Box::new(torrent(info_hash)),
+                options: options.clone(),
+                respond: crossbeam_channel::bounded(1).0,
+            });
+        }
+        let addr = session.torrents[&[1; 20][..]].addr.clone();
+
+        assert!(session.served.read().contains(&[1; 20][..]));
+        let (respond, removed) = crossbeam_channel::bounded(1);
+        session.dispatch(SessionCommand::RemoveTorrent {
+            info_hash: Arc::new([1; 20]),
+            respond,
+        });
+        assert_eq!(removed.try_recv(), Ok(true));
+
+        // The next torrent takes the slot
+        assert!(!session.torrents.contains_key(&[1; 20][..]));
+        assert!(!session.served.read().contains(&[1; 20][..]));
+        assert_eq!(state(&session, 2), QueueState::Downloading);
+
+        // The supervisor flushes the disk, then stops
+        let id = runtime.block_on(async {
+            loop {
+                match fs_recv.recv().await {
+                    Ok(FSMessage::Flush { id, done }) => {
+                        done.send(()).await.unwrap();
+                        return id;
+                    }
+                    Ok(_) => {}
+                    Err(_) => panic!("Missing flush"),
+                }
+            }
+        });
+
+        // Sent when the supervisor is dropped
+        runtime.block_on(async {
+            loop {
+                match fs_recv.recv().await {
+                    Ok(FSMessage::RemoveTorrent { id: removed }) if removed == id => return,
+                    Ok(_) => {}
+                    Err(_) => panic!("Missing removal"),
+                }
+            }
+        });
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !addr.is_closed() {
+            assert!(Instant::now() < deadline, "Supervisor not dropped");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // Unknown torrent
+        let (respond, removed) = crossbeam_channel::bounded(1);
+        session.dispatch(SessionCommand::RemoveTorrent {
+            info_hash: Arc::new([1; 20]),
+            respond,
+        });
+        assert_eq!(removed.try_recv(), Ok(false));
+        assert_eq!(session.torrents.len(), 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn duplicate_torrent() {
+        let mut session = Session::with_config(SessionConfig {
+            fs_backend: Some(FsBackend::Standard),
+            ..Default::default()
+        });
+        let options = TorrentOptions {
+            disable_trackers: true,
+            ..Default::default()
+        };
+
+        session
+            .add_torrent_with_options(torrent(1), options.clone())
+            .unwrap();
+        assert!(matches!(
+            session.add_torrent_with_options(torrent(1), options.clone()),
+            Err(AddError::Duplicate)
+        ));
+
+        assert!(session.remove_torrent(&[1; 20]));
+        assert!(!session.remove_torrent(&[1; 20]));
+        session
+            .add_torrent_with_options(torrent(1), options)
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn add_torrent_with_resume() {
+        let mut session = Session::with_config(SessionConfig {
+            fs_backend: Some(FsBackend::Standard),
+            ..Default::default()
+        });
+        let resume = |bitfield| ResumeData {
+            bitfield,
+            completed: false,
+            info_hash: vec![1; 20],
+            partial: Vec::new(),
+            piece_length: 1000,
+            downloaded: 0,
+            uploaded: 0,
+        };
+
+        // The torrent has 4 pieces, its bitfield is 1 byte
+        assert!(matches!(
+            session.add_torrent_with_resume(torrent(1), resume(vec![0xF0, 0])),
+            Err(AddError::Resume(ResumeError::Mismatch))
+        ));
+        session
+            .add_torrent_with_resume(torrent(1), resume(vec![0b1100_0000]))
+            .unwrap();
+
+        let resume = session.export_resume(&[1; 20]).unwrap();
+        assert_eq!(resume.bitfield, vec![0b1100_0000]);
+        assert!(session.export_resume(&[2; 20]).is_none());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn export_resume_queued() {
+        let mut session = Session::with_config(SessionConfig {
+            fs_backend: Some(FsBackend::Standard),
+            max_active_downloads: Some(1),
+            ..Default::default()
+        });
+        let resume = ResumeData {
+            bitfield: vec![0b1010_0000],
+            completed: false,
+            info_hash: vec![4; 20],
+            partial: Vec::new(),
+            piece_length: 1000,
+            downloaded: 0,
+            uploaded: 0,
+        };
+
+        session.add_torrent(torrent(3)).unwrap();
+        session.add_torrent_with_resume(torrent(4), resume).unwrap();
+
+        // The 2nd torrent waits in the queue, with its resume data
+        let resume = session.export_resume(&[4; 20]).unwrap();
+        assert_eq!(resume.bitfield, vec![0b1010_0000]);
+        assert!(session.export_resume_file(&[4; 20]).is_some());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn encrypted_resume_file() {
+        let config = |key: Option<u8>| SessionConfig {
+            fs_backend: Some(FsBackend::Standard),
+            resume_cipher: key.map(|key| Arc::new(XorCipher(key)) as Arc<dyn ResumeCipher>),
+            ..Default::default()
+        };
+
+        let mut session = Session::with_config(config(Some(0x5A)));
+        session.add_torrent(torrent(12)).unwrap();
+        let file = session.export_resume_file(&[12; 20]).unwrap();
+        drop(session);
+
+        for (key, error) in &[
+            (None, ResumeError::MissingKey),
+            (Some(0x33), ResumeError::WrongKey),
+        ] {
+            let mut session = Session::with_config(config(*key));
+            assert!(matches!(
+                session.add_torrent_with_resume_file(torrent(12), &file),
+                Err(AddError::Resume(e)) if e == *error
+            ));
+        }
+
+        let mut session = Session::with_config(config(Some(0x5A)));
+        session
+            .add_torrent_with_resume_file(torrent(12), &file)
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn torrents_by_label() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let _guard = runtime.enter();
+
+        let (_cmds_sender, cmds) = crossbeam_channel::unbounded();
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+        let config = SessionConfig {
+            max_active_downloads: Some(1),
+            labels: vec!["session".to_string()],
+            ..Default::default()
+        };
+
+        let mut session = SessionInner::new(
+            cmds,
+            config,
+            sha1_workers,
+            fs,
+            runtime.clone(),
+            Arc::new(PeerExternId::generate()),
+        );
+
+        for (info_hash, labels) in &[(1, &["movies"][..]), (2, &["linux", "movies"]), (3, &[])] {
+            session.dispatch(SessionCommand::AddTorrent {
+                torrent: Box::new(torrent(*info_hash)),
+                options: TorrentOptions {
+                    disable_trackers: true,
+                    labels: labels.iter().map(|l| l.to_string()).collect(),
+                    ..Default::default()
+                },
+                respond: crossbeam_channel::bounded(1).0,
+            });
+        }
+
+        let by_label = |session: &mut SessionInner, label: &str| {
+            let (respond, receiver) = crossbeam_channel::bounded(1);
+            session.dispatch(SessionCommand::TorrentsByLabel {
+                label: label.to_string(),
+                respond,
+            });
+            let mut statuses: Vec<TorrentStatus> = receiver.recv().unwrap();
+            statuses.sort_by(|a, b| a.info_hash.cmp(&b.info_hash));
+            statuses
+        };
+
+        let movies = by_label(&mut session, "movies");
+        let info_hashes: Vec<_> = movies.iter().map(|s| s.info_hash[0]).collect();
+        assert_eq!(info_hashes, vec![1, 2]);
+        assert_eq!(movies[1].labels, vec!["linux", "movies", "session"]);
+        // Only 1 active download, the 2nd torrent is queued
+        assert!(!movies[0].queued);
+        assert!(movies[1].queued);
+
+        assert_eq!(by_label(&mut session, "session").len(), 3);
+        assert!(by_label(&mut session, "music").is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn metrics_text() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let _guard = runtime.enter();
+
+        let (_cmds_sender, cmds) = crossbeam_channel::unbounded();
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+        let config = SessionConfig {
+            max_active_downloads: Some(1),
+            ..Default::default()
+        };
+
+        let mut session = SessionInner::new(
+            cmds,
+            config,
+            sha1_workers,
+            fs,
+            runtime.clone(),
+            Arc::new(PeerExternId::generate()),
+        );
+
+        // 2 downloads, the 2nd one is queued, and a seed
+        for (info_hash, read_only) in &[(1, false), (2, false), (3, true)] {
+            session.dispatch(SessionCommand::AddTorrent {
+                torrent: Box::new(torrent(*info_hash)),
+                options: TorrentOptions {
+                    disable_trackers: true,
+                    read_only: *read_only,
+                    ..Default::default()
+                },
+                respond: crossbeam_channel::bounded(1).0,
+            });
+        }
+
+        session.torrents[&[1; 20][..]]
+            .counters
+            .add_downloaded(1000, 1100);
+
+        let (respond, receiver) = crossbeam_channel::bounded(1);
+        session.dispatch(SessionCommand::Metrics { respond });
+        let text = receiver.recv().unwrap().to_prometheus();
+
+        for line in &[
+            "# TYPE rustorrent_torrents gauge",
+            "rustorrent_torrents 3",
+            "rustorrent_torrents_active 2",
+            "rustorrent_torrents_queued 1",
+            "# TYPE rustorrent_downloaded_bytes_total counter",
+            "rustorrent_downloaded_bytes_total 1000",
+            // The pieces of the seed are counted once checked on the disk
+            "rustorrent_pieces_verified 0",
+        ] {
+            assert!(
+                text.lines().any(|l| l == *line),
+                "{:?} not in\n{}",
+                line,
+                text
+            );
+        }
+        assert!(text.contains("rustorrent_peers "));
+        assert!(text.contains("rustorrent_disk_queue "));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn peer_id() {
+        let session = Session::new();
+        let other = Session::new();
+
+        let id = session.peer_id();
+        assert_eq!(&id[..8], b"-RR0001-");
+        assert_eq!(session.peer_id(), id);
+
+        assert_eq!(&other.peer_id()[..8], b"-RR0001-");
+        assert_ne!(other.peer_id()[8..], id[8..]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn fs_fallback() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+
+        let mut backend = None;
+        let output = crate::logger::capture(|| {
+            let (fs, fs_backend) = init_fs(&runtime, |_| Err(UringInitError::Disabled));
+            assert!(fs
+                .try_send(FSMessage::SetWriteRate { bytes_per_sec: 0 })
+                .is_ok());
+            backend = Some(fs_backend);
+        });
+
+        assert_eq!(backend, Some(FsBackend::Standard));
+        assert!(output.contains("io_uring unavailable"), "{}", output);
+        assert!(output.contains("Disabled"), "{}", output);
+
+        let session = Session::new();
+        assert_ne!(session.fs_backend(), FsBackend::Custom);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn sha1_workers() {
+        let session = Session::with_config(SessionConfig {
+            sha1_workers: 1,
+            fs_backend: Some(FsBackend::Standard),
+            ..Default::default()
+        });
+        assert_eq!(session.fs_backend(), FsBackend::Standard);
+
+        let (started, started_recv) = crossbeam_channel::unbounded();
+        let (release, release_recv) = crossbeam_channel::unbounded();
+        for _ in 0..2 {
+            session
+                .sha1_workers
+                .send(Sha1Task::Block {
+                    started: started.clone(),
+                    release: release_recv.clone(),
+                })
+                .unwrap();
+        }
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(started_recv.recv_timeout(timeout).unwrap(), "sha-1");
+        // No other worker takes the second task
+        assert!(started_recv
+            .recv_timeout(Duration::from_millis(200))
+            .is_err());
+
+        release.send(()).unwrap();
+        assert_eq!(started_recv.recv_timeout(timeout).unwrap(), "sha-1");
+        release.send(()).unwrap();
+    }
+
+    /// Threads of the process
+    #[cfg(target_os = "linux")]
+    fn count_threads() -> usize {
+        std::fs::read_dir("/proc/self/task").unwrap().count()
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn drop_session() {
+        let config = SessionConfig {
+            fs_backend: Some(FsBackend::Standard),
+            ..Default::default()
+        };
+        // Start the logger and its thread
+        drop(Session::with_config(config.clone()));
+
+        let before = count_threads();
+        let session = Session::with_config(config.clone());
+        let per_session = count_threads().saturating_sub(before).max(1);
+        drop(session);
+
+        for _ in 0..10 {
+            let mut session = Session::with_config(config.clone());
+            session.add_torrent(torrent(10)).unwrap();
+        }
+
+        // The disk and sha1 threads exit after the session thread. The
+        // other tests run in parallel and have threads too, but fewer
+        // than 10 leaked sessions
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while count_threads() > before + 2 * per_session {
+            assert!(
+                Instant::now() < deadline,
+                "Threads leaked: {} before, {} per session, {} after",
+                before,
+                per_session,
+                count_threads()
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    async fn drop_in_runtime() {
+        let mut session = Session::with_config(SessionConfig {
+            fs_backend: Some(FsBackend::Standard),
+            ..Default::default()
+        });
+        session.add_torrent(torrent(11)).unwrap();
+
+        // The runtime of the session isn't dropped in this async context
+        drop(session);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn shutdown_order() {
+        let steps = Arc::new(Mutex::new(Vec::new()));
+        let push = |step: &'static str| {
+            let steps = Arc::clone(&steps);
+            move || steps.lock().unwrap().push(step)
+        };
+
+        // Tracker recording `Stopped`
+        let tracker = TcpListener::bind("127.0.0.1:0").unwrap();
+        let announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+        let (started, announced) = crossbeam_channel::unbounded();
+        let stopped = push("stopped announced");
+        std::thread::spawn(move || {
+            for stream in tracker.incoming() {
+                let mut stream = stream.unwrap();
+
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..n]);
+                }
+
+                let request = String::from_utf8_lossy(&request);
+                if request.contains("event=stopped") {
+                    stopped();
+                } else {
+                    started.send(()).ok();
+                }
+
+                let body = b"d8:intervali1800e5:peers0:e";
+                let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                stream.write_all(header.as_bytes()).unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+
+        // Peer recording when its connection is closed
+        let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let disconnected = push("peer disconnected");
+        std::thread::spawn(move || {
+            let mut stream = peer.accept().unwrap().0;
+
+            let mut handshake = [0; 68];
+            stream.read_exact(&mut handshake).unwrap();
+            stream.write_all(&handshake).unwrap();
+
+            let mut buffer = [0; 1024];
+            while matches!(stream.read(&mut buffer), Ok(n) if n > 0) {}
+            disconnected();
+        });
+
+        // Disk recording the flush
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let (fs, fs_recv) = async_channel::unbounded();
+        let flushed = push("disk flushed");
+        runtime.spawn(async move {
+            while let Ok(msg) = fs_recv.recv().await {
+                if let FSMessage::Flush { done, .. } = msg {
+                    flushed();
+                    done.send(()).await.ok();
+                }
+            }
+        });
+
+        let mut session = Session::start(SessionConfig::default(), runtime, fs, FsBackend::Custom);
+        let mut torrent = torrent(60);
+        let info_hash = Arc::clone(&torrent.info_hash);
+        torrent.meta.announce = Some(announce);
+        session.add_torrent(torrent).unwrap();
+        session.add_peers(&info_hash, vec![peer_addr]);
+
+        announced.recv_timeout(Duration::from_secs(10)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !session.metrics_text().contains("rustorrent_peers 1") {
+            assert!(Instant::now() < deadline, "Peer not connected");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        session.shutdown();
+        push("session joined")();
+
+        assert_eq!(
+            *steps.lock().unwrap(),
+            vec![
+                "peer disconnected",
+                "stopped announced",
+                "disk flushed",
+                "session joined"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Miri doesn't support tokio's runtime
+    fn listen_addrs() {
+        let mut session = Session::with_config(SessionConfig {
+            listen_addrs: vec!["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()],
+            ..Default::default()
+        });
+
+        let addrs = session.listen_addrs().to_vec();
+        assert_eq!(addrs.len(), 2);
+        assert!(addrs[0].is_ipv4() && addrs[1].is_ipv6());
+        assert!(addrs.iter().all(|addr| addr.port() != 0));
+
+        let options = TorrentOptions {
+            disable_trackers: true,
+            ..Default::default()
+        };
+        for info_hash in &[70, 71] {
+            let torrent = torrent(*info_hash);
+            session
+                .add_torrent_with_options(torrent, options.clone())
+                .unwrap();
+            // The torrent is started
+            assert!(session.debug_pieces(&[*info_hash; 20]).is_some());
+        }
+
+        let connect = |addr: &SocketAddr, info_hash: u8| {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+
+            let mut handshake = b"\x13BitTorrent protocol".to_vec();
+            handshake.extend_from_slice(&[0; 8]);
+            handshake.extend_from_slice(&[info_hash; 20]);
+            handshake.extend_from_slice(b"-TEST01-123456789012");
+            stream.write_all(&handshake).unwrap();
+
+            stream
+        };
+
+        // Each address reaches both torrents
+        for addr in &addrs {
+            for info_hash in &[70, 71] {
+                let mut stream = connect(addr, *info_hash);
+
+                let mut handshake = [0; 68];
+                stream.read_exact(&mut handshake).unwrap();
+                assert_eq!(&handshake[1..20], b"BitTorrent protocol");
+                assert_eq!(handshake[28..48], [*info_hash; 20]);
+            }
+        }
+
+        // Unknown torrent, closed without a handshake
+        let mut stream = connect(&addrs[1], 72);
+        let mut buffer = [0; 68];
+        assert!(matches!(stream.read(&mut buffer), Ok(0) | Err(_)));
     }
 }
@@ -1,3 +1,5 @@
+use crate::cache_line::CacheAligned;
+
 use std::{
     cell::UnsafeCell,
     fmt::Debug,
@@ -9,6 +11,7 @@ use std::{
         },
         Arc,
     },
+    time::Duration,
 };
 
 const SHIFT: usize = (std::mem::size_of::<AtomicUsize>() * 8) - 1;
@@ -46,11 +49,40 @@ pub enum PopError {
     Closed,
 }
 
+#[derive(Debug, Eq, PartialEq)]
+pub enum RecvError {
+    /// The queue is empty and the producer is gone
+    Closed,
+}
+
+/// Number of steps spinning, then yielding, before parking the thread
+const SPIN_STEPS: u32 = 6;
+const YIELD_STEPS: u32 = 10;
+const PARK_DURATION: Duration = Duration::from_micros(100);
+
+/// Index written by one side, with the last index of the other side it
+/// read. They share a cache line, apart from the other side
+struct Index {
+    index: AtomicUsize,
+    /// Reloaded only when the queue looks full, or empty, with it.
+    /// It's behind the real one, so the queue looks fuller or emptier
+    cached: AtomicUsize,
+}
+
+impl Index {
+    fn new() -> CacheAligned<Index> {
+        CacheAligned::new(Index {
+            index: AtomicUsize::new(0),
+            cached: AtomicUsize::new(0),
+        })
+    }
+}
+
 pub struct Queue<T> {
-    /// pop modify the head
-    head: AtomicUsize,
-    /// push modify the tail
-    tail: AtomicUsize,
+    /// pop modify the head, it caches the tail
+    head: CacheAligned<Index>,
+    /// push modify the tail, it caches the head
+    tail: CacheAligned<Index>,
     buffer: Box<[Elem<T>]>,
     /// Read-only value
     mask_bit: usize,
@@ -65,6 +97,50 @@ impl<T> Consumer<T> {
         self.queue.pop()
     }
 
+    /// Pop up to `max` values into `out`, without waiting.
+    /// Returns the number of values popped, it stops early when
+    /// the queue is empty or closed
+    pub fn pop_n(&mut self, out: &mut Vec<T>, max: usize) -> usize {
+        let mut npopped = 0;
+
+        while npopped < max {
+            match self.queue.pop() {
+                Ok(value) => out.push(value),
+                Err(_) => break,
+            }
+            npopped += 1;
+        }
+
+        npopped
+    }
+
+    /// Wait for a value, spinning then parking the thread with a backoff.
+    /// The values pushed before the producer is closed are returned
+    /// before `RecvError::Closed`
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        let mut step = 0;
+
+        loop {
+            match self.queue.pop() {
+                Ok(value) => return Ok(value),
+                Err(PopError::Closed) => return Err(RecvError::Closed),
+                Err(PopError::Empty) => {}
+            }
+
+            if step < SPIN_STEPS {
+                for _ in 0..1 << step {
+                    std::hint::spin_loop();
+                }
+            } else if step < SPIN_STEPS + YIELD_STEPS {
+                std::thread::yield_now();
+            } else {
+                std::thread::park_timeout(PARK_DURATION);
+            }
+
+            step = step.saturating_add(1);
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.queue.len()
     }
@@ -72,6 +148,12 @@ impl<T> Consumer<T> {
     pub fn is_empty(&self) -> bool {
         self.queue.is_empty()
     }
+
+    /// Close the queue, like dropping the consumer.
+    /// The producer gets `PushError::Closed`
+    pub fn close(self) {
+        self.queue.set_closed();
+    }
 }
 
 pub struct Producer<T> {
@@ -94,6 +176,12 @@ impl<T> Producer<T> {
     pub fn available(&self) -> usize {
         self.queue.available()
     }
+
+    /// Close the queue, like dropping the producer.
+    /// The consumer pops the remaining values, then gets `PopError::Closed`
+    pub fn close(self) {
+        self.queue.set_closed();
+    }
 }
 
 impl<T: Copy> Producer<T> {
@@ -105,6 +193,55 @@ impl<T: Copy> Producer<T> {
 unsafe impl<T> Send for Producer<T> {}
 unsafe impl<T> Send for Consumer<T> {}
 
+struct MpscShared<T> {
+    queue: Arc<Queue<T>>,
+    /// Tail reserved by the producers, ahead of the one of the queue
+    /// while they write their values. The consumer only reads the tail
+    /// of the queue, so the pop path stays the one of the spsc queue
+    reserved: CacheAligned<AtomicUsize>,
+}
+
+impl<T> Drop for MpscShared<T> {
+    fn drop(&mut self) {
+        self.queue.set_closed();
+    }
+}
+
+/// Producer which can be cloned, the queue is closed when the last
+/// clone is dropped
+pub struct MpscSender<T> {
+    shared: Arc<MpscShared<T>>,
+}
+
+impl<T> Clone for MpscSender<T> {
+    fn clone(&self) -> Self {
+        MpscSender {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> MpscSender<T> {
+    pub fn push(&self, value: T) -> Result<(), PushError<T>> {
+        self.shared.queue.push_mpsc(&self.shared.reserved, value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.queue.is_empty()
+    }
+
+    pub fn available(&self) -> usize {
+        self.shared.queue.available()
+    }
+}
+
+unsafe impl<T: Send> Send for MpscSender<T> {}
+unsafe impl<T: Send> Sync for MpscSender<T> {}
+
 impl<T> Drop for Producer<T> {
     fn drop(&mut self) {
         self.queue.set_closed();
@@ -130,6 +267,33 @@ pub fn bounded<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
     Queue::new(capacity)
 }
 
+/// Sending half of the queue, same as `Producer`
+pub type Sender<T> = Producer<T>;
+/// Receiving half of the queue, same as `Consumer`
+pub type Receiver<T> = Consumer<T>;
+
+/// Same as `bounded`, with the names of the other channels.
+/// Holds at most `capacity` values
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    bounded(capacity)
+}
+
+/// Queue with multiple producers, the consumer is the same as the one
+/// of `channel`. The producers reserve their slots with a CAS, use
+/// `channel` with a single producer
+pub fn mpsc_channel<T>(capacity: usize) -> (MpscSender<T>, Receiver<T>) {
+    let queue = Arc::new(Queue::new_queue(capacity));
+
+    let sender = MpscSender {
+        shared: Arc::new(MpscShared {
+            queue: Arc::clone(&queue),
+            reserved: CacheAligned::new(AtomicUsize::new(0)),
+        }),
+    };
+
+    (sender, Consumer { queue })
+}
+
 impl<T> Queue<T> {
     fn new_queue(capacity: usize) -> Self {
         assert!(capacity > 0);
@@ -140,8 +304,8 @@ impl<T> Queue<T> {
         }
 
         Queue {
-            head: AtomicUsize::new(0),
-            tail: AtomicUsize::new(0),
+            head: Index::new(),
+            tail: Index::new(),
             buffer: buffer.into_boxed_slice(),
             mask_bit: (capacity + 1).next_power_of_two(),
         }
@@ -161,13 +325,14 @@ impl<T> Queue<T> {
 
     fn set_closed(&self) {
         self.tail
+            .index
             .fetch_update(Release, Relaxed, |tail| Some(tail | CLOSED_BIT))
             .unwrap();
     }
 
     fn len(&self) -> usize {
-        let tail = self.tail.load(Acquire) & (self.mask_bit - 1);
-        let head = self.head.load(Acquire) & (self.mask_bit - 1);
+        let tail = self.tail.index.load(Acquire) & (self.mask_bit - 1);
+        let head = self.head.index.load(Acquire) & (self.mask_bit - 1);
 
         if tail < head {
             (tail + self.buffer.len()) - head
@@ -184,13 +349,30 @@ impl<T> Queue<T> {
         self.buffer.len() - self.len()
     }
 
+    /// Head for the producer, reloaded when `is_full` is true with the
+    /// cached one
+    fn head_for_push(&self, is_full: impl Fn(usize) -> bool) -> usize {
+        let head = self.tail.cached.load(Relaxed);
+
+        if !is_full(head) {
+            return head;
+        }
+
+        let head = self.head.index.load(Acquire);
+        self.tail.cached.store(head, Relaxed);
+        head
+    }
+
     fn push(&self, elem: T) -> Result<(), PushError<T>> {
-        let tail = self.tail.load(Relaxed);
-        let head = self.head.load(Acquire);
+        let tail = self.tail.index.load(Relaxed);
 
         if tail & CLOSED_BIT != 0 {
-            Err(PushError::Closed(elem))
-        } else if head.wrapping_add(self.mask_bit) == tail {
+            return Err(PushError::Closed(elem));
+        }
+
+        let is_full = |head: usize| head.wrapping_add(self.mask_bit) == tail;
+
+        if is_full(self.head_for_push(is_full)) {
             Err(PushError::Full(elem))
         } else {
             let index = tail & (self.mask_bit - 1);
@@ -206,15 +388,91 @@ impl<T> Queue<T> {
                 (tail & !(self.mask_bit - 1)).wrapping_add(self.mask_bit)
             };
 
-            self.tail.store(next, Release);
+            self.tail.index.store(next, Release);
 
             Ok(())
         }
     }
 
+    /// Index following `index`, the lap changes after the last element
+    fn next_index(&self, index: usize) -> usize {
+        if (index & (self.mask_bit - 1)) + 1 < self.buffer.len() {
+            index + 1
+        } else {
+            (index & !(self.mask_bit - 1)).wrapping_add(self.mask_bit)
+        }
+    }
+
+    /// Push of the producers sharing `reserved`: a slot is reserved with a
+    /// CAS, written, then the tail is moved to it once the producers which
+    /// reserved the previous slots moved it to theirs.
+    /// The head isn't cached, the producers would write it out of order
+    fn push_mpsc(&self, reserved: &AtomicUsize, elem: T) -> Result<(), PushError<T>> {
+        let mut tail = reserved.load(Relaxed);
+
+        let next = loop {
+            if self.tail.index.load(Relaxed) & CLOSED_BIT != 0 {
+                return Err(PushError::Closed(elem));
+            }
+
+            if self.head.index.load(Acquire).wrapping_add(self.mask_bit) == tail {
+                return Err(PushError::Full(elem));
+            }
+
+            let next = self.next_index(tail);
+
+            match reserved.compare_exchange_weak(tail, next, Relaxed, Relaxed) {
+                Ok(_) => break next,
+                Err(current) => tail = current,
+            }
+        };
+
+        let data = self.buffer[tail & (self.mask_bit - 1)].data.get();
+        unsafe {
+            data.write(MaybeUninit::new(elem));
+        }
+
+        // The closed bit is kept when the consumer is gone meanwhile
+        let mut step = 0;
+        let mut current = self.tail.index.load(Relaxed);
+
+        loop {
+            if current & !CLOSED_BIT == tail {
+                let closed = current & CLOSED_BIT;
+
+                match self.tail.index.compare_exchange_weak(
+                    current,
+                    next | closed,
+                    Release,
+                    Relaxed,
+                ) {
+                    Ok(_) => return Ok(()),
+                    Err(actual) => current = actual,
+                }
+                continue;
+            }
+
+            if step < SPIN_STEPS {
+                for _ in 0..1 << step {
+                    std::hint::spin_loop();
+                }
+                step += 1;
+            } else {
+                std::thread::yield_now();
+            }
+
+            current = self.tail.index.load(Relaxed);
+        }
+    }
+
     fn pop(&self) -> Result<T, PopError> {
-        let head = self.head.load(Relaxed);
-        let tail = self.tail.load(Acquire);
+        let head = self.head.index.load(Relaxed);
+        let mut tail = self.head.cached.load(Relaxed);
+
+        if tail & !CLOSED_BIT == head {
+            tail = self.tail.index.load(Acquire);
+            self.head.cached.store(tail, Relaxed);
+        }
 
         if tail & !CLOSED_BIT == head {
             if tail & CLOSED_BIT != 0 {
@@ -234,7 +492,7 @@ impl<T> Queue<T> {
                 (head & !(self.mask_bit - 1)).wrapping_add(self.mask_bit)
             };
 
-            self.head.store(next, Release);
+            self.head.index.store(next, Release);
 
             Ok(data)
         }
@@ -243,8 +501,7 @@ impl<T> Queue<T> {
 
 impl<T: Copy> Queue<T> {
     fn push_slice(&self, slice: &[T]) -> Result<(), PushError<()>> {
-        let tail = self.tail.load(Relaxed);
-        let head = self.head.load(Acquire);
+        let tail = self.tail.index.load(Relaxed);
 
         if tail & CLOSED_BIT != 0 {
             return Err(PushError::Closed(()));
@@ -262,7 +519,9 @@ impl<T: Copy> Queue<T> {
                 .wrapping_add((index + slice_length) % buffer_length)
         };
 
-        if head.wrapping_add(self.mask_bit) < next_tail {
+        let is_full = |head: usize| head.wrapping_add(self.mask_bit) < next_tail;
+
+        if is_full(self.head_for_push(is_full)) {
             Err(PushError::Full(()))
         } else {
             let buffer: &mut [T] = unsafe {
@@ -279,7 +538,7 @@ impl<T: Copy> Queue<T> {
                 buffer[index..index + slice_length].copy_from_slice(slice);
             }
 
-            self.tail.store(next_tail, Release);
+            self.tail.index.store(next_tail, Release);
 
             Ok(())
         }
@@ -288,9 +547,10 @@ impl<T: Copy> Queue<T> {
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{sync::atomic::Ordering::Relaxed, time::Duration};
 
-    use super::{PopError, PushError, Queue};
+    use super::{channel, mpsc_channel, Index, PopError, PushError, Queue, RecvError};
+    use crate::cache_line::CacheAligned;
 
     #[test]
     fn simple() {
@@ -403,6 +663,42 @@ mod tests {
         assert!(queue.push(3).is_err());
     }
 
+    #[test]
+    fn cached_indexes() {
+        let queue = Queue::new_queue(2);
+
+        // The head and the tail are on their own cache line
+        let head = &queue.head as *const _ as usize;
+        let tail = &queue.tail as *const _ as usize;
+        assert!(head.abs_diff(tail) >= std::mem::align_of::<CacheAligned<Index>>());
+
+        queue.push(1).unwrap();
+        queue.push(2).unwrap();
+        assert!(matches!(queue.push(3), Err(PushError::Full(3))));
+
+        // The producer reloads the head only when the queue looks full
+        assert_eq!(queue.pop(), Ok(1));
+        assert_eq!(queue.tail.cached.load(Relaxed), 0);
+        queue.push(3).unwrap();
+        assert_eq!(queue.tail.cached.load(Relaxed), 1);
+        assert!(matches!(queue.push(4), Err(PushError::Full(4))));
+
+        // The consumer reloads the tail only when the queue looks empty
+        let cached_tail = queue.head.cached.load(Relaxed);
+        assert_eq!(queue.pop(), Ok(2));
+        assert_eq!(queue.head.cached.load(Relaxed), cached_tail);
+        assert_eq!(queue.pop(), Ok(3));
+        assert_eq!(
+            queue.head.cached.load(Relaxed),
+            queue.tail.index.load(Relaxed)
+        );
+        assert_eq!(queue.pop(), Err(PopError::Empty));
+
+        // Closed after the consumer cached the tail
+        queue.set_closed();
+        assert_eq!(queue.pop(), Err(PopError::Closed));
+    }
+
     #[test]
     fn empty() {
         let queue = Queue::<usize>::new_queue(2);
@@ -492,6 +788,45 @@ mod tests {
         }
     }
 
+    #[test]
+    fn close() {
+        let (mut sender, mut recv) = Queue::new(10);
+
+        sender.push(1).unwrap();
+        sender.close();
+
+        assert_eq!(recv.pop(), Ok(1));
+        assert_eq!(recv.pop(), Err(PopError::Closed));
+
+        let (mut sender, recv) = Queue::<usize>::new(10);
+
+        recv.close();
+
+        assert!(matches!(sender.push(2), Err(PushError::Closed(2))));
+        assert!(matches!(
+            sender.push_slice(&[3]),
+            Err(PushError::Closed(()))
+        ));
+    }
+
+    #[test]
+    fn pop_n() {
+        let (mut sender, mut recv) = Queue::new(16);
+
+        for n in 0..10 {
+            sender.push(n).unwrap();
+        }
+
+        let mut out = Vec::new();
+        assert_eq!(recv.pop_n(&mut out, 4), 4);
+        assert_eq!(recv.pop_n(&mut out, 4), 4);
+        assert_eq!(recv.pop_n(&mut out, 4), 2);
+        assert_eq!(out, (0..10).collect::<Vec<_>>());
+
+        sender.close();
+        assert_eq!(recv.pop_n(&mut out, 4), 0);
+    }
+
     #[test]
     #[cfg_attr(miri, ignore)] // Way too slow on miri
     fn threads() {
@@ -581,4 +916,111 @@ mod tests {
             assert_eq!(recv.pop(), Err(PopError::Closed));
         }
     }
+
+    #[test]
+    fn channel_between_threads() {
+        let (mut sender, mut recv) = channel::<u32>(8);
+
+        assert!(recv.is_empty());
+        sender.push(1).unwrap();
+        sender.push(2).unwrap();
+        assert_eq!(recv.len(), 2);
+
+        let writer = std::thread::spawn(move || {
+            for n in 3..100 {
+                while let Err(PushError::Full(_)) = sender.push(n) {}
+            }
+        });
+
+        for n in 1..100 {
+            assert_eq!(recv.recv(), Ok(n));
+        }
+
+        writer.join().unwrap();
+        assert_eq!(recv.recv(), Err(RecvError::Closed));
+    }
+
+    #[test]
+    fn recv_before_closed() {
+        let (mut sender, mut recv) = channel(4);
+
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            sender.push(1).unwrap();
+            drop(sender);
+        });
+
+        // The last value is received before the close
+        assert_eq!(recv.recv(), Ok(1));
+        assert_eq!(recv.recv(), Err(RecvError::Closed));
+
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn mpsc_full_closed() {
+        let (sender, mut recv) = mpsc_channel(2);
+        let other = sender.clone();
+
+        // Around the end of the buffer, from both senders
+        for n in 0..5 {
+            sender.push(n).unwrap();
+            other.push(n + 10).unwrap();
+            assert!(matches!(sender.push(20), Err(PushError::Full(20))));
+            assert_eq!(recv.pop(), Ok(n));
+            assert_eq!(recv.pop(), Ok(n + 10));
+        }
+
+        other.push(1).unwrap();
+        drop(recv);
+        assert!(matches!(sender.push(2), Err(PushError::Closed(2))));
+        assert!(matches!(other.push(3), Err(PushError::Closed(3))));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Way too slow on miri
+    fn mpsc_threads() {
+        const PRODUCERS: usize = 4;
+        const ITEMS: usize = 250_000;
+
+        let (sender, mut recv) = mpsc_channel(1024);
+
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|producer| {
+                let sender = sender.clone();
+
+                std::thread::spawn(move || {
+                    for n in 0..ITEMS {
+                        let mut value = (producer, n);
+                        loop {
+                            match sender.push(value) {
+                                Ok(_) => break,
+                                Err(PushError::Full(v)) => {
+                                    value = v;
+                                    std::thread::yield_now();
+                                }
+                                Err(PushError::Closed(_)) => panic!("closed"),
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        drop(sender);
+
+        // The values of each producer arrive in order
+        let mut next = [0; PRODUCERS];
+
+        while let Ok((producer, n)) = recv.recv() {
+            assert_eq!(n, next[producer], "producer={}", producer);
+            next[producer] += 1;
+        }
+
+        assert_eq!(next, [ITEMS; PRODUCERS]);
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+    }
 }
@@ -0,0 +1,317 @@
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+
+use crate::peer::peer::PeerId;
+
+/// Unchoke slots and interval of BEP 3, used for small swarms
+/// and when the upload bandwidth is unknown
+pub const DEFAULT_SLOTS: usize = 4;
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time an optimistic unchoke lasts before it moves to another peer
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+const MAX_SLOTS: usize = 50;
+const MAX_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Upload rate, in bytes per second, given to each unchoked peer.
+/// A faster link unchokes more peers
+const RATE_PER_SLOT: u64 = 32 * 1024;
+
+/// The interval grows by `INTERVAL_STEP` for each `PEERS_STEP` peers
+/// above `PEERS_STEP`, the choke state of large swarms is recomputed
+/// less often
+const PEERS_STEP: usize = 50;
+const INTERVAL_STEP: Duration = Duration::from_secs(5);
+
+/// When and how many peers are unchoked
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ChokeSettings {
+    /// Time between 2 recomputations of the unchoked peers
+    pub interval: Duration,
+    /// Number of peers unchoked, without the optimistic unchoke
+    pub slots: usize,
+}
+
+impl Default for ChokeSettings {
+    fn default() -> Self {
+        ChokeSettings {
+            interval: DEFAULT_INTERVAL,
+            slots: DEFAULT_SLOTS,
+        }
+    }
+}
+
+impl ChokeSettings {
+    /// Settings for `npeers` connected peers and an upload bandwidth
+    /// of `upload_rate` bytes per second, 0 when it's unknown
+    pub fn adapt(npeers: usize, upload_rate: u64) -> ChokeSettings {
+        let slots = (upload_rate / RATE_PER_SLOT) as usize;
+        let slots = slots.clamp(DEFAULT_SLOTS, MAX_SLOTS);
+
+        let steps = npeers.saturating_sub(1) / PEERS_STEP;
+        let interval = (DEFAULT_INTERVAL + INTERVAL_STEP * steps as u32).min(MAX_INTERVAL);
+
+        ChokeSettings { interval, slots }
+    }
+}
+
+/// Message to send to a peer whose choke state changed
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChokeChange {
+    Choke(PeerId),
+    Unchoke(PeerId),
+}
+
+#[derive(Debug)]
+struct ChokePeer {
+    interested: bool,
+    choked: bool,
+    /// Payload received from the peer, at the previous tick
+    downloaded: u64,
+    /// Payload sent to the peer, at the previous tick
+    uploaded: u64,
+    /// Bytes per second received between the 2 last ticks
+    download_rate: f64,
+    /// Bytes per second sent between the 2 last ticks
+    upload_rate: f64,
+}
+
+/// Which peers we upload to (BEP 3).
+///
+/// The `slots` interested peers uploading the fastest to us are
+/// unchoked, or the ones we upload the fastest to when we're seeding
+/// (nobody uploads to a seed), plus an optimistic unchoke rotating every
+/// `OPTIMISTIC_INTERVAL` among the other interested peers, so new
+/// peers get a chance to show their rate
+#[derive(Debug)]
+pub struct ChokeManager {
+    settings: ChokeSettings,
+    peers: HashMap<PeerId, ChokePeer>,
+    /// With the time it was unchoked
+    optimistic: Option<(PeerId, Instant)>,
+    last_tick: Option<Instant>,
+}
+
+impl ChokeManager {
+    pub fn new(settings: ChokeSettings) -> ChokeManager {
+        ChokeManager {
+            settings,
+            peers: HashMap::default(),
+            optimistic: None,
+            last_tick: None,
+        }
+    }
+
+    pub fn settings(&self) -> ChokeSettings {
+        self.settings
+    }
+
+    /// Used from the next tick
+    pub fn set_settings(&mut self, settings: ChokeSettings) {
+        self.settings = settings;
+    }
+
+    /// Recompute the unchoked peers, every `settings.interval`.
+    /// `peers` are all the connected peers, whether they're interested
+    /// and the payload received from them and sent to them since
+    /// they're connected.
+    /// The peers start choked, only the changes are returned
+    pub fn tick<I>(&mut self, now: Instant, seeding: bool, peers: I) -> Vec<ChokeChange>
+    where
+        I: IntoIterator<Item = (PeerId, bool, u64, u64)>,
+    {
+        let elapsed = match self.last_tick.replace(now) {
+            Some(last) => now.saturating_duration_since(last),
+            None => self.settings.interval,
+        }
+        .as_secs_f64();
+
+        // The peers not given are gone
+        let mut previous = std::mem::take(&mut self.peers);
+        for (id, interested, downloaded, uploaded) in peers {
+            let mut peer = previous.remove(&id).unwrap_or(ChokePeer {
+                interested,
+                choked: true,
+                downloaded: 0,
+                uploaded: 0,
+                download_rate: 0.0,
+                upload_rate: 0.0,
+            });
+            if elapsed > 0.0 {
+                peer.download_rate = downloaded.saturating_sub(peer.downloaded) as f64 / elapsed;
+                peer.upload_rate = uploaded.saturating_sub(peer.uploaded) as f64 / elapsed;
+            }
+            peer.downloaded = downloaded;
+            peer.uploaded = uploaded;
+            peer.interested = interested;
+            self.peers.insert(id, peer);
+        }
+
+        let mut interested: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.interested)
+            .map(|(id, peer)| match seeding {
+                true => (*id, peer.upload_rate),
+                false => (*id, peer.download_rate),
+            })
+            .collect();
+        interested.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+
+        let slots = self.settings.slots.min(interested.len());
+        let (fastest, others) = interested.split_at(slots);
+        let mut others: Vec<_> = others.iter().map(|(id, _)| *id).collect();
+        others.sort();
+
+        let keep_optimistic = match self.optimistic {
+            Some((id, since)) => {
+                others.contains(&id) && now.saturating_duration_since(since) < OPTIMISTIC_INTERVAL
+            }
+            None => false,
+        };
+        if !keep_optimistic {
+            // Round robin, in the order of the ids
+            let last = self.optimistic.map(|(id, _)| id);
+            let next = others
+                .iter()
+                .find(|id| Some(**id) > last)
+                .or_else(|| others.first());
+            self.optimistic = next.map(|id| (*id, now));
+        }
+
+        let optimistic = self.optimistic.map(|(id, _)| id);
+        let mut changes = Vec::new();
+
+        for (id, peer) in &mut self.peers {
+            let unchoked = optimistic == Some(*id) || fastest.iter().any(|(f, _)| f == id);
+
+            if peer.choked == unchoked {
+                peer.choked = !unchoked;
+                changes.push(match unchoked {
+                    true => ChokeChange::Unchoke(*id),
+                    false => ChokeChange::Choke(*id),
+                });
+            }
+        }
+
+        changes.sort();
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        ChokeChange::{Choke, Unchoke},
+        ChokeManager, ChokeSettings, DEFAULT_SLOTS, MAX_INTERVAL, MAX_SLOTS,
+    };
+    use crate::peer::peer::PeerId;
+
+    #[test]
+    fn adaptive_settings() {
+        // Small swarm, unknown bandwidth: the defaults of the spec
+        assert_eq!(ChokeSettings::adapt(20, 0), ChokeSettings::default());
+
+        // More slots on faster links
+        let slow = ChokeSettings::adapt(20, 64 * 1024).slots;
+        let fast = ChokeSettings::adapt(20, 1024 * 1024).slots;
+        assert_eq!(slow, DEFAULT_SLOTS);
+        assert_eq!(fast, 32);
+        assert_eq!(ChokeSettings::adapt(20, u64::MAX).slots, MAX_SLOTS);
+
+        // Large swarms are recomputed less often
+        assert_eq!(
+            ChokeSettings::adapt(50, 0).interval,
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            ChokeSettings::adapt(51, 0).interval,
+            Duration::from_secs(15)
+        );
+        assert_eq!(ChokeSettings::adapt(1000, 0).interval, MAX_INTERVAL);
+    }
+
+    #[test]
+    fn unchoke_fastest() {
+        let mut manager = ChokeManager::new(ChokeSettings::default());
+        let peers: Vec<_> = (0..8).map(PeerId::new).collect();
+        let interval = manager.settings().interval;
+        let mut now = Instant::now();
+
+        // Bytes received from each peer on every interval. Peer 5 isn't
+        // interested, 6 and 7 connect later and never send anything
+        let rates = [400, 100, 500, 300, 1000, 2000, 0, 0];
+        let mut downloaded = [0; 8];
+        let mut tick = |manager: &mut ChokeManager, now: Instant, connected: &[usize]| {
+            for (total, rate) in downloaded.iter_mut().zip(&rates) {
+                *total += rate;
+            }
+            let connected = connected
+                .iter()
+                .map(|&i| (peers[i], i != 5, downloaded[i], 0));
+            manager.tick(now, false, connected)
+        };
+
+        // 4 fastest interested peers, and peer 1 as the optimistic unchoke
+        let changes = tick(&mut manager, now, &[0, 1, 2, 3, 4, 5]);
+        assert_eq!(
+            changes,
+            (0..5).map(|i| Unchoke(peers[i])).collect::<Vec<_>>()
+        );
+
+        // No message without changes. The new peers wait for their turn
+        let all: Vec<_> = (0..8).collect();
+        for _ in 0..2 {
+            now += interval;
+            assert_eq!(tick(&mut manager, now, &all), vec![]);
+        }
+
+        // The optimistic unchoke rotates every 30s
+        now += interval;
+        assert_eq!(
+            tick(&mut manager, now, &all),
+            vec![Choke(peers[1]), Unchoke(peers[6])]
+        );
+        for _ in 0..2 {
+            now += interval;
+            assert_eq!(tick(&mut manager, now, &all), vec![]);
+        }
+        now += interval;
+        assert_eq!(
+            tick(&mut manager, now, &all),
+            vec![Choke(peers[6]), Unchoke(peers[7])]
+        );
+
+        // One of the fastest is gone, peer 1 takes its slot
+        now += interval;
+        assert_eq!(
+            tick(&mut manager, now, &[0, 1, 3, 4, 5, 6, 7]),
+            vec![Unchoke(peers[1])]
+        );
+    }
+
+    #[test]
+    fn unchoke_fastest_seeding() {
+        let mut manager = ChokeManager::new(ChokeSettings {
+            interval: Duration::from_secs(10),
+            slots: 2,
+        });
+        let peers: Vec<_> = (0..4).map(PeerId::new).collect();
+
+        // Peer 3 uploaded to us before we completed, when seeding the
+        // peers downloading the fastest from us are kept. Peer 0 is the
+        // optimistic unchoke
+        let downloaded = [0, 0, 0, 5000];
+        let uploaded = [0, 3000, 2000, 0];
+        let connected = (0..4).map(|i| (peers[i], true, downloaded[i], uploaded[i]));
+
+        assert_eq!(
+            manager.tick(Instant::now(), true, connected),
+            vec![Unchoke(peers[0]), Unchoke(peers[1]), Unchoke(peers[2])]
+        );
+    }
+}
@@ -1,2 +1,4 @@
+pub mod choke;
+pub mod peer_score;
 pub mod torrent;
 pub mod tracker;
@@ -0,0 +1,87 @@
+/// Download rate giving half of the rate component of the score.
+/// Faster peers get closer to 1, without bound on the rate
+const REFERENCE_RATE: f64 = 64.0 * 1024.0;
+
+const RATE_WEIGHT: f64 = 0.5;
+const RELIABILITY_WEIGHT: f64 = 0.3;
+const NEEDED_WEIGHT: f64 = 0.2;
+
+/// Score of a peer unknown yet, between the good and the bad ones
+pub const NEUTRAL_SCORE: f64 = 0.5 * RELIABILITY_WEIGHT + 0.5 * NEEDED_WEIGHT;
+
+/// What we know of a connected peer, to rank it against the others.
+/// The peers with the lowest score are dropped first when there are
+/// too many connections
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PeerScore {
+    /// Payload received, in bytes per second
+    pub download_rate: f64,
+    /// Blocks received from the peer
+    pub blocks_received: usize,
+    /// Blocks timed out, or part of a piece failing its hash
+    pub blocks_failed: usize,
+    /// Pieces the peer has and we don't
+    pub needed_pieces: usize,
+    /// Pieces we don't have
+    pub missing_pieces: usize,
+}
+
+impl PeerScore {
+    /// Between 0 and 1, higher is better
+    pub fn value(&self) -> f64 {
+        let rate = self.download_rate / (self.download_rate + REFERENCE_RATE);
+
+        // A peer without history is neither reliable nor unreliable
+        let reliability = (self.blocks_received as f64 + 1.0)
+            / (self.blocks_received + self.blocks_failed + 2) as f64;
+
+        let needed = match self.missing_pieces {
+            0 => 0.0,
+            missing => self.needed_pieces.min(missing) as f64 / missing as f64,
+        };
+
+        RATE_WEIGHT * rate + RELIABILITY_WEIGHT * reliability + NEEDED_WEIGHT * needed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PeerScore, NEUTRAL_SCORE};
+
+    #[test]
+    fn ranking() {
+        let unknown = PeerScore {
+            missing_pieces: 10,
+            needed_pieces: 5,
+            ..Default::default()
+        };
+        assert!((unknown.value() - NEUTRAL_SCORE).abs() < f64::EPSILON);
+
+        let fast = PeerScore {
+            download_rate: 1024.0 * 1024.0,
+            blocks_received: 100,
+            needed_pieces: 10,
+            missing_pieces: 10,
+            ..Default::default()
+        };
+        let slow = PeerScore {
+            download_rate: 1024.0,
+            ..fast
+        };
+        let unreliable = PeerScore {
+            blocks_received: 0,
+            blocks_failed: 100,
+            ..slow
+        };
+        let useless = PeerScore {
+            needed_pieces: 0,
+            ..slow
+        };
+
+        let mut peers = vec![unreliable, useless, unknown, fast, slow];
+        peers.sort_by(|a, b| b.value().partial_cmp(&a.value()).unwrap());
+
+        assert_eq!(peers, vec![fast, slow, useless, unknown, unreliable]);
+        assert!(peers.iter().all(|p| (0.0..=1.0).contains(&p.value())));
+    }
+}
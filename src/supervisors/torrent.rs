@@ -1,30 +1,42 @@
 use async_channel::{bounded, Receiver, Sender};
 use crossbeam_channel::Sender as SyncSender;
-use hashbrown::HashSet;
+use hashbrown::{HashMap, HashSet};
+use std::collections::VecDeque;
 use std::sync::{
     atomic::{
-        AtomicUsize,
-        Ordering::{self, Acquire, Relaxed},
+        AtomicBool, AtomicU64, AtomicUsize,
+        Ordering::{self, Acquire, Relaxed, Release},
     },
     Arc,
 };
+use tokio::{net::TcpStream, sync::watch};
 // use log::info;
-use kv_log_macro::{debug, info, warn};
+use kv_log_macro::{debug, error, info, warn};
 
-use std::net::SocketAddr;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
 
 use crate::{
-    actors::sha1::Sha1Task,
+    actors::{listener::IncomingPeer, sha1::Sha1Task, tracker::batch::AnnounceBatcher},
     bitfield::{BitField, BitFieldUpdate},
     errors::TorrentError,
+    extensions::{Capabilities, EncryptionPolicy},
     fs::FSMessage,
     metadata::Torrent,
     peer::peer::{Peer, PeerCommand, PeerExternId, PeerId},
     piece_collector::{Block, PieceCollector},
-    piece_picker::{PieceIndex, PiecePicker},
+    piece_picker::{BlockIndex, PieceIndex, PiecePicker},
     pieces::{Pieces, TaskDownload},
+    rate_limit::BandwidthLimits,
+    resume::{ResumeBlock, ResumeData},
     spsc::{self, Producer},
-    supervisors::tracker::TrackerSupervisor,
+    supervisors::{
+        choke::{ChokeChange, ChokeManager, ChokeSettings},
+        peer_score::{PeerScore, NEUTRAL_SCORE},
+        tracker::TrackerSupervisor,
+    },
     utils::{send_to, Map},
 };
 
@@ -49,6 +61,12 @@ impl TorrentId {
 pub struct Shared {
     pub nbytes_on_tasks: AtomicUsize,
     pub socket: SocketAddr,
+    /// The peer chokes us
+    pub am_choked: AtomicBool,
+    /// The peer wants to download from us
+    pub peer_interested: AtomicBool,
+    /// Payload sent to the peer
+    pub uploaded: AtomicU64,
 }
 
 impl Shared {
@@ -56,10 +74,122 @@ impl Shared {
         Shared {
             socket,
             nbytes_on_tasks: AtomicUsize::new(0),
+            am_choked: AtomicBool::new(true),
+            peer_interested: AtomicBool::new(false),
+            uploaded: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Bytes exchanged with the peers of a torrent.
+/// The payload is the data of the PIECE messages, the total includes
+/// the protocol overhead (handshakes, headers and other messages)
+#[derive(Debug, Default)]
+pub struct ByteCounters {
+    payload_downloaded: AtomicU64,
+    payload_uploaded: AtomicU64,
+    total_downloaded: AtomicU64,
+    total_uploaded: AtomicU64,
+    wasted: AtomicU64,
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ByteStats {
+    pub payload_downloaded: u64,
+    pub payload_uploaded: u64,
+    pub total_downloaded: u64,
+    pub total_uploaded: u64,
+    /// Payload received without being requested, and dropped
+    pub wasted: u64,
+}
+
+impl ByteCounters {
+    pub fn add_downloaded(&self, payload: usize, total: usize) {
+        self.payload_downloaded.fetch_add(payload as u64, Relaxed);
+        self.total_downloaded.fetch_add(total as u64, Relaxed);
+    }
+
+    pub fn add_uploaded(&self, payload: usize, total: usize) {
+        self.payload_uploaded.fetch_add(payload as u64, Relaxed);
+        self.total_uploaded.fetch_add(total as u64, Relaxed);
+    }
+
+    pub fn add_wasted(&self, payload: usize) {
+        self.wasted.fetch_add(payload as u64, Relaxed);
+    }
+
+    pub fn stats(&self) -> ByteStats {
+        ByteStats {
+            payload_downloaded: self.payload_downloaded.load(Relaxed),
+            payload_uploaded: self.payload_uploaded.load(Relaxed),
+            total_downloaded: self.total_downloaded.load(Relaxed),
+            total_uploaded: self.total_uploaded.load(Relaxed),
+            wasted: self.wasted.load(Relaxed),
         }
     }
 }
 
+/// Values of the supervisor read by the session, for the metrics
+/// and `Session::status`
+#[derive(Debug, Default)]
+pub struct TorrentGauges {
+    peers: AtomicUsize,
+    pieces_verified: AtomicUsize,
+    /// Size of the pieces verified
+    verified_bytes: AtomicU64,
+    /// Updated every `PROGRESS_INTERVAL`
+    downloaded_bytes: AtomicU64,
+    download_rate: AtomicU64,
+    total_bytes: u64,
+    total_pieces: usize,
+}
+
+impl TorrentGauges {
+    pub(crate) fn new(total_bytes: u64, total_pieces: usize) -> TorrentGauges {
+        TorrentGauges {
+            total_bytes,
+            total_pieces,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn set(&self, peers: usize, pieces_verified: usize, verified_bytes: u64) {
+        self.peers.store(peers, Relaxed);
+        self.pieces_verified.store(pieces_verified, Relaxed);
+        self.verified_bytes.store(verified_bytes, Relaxed);
+    }
+
+    /// Progress of the torrent, `info_hash`, `labels` and `queued` are
+    /// left to the session
+    pub fn status(&self, info_hash: Arc<[u8]>, bytes: ByteStats) -> TorrentStatus {
+        TorrentStatus {
+            info_hash,
+            labels: Vec::new(),
+            queued: false,
+            bytes,
+            total_bytes: self.total_bytes,
+            downloaded_bytes: self.downloaded_bytes.load(Relaxed),
+            verified_pieces: self.pieces_verified(),
+            total_pieces: self.total_pieces,
+            num_peers: self.peers(),
+            download_rate_bps: self.download_rate.load(Relaxed),
+        }
+    }
+
+    pub fn peers(&self) -> usize {
+        self.peers.load(Relaxed)
+    }
+
+    pub fn pieces_verified(&self) -> usize {
+        self.pieces_verified.load(Relaxed)
+    }
+
+    /// Bytes of the pieces not verified yet
+    pub fn left(&self) -> u64 {
+        self.total_bytes.saturating_sub(self.verified_bytes.load(Relaxed))
+    }
+}
+
 struct PeerState {
     bitfield: BitField,
     queue_tasks: Producer<TaskDownload>,
@@ -67,6 +197,14 @@ struct PeerState {
     extern_id: Arc<PeerExternId>,
     tasks_nbytes: usize,
     shared: Arc<Shared>,
+    /// We initiated the connection
+    outbound: bool,
+    connected_at: coarsetime::Instant,
+    /// Payload received
+    downloaded: u64,
+    blocks_received: usize,
+    /// Blocks timed out or part of a piece failing its hash
+    blocks_failed: usize,
 }
 
 pub struct NewPeer {
@@ -75,6 +213,8 @@ pub struct NewPeer {
     pub addr: Sender<PeerCommand>,
     pub extern_id: Arc<PeerExternId>,
     pub shared: Arc<Shared>,
+    /// We initiated the connection
+    pub outbound: bool,
 }
 
 /// Message sent to TorrentSupervisor
@@ -103,6 +243,11 @@ pub enum TorrentNotification {
         id: PeerId,
         update: Box<BitFieldUpdate>,
     },
+    /// A peer didn't send in time a block of the piece
+    PieceTimeout {
+        id: PeerId,
+        piece_index: PieceIndex,
+    },
     /// Whether or not the piece match its sha1 sum
     ValidatePiece {
         piece_index: PieceIndex,
@@ -111,6 +256,69 @@ pub enum TorrentNotification {
     /// When a tracker discover peers, it send this message
     PeerDiscovered {
         addrs: Box<[SocketAddr]>,
+        origin: PeerOrigin,
+    },
+    /// A peer connected to a listen address of the session, with
+    /// the info hash of this torrent. Its handshake was already read
+    Accepted {
+        peer: Box<IncomingPeer>,
+    },
+    /// Request a snapshot of the pieces state
+    DebugPieces {
+        respond: SyncSender<PiecesDebug>,
+    },
+    /// Move the read-ahead window to this offset of the torrent
+    SetPlaybackPosition {
+        offset: u64,
+    },
+    /// Request the files of the torrent and their completion
+    TorrentFiles {
+        respond: SyncSender<Vec<FileProgress>>,
+    },
+    /// A piece read from the disk for the final verification
+    PieceRead {
+        piece_index: PieceIndex,
+        data: Box<[u8]>,
+    },
+    /// Result of the final verification of a piece
+    PieceVerified {
+        piece_index: PieceIndex,
+        valid: bool,
+    },
+    /// Send the `PieceEvent`s of this torrent to `sender`
+    SubscribePieces {
+        sender: Sender<PieceEvent>,
+    },
+    /// Request the state of the download, to resume it later
+    ExportResume {
+        respond: SyncSender<ResumeData>,
+    },
+    /// Read all pieces from the disk and check them again
+    Recheck,
+    /// Disconnect the peers and stop announcing, the state is kept
+    Pause,
+    /// Announce and connect to the peers again after a `Pause`
+    Resume,
+    /// Disconnect the peers, announce `Stopped` and flush the disk, in
+    /// this order. `done` is notified at the end, the supervisor stops
+    Shutdown {
+        done: SyncSender<()>,
+    },
+    /// The disk is almost full, the piece wasn't written
+    WriteRejected {
+        piece_index: PieceIndex,
+    },
+    /// There is room again on the disk, after a `WriteRejected`
+    DiskSpaceAvailable,
+    /// The disk doesn't have room to preallocate the files, in bytes
+    AllocationFailed {
+        needed: u64,
+        available: u64,
+    },
+    /// A file of the torrent can't be opened, a missing file of a
+    /// read-only torrent for example. The pieces over it can't be read
+    FileError {
+        error: std::io::Error,
     },
 }
 
@@ -138,16 +346,331 @@ impl std::fmt::Debug for TorrentNotification {
                 .debug_struct("TorrentNotification")
                 .field("UpdateBitfield", &id)
                 .finish(),
+            PieceTimeout { id, piece_index } => f
+                .debug_struct("TorrentNotification")
+                .field("PieceTimeout", &id)
+                .field("PieceIndex", &piece_index)
+                .finish(),
             ValidatePiece { piece_index, valid } => f
                 .debug_struct("TorrentNotification")
                 .field("PieceIndex", &piece_index)
                 .field("valid", &valid)
                 .finish(),
-            PeerDiscovered { addrs } => f
+            PeerDiscovered { addrs, origin } => f
                 .debug_struct("TorrentNotification")
                 .field("addrs", &addrs)
+                .field("origin", &origin)
+                .finish(),
+            Accepted { peer } => f
+                .debug_struct("TorrentNotification")
+                .field("Accepted", &peer.addr)
+                .finish(),
+            DebugPieces { .. } => f
+                .debug_struct("TorrentNotification")
+                .field("DebugPieces", &"")
+                .finish(),
+            SetPlaybackPosition { offset } => f
+                .debug_struct("TorrentNotification")
+                .field("SetPlaybackPosition", &offset)
+                .finish(),
+            TorrentFiles { .. } => f
+                .debug_struct("TorrentNotification")
+                .field("TorrentFiles", &"")
+                .finish(),
+            PieceRead { piece_index, .. } => f
+                .debug_struct("TorrentNotification")
+                .field("PieceRead", &piece_index)
+                .finish(),
+            PieceVerified { piece_index, valid } => f
+                .debug_struct("TorrentNotification")
+                .field("PieceVerified", &piece_index)
+                .field("valid", &valid)
+                .finish(),
+            SubscribePieces { .. } => f
+                .debug_struct("TorrentNotification")
+                .field("SubscribePieces", &"")
+                .finish(),
+            ExportResume { .. } => f
+                .debug_struct("TorrentNotification")
+                .field("ExportResume", &"")
+                .finish(),
+            Recheck => f
+                .debug_struct("TorrentNotification")
+                .field("Recheck", &"")
+                .finish(),
+            Pause => f
+                .debug_struct("TorrentNotification")
+                .field("Pause", &"")
+                .finish(),
+            Resume => f
+                .debug_struct("TorrentNotification")
+                .field("Resume", &"")
+                .finish(),
+            Shutdown { .. } => f
+                .debug_struct("TorrentNotification")
+                .field("Shutdown", &"")
                 .finish(),
+            WriteRejected { piece_index } => f
+                .debug_struct("TorrentNotification")
+                .field("WriteRejected", &piece_index)
+                .finish(),
+            DiskSpaceAvailable => f
+                .debug_struct("TorrentNotification")
+                .field("DiskSpaceAvailable", &"")
+                .finish(),
+            AllocationFailed { needed, available } => f
+                .debug_struct("TorrentNotification")
+                .field("AllocationFailed", &(needed, available))
+                .finish(),
+            FileError { error } => f
+                .debug_struct("TorrentNotification")
+                .field("FileError", &error)
+                .finish(),
+        }
+    }
+}
+
+/// Where the address of a peer comes from
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PeerOrigin {
+    Tracker,
+    Pex,
+    /// Added with `Session::add_peers`
+    Manual,
+    /// A custom `PeerSource`
+    PeerSource,
+}
+
+/// Options of a torrent, given when it's added to the session
+#[derive(Debug, Default, Clone)]
+pub struct TorrentOptions {
+    /// Seed from existing data, without ever writing to disk.
+    /// All pieces are considered complete and nothing is downloaded
+    pub read_only: bool,
+    /// Don't announce to the trackers, peers are only those
+    /// added with `Session::add_peers`
+    pub disable_trackers: bool,
+    /// Index of the files downloaded in order (for streaming),
+    /// the other files are downloaded rarest first
+    pub sequential_files: Vec<usize>,
+    /// Number of pieces requested in priority after the playback
+    /// position, so streaming doesn't stall. 0 to disable.
+    /// The position starts at the first sequential file and is moved
+    /// with `Session::set_playback_position`
+    pub read_ahead: usize,
+    /// Once all pieces are downloaded, read them back from the disk
+    /// and check their sha1 before considering the torrent completed
+    pub verify_on_complete: bool,
+    /// Read each piece back from the disk once written and check its
+    /// sha1 again before it counts as downloaded. A mismatch means the
+    /// disk corrupted it, the piece is downloaded again
+    pub verify_written: bool,
+    /// Maximum number of pieces sent at once to the sha1 workers.
+    /// 0 or 1 sends each piece on its own
+    pub sha1_batch_size: usize,
+    /// Write over the existing files with a different content.
+    /// Without it, a torrent finding such files doesn't start and
+    /// sends `TorrentEvent::FileConflict`
+    pub overwrite_existing: bool,
+    /// Download only: all peers stay choked and their requests are
+    /// ignored. This is bad for the swarm, use it only on links
+    /// where uploading is not possible
+    pub no_upload: bool,
+    /// Which peers found with PEX are dialed, depending on their
+    /// support of encryption
+    pub encryption: EncryptionPolicy,
+    /// Don't announce the extension protocol (BEP 10) in the handshakes,
+    /// there is no PEX then
+    pub disable_extensions: bool,
+    /// Arbitrary labels, to group the torrents of the session.
+    /// See `Session::torrents_by_label`
+    pub labels: Vec<String>,
+    /// Pieces verified in a previous session. When it doesn't match
+    /// the torrent, it's discarded and all pieces are rechecked
+    pub resume: Option<ResumeData>,
+    /// Don't trust the pieces of `resume`, read them all back from the
+    /// disk and check their sha1 when the torrent starts
+    pub verify_on_resume: bool,
+    /// Maximum number of peers connected, 0 for no limit. Over it, the
+    /// peers with the lowest `PeerScore` are dropped
+    pub max_peers: usize,
+    /// Number of block requests kept outstanding to each peer, refilled
+    /// as the blocks arrive. 0 for the default of 16. A peer announcing
+    /// a lower `reqq` gets at most that many
+    pub pipeline_depth: usize,
+    /// Maximum time waited for a block requested to a peer, lower with
+    /// the peers answering fast. Past it, the block is canceled and
+    /// requested to another peer. 0 for the default of 60s
+    pub request_timeout: std::time::Duration,
+}
+
+/// A peer is banned once it supplied blocks of that many pieces
+/// failing their hash
+const MAX_HASH_FAILURES: usize = 3;
+
+/// Once that many different peers timed out on its blocks, a piece
+/// is suspect and requested to all the peers having it
+const MAX_ASSEMBLY_FAILURES: usize = 3;
+
+/// A peer connected for less than this has the `NEUTRAL_SCORE`, it
+/// didn't have the time to send its bitfield and blocks
+const SCORE_GRACE_PERIOD: u64 = 30;
+
+/// Addresses kept in `known_peers`, the ones with the lowest score
+/// are forgotten first
+const MAX_KNOWN_PEERS: usize = 1000;
+
+/// Interval between the summaries of the connections in the logs
+const STATS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Maximum number of connections being established at the same time
+const MAX_HALF_OPEN: usize = 8;
+/// New peers are dialed gradually, a random number of them (up to
+/// `MAX_DIALS_PER_TICK`) on each tick
+const DIAL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+const MAX_DIALS_PER_TICK: usize = 5;
+
+/// Number of pieces read from the disk at the same time
+/// during the final verification
+const RECHECK_IN_FLIGHT: usize = 4;
+
+/// Port announced to the trackers by a torrent outside of a session
+/// accepting connections
+const DEFAULT_LISTEN_PORT: u16 = 6881;
+
+/// Interval between the checks of the free space, while the torrent
+/// is paused because the disk is almost full
+const DISK_SPACE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Interval between the updates of the progress and of the
+/// download rate
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Weight of the last second in the download rate
+const RATE_SMOOTHING: f64 = 0.3;
+
+/// Maximum duration of each step of the shutdown
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Progress of the final verification, or of a recheck requested
+/// with `Session::recheck`
+#[derive(Debug, Default)]
+struct Recheck {
+    /// Next piece to read
+    next: usize,
+    /// Number of pieces checked
+    checked: usize,
+    failed: Vec<PieceIndex>,
+    /// Requested by the user: the bitfield is replaced by the result,
+    /// the pieces found valid are added to it
+    manual: bool,
+}
+
+/// A torrent without any new block during this duration (in seconds) is stalled
+const STALL_TIMEOUT: u64 = 5 * 60;
+
+/// Event sent by the supervisor to the session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentEvent {
+    /// All pieces are downloaded and verified
+    Completed { info_hash: Arc<[u8]> },
+    /// No block received for `STALL_TIMEOUT`
+    Stalled { info_hash: Arc<[u8]> },
+    /// Blocks are received again after a stall
+    Resumed { info_hash: Arc<[u8]> },
+    /// The final verification, or the recheck of a complete torrent,
+    /// found invalid pieces on the disk. They are downloaded again
+    VerificationFailed {
+        info_hash: Arc<[u8]>,
+        pieces: Box<[PieceIndex]>,
+    },
+    /// Files of the torrent already exist with another content,
+    /// see `TorrentOptions::overwrite_existing`
+    FileConflict {
+        info_hash: Arc<[u8]>,
+        files: Box<[PathBuf]>,
+    },
+    /// The disk doesn't have room to preallocate the files, the torrent
+    /// is paused. See `SessionConfig::allocation`
+    AllocationFailed {
+        info_hash: Arc<[u8]>,
+        needed: u64,
+        available: u64,
+    },
+}
+
+/// Result of the sha1 check of a piece, see `Session::subscribe_pieces`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PieceEvent {
+    PieceVerified(PieceIndex),
+    PieceFailed(PieceIndex),
+}
+
+/// A torrent of the session, with its labels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentStatus {
+    pub info_hash: Arc<[u8]>,
+    /// Labels of the torrent, then those of the session
+    pub labels: Vec<String>,
+    /// Whether the torrent is waiting for an active slot
+    pub queued: bool,
+    pub bytes: ByteStats,
+    /// Size of all the files
+    pub total_bytes: u64,
+    /// Bytes of the verified pieces and of the blocks received for
+    /// the others
+    pub downloaded_bytes: u64,
+    pub verified_pieces: usize,
+    pub total_pieces: usize,
+    pub num_peers: usize,
+    /// Payload received, in bytes per second. Moving average over
+    /// the last seconds
+    pub download_rate_bps: u64,
+}
+
+/// A file of a torrent, with the number of bytes verified
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileProgress {
+    /// Path of the file, starting with the torrent name
+    /// for multi-file torrents
+    pub path: PathBuf,
+    pub length: u64,
+    /// Bytes of the file in verified pieces
+    pub downloaded: u64,
+}
+
+impl FileProgress {
+    pub fn is_complete(&self) -> bool {
+        self.downloaded == self.length
+    }
+}
+
+/// Snapshot of the pieces of a torrent
+#[derive(Debug, Clone)]
+pub struct PiecesDebug {
+    /// Verified pieces, in the format of the BITFIELD message
+    pub bitfield: Box<[u8]>,
+    pub num_pieces: usize,
+    pub missing: usize,
+    pub downloading: usize,
+    /// Pieces downloaded, waiting for their sha1 to be checked
+    pub checking: usize,
+    pub verified: usize,
+    /// All the peers having pieces we want choke us
+    pub choked_by_all: bool,
+}
+
+impl std::fmt::Display for PiecesDebug {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pieces={} verified={} checking={} downloading={} missing={} bitfield=",
+            self.num_pieces, self.verified, self.checking, self.downloading, self.missing
+        )?;
+        for byte in &*self.bitfield {
+            write!(f, "{:02x}", byte)?;
         }
+        Ok(())
     }
 }
 
@@ -155,6 +678,7 @@ pub struct TorrentSupervisor {
     id: TorrentId,
 
     metadata: Arc<Torrent>,
+    options: TorrentOptions,
     receiver: Receiver<TorrentNotification>,
     // We keep a Sender to not close the channel
     // in case there is no peer
@@ -163,16 +687,111 @@ pub struct TorrentSupervisor {
     pieces_infos: Arc<Pieces>,
 
     peers_socket: HashSet<SocketAddr>,
+    /// Peers discovered, waiting to be dialed
+    dial_queue: VecDeque<SocketAddr>,
+    /// Number of connections in progress
+    half_open: Arc<AtomicUsize>,
+    /// Number of peer tasks connected, until their socket is closed
+    running_peers: Arc<AtomicUsize>,
+
+    /// Source of the blocks of the pieces being downloaded.
+    /// A block received twice (endgame) is attributed to the last
+    /// peer, its data is the one kept
+    block_sources: Map<PieceIndex, HashMap<BlockIndex, IpAddr>>,
+    /// Peers who supplied the pieces waiting for their sha1
+    checking_sources: Map<PieceIndex, Vec<IpAddr>>,
+    /// Pieces read back from the disk once written, see
+    /// `TorrentOptions::verify_written`
+    reading_back: HashSet<PieceIndex>,
+    /// Number of pieces failing their hash, by peer
+    hash_failures: HashMap<IpAddr, usize>,
+    banned: HashSet<IpAddr>,
+    /// All the addresses discovered and their origin, to dial them
+    /// again when all the peers choke us
+    known_peers: HashMap<SocketAddr, PeerOrigin>,
+    /// Score of the peers when they disconnected, the best ones are
+    /// dialed first
+    peer_scores: HashMap<SocketAddr, f64>,
+    /// Number of connections failed or terminated with an error
+    peer_errors: Arc<AtomicUsize>,
+    choked_by_all: bool,
+    /// The peers we upload to
+    choke: ChokeManager,
+    /// Peers who timed out on the blocks of a piece
+    assembly_failures: Map<PieceIndex, HashSet<PeerId>>,
     peers: Map<PeerId, PeerState>,
 
     piece_picker: PiecePicker,
 
+    /// Pieces we have, with a valid sha1
+    bitfield: BitField,
+
     collector: PieceCollector,
 
     sha1_workers: SyncSender<Sha1Task>,
 
     extern_id: Arc<PeerExternId>,
 
+    counters: Arc<ByteCounters>,
+    gauges: Arc<TorrentGauges>,
+
+    /// Number of bits set in `bitfield`
+    num_verified: usize,
+    /// Last time we received a block
+    last_progress: coarsetime::Instant,
+    /// Payload downloaded at the last progress update, and its time
+    rate_sample: (coarsetime::Instant, u64),
+    /// Bytes per second
+    download_rate: f64,
+    stalled: bool,
+    events: Option<SyncSender<TorrentEvent>>,
+    /// Receive the result of each piece checked
+    piece_subscribers: Vec<Sender<PieceEvent>>,
+    /// Shared with the other torrents to batch the announces
+    announce_batcher: Option<AnnounceBatcher>,
+    /// Announced to the trackers, `DEFAULT_LISTEN_PORT` until the session
+    /// gives its own
+    listen_port: u16,
+    bandwidth: BandwidthLimits,
+    /// Final verification in progress
+    recheck: Option<Recheck>,
+    /// Tells the trackers the torrent is complete. It starts as `true`
+    /// for a torrent already complete, so `Completed` is announced only
+    /// when the download finishes
+    completion: watch::Sender<bool>,
+    /// Kept for the trackers, and so `completion` is never closed
+    completion_recv: watch::Receiver<bool>,
+    /// `Completed` was announced before a restart, the trackers don't
+    /// announce it again
+    completed_before: bool,
+    /// Payload downloaded and uploaded in the previous sessions, from
+    /// the resume data
+    bytes_before: (u64, u64),
+    /// Whether the torrent is paused, the trackers stop announcing
+    paused: watch::Sender<bool>,
+    paused_recv: watch::Receiver<bool>,
+    /// Set on shutdown, the trackers announce `Stopped` and end
+    shutdown: watch::Sender<bool>,
+    shutdown_recv: watch::Receiver<bool>,
+    /// Task of the `TrackerSupervisor`, awaited on shutdown
+    trackers: Option<tokio::task::JoinHandle<()>>,
+    /// Paused because the disk is almost full, resumed once
+    /// there is room again
+    disk_full: bool,
+    /// Paused because the files couldn't be preallocated, the
+    /// allocation is made again on resume
+    allocation_failed: bool,
+    /// Tasks waiting to be sent to the sha1 workers
+    sha1_batch: Vec<Sha1Task>,
+    /// Delay of the first announce and peer connections, to spread the
+    /// torrents starting with the session
+    start_delay: std::time::Duration,
+    /// No peer is dialed before this instant
+    dial_after: Option<tokio::time::Instant>,
+    /// The resume data didn't match the torrent, verify all the
+    /// pieces once started
+    recheck_on_start: bool,
+
     fs: Sender<FSMessage>,
 }
 
@@ -181,6 +800,7 @@ pub type Result<T> = std::result::Result<T, TorrentError>;
 impl TorrentSupervisor {
     pub fn new(
         torrent: Torrent,
+        options: TorrentOptions,
         sha1_workers: SyncSender<Sha1Task>,
         fs: Sender<FSMessage>,
     ) -> TorrentSupervisor {
@@ -189,95 +809,795 @@ impl TorrentSupervisor {
 
         let extern_id = Arc::new(PeerExternId::generate());
 
-        let collector = PieceCollector::new(&pieces_infos);
-        let piece_picker = PiecePicker::new(&pieces_infos);
+        let mut collector = PieceCollector::new(&pieces_infos);
+        let mut piece_picker = PiecePicker::new(&pieces_infos);
+        let mut bitfield = BitField::new(pieces_infos.num_pieces);
+
+        let files = torrent.files();
+        for &file_index in &options.sequential_files {
+            if let Some(file) = files.get(file_index) {
+                let offset: u64 = files[..file_index].iter().map(|f| f.length).sum();
+                let (start, end) =
+                    pieces_infos.pieces_of_range(offset as usize, file.length as usize);
+                piece_picker.set_sequential(start, end);
+            }
+        }
+
+        if options.read_ahead > 0 {
+            piece_picker.set_read_ahead(options.read_ahead);
+
+            if let Some(&file_index) = options.sequential_files.first() {
+                if file_index < files.len() {
+                    let offset: u64 = files[..file_index].iter().map(|f| f.length).sum();
+                    let (start, _) = pieces_infos.pieces_of_range(offset as usize, 0);
+                    piece_picker.set_playback_position(start);
+                }
+            }
+        }
+
+        let (total_bytes, total_pieces) = (pieces_infos.files_size as u64, pieces_infos.num_pieces);
+        let mut num_verified = 0;
+        let mut recheck_on_start = false;
+        let mut completed_before = false;
+        let mut bytes_before = (0, 0);
+
+        if options.read_only {
+            // Nothing is downloaded, the pieces are seeded once their
+            // sha1 is checked on the disk
+            piece_picker.set_all_as_downloaded();
+            recheck_on_start = true;
+        } else if let Some(resume) = options.resume.as_ref() {
+            match resume.check(&torrent) {
+                Ok(_) => {
+                    for index in 0..pieces_infos.num_pieces {
+                        if resume.has_piece(index) {
+                            piece_picker.set_as_downloaded((index as u32).into(), true);
+                            bitfield.set_bit(index);
+                            num_verified += 1;
+                        }
+                    }
+
+                    for block in &resume.partial {
+                        if resume.has_piece(block.piece as usize) {
+                            continue;
+                        }
+                        // A complete piece is unexpected, it's downloaded again
+                        collector.add_block(&Block {
+                            piece_index: block.piece.into(),
+                            index: block.offset.into(),
+                            block: block.data.as_slice().into(),
+                        });
+                    }
+
+                    recheck_on_start = options.verify_on_resume;
+                    completed_before = resume.completed;
+                    bytes_before = (resume.downloaded, resume.uploaded);
+                }
+                Err(e) => {
+                    warn!("Resume data discarded, {:?}", e);
+                    recheck_on_start = true;
+                }
+            }
+        }
 
         let id = TorrentId::new();
+        // A read-only torrent has nothing to download, it never announces
+        // `Completed`
+        let complete = options.read_only || num_verified == pieces_infos.num_pieces;
+        let (completion, completion_recv) = watch::channel(complete);
+        let (paused, paused_recv) = watch::channel(false);
+        let (shutdown, shutdown_recv) = watch::channel(false);
 
-        TorrentSupervisor {
+        let supervisor = TorrentSupervisor {
             id,
             metadata: Arc::new(torrent),
+            options,
             receiver,
             my_addr,
             pieces_infos,
             peers_socket: HashSet::new(),
+            dial_queue: VecDeque::new(),
+            half_open: Arc::new(AtomicUsize::new(0)),
+            running_peers: Arc::new(AtomicUsize::new(0)),
+            block_sources: Map::default(),
+            checking_sources: Map::default(),
+            reading_back: HashSet::new(),
+            hash_failures: HashMap::default(),
+            banned: HashSet::new(),
+            known_peers: HashMap::default(),
+            peer_scores: HashMap::default(),
+            peer_errors: Arc::new(AtomicUsize::new(0)),
+            choked_by_all: false,
+            choke: ChokeManager::new(ChokeSettings::default()),
+            assembly_failures: Map::default(),
             peers: Map::default(),
             piece_picker,
+            bitfield,
             collector,
             sha1_workers,
             extern_id,
+            counters: Arc::new(ByteCounters::default()),
+            gauges: Arc::new(TorrentGauges::new(total_bytes, total_pieces)),
+            num_verified,
+            last_progress: coarsetime::Instant::now(),
+            rate_sample: (coarsetime::Instant::now(), 0),
+            download_rate: 0.0,
+            stalled: false,
+            events: None,
+            piece_subscribers: Vec::new(),
+            announce_batcher: None,
+            listen_port: DEFAULT_LISTEN_PORT,
+            bandwidth: BandwidthLimits::default(),
+            recheck: None,
+            completion,
+            completion_recv,
+            completed_before,
+            bytes_before,
+            paused,
+            paused_recv,
+            shutdown,
+            shutdown_recv,
+            trackers: None,
+            disk_full: false,
+            allocation_failed: false,
+            sha1_batch: Vec::new(),
+            start_delay: std::time::Duration::from_secs(0),
+            dial_after: None,
+            recheck_on_start,
             fs,
+        };
+        // The pieces of the resume data
+        supervisor.update_gauges();
+        supervisor
+    }
+
+    /// Send the `TorrentEvent`s of this torrent to `events`
+    pub(crate) fn set_events(&mut self, events: SyncSender<TorrentEvent>) {
+        self.events = Some(events);
+    }
+
+    /// Wait this duration before announcing and connecting to the peers
+    pub(crate) fn set_start_delay(&mut self, delay: std::time::Duration) {
+        self.start_delay = delay;
+    }
+
+    /// Use the peer id of the session, instead of one for this torrent
+    pub(crate) fn set_extern_id(&mut self, extern_id: Arc<PeerExternId>) {
+        self.extern_id = extern_id;
+    }
+
+    /// Port accepting the peers, announced to the trackers
+    pub(crate) fn set_listen_port(&mut self, port: u16) {
+        self.listen_port = port;
+    }
+
+    /// Announce to the HTTP trackers with the other torrents of the session
+    pub(crate) fn set_announce_batcher(&mut self, batcher: AnnounceBatcher) {
+        self.announce_batcher = Some(batcher);
+    }
+
+    /// Throttle the peers with the limits of the session
+    pub(crate) fn set_bandwidth_limits(&mut self, bandwidth: BandwidthLimits) {
+        self.bandwidth = bandwidth;
+    }
+
+    fn is_paused(&self) -> bool {
+        *self.paused_recv.borrow()
+    }
+
+    fn pause(&mut self) {
+        if self.is_paused() {
+            return;
+        }
+
+        info!("Pausing torrent", { id: self.id.to_string() });
+        self.paused.send(true).ok();
+
+        // Dialed again on resume
+        for socket in self.disconnect_peers() {
+            if !self.dial_queue.contains(&socket) {
+                self.dial_queue.push_back(socket);
+            }
+        }
+    }
+
+    /// The piece is downloaded again later, the torrent is paused until
+    /// the disk has room
+    fn on_write_rejected(&mut self, piece_index: PieceIndex) {
+        let index: usize = piece_index.into();
+
+        if self.bitfield.get_bit(index) {
+            self.piece_picker.set_as_downloaded(piece_index, false);
+            self.bitfield.clear_bit(index);
+            self.num_verified -= 1;
+        }
+
+        if !self.is_paused() {
+            warn!("Disk almost full, pausing", { id: self.id.to_string() });
+            self.disk_full = true;
+            self.pause();
+        }
+    }
+
+    /// Tell all the peers to die, returns their addresses
+    fn disconnect_peers(&mut self) -> Vec<SocketAddr> {
+        let ids: Vec<_> = self.peers.keys().copied().collect();
+        let mut sockets = Vec::with_capacity(ids.len());
+
+        for id in ids {
+            if let Some(peer) = self.peers.get(&id) {
+                sockets.push(peer.shared.socket);
+                send_to(&peer.addr, PeerCommand::Die);
+            }
+            self.remove_peer(id);
+        }
+
+        sockets
+    }
+
+    /// Stop the torrent. The order matters:
+    ///
+    /// 1. The peers are disconnected, nothing new is downloaded
+    /// 2. The trackers announce `Stopped`, while we can still reach them
+    /// 3. The blocks written by the peers are flushed to the disk
+    ///
+    /// Each step waits at most `SHUTDOWN_TIMEOUT`
+    async fn shutdown(&mut self) {
+        use tokio::time::{sleep, timeout, Instant};
+
+        info!("Shutting down torrent", { id: self.id.to_string() });
+
+        self.disconnect_peers();
+        self.dial_queue.clear();
+
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while self.running_peers.load(Acquire) > 0 && Instant::now() < deadline {
+            sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        self.shutdown.send(true).ok();
+        if let Some(trackers) = self.trackers.take() {
+            timeout(SHUTDOWN_TIMEOUT, trackers).await.ok();
+        }
+
+        // Blocks waiting for their sha1 are written after the flush,
+        // they are verified again on the next start
+        self.flush_sha1();
+
+        let (done, flushed) = bounded(1);
+        let flush = FSMessage::Flush { id: self.id, done };
+        if self.fs.send(flush).await.is_ok() {
+            timeout(SHUTDOWN_TIMEOUT, flushed.recv()).await.ok();
+        }
+    }
+
+    fn resume(&mut self) {
+        if !self.is_paused() {
+            return;
+        }
+
+        info!("Resuming torrent", { id: self.id.to_string() });
+        self.paused.send(false).ok();
+
+        // Paused again if it still fails
+        if self.allocation_failed {
+            self.allocation_failed = false;
+            send_to(&self.fs, FSMessage::Allocate { id: self.id });
         }
+        // The time paused doesn't count as a stall
+        self.last_progress = coarsetime::Instant::now();
+    }
+
+    fn is_complete(&self) -> bool {
+        self.num_verified == self.pieces_infos.num_pieces
+    }
+
+    fn send_event(&self, event: TorrentEvent) {
+        if let Some(events) = self.events.as_ref() {
+            events.try_send(event).ok();
+        }
+    }
+
+    pub(crate) fn addr(&self) -> Sender<TorrentNotification> {
+        self.my_addr.clone()
+    }
+
+    pub(crate) fn counters(&self) -> Arc<ByteCounters> {
+        Arc::clone(&self.counters)
+    }
+
+    pub(crate) fn gauges(&self) -> Arc<TorrentGauges> {
+        Arc::clone(&self.gauges)
     }
 
     pub async fn start(&mut self) {
-        let metadata = Arc::clone(&self.metadata);
-        let my_addr = self.my_addr.clone();
-        let extern_id = self.extern_id.clone();
+        if !self.options.overwrite_existing && !self.options.read_only {
+            let files = self.metadata.files();
+            let pieces_infos = Arc::clone(&self.pieces_infos);
+            // The pieces of the resume data
+            let ours = self.bitfield.clone();
+
+            let conflicts = tokio::task::spawn_blocking(move || {
+                crate::fs::find_conflicts(&files, &pieces_infos, &ours)
+            })
+            .await
+            .unwrap_or_default();
 
-        tokio::spawn(async {
-            TrackerSupervisor::new(my_addr, metadata, extern_id)
+            if !conflicts.is_empty() {
+                error!("Existing files differ from the torrent, not overwriting them: {:?}", conflicts, {
+                    id: self.id.to_string()
+                });
+                self.send_event(TorrentEvent::FileConflict {
+                    info_hash: Arc::clone(&self.metadata.info_hash),
+                    files: conflicts.into_boxed_slice(),
+                });
+                return;
+            }
+        }
+
+        if !self.options.disable_trackers {
+            let metadata = Arc::clone(&self.metadata);
+            let my_addr = self.my_addr.clone();
+            let extern_id = self.extern_id.clone();
+            let counters = self.counters();
+            let gauges = self.gauges();
+            let port = self.listen_port;
+            let batcher = self.announce_batcher.clone();
+            let completion = self.completion_recv.clone();
+            let announce_completed = !self.completed_before;
+            let paused = self.paused_recv.clone();
+            let shutdown = self.shutdown_recv.clone();
+            let delay = self.start_delay;
+
+            self.trackers = Some(tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                TrackerSupervisor::new(
+                    my_addr,
+                    metadata,
+                    extern_id,
+                    counters,
+                    gauges,
+                    port,
+                    batcher,
+                    completion,
+                    announce_completed,
+                    paused,
+                    shutdown,
+                )
                 .start()
                 .await;
-        });
+            }));
+        }
+
+        self.dial_after = Some(tokio::time::Instant::now() + self.start_delay);
 
         self.fs
             .send(FSMessage::AddTorrent {
                 id: self.id,
                 meta: Arc::clone(&self.metadata),
                 pieces_infos: Arc::clone(&self.pieces_infos),
+                read_only: self.options.read_only,
+                supervisor: self.my_addr.clone(),
             })
             .await
             .unwrap();
 
+        if self.recheck_on_start {
+            self.start_recheck(true);
+        }
+
         self.process_cmds().await;
     }
 
     fn connect_to_peers(&self, addr: &SocketAddr) {
         debug!("Connecting", { addr: addr.to_string() });
 
-        let addr = *addr;
+        self.spawn_peer(*addr, None);
+    }
+
+    fn accept_peer(&self, peer: IncomingPeer) {
+        debug!("Accepted", { addr: peer.addr.to_string() });
+
+        self.spawn_peer(peer.addr, Some((peer.stream, peer.extern_id)));
+    }
+
+    /// Run a peer, `inbound` is the stream and the id of a peer which
+    /// connected to us. Without it, the peer is dialed
+    fn spawn_peer(&self, addr: SocketAddr, inbound: Option<(TcpStream, PeerExternId)>) {
         let my_addr = self.my_addr.clone();
+        let supervisor = self.my_addr.clone();
         let pieces_infos = self.pieces_infos.clone();
         let extern_id = self.extern_id.clone();
         let fs = self.fs.clone();
+        let counters = self.counters();
         let id = self.id;
+        let bitfield = self.our_bitfield();
+        let half_open = Arc::clone(&self.half_open);
+        let running_peers = Arc::clone(&self.running_peers);
+        let peer_errors = Arc::clone(&self.peer_errors);
+        let no_upload = self.options.no_upload;
+        let pipeline_depth = self.options.pipeline_depth;
+        let request_timeout = self.options.request_timeout;
+        let bandwidth = self.bandwidth.clone();
+        let encryption = self.options.encryption;
+        let capabilities = if self.options.disable_extensions {
+            Capabilities::empty()
+        } else {
+            Capabilities::default()
+        };
+
+        if inbound.is_none() {
+            half_open.fetch_add(1, Relaxed);
+        }
 
         tokio::spawn(async move {
             let (producer, consumer) = spsc::bounded(256);
 
-            let mut peer =
-                match Peer::new(id, addr, pieces_infos, my_addr, extern_id, consumer, fs).await {
-                    Ok(peer) => peer,
-                    Err(e) => {
-                        warn!("Peer error {:?}", e, { addr: addr.to_string() });
-                        return;
-                    }
-                };
-            let result = peer.start(producer).await;
-            warn!("[{}] Peer terminated: {:?}", peer.internal_id(), result, { addr: addr.to_string() });
+            let peer = match inbound {
+                Some((stream, remote_id)) => Ok(Peer::accepted(
+                    id,
+                    addr,
+                    stream,
+                    remote_id,
+                    pieces_infos,
+                    my_addr,
+                    extern_id,
+                    consumer,
+                    fs,
+                    counters,
+                )),
+                None => {
+                    let peer = Peer::new(
+                        id,
+                        addr,
+                        pieces_infos,
+                        my_addr,
+                        extern_id,
+                        consumer,
+                        fs,
+                        counters,
+                    )
+                    .await;
+
+                    half_open.fetch_sub(1, Relaxed);
+                    peer
+                }
+            };
+
+            let mut peer = match peer {
+                Ok(peer) => peer,
+                Err(e) => {
+                    warn!("Peer error {:?}", e, { addr: addr.to_string() });
+                    peer_errors.fetch_add(1, Relaxed);
+                    return;
+                }
+            };
+            running_peers.fetch_add(1, Relaxed);
+            peer.set_no_upload(no_upload);
+            peer.set_encryption(encryption);
+            peer.set_capabilities(capabilities);
+            peer.set_pipeline_depth(pipeline_depth);
+            peer.set_request_timeout(request_timeout);
+            peer.set_bandwidth_limits(bandwidth);
+
+            let result = peer.start(producer, bitfield).await;
+            if result.is_err() {
+                peer_errors.fetch_add(1, Relaxed);
+            }
+            let peer_id = peer.internal_id();
+            warn!("[{}] Peer terminated: {:?}", peer_id, result, { addr: addr.to_string() });
+
+            // Close the socket before the shutdown goes on
+            drop(peer);
+            running_peers.fetch_sub(1, Release);
+
+            send_to(&supervisor, TorrentNotification::RemovePeer { id: peer_id });
         });
     }
 
-    async fn process_cmds(&mut self) {
-        while let Ok(msg) = self.receiver.recv().await {
-            self.process_cmd(msg);
+    /// Dial some of the queued peers, without exceeding `MAX_HALF_OPEN`
+    fn dial_queued(&mut self) {
+        if self.dial_queue.is_empty() {
+            return;
         }
-    }
 
-    fn process_cmd(&mut self, msg: TorrentNotification) {
-        use TorrentNotification::*;
+        if self.is_paused() {
+            return;
+        }
 
-        match msg {
-            UpdateBitfield { id, update } => {
-                let peer = match self.peers.get_mut(&id) {
-                    Some(peer) => peer,
-                    None => return,
+        if let Some(after) = self.dial_after {
+            if tokio::time::Instant::now() < after {
+                return;
+            }
+        }
+
+        let half_open = self.half_open.load(Relaxed);
+        let mut available = MAX_HALF_OPEN.saturating_sub(half_open);
+
+        if self.options.max_peers > 0 {
+            let slots = self
+                .options
+                .max_peers
+                .saturating_sub(self.peers.len() + half_open);
+            available = available.min(slots);
+        }
+
+        let ndials = fastrand::usize(1..=MAX_DIALS_PER_TICK).min(available);
+
+        // The sort is stable, the unknown peers stay in their order
+        let scores = &self.peer_scores;
+        let score = |addr: &SocketAddr| scores.get(addr).copied().unwrap_or(NEUTRAL_SCORE);
+        self.dial_queue
+            .make_contiguous()
+            .sort_by(|a, b| score(b).partial_cmp(&score(a)).unwrap());
+
+        for _ in 0..ndials {
+            match self.dial_queue.pop_front() {
+                Some(addr) => self.connect_to_peers(&addr),
+                None => return,
+            }
+        }
+    }
+
+    async fn process_cmds(&mut self) {
+        let mut stall_check = tokio::time::interval(std::time::Duration::from_secs(30));
+        let mut dial_tick = tokio::time::interval(DIAL_INTERVAL);
+        let mut stats_tick = tokio::time::interval(STATS_INTERVAL);
+        let mut disk_space_tick = tokio::time::interval(DISK_SPACE_INTERVAL);
+        let mut progress_tick = tokio::time::interval(PROGRESS_INTERVAL);
+        let mut choke_interval = self.choke.settings().interval;
+        let mut choke_tick = tokio::time::interval(choke_interval);
+
+        loop {
+            tokio::select! {
+                msg = self.receiver.recv() => match msg {
+                    Ok(TorrentNotification::Shutdown { done }) => {
+                        self.shutdown().await;
+                        done.send(()).ok();
+                        return;
+                    }
+                    Ok(msg) => {
+                        self.process_cmd(msg);
+                        self.update_gauges();
+                        // Don't keep a partial batch when there is nothing
+                        // more to process
+                        if self.receiver.is_empty() {
+                            self.flush_sha1();
+                        }
+                    }
+                    Err(_) => return,
+                },
+                _ = stall_check.tick() => {
+                    self.check_stalled();
+                    self.check_choked();
+                }
+                _ = dial_tick.tick() => self.dial_queued(),
+                _ = stats_tick.tick() => self.log_stats(),
+                _ = progress_tick.tick() => self.update_progress(),
+                _ = choke_tick.tick() => {
+                    self.update_chokes();
+
+                    let interval = self.choke.settings().interval;
+                    if interval != choke_interval {
+                        choke_interval = interval;
+                        let start = tokio::time::Instant::now() + interval;
+                        choke_tick = tokio::time::interval_at(start, interval);
+                    }
+                }
+                _ = disk_space_tick.tick(), if self.disk_full => {
+                    send_to(&self.fs, FSMessage::CheckFreeSpace { id: self.id });
+                }
+            }
+        }
+    }
+
+    /// Our bitfield to send to new peers, `None` when we have nothing
+    fn our_bitfield(&self) -> Option<Box<[u8]>> {
+        if self.num_verified > 0 {
+            Some(self.bitfield.as_bytes().into())
+        } else {
+            None
+        }
+    }
+
+    fn check_stalled(&mut self) {
+        if self.stalled || self.is_complete() || self.is_paused() {
+            return;
+        }
+
+        if self.last_progress.elapsed().as_secs() >= STALL_TIMEOUT {
+            info!("Torrent stalled", { id: self.id.to_string() });
+            self.stalled = true;
+            self.send_event(TorrentEvent::Stalled {
+                info_hash: Arc::clone(&self.metadata.info_hash),
+            });
+        }
+    }
+
+    /// Unchoke the peers uploading the fastest to us, the ones we upload
+    /// the fastest to when we're seeding, and send the changes to the
+    /// peers. The settings follow the swarm and the upload limit
+    fn update_chokes(&mut self) {
+        if self.options.no_upload {
+            return;
+        }
+
+        let upload_rate = self
+            .bandwidth
+            .upload
+            .as_ref()
+            .map(|l| l.rate())
+            .unwrap_or(0);
+        self.choke
+            .set_settings(ChokeSettings::adapt(self.peers.len(), upload_rate));
+
+        let seeding = self.is_complete();
+        let peers = self.peers.iter().map(|(id, peer)| {
+            let interested = peer.shared.peer_interested.load(Relaxed);
+            let uploaded = peer.shared.uploaded.load(Relaxed);
+            (*id, interested, peer.downloaded, uploaded)
+        });
+        let changes = self.choke.tick(std::time::Instant::now(), seeding, peers);
+
+        for change in changes {
+            let (id, cmd) = match change {
+                ChokeChange::Choke(id) => (id, PeerCommand::Choke),
+                ChokeChange::Unchoke(id) => (id, PeerCommand::UnChoke),
+            };
+            if let Some(peer) = self.peers.get(&id) {
+                send_to(&peer.addr, cmd);
+            }
+        }
+    }
+
+    fn update_gauges(&self) {
+        self.gauges.set(self.peers.len(), self.num_verified, self.verified_bytes());
+    }
+
+    /// Size of the pieces in the bitfield
+    fn verified_bytes(&self) -> u64 {
+        let pieces = &self.pieces_infos;
+        let mut verified = (self.num_verified * pieces.piece_length) as u64;
+        if let Some(last) = pieces.num_pieces.checked_sub(1) {
+            if self.bitfield.get_bit(last) {
+                // The last piece is shorter
+                verified -= (pieces.piece_length
+                    - pieces.piece_size_of((last as u32).into()) as usize)
+                    as u64;
+            }
+        }
+        verified
+    }
+
+    /// Bytes downloaded and exponentially weighted average of the rate
+    fn update_progress(&mut self) {
+        let now = coarsetime::Instant::now();
+        let payload = self.counters.stats().payload_downloaded;
+
+        let (last, last_payload) = self.rate_sample;
+        let elapsed = now.duration_since(last).as_f64();
+        if elapsed > 0.0 {
+            let rate = payload.saturating_sub(last_payload) as f64 / elapsed;
+            self.download_rate += RATE_SMOOTHING * (rate - self.download_rate);
+            self.rate_sample = (now, payload);
+        }
+
+        let gauges = &self.gauges;
+        gauges
+            .downloaded_bytes
+            .store(self.verified_bytes() + self.collector.nbytes(), Relaxed);
+        gauges
+            .download_rate
+            .store(self.download_rate.round() as u64, Relaxed);
+    }
+
+    /// Summary of the connections of the torrent
+    fn log_stats(&self) {
+        let mut by_origin: HashMap<PeerOrigin, usize> = HashMap::default();
+        let mut unknown = 0;
+
+        for peer in self.peers.values() {
+            match self.known_peers.get(&peer.shared.socket) {
+                Some(origin) => *by_origin.entry(*origin).or_default() += 1,
+                None => unknown += 1,
+            }
+        }
+
+        let count = |origin| by_origin.get(&origin).copied().unwrap_or(0);
+
+        info!("Connections", {
+            id: self.id.to_string(),
+            peers: self.peers.len(),
+            half_open: self.half_open.load(Relaxed),
+            queued: self.dial_queue.len(),
+            tracker: count(PeerOrigin::Tracker),
+            pex: count(PeerOrigin::Pex),
+            manual: count(PeerOrigin::Manual),
+            peer_source: count(PeerOrigin::PeerSource),
+            unknown: unknown,
+            errors: self.peer_errors.load(Relaxed),
+            banned: self.banned.len()
+        });
+    }
+
+    /// When all the peers having pieces we want choke us, nothing is
+    /// downloaded: ask them again and dial more peers
+    fn check_choked(&mut self) {
+        if self.is_complete() {
+            self.choked_by_all = false;
+            return;
+        }
+
+        let bitfield = &self.bitfield;
+        let mut interesting = self
+            .peers
+            .values()
+            .filter(|peer| {
+                (0..self.pieces_infos.num_pieces)
+                    .any(|index| peer.bitfield.get_bit(index) && !bitfield.get_bit(index))
+            })
+            .peekable();
+
+        let choked_by_all = interesting.peek().is_some()
+            && interesting.all(|peer| peer.shared.am_choked.load(Relaxed));
+
+        if !choked_by_all {
+            if self.choked_by_all {
+                info!("A peer unchoked us", { id: self.id.to_string() });
+            }
+            self.choked_by_all = false;
+            return;
+        }
+
+        warn!("Choked by all peers, looking for new peers", {
+            id: self.id.to_string(),
+            npeers: self.peers.len()
+        });
+        self.choked_by_all = true;
+
+        // Peers rotate their optimistic unchoke among the interested peers
+        for peer in self.peers.values() {
+            send_to(&peer.addr, PeerCommand::Interested);
+        }
+
+        self.dial_known_peers();
+    }
+
+    /// Queue the known peers not connected, nor banned, to be dialed
+    fn dial_known_peers(&mut self) {
+        for addr in self.known_peers.keys() {
+            if !self.banned.contains(&addr.ip())
+                && !self.peers_socket.contains(addr)
+                && !self.dial_queue.contains(addr)
+            {
+                self.dial_queue.push_back(*addr);
+            }
+        }
+    }
+
+    fn process_cmd(&mut self, msg: TorrentNotification) {
+        use TorrentNotification::*;
+
+        match msg {
+            UpdateBitfield { id, update } => {
+                let complete = self.is_complete();
+                let num_pieces = self.pieces_infos.num_pieces;
+
+                let peer = match self.peers.get_mut(&id) {
+                    Some(peer) => peer,
+                    None => return,
                 };
 
                 self.piece_picker.update(&update);
                 peer.bitfield.update(*update);
 
+                if complete && peer.bitfield.count_ones() == num_pieces {
+                    info!("[{}] Both seeds, disconnecting", id);
+                    send_to(&peer.addr, PeerCommand::Die);
+                    self.remove_peer(id);
+                    return;
+                }
+
                 if !peer.queue_tasks.is_empty() {
                     return;
                 }
@@ -285,13 +1605,18 @@ impl TorrentSupervisor {
                 let tasks_nbytes = peer.tasks_nbytes;
                 let available = peer.queue_tasks.available();
 
-                if let Some((nbytes, tasks)) = self.piece_picker.pick_piece(
-                    id,
-                    tasks_nbytes,
-                    available,
-                    &peer.bitfield,
-                    &self.collector,
-                ) {
+                let picked = match self.recheck {
+                    Some(_) => None,
+                    None => self.piece_picker.pick_piece(
+                        id,
+                        tasks_nbytes,
+                        available,
+                        &peer.bitfield,
+                        &self.collector,
+                    ),
+                };
+
+                if let Some((nbytes, tasks)) = picked {
                     warn!("[{}] Tasks found {:?}", id, tasks);
                     peer.shared.nbytes_on_tasks.fetch_add(nbytes, Relaxed);
                     peer.queue_tasks.push_slice(tasks).unwrap();
@@ -302,14 +1627,12 @@ impl TorrentSupervisor {
                 send_to(&peer.addr, PeerCommand::TasksAvailables);
             }
             RemovePeer { id } => {
-                let peer = match self.peers.get_mut(&id) {
-                    Some(peer) => peer,
+                let socket = match self.peers.get(&id) {
+                    Some(peer) => peer.shared.socket,
                     None => return,
                 };
-
-                self.peers_socket.remove(&peer.shared.socket);
-                self.peers.remove(&id);
-                self.piece_picker.remove_peer(id);
+                self.remove_peer(id);
+                self.replace_peer(socket);
             }
             IncreaseTasksPeer { id } => {
                 let peer = match self.peers.get_mut(&id) {
@@ -330,45 +1653,103 @@ impl TorrentSupervisor {
                 }
             }
             AddPeer { peer } => {
-                if self.is_duplicate_peer(&peer.extern_id) {
-                    // We are already connected to this peer, disconnect.
-                    // This happens when we are connected to its ipv4 and ipv6 addresses
-
+                if self.banned.contains(&peer.shared.socket.ip()) || self.is_paused() {
                     send_to(&peer.addr, PeerCommand::Die);
-                } else {
-                    self.peers_socket.insert(peer.shared.socket);
-                    self.peers.insert(
-                        peer.id,
-                        PeerState {
-                            bitfield: BitField::new(self.pieces_infos.num_pieces),
-                            queue_tasks: peer.queue,
-                            addr: peer.addr,
-                            extern_id: peer.extern_id,
-                            shared: peer.shared,
-                            tasks_nbytes: self.pieces_infos.piece_length,
-                        },
-                    );
+                    return;
+                }
+
+                if let Some(existing) = self.find_duplicate_peer(&peer.extern_id) {
+                    // We are already connected to this peer, keep only 1 connection.
+                    // This happens when we are connected to its ipv4 and ipv6 addresses,
+                    // or when we both dialed each other at the same time
+
+                    if self.keep_new_connection(existing, &peer) {
+                        debug!("[{}] Simultaneous open, keep {}", existing, peer.id);
+                        if let Some(existing) = self.peers.get(&existing) {
+                            send_to(&existing.addr, PeerCommand::Die);
+                        }
+                        self.remove_peer(existing);
+                    } else {
+                        send_to(&peer.addr, PeerCommand::Die);
+                        return;
+                    }
+                }
+
+                self.peers_socket.insert(peer.shared.socket);
+                self.peers.insert(
+                    peer.id,
+                    PeerState {
+                        bitfield: BitField::new(self.pieces_infos.num_pieces),
+                        queue_tasks: peer.queue,
+                        addr: peer.addr,
+                        extern_id: peer.extern_id,
+                        shared: peer.shared,
+                        tasks_nbytes: self.pieces_infos.piece_length,
+                        outbound: peer.outbound,
+                        connected_at: coarsetime::Instant::now(),
+                        downloaded: 0,
+                        blocks_received: 0,
+                        blocks_failed: 0,
+                    },
+                );
+
+                if self.options.max_peers > 0 && self.peers.len() > self.options.max_peers {
+                    self.drop_lowest_score();
                 }
             }
+            AddBlock { .. } if self.options.read_only => {
+                // Nothing is downloaded in read-only mode
+            }
             AddBlock { id, block } => {
                 let piece_index = block.piece_index;
 
+                self.last_progress = coarsetime::Instant::now();
+                if self.stalled {
+                    self.stalled = false;
+                    self.send_event(TorrentEvent::Resumed {
+                        info_hash: Arc::clone(&self.metadata.info_hash),
+                    });
+                }
+
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    peer.downloaded += block.block.len() as u64;
+                    peer.blocks_received += 1;
+                    self.block_sources
+                        .entry(piece_index)
+                        .or_default()
+                        .insert(block.index, peer.shared.socket.ip());
+                }
+
                 if let Some(piece) = self.collector.add_block(&block) {
                     info!("[{}] Piece completed {:?}", id, piece_index);
 
+                    if let Some(failures) = self.assembly_failures.get(&piece_index) {
+                        warn!(
+                            "[{}] Piece {:?} assembled after {} peer(s) timed out, verifying",
+                            id,
+                            piece_index,
+                            failures.len()
+                        );
+                    }
+
+                    if let Some(sources) = self.block_sources.remove(&piece_index) {
+                        let mut sources: Vec<_> = sources.into_iter().map(|(_, ip)| ip).collect();
+                        sources.sort();
+                        sources.dedup();
+                        self.checking_sources.insert(piece_index, sources);
+                    }
+
                     self.piece_picker.set_as_downloaded(piece_index, true);
 
                     let index: usize = piece_index.into();
 
-                    self.sha1_workers
-                        .try_send(Sha1Task::CheckSum {
-                            torrent_id: self.id,
-                            piece,
-                            sum_metadata: Arc::clone(&self.pieces_infos.sha1_pieces[index]),
-                            addr: self.my_addr.clone(),
-                            piece_index,
-                        })
-                        .unwrap();
+                    self.send_sha1(Sha1Task::CheckSum {
+                        torrent_id: self.id,
+                        piece,
+                        sum_metadata: Arc::clone(&self.pieces_infos.sha1_pieces[index]),
+                        addr: self.my_addr.clone(),
+                        piece_index,
+                    });
                 }
 
                 let peer = match self.peers.get_mut(&id) {
@@ -378,7 +1759,9 @@ impl TorrentSupervisor {
 
                 let tasks_nbytes = peer.tasks_nbytes;
 
-                if peer.shared.nbytes_on_tasks.load(Acquire) < tasks_nbytes / 2 {
+                if self.recheck.is_none()
+                    && peer.shared.nbytes_on_tasks.load(Acquire) < tasks_nbytes / 2
+                {
                     let available = peer.queue_tasks.available().saturating_sub(1);
 
                     if let Some((nbytes, tasks)) = self.piece_picker.pick_piece(
@@ -402,35 +1785,2179 @@ impl TorrentSupervisor {
                     }
                 }
             }
+            PieceTimeout { id, piece_index } => {
+                self.on_request_timeout(id, piece_index);
+            }
             ValidatePiece { valid, piece_index } => {
                 self.piece_picker.set_as_downloaded(piece_index, valid);
 
+                // Valid or not, the assembly is over: a corrupted piece is
+                // picked again by its usual peers, and its timeouts counted
+                // from zero
+                if self.assembly_failures.remove(&piece_index).is_some() {
+                    self.piece_picker.clear_suspect(piece_index);
+                }
+
+                if let Some(sources) = self.checking_sources.remove(&piece_index) {
+                    if !valid {
+                        self.blame_peers(piece_index, &sources);
+                    }
+                }
+
+                if !valid {
+                    self.send_piece_event(PieceEvent::PieceFailed(piece_index));
+                } else if self.options.verify_written {
+                    // The worker writing the piece sent it to the disk
+                    // before this message, the disk reads it after the
+                    // write
+                    self.reading_back.insert(piece_index);
+                    send_to(
+                        &self.fs,
+                        FSMessage::ReadPiece {
+                            id: self.id,
+                            piece: piece_index,
+                            supervisor: self.my_addr.clone(),
+                        },
+                    );
+                } else {
+                    self.on_piece_downloaded(piece_index);
+                }
+
                 // debug!("Piece checked from the pool: {}", valid);
             }
-            PeerDiscovered { addrs } => {
-                for addr in addrs.iter().filter(|a| !self.peers_socket.contains(a)) {
-                    self.connect_to_peers(addr);
+            PeerDiscovered { addrs, origin } => {
+                for addr in addrs.iter() {
+                    if !self.known_peers.contains_key(addr)
+                        && self.known_peers.len() >= MAX_KNOWN_PEERS
+                    {
+                        self.forget_known_peer();
+                    }
+                    self.known_peers.entry(*addr).or_insert(origin);
+
+                    if !self.banned.contains(&addr.ip())
+                        && !self.peers_socket.contains(addr)
+                        && !self.dial_queue.contains(addr)
+                    {
+                        self.dial_queue.push_back(*addr);
+                    }
+                }
+            }
+            Accepted { peer } => {
+                if !self.banned.contains(&peer.addr.ip()) && !self.is_paused() {
+                    self.accept_peer(*peer);
+                }
+            }
+            DebugPieces { respond } => {
+                respond.try_send(self.pieces_debug()).ok();
+            }
+            SetPlaybackPosition { offset } => {
+                let (piece, _) = self.pieces_infos.pieces_of_range(offset as usize, 0);
+                self.piece_picker.set_playback_position(piece);
+            }
+            TorrentFiles { respond } => {
+                respond.try_send(self.files_progress()).ok();
+            }
+            ExportResume { respond } => {
+                respond.try_send(self.export_resume()).ok();
+            }
+            PieceRead { piece_index, data } => {
+                let index: usize = piece_index.into();
+
+                self.send_sha1(Sha1Task::Verify {
+                    piece: data,
+                    sum_metadata: Arc::clone(&self.pieces_infos.sha1_pieces[index]),
+                    addr: self.my_addr.clone(),
+                    piece_index,
+                });
+            }
+            PieceVerified { piece_index, valid } => {
+                if self.reading_back.remove(&piece_index) {
+                    self.on_written_verified(piece_index, valid);
+                } else {
+                    self.on_piece_verified(piece_index, valid);
+                }
+            }
+            SubscribePieces { sender } => {
+                self.piece_subscribers.push(sender);
+            }
+            Recheck => {
+                self.start_recheck(true);
+            }
+            Pause => self.pause(),
+            Resume => self.resume(),
+            // Handled in `process_cmds`, it's async
+            Shutdown { .. } => {}
+            WriteRejected { piece_index } => self.on_write_rejected(piece_index),
+            AllocationFailed { needed, available } => {
+                self.allocation_failed = true;
+                self.pause();
+                self.send_event(TorrentEvent::AllocationFailed {
+                    info_hash: Arc::clone(&self.metadata.info_hash),
+                    needed,
+                    available,
+                });
+            }
+            FileError { error } => {
+                warn!("File error: {}", error, { id: self.id.to_string() });
+            }
+            DiskSpaceAvailable => {
+                if self.disk_full {
+                    info!("Disk space available", { id: self.id.to_string() });
+                    self.disk_full = false;
+                    self.resume();
                 }
             }
         }
     }
 
-    /// Check if the peer extern id is already in our state
-    fn is_duplicate_peer(&self, id: &PeerExternId) -> bool {
-        self.peers.values().any(|p| &*p.extern_id == id)
+    /// The piece matches its sum, and is on the disk
+    fn on_piece_downloaded(&mut self, piece_index: PieceIndex) {
+        self.send_piece_event(PieceEvent::PieceVerified(piece_index));
+
+        if !self.bitfield.get_bit(piece_index) {
+            self.bitfield.set_bit(piece_index);
+            self.num_verified += 1;
+
+            if self.is_complete() {
+                self.on_complete();
+            }
+        }
     }
-}
 
-impl Drop for TorrentSupervisor {
-    fn drop(&mut self) {
-        self.fs
-            .try_send(FSMessage::RemoveTorrent { id: self.id })
-            .unwrap();
+    fn send_piece_event(&mut self, event: PieceEvent) {
+        // The subscribers dropping their receiver are removed
+        self.piece_subscribers
+            .retain(|sender| sender.try_send(event).is_ok());
+    }
+
+    fn send_sha1(&mut self, task: Sha1Task) {
+        self.sha1_batch.push(task);
+
+        if self.sha1_batch.len() >= self.options.sha1_batch_size {
+            self.flush_sha1();
+        }
+    }
+
+    fn flush_sha1(&mut self) {
+        let task = match self.sha1_batch.len() {
+            0 => return,
+            1 => self.sha1_batch.pop().unwrap(),
+            _ => Sha1Task::Batch(std::mem::take(&mut self.sha1_batch)),
+        };
+
+        self.sha1_workers.try_send(task).unwrap();
+    }
+
+    /// The peer canceled its requests of the piece, its missing blocks
+    /// go to the other peers. Count the peers failing to send them, and
+    /// widen its peer set when they are too many
+    fn on_request_timeout(&mut self, id: PeerId, piece_index: PieceIndex) {
+        if self.bitfield.get_bit(piece_index) {
+            return;
+        }
+
+        match self.peers.get_mut(&id) {
+            Some(peer) => peer.blocks_failed += 1,
+            None => return,
+        }
+
+        self.piece_picker.remove_worker(piece_index, id);
+
+        let failures = self.assembly_failures.entry(piece_index).or_default();
+
+        if failures.insert(id) && failures.len() == MAX_ASSEMBLY_FAILURES {
+            warn!(
+                "Piece {:?} failed to be assembled by {} peers, requesting it to all peers",
+                piece_index,
+                failures.len()
+            );
+
+            self.piece_picker.set_suspect(piece_index);
+        }
+
+        if self.recheck.is_some() {
+            return;
+        }
+
+        // Give the piece to the idle peers having it, the others
+        // pick it with their next tasks
+        let timed_out = id;
+        for (id, peer) in self.peers.iter_mut() {
+            if *id == timed_out
+                || !peer.queue_tasks.is_empty()
+                || !peer.bitfield.get_bit(piece_index)
+            {
+                continue;
+            }
+
+            if let Some((nbytes, tasks)) = self.piece_picker.pick_piece(
+                *id,
+                peer.tasks_nbytes,
+                peer.queue_tasks.available(),
+                &peer.bitfield,
+                &self.collector,
+            ) {
+                peer.shared.nbytes_on_tasks.fetch_add(nbytes, Relaxed);
+                peer.queue_tasks.push_slice(tasks).unwrap();
+
+                send_to(&peer.addr, PeerCommand::TasksAvailables);
+            }
+        }
+    }
+
+    /// Count a hash failure for each peer who supplied a block of the piece,
+    /// and ban the repeat offenders
+    fn blame_peers(&mut self, piece_index: PieceIndex, sources: &[IpAddr]) {
+        for ip in sources {
+            for peer in self.peers.values_mut() {
+                if peer.shared.socket.ip() == *ip {
+                    peer.blocks_failed += 1;
+                }
+            }
+
+            let failures = self.hash_failures.entry(*ip).or_insert(0);
+            *failures += 1;
+
+            warn!("Piece {:?} failed its hash, {} failure(s)", piece_index, failures, {
+                ip: ip.to_string()
+            });
+
+            if *failures >= MAX_HASH_FAILURES {
+                self.ban_peer(*ip);
+            }
+        }
+    }
+
+    fn ban_peer(&mut self, ip: IpAddr) {
+        warn!("Banning peer", { ip: ip.to_string() });
+
+        self.banned.insert(ip);
+        self.dial_queue.retain(|addr| addr.ip() != ip);
+
+        let ids: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, p)| p.shared.socket.ip() == ip)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in ids {
+            if let Some(peer) = self.peers.get(&id) {
+                send_to(&peer.addr, PeerCommand::Die);
+            }
+            self.remove_peer(id);
+        }
+    }
+
+    fn on_complete(&mut self) {
+        if !self.options.verify_on_complete {
+            info!("Torrent completed", { id: self.id.to_string() });
+            self.completion.send(true).ok();
+            self.disconnect_seeds();
+            self.send_event(TorrentEvent::Completed {
+                info_hash: Arc::clone(&self.metadata.info_hash),
+            });
+            return;
+        }
+
+        self.start_recheck(false);
+    }
+
+    /// No new task is given to the peers until the recheck is done
+    fn start_recheck(&mut self, manual: bool) {
+        if self.recheck.is_none() {
+            info!("Verifying all pieces", { id: self.id.to_string() });
+            self.recheck = Some(Recheck {
+                manual,
+                ..Default::default()
+            });
+            for _ in 0..RECHECK_IN_FLIGHT {
+                self.recheck_next_piece();
+            }
+        }
+    }
+
+    /// Check the pieces already on the disk, a copy placed there before
+    /// downloading for example. The files are read in place on a
+    /// blocking thread, the supervisor isn't blocked and the missing
+    /// files are not created
+    pub fn scan_existing(&self) -> impl std::future::Future<Output = BitField> + Send + 'static {
+        let files = self.metadata.files();
+        let pieces_infos = Arc::clone(&self.pieces_infos);
+        let num_pieces = pieces_infos.num_pieces;
+
+        async move {
+            tokio::task::spawn_blocking(move || crate::fs::scan_existing(&files, &pieces_infos))
+                .await
+                .unwrap_or_else(|_| BitField::new(num_pieces))
+        }
+    }
+
+    fn recheck_next_piece(&mut self) {
+        let recheck = match self.recheck.as_mut() {
+            Some(recheck) => recheck,
+            None => return,
+        };
+
+        if recheck.next >= self.pieces_infos.num_pieces {
+            return;
+        }
+
+        let piece = (recheck.next as u32).into();
+        recheck.next += 1;
+
+        send_to(
+            &self.fs,
+            FSMessage::ReadPiece {
+                id: self.id,
+                piece,
+                supervisor: self.my_addr.clone(),
+            },
+        );
+    }
+
+    fn on_written_verified(&mut self, piece_index: PieceIndex, valid: bool) {
+        if valid {
+            self.on_piece_downloaded(piece_index);
+            return;
+        }
+
+        error!("Piece {:?} is corrupted on the disk, downloading it again", piece_index, {
+            id: self.id.to_string()
+        });
+
+        self.piece_picker.set_as_downloaded(piece_index, false);
+        if self.bitfield.get_bit(piece_index) {
+            self.bitfield.clear_bit(piece_index);
+            self.num_verified -= 1;
+        }
+
+        self.send_piece_event(PieceEvent::PieceFailed(piece_index));
+    }
+
+    fn on_piece_verified(&mut self, piece_index: PieceIndex, valid: bool) {
+        let recheck = match self.recheck.as_mut() {
+            Some(recheck) => recheck,
+            None => return,
+        };
+
+        recheck.checked += 1;
+        if !valid {
+            recheck.failed.push(piece_index);
+        }
+
+        if recheck.checked < self.pieces_infos.num_pieces {
+            self.recheck_next_piece();
+            return;
+        }
+
+        let recheck = self.recheck.take().unwrap_or_default();
+        if recheck.manual {
+            self.on_manual_recheck(recheck.failed);
+            return;
+        }
+
+        let failed = recheck.failed;
+        let info_hash = Arc::clone(&self.metadata.info_hash);
+
+        if failed.is_empty() {
+            info!("Torrent completed", { id: self.id.to_string() });
+            self.completion.send(true).ok();
+            self.disconnect_seeds();
+            self.send_event(TorrentEvent::Completed { info_hash });
+            return;
+        }
+
+        error!("Final verification failed on {} pieces: {:?}", failed.len(), failed, {
+            id: self.id.to_string()
+        });
+
+        for &piece in &failed {
+            self.piece_picker.set_as_downloaded(piece, false);
+            self.bitfield.clear_bit(piece);
+            self.num_verified -= 1;
+        }
+
+        self.send_event(TorrentEvent::VerificationFailed {
+            info_hash,
+            pieces: failed.into_boxed_slice(),
+        });
+    }
+
+    /// Replace the bitfield with the result of the recheck and
+    /// resume the download
+    fn on_manual_recheck(&mut self, failed: Vec<PieceIndex>) {
+        let was_complete = self.is_complete();
+
+        for index in 0..self.pieces_infos.num_pieces {
+            let piece: PieceIndex = (index as u32).into();
+            let valid = !failed.contains(&piece);
+
+            if valid == self.bitfield.get_bit(index) {
+                continue;
+            }
+
+            // A read-only torrent never downloads the pieces it doesn't have
+            if !self.options.read_only {
+                self.piece_picker.set_as_downloaded(piece, valid);
+            }
+            if valid {
+                self.bitfield.set_bit(index);
+                self.num_verified += 1;
+            } else {
+                self.bitfield.clear_bit(index);
+                self.num_verified -= 1;
+            }
+        }
+
+        info!("Recheck done, {} pieces failed: {:?}", failed.len(), failed, {
+            id: self.id.to_string()
+        });
+
+        if !was_complete && self.is_complete() {
+            self.completion.send(true).ok();
+            self.disconnect_seeds();
+            self.send_event(TorrentEvent::Completed {
+                info_hash: Arc::clone(&self.metadata.info_hash),
+            });
+            return;
+        }
+
+        if was_complete && !self.is_complete() {
+            self.completion.send(false).ok();
+            self.send_event(TorrentEvent::VerificationFailed {
+                info_hash: Arc::clone(&self.metadata.info_hash),
+                pieces: failed.into_boxed_slice(),
+            });
+            // The seeds were disconnected when the torrent completed
+            self.dial_known_peers();
+        }
+
+        for (id, peer) in self.peers.iter_mut() {
+            if !peer.queue_tasks.is_empty() {
+                continue;
+            }
+
+            if let Some((nbytes, tasks)) = self.piece_picker.pick_piece(
+                *id,
+                peer.tasks_nbytes,
+                peer.queue_tasks.available(),
+                &peer.bitfield,
+                &self.collector,
+            ) {
+                peer.shared.nbytes_on_tasks.fetch_add(nbytes, Relaxed);
+                peer.queue_tasks.push_slice(tasks).unwrap();
+
+                send_to(&peer.addr, PeerCommand::TasksAvailables);
+            }
+        }
+    }
+
+    pub(crate) fn pieces_debug(&self) -> PiecesDebug {
+        let count = self.piece_picker.state_count();
+        let verified = self.bitfield.count_ones();
+
+        PiecesDebug {
+            bitfield: self.bitfield.as_bytes().into(),
+            num_pieces: self.pieces_infos.num_pieces,
+            missing: count.missing,
+            downloading: count.downloading,
+            checking: count.downloaded.saturating_sub(verified),
+            verified,
+            choked_by_all: self.choked_by_all,
+        }
+    }
+
+    /// Verified pieces, blocks received and bytes exchanged, to give
+    /// back with `TorrentOptions::resume`
+    pub fn export_resume(&self) -> ResumeData {
+        let partial = self
+            .collector
+            .blocks()
+            .into_iter()
+            .map(|block| ResumeBlock {
+                data: block.block.into(),
+                offset: block.index.into(),
+                piece: block.piece_index.into(),
+            })
+            .collect();
+
+        let bytes = self.counters.stats();
+
+        ResumeData {
+            bitfield: self.bitfield.as_bytes().to_vec(),
+            completed: self.completed_before || self.is_complete(),
+            downloaded: self.bytes_before.0 + bytes.payload_downloaded,
+            info_hash: self.metadata.info_hash.to_vec(),
+            partial,
+            piece_length: self.pieces_infos.piece_length as u64,
+            uploaded: self.bytes_before.1 + bytes.payload_uploaded,
+        }
+    }
+
+    pub(crate) fn files_progress(&self) -> Vec<FileProgress> {
+        let piece_length = self.pieces_infos.piece_length as u64;
+        let mut offset = 0;
+
+        self.metadata
+            .files()
+            .into_iter()
+            .map(|file| {
+                let (start, end) = self
+                    .pieces_infos
+                    .pieces_of_range(offset as usize, file.length as usize);
+                let file_end = offset + file.length;
+
+                let downloaded = (u32::from(start)..u32::from(end))
+                    .filter(|piece| self.bitfield.get_bit(*piece as usize))
+                    .map(|piece| {
+                        // Bytes of the piece overlapping the file
+                        let piece_start = piece as u64 * piece_length;
+                        let piece_end =
+                            piece_start + self.pieces_infos.piece_size_of(piece.into()) as u64;
+
+                        piece_end.min(file_end) - piece_start.max(offset)
+                    })
+                    .sum();
+
+                offset = file_end;
+
+                FileProgress {
+                    path: file.path,
+                    length: file.length,
+                    downloaded,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the peer with the same extern id, if any
+    fn find_duplicate_peer(&self, id: &PeerExternId) -> Option<PeerId> {
+        self.peers
+            .iter()
+            .find(|(_, p)| &*p.extern_id == id)
+            .map(|(id, _)| *id)
+    }
+
+    /// When there is an inbound and an outbound connection to the same peer,
+    /// both sides keep the connection dialed by the lowest peer id.
+    /// Otherwise we keep the existing one
+    fn keep_new_connection(&self, existing: PeerId, new: &NewPeer) -> bool {
+        let existing = match self.peers.get(&existing) {
+            Some(existing) => existing,
+            None => return true,
+        };
+
+        if existing.outbound == new.outbound {
+            return false;
+        }
+
+        let we_dialed = **self.extern_id < **new.extern_id;
+
+        new.outbound == we_dialed
+    }
+
+    /// Once we are a seed, there is nothing to exchange with the other
+    /// seeds: drop them to leave the slots to the peers needing data
+    fn disconnect_seeds(&mut self) {
+        let num_pieces = self.pieces_infos.num_pieces;
+
+        let seeds: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.bitfield.count_ones() == num_pieces)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in seeds {
+            info!("[{}] Both seeds, disconnecting", id);
+            if let Some(peer) = self.peers.get(&id) {
+                send_to(&peer.addr, PeerCommand::Die);
+            }
+            self.remove_peer(id);
+        }
+    }
+
+    fn peer_score(&self, peer: &PeerState) -> PeerScore {
+        let elapsed = peer.connected_at.elapsed().as_f64().max(1.0);
+
+        PeerScore {
+            download_rate: peer.downloaded as f64 / elapsed,
+            blocks_received: peer.blocks_received,
+            blocks_failed: peer.blocks_failed,
+            needed_pieces: self
+                .bitfield
+                .iter_missing()
+                .filter(|index| peer.bitfield.get_bit(*index))
+                .count(),
+            missing_pieces: self.pieces_infos.num_pieces - self.num_verified,
+        }
+    }
+
+    /// See `SCORE_GRACE_PERIOD`
+    fn score_value(&self, peer: &PeerState) -> f64 {
+        if peer.connected_at.elapsed().as_secs() < SCORE_GRACE_PERIOD {
+            return NEUTRAL_SCORE;
+        }
+        self.peer_score(peer).value()
+    }
+
+    /// Over `TorrentOptions::max_peers`, make room for the others
+    fn drop_lowest_score(&mut self) {
+        let lowest = self
+            .peers
+            .iter()
+            .map(|(id, peer)| (*id, self.score_value(peer)))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+        if let Some((id, score)) = lowest {
+            info!(
+                "[{}] Too many peers, dropping the lowest score {:.3}",
+                id, score
+            );
+            if let Some(peer) = self.peers.get(&id) {
+                send_to(&peer.addr, PeerCommand::Die);
+            }
+            self.remove_peer(id);
+        }
+    }
+
+    /// Forget the known peer with the lowest score, not connected, and
+    /// its score
+    fn forget_known_peer(&mut self) {
+        let scores = &self.peer_scores;
+        let score = |addr: &SocketAddr| scores.get(addr).copied().unwrap_or(NEUTRAL_SCORE);
+
+        let worst = self
+            .known_peers
+            .keys()
+            .filter(|addr| !self.peers_socket.contains(addr))
+            .min_by(|a, b| score(a).partial_cmp(&score(b)).unwrap())
+            .copied();
+
+        if let Some(addr) = worst {
+            self.known_peers.remove(&addr);
+            self.peer_scores.remove(&addr);
+        }
+    }
+
+    /// A peer dropped, dial the best known peer not connected in its
+    /// place. Nothing to do when other peers are waiting to be dialed
+    fn replace_peer(&mut self, dropped: SocketAddr) {
+        if !self.dial_queue.is_empty() || self.is_paused() {
+            return;
+        }
+
+        if self.options.max_peers > 0
+            && self.peers.len() + self.half_open.load(Relaxed) >= self.options.max_peers
+        {
+            return;
+        }
+
+        let scores = &self.peer_scores;
+        let score = |addr: &SocketAddr| scores.get(addr).copied().unwrap_or(NEUTRAL_SCORE);
+
+        let replacement = self
+            .known_peers
+            .keys()
+            .filter(|addr| {
+                **addr != dropped
+                    && !self.banned.contains(&addr.ip())
+                    && !self.peers_socket.contains(addr)
+            })
+            .max_by(|a, b| score(a).partial_cmp(&score(b)).unwrap());
+
+        if let Some(addr) = replacement {
+            debug!("Replacing a dropped peer", { id: self.id.to_string(), addr: addr.to_string() });
+            self.dial_queue.push_back(*addr);
+        }
+    }
+
+    fn remove_peer(&mut self, id: PeerId) {
+        let peer = match self.peers.get(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+
+        // The port of an inbound peer isn't the one it listens on
+        if peer.outbound {
+            let score = self.score_value(peer);
+            self.peer_scores.insert(peer.shared.socket, score);
+        }
+
+        self.piece_picker.remove_bitfield(&peer.bitfield);
+        self.peers_socket.remove(&peer.shared.socket);
+        self.peers.remove(&id);
+        self.piece_picker.remove_peer(id);
+    }
+}
+
+impl Drop for TorrentSupervisor {
+    fn drop(&mut self) {
+        // The FS might be closed already when the runtime is shutting down
+        self.fs
+            .try_send(FSMessage::RemoveTorrent { id: self.id })
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::TryFrom, path::PathBuf, sync::Arc};
+
+    use crate::{
+        actors::sha1::{compare_20_bytes, Sha1Task},
+        bitfield::{BitField, BitFieldUpdate},
+        fs::FSMessage,
+        metadata::{
+            InfoFile::{Multiple, Single},
+            MetaFile, TestTorrent, Torrent,
+        },
+        peer::peer::{PeerCommand, PeerExternId, PeerId},
+        piece_collector::Block,
+        pieces::TaskDownload,
+        resume::ResumeData,
+        sha1::sha1,
+        spsc,
+    };
+
+    use super::{
+        FileProgress, NewPeer, PeerOrigin, PieceEvent, Shared, TorrentEvent, TorrentGauges,
+        TorrentNotification::*, TorrentOptions, TorrentSupervisor, MAX_ASSEMBLY_FAILURES,
+        MAX_DIALS_PER_TICK, MAX_HALF_OPEN,
+    };
+
+    fn torrent(num_pieces: usize) -> Torrent {
+        TestTorrent::new().pieces(num_pieces).build()
+    }
+
+    #[test]
+    fn debug_pieces() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(10), TorrentOptions::default(), sha1_workers, fs);
+
+        supervisor.process_cmd(ValidatePiece {
+            piece_index: 0.into(),
+            valid: true,
+        });
+        supervisor.process_cmd(ValidatePiece {
+            piece_index: 2.into(),
+            valid: true,
+        });
+        supervisor.process_cmd(ValidatePiece {
+            piece_index: 9.into(),
+            valid: true,
+        });
+        supervisor.process_cmd(ValidatePiece {
+            piece_index: 3.into(),
+            valid: false,
+        });
+        supervisor.piece_picker.set_as_downloaded(5.into(), true);
+
+        let (respond, receiver) = crossbeam_channel::bounded(1);
+        supervisor.process_cmd(DebugPieces { respond });
+        let debug = receiver.recv().unwrap();
+
+        assert_eq!(&*debug.bitfield, &[0b1010_0000, 0b0100_0000]);
+        assert_eq!(debug.num_pieces, 10);
+        assert_eq!(debug.verified, 3);
+        assert_eq!(debug.checking, 1);
+        assert_eq!(debug.downloading, 0);
+        assert_eq!(debug.missing, 6);
+    }
+
+    #[test]
+    fn completed_event() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+        let (events, events_recv) = crossbeam_channel::unbounded();
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(2), TorrentOptions::default(), sha1_workers, fs);
+        supervisor.set_events(events);
+
+        for _ in 0..2 {
+            supervisor.process_cmd(ValidatePiece {
+                piece_index: 0.into(),
+                valid: true,
+            });
+        }
+        assert!(events_recv.try_recv().is_err());
+
+        supervisor.process_cmd(ValidatePiece {
+            piece_index: 1.into(),
+            valid: true,
+        });
+        assert_eq!(
+            events_recv.try_recv(),
+            Ok(TorrentEvent::Completed {
+                info_hash: Arc::new([7; 20])
+            })
+        );
+        assert!(events_recv.try_recv().is_err());
+    }
+
+    #[test]
+    fn verify_on_complete() {
+        let (sha1_workers, sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, fs_recv) = async_channel::bounded(10);
+        let (events, events_recv) = crossbeam_channel::unbounded();
+
+        let pieces_data = [vec![1; 1000], vec![2; 1000]];
+        let mut torrent = torrent(2);
+        torrent.meta.info.pieces = pieces_data.iter().flat_map(|p| sha1(p).to_vec()).collect();
+
+        let options = TorrentOptions {
+            verify_on_complete: true,
+            ..Default::default()
+        };
+        let mut supervisor = TorrentSupervisor::new(torrent, options, sha1_workers, fs);
+        supervisor.set_events(events);
+
+        for piece_index in 0..2 {
+            supervisor.process_cmd(ValidatePiece {
+                piece_index: piece_index.into(),
+                valid: true,
+            });
+        }
+        // Not completed until the pieces on disk are checked
+        assert!(events_recv.try_recv().is_err());
+
+        for _ in 0..2 {
+            let piece_index = match fs_recv.try_recv() {
+                Ok(FSMessage::ReadPiece { piece, .. }) => piece,
+                _ => panic!("Expected a ReadPiece"),
+            };
+            let index: usize = piece_index.into();
+
+            // The second piece got corrupted on the disk
+            let mut data = pieces_data[index].clone();
+            if index == 1 {
+                data[500] = 0;
+            }
+
+            supervisor.process_cmd(PieceRead {
+                piece_index,
+                data: data.into_boxed_slice(),
+            });
+        }
+
+        for _ in 0..2 {
+            let (piece, sum_metadata, piece_index) = match sha1_recv.try_recv() {
+                Ok(Sha1Task::Verify {
+                    piece,
+                    sum_metadata,
+                    piece_index,
+                    ..
+                }) => (piece, sum_metadata, piece_index),
+                _ => panic!("Expected a Verify task"),
+            };
+
+            supervisor.process_cmd(PieceVerified {
+                piece_index,
+                valid: compare_20_bytes(&sha1(&piece), &sum_metadata[..]),
+            });
+        }
+
+        assert_eq!(
+            events_recv.try_recv(),
+            Ok(TorrentEvent::VerificationFailed {
+                info_hash: Arc::new([7; 20]),
+                pieces: vec![1.into()].into_boxed_slice(),
+            })
+        );
+        assert!(events_recv.try_recv().is_err());
+        assert!(supervisor.bitfield.get_bit(0usize));
+        assert!(!supervisor.bitfield.get_bit(1usize));
+        assert_eq!(supervisor.piece_picker.state_count().missing, 1);
+    }
+
+    #[test]
+    fn verify_written() {
+        let (sha1_workers, sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, fs_recv) = async_channel::bounded(10);
+        let (sender, pieces_events) = async_channel::unbounded();
+
+        let options = TorrentOptions {
+            verify_written: true,
+            ..Default::default()
+        };
+        let mut supervisor = TorrentSupervisor::new(torrent(2), options, sha1_workers, fs);
+        supervisor.process_cmd(SubscribePieces { sender });
+
+        for piece_index in 0..2u32 {
+            supervisor.process_cmd(ValidatePiece {
+                piece_index: piece_index.into(),
+                valid: true,
+            });
+
+            // Read back without a flush
+            match fs_recv.try_recv() {
+                Ok(FSMessage::ReadPiece { piece, .. }) => assert_eq!(piece, piece_index.into()),
+                _ => panic!("Expected a ReadPiece"),
+            }
+            assert!(fs_recv.is_empty());
+
+            supervisor.process_cmd(PieceRead {
+                piece_index: piece_index.into(),
+                data: vec![0; 1000].into_boxed_slice(),
+            });
+            assert!(matches!(
+                sha1_recv.try_recv(),
+                Ok(Sha1Task::Verify { piece_index: index, .. }) if index == piece_index.into()
+            ));
+        }
+        // Not downloaded until read back
+        assert!(pieces_events.try_recv().is_err());
+        assert_eq!(supervisor.num_verified, 0);
+
+        // The second piece got corrupted on the disk
+        supervisor.process_cmd(PieceVerified {
+            piece_index: 0.into(),
+            valid: true,
+        });
+        supervisor.process_cmd(PieceVerified {
+            piece_index: 1.into(),
+            valid: false,
+        });
+        assert!(supervisor.reading_back.is_empty());
+
+        assert_eq!(
+            pieces_events.try_recv(),
+            Ok(PieceEvent::PieceVerified(0.into()))
+        );
+        assert_eq!(
+            pieces_events.try_recv(),
+            Ok(PieceEvent::PieceFailed(1.into()))
+        );
+        assert!(supervisor.bitfield.get_bit(0usize));
+        assert!(!supervisor.bitfield.get_bit(1usize));
+        assert_eq!(supervisor.num_verified, 1);
+        // Downloaded again
+        assert_eq!(supervisor.piece_picker.state_count().missing, 1);
+    }
+
+    #[test]
+    fn resume_round_trip() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let mut supervisor = TorrentSupervisor::new(
+            torrent(3),
+            TorrentOptions::default(),
+            sha1_workers.clone(),
+            fs.clone(),
+        );
+        for index in 0..2u32 {
+            supervisor.process_cmd(ValidatePiece {
+                piece_index: index.into(),
+                valid: true,
+            });
+        }
+        // Half of the last piece
+        supervisor.process_cmd(AddBlock {
+            id: PeerId::new(1),
+            block: Block {
+                piece_index: 2.into(),
+                index: 0.into(),
+                block: vec![3; 500].into_boxed_slice(),
+            },
+        });
+        supervisor.counters.add_downloaded(2500, 2600);
+        supervisor.counters.add_uploaded(700, 800);
+
+        let bytes = supervisor.export_resume().to_bytes();
+        let options = |verify_on_resume| TorrentOptions {
+            resume: Some(ResumeData::from_bytes(&bytes).unwrap()),
+            verify_on_resume,
+            ..Default::default()
+        };
+
+        let supervisor =
+            TorrentSupervisor::new(torrent(3), options(true), sha1_workers.clone(), fs.clone());
+        assert!(supervisor.recheck_on_start);
+
+        let mut supervisor = TorrentSupervisor::new(torrent(3), options(false), sha1_workers, fs);
+        assert_eq!(supervisor.num_verified, 2);
+        assert!(!supervisor.recheck_on_start);
+
+        // The stats of the previous session are kept
+        supervisor.counters.add_uploaded(100, 120);
+        let resume = supervisor.export_resume();
+        assert_eq!((resume.downloaded, resume.uploaded), (2500, 800));
+
+        let (mut peer, _recv) = new_peer(1, b"-ZZ0001-000000000001", true);
+        let (queue, mut tasks) = spsc::bounded(16);
+        peer.queue = queue;
+        let peer_id = peer.id;
+
+        let all_pieces = BitField::try_from((&[0xE0][..], 3)).unwrap();
+        supervisor.process_cmd(AddPeer { peer });
+        supervisor.process_cmd(UpdateBitfield {
+            id: peer_id,
+            update: Box::new(BitFieldUpdate::from(all_pieces)),
+        });
+
+        // Only the missing half is requested
+        assert_eq!(
+            tasks.pop().ok(),
+            Some(TaskDownload::BlockRange {
+                piece_index: 2.into(),
+                start: 500.into(),
+                end: 1000.into(),
+            })
+        );
+        assert!(tasks.pop().is_err());
+    }
+
+    #[tokio::test]
+    async fn resume_mismatch() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, fs_recv) = async_channel::bounded(10);
+
+        let resume = ResumeData {
+            info_hash: vec![7; 20],
+            piece_length: 1000,
+            bitfield: vec![0b1000_0000],
+            completed: true,
+            partial: Vec::new(),
+            downloaded: 0,
+            uploaded: 0,
+        };
+        let options = |resume| TorrentOptions {
+            disable_trackers: true,
+            overwrite_existing: true,
+            resume: Some(resume),
+            ..Default::default()
+        };
+
+        // Saved for this torrent, the piece is trusted
+        let supervisor = TorrentSupervisor::new(
+            torrent(2),
+            options(resume.clone()),
+            sha1_workers.clone(),
+            fs.clone(),
+        );
+        assert_eq!(supervisor.num_verified, 1);
+        assert!(supervisor.bitfield.get_bit(0usize));
+        assert!(!supervisor.recheck_on_start);
+        // Completed before a piece went missing, it's kept in the export
+        assert!(supervisor.completed_before);
+        assert!(supervisor.export_resume().completed);
+
+        // Saved for another torrent
+        let other = ResumeData {
+            info_hash: vec![8; 20],
+            ..resume
+        };
+        let mut supervisor = TorrentSupervisor::new(torrent(2), options(other), sha1_workers, fs);
+        assert_eq!(supervisor.num_verified, 0);
+        assert!(!supervisor.bitfield.get_bit(0usize));
+        assert!(!supervisor.completed_before);
+
+        tokio::spawn(async move { supervisor.start().await });
+
+        assert!(matches!(
+            fs_recv.recv().await,
+            Ok(FSMessage::AddTorrent { .. })
+        ));
+
+        // All the pieces are read back
+        for index in 0..2u32 {
+            match fs_recv.recv().await {
+                Ok(FSMessage::ReadPiece { piece, .. }) => assert_eq!(piece, index.into()),
+                _ => panic!("Expected a ReadPiece"),
+            }
+        }
+    }
+
+    #[test]
+    fn manual_recheck() {
+        let (sha1_workers, sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, fs_recv) = async_channel::bounded(10);
+        let (events, events_recv) = crossbeam_channel::unbounded();
+
+        let pieces_data = [vec![1; 1000], vec![2; 1000]];
+        let mut torrent = torrent(2);
+        torrent.meta.info.pieces = pieces_data.iter().flat_map(|p| sha1(p).to_vec()).collect();
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent, TorrentOptions::default(), sha1_workers, fs);
+        supervisor.set_events(events);
+
+        for piece_index in 0..2 {
+            supervisor.process_cmd(ValidatePiece {
+                piece_index: piece_index.into(),
+                valid: true,
+            });
+        }
+        assert!(matches!(
+            events_recv.try_recv(),
+            Ok(TorrentEvent::Completed { .. })
+        ));
+        assert!(fs_recv.try_recv().is_err());
+
+        supervisor.process_cmd(Recheck);
+
+        for _ in 0..2 {
+            let piece_index = match fs_recv.try_recv() {
+                Ok(FSMessage::ReadPiece { piece, .. }) => piece,
+                _ => panic!("Expected a ReadPiece"),
+            };
+            let index: usize = piece_index.into();
+
+            // The file got corrupted on the disk, in the first piece
+            let mut data = pieces_data[index].clone();
+            if index == 0 {
+                data[10] = 0;
+            }
+
+            supervisor.process_cmd(PieceRead {
+                piece_index,
+                data: data.into_boxed_slice(),
+            });
+        }
+
+        for _ in 0..2 {
+            let (piece, sum_metadata, piece_index) = match sha1_recv.try_recv() {
+                Ok(Sha1Task::Verify {
+                    piece,
+                    sum_metadata,
+                    piece_index,
+                    ..
+                }) => (piece, sum_metadata, piece_index),
+                _ => panic!("Expected a Verify task"),
+            };
+
+            supervisor.process_cmd(PieceVerified {
+                piece_index,
+                valid: compare_20_bytes(&sha1(&piece), &sum_metadata[..]),
+            });
+        }
+
+        assert!(supervisor.recheck.is_none());
+        assert!(!supervisor.bitfield.get_bit(0usize));
+        assert!(supervisor.bitfield.get_bit(1usize));
+        assert_eq!(supervisor.num_verified, 1);
+        assert_eq!(supervisor.piece_picker.state_count().missing, 1);
+
+        // Not complete anymore, the piece is downloaded again
+        assert!(!*supervisor.completion_recv.borrow());
+        assert_eq!(
+            events_recv.try_recv(),
+            Ok(TorrentEvent::VerificationFailed {
+                info_hash: Arc::clone(&supervisor.metadata.info_hash),
+                pieces: vec![0.into()].into_boxed_slice(),
+            })
+        );
+        assert!(events_recv.try_recv().is_err());
+    }
+
+    #[test]
+    fn read_only() {
+        let (sha1_workers, sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let options = TorrentOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let mut supervisor = TorrentSupervisor::new(torrent(10), options, sha1_workers, fs);
+
+        // Nothing to download, the pieces are seeded once they are checked
+        assert!(supervisor.recheck_on_start);
+        let pieces = |supervisor: &mut TorrentSupervisor| {
+            let (respond, receiver) = crossbeam_channel::bounded(1);
+            supervisor.process_cmd(DebugPieces { respond });
+            receiver.recv().unwrap()
+        };
+        let debug = pieces(&mut supervisor);
+        assert_eq!(debug.verified, 0);
+        assert_eq!(debug.missing, 0);
+
+        supervisor.start_recheck(true);
+        for index in 0..10u32 {
+            supervisor.process_cmd(PieceVerified {
+                piece_index: index.into(),
+                valid: index != 3,
+            });
+        }
+
+        // The invalid piece isn't downloaded
+        let debug = pieces(&mut supervisor);
+        assert_eq!(&*debug.bitfield, &[0b1110_1111, 0b1100_0000]);
+        assert_eq!(debug.verified, 9);
+        assert_eq!(debug.missing, 0);
+
+        supervisor.process_cmd(AddBlock {
+            id: PeerId::new(0),
+            block: Block::from((0.into(), 0.into(), &[0; 1000][..])),
+        });
+        assert!(sha1_recv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn inject_peer() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let options = TorrentOptions {
+            disable_trackers: true,
+            ..Default::default()
+        };
+        let mut supervisor = TorrentSupervisor::new(torrent(10), options, sha1_workers, fs);
+        let supervisor_addr = supervisor.addr();
+
+        tokio::spawn(async move { supervisor.start().await });
+
+        supervisor_addr
+            .send(PeerDiscovered {
+                addrs: vec![addr].into_boxed_slice(),
+                origin: PeerOrigin::Manual,
+            })
+            .await
+            .unwrap();
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+
+        let mut handshake = [0; 68];
+        socket.read_exact(&mut handshake).await.unwrap();
+
+        assert_eq!(handshake[0], 19);
+        assert_eq!(&handshake[1..20], b"BitTorrent protocol");
+        assert_eq!(&handshake[28..48], &[7; 20]);
+
+        handshake[48..].copy_from_slice(b"-MK0001-123456789012");
+        socket.write_all(&handshake).await.unwrap();
+
+        // Once the handshake is done, the peer is known by the supervisor,
+        // which gives it pieces to download from our bitfield
+        socket
+            .write_all(&[0, 0, 0, 3, 5, 0xFF, 0b1100_0000])
+            .await
+            .unwrap();
+
+        // We have no piece, so no BITFIELD is sent
+        let mut interested = [0; 5];
+        socket.read_exact(&mut interested).await.unwrap();
+
+        assert_eq!(interested, [0, 0, 0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn bitfield_first_message() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let options = TorrentOptions {
+            disable_trackers: true,
+            ..Default::default()
+        };
+        let mut supervisor = TorrentSupervisor::new(torrent(10), options, sha1_workers, fs);
+        for piece_index in &[0, 9] {
+            supervisor.process_cmd(ValidatePiece {
+                piece_index: (*piece_index).into(),
+                valid: true,
+            });
+        }
+        let supervisor_addr = supervisor.addr();
+
+        tokio::spawn(async move { supervisor.start().await });
+
+        supervisor_addr
+            .send(PeerDiscovered {
+                addrs: vec![addr].into_boxed_slice(),
+                origin: PeerOrigin::Manual,
+            })
+            .await
+            .unwrap();
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+
+        let mut handshake = [0; 68];
+        socket.read_exact(&mut handshake).await.unwrap();
+        handshake[48..].copy_from_slice(b"-MK0001-123456789012");
+        socket.write_all(&handshake).await.unwrap();
+
+        let mut bitfield = [0; 7];
+        socket.read_exact(&mut bitfield).await.unwrap();
+
+        assert_eq!(bitfield, [0, 0, 0, 3, 5, 0b1000_0000, 0b0100_0000]);
+    }
+
+    #[tokio::test]
+    async fn dial_ramp() {
+        use std::sync::atomic::Ordering::Relaxed;
+
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(10), TorrentOptions::default(), sha1_workers, fs);
+
+        let addrs: Vec<_> = (0..30)
+            .map(|i| format!("127.0.0.1:{}", 7000 + i).parse().unwrap())
+            .collect();
+
+        // An announce with 30 peers, sent twice
+        for _ in 0..2 {
+            supervisor.process_cmd(PeerDiscovered {
+                addrs: addrs.clone().into_boxed_slice(),
+                origin: PeerOrigin::Tracker,
+            });
+        }
+
+        // Nothing is dialed on the announce
+        assert_eq!(supervisor.dial_queue.len(), 30);
+        assert_eq!(supervisor.half_open.load(Relaxed), 0);
+
+        supervisor.dial_queued();
+
+        let dialed = 30 - supervisor.dial_queue.len();
+        assert!((1..=MAX_DIALS_PER_TICK).contains(&dialed));
+        assert_eq!(supervisor.half_open.load(Relaxed), dialed);
+        assert_eq!(supervisor.dial_queue.front(), Some(&addrs[dialed]));
+
+        // Too many connections in progress
+        supervisor.half_open.store(MAX_HALF_OPEN, Relaxed);
+        supervisor.dial_queued();
+
+        assert_eq!(30 - supervisor.dial_queue.len(), dialed);
+    }
+
+    fn new_peer(
+        id: usize,
+        extern_id: &[u8],
+        outbound: bool,
+    ) -> (Box<NewPeer>, async_channel::Receiver<PeerCommand>) {
+        let (queue, _) = crate::spsc::bounded(16);
+        let (addr, recv) = async_channel::unbounded();
+        let socket = format!("127.0.0.{}:6000", id).parse().unwrap();
+
+        let peer = Box::new(NewPeer {
+            id: PeerId::new(id),
+            queue,
+            addr,
+            extern_id: Arc::new(PeerExternId::new(extern_id)),
+            shared: Arc::new(Shared::new(socket)),
+            outbound,
+        });
+
+        (peer, recv)
+    }
+
+    #[test]
+    fn ban_bad_peer() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(10), TorrentOptions::default(), sha1_workers, fs);
+
+        let (bad, bad_recv) = new_peer(1, b"-ZZ0001-000000000001", true);
+        let (good, good_recv) = new_peer(2, b"-ZZ0001-000000000002", true);
+        let (bad_id, good_id) = (bad.id, good.id);
+        let (bad_ip, good_ip) = (bad.shared.socket.ip(), good.shared.socket.ip());
+
+        supervisor.process_cmd(AddPeer { peer: bad });
+        supervisor.process_cmd(AddPeer { peer: good });
+
+        let block = |id, piece: u32, index: u32| AddBlock {
+            id,
+            block: Block {
+                piece_index: piece.into(),
+                index: index.into(),
+                block: vec![0; 500].into_boxed_slice(),
+            },
+        };
+
+        for piece in 0..3 {
+            // Endgame: both peers send the first block, the one
+            // from the bad peer is received last and kept
+            supervisor.process_cmd(block(good_id, piece, 0));
+            supervisor.process_cmd(block(bad_id, piece, 0));
+            supervisor.process_cmd(block(bad_id, piece, 500));
+
+            assert!(!supervisor.banned.contains(&bad_ip));
+
+            supervisor.process_cmd(ValidatePiece {
+                piece_index: piece.into(),
+                valid: false,
+            });
+        }
+
+        assert!(supervisor.banned.contains(&bad_ip));
+        assert!(!supervisor.banned.contains(&good_ip));
+        assert_eq!(supervisor.hash_failures.get(&good_ip), None);
+
+        assert!(!supervisor.peers.contains_key(&bad_id));
+        assert!(supervisor.peers.contains_key(&good_id));
+        assert!(std::iter::from_fn(|| bad_recv.try_recv().ok())
+            .any(|cmd| matches!(cmd, PeerCommand::Die)));
+        assert!(!std::iter::from_fn(|| good_recv.try_recv().ok())
+            .any(|cmd| matches!(cmd, PeerCommand::Die)));
+
+        // The banned peer can't connect again
+        let (again, again_recv) = new_peer(1, b"-ZZ0001-000000000003", false);
+        supervisor.process_cmd(AddPeer { peer: again });
+
+        assert_eq!(supervisor.peers.len(), 1);
+        assert!(matches!(again_recv.try_recv(), Ok(PeerCommand::Die)));
+    }
+
+    #[test]
+    fn drop_lowest_score() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let options = TorrentOptions {
+            max_peers: 3,
+            ..Default::default()
+        };
+        let mut supervisor = TorrentSupervisor::new(torrent(10), options, sha1_workers, fs);
+
+        let mut receivers = Vec::new();
+        for id in 1..=3 {
+            let extern_id = format!("-ZZ0001-00000000000{}", id);
+            let (peer, recv) = new_peer(id, extern_id.as_bytes(), true);
+            supervisor.process_cmd(AddPeer { peer });
+            receivers.push(recv);
+        }
+
+        let set_peer =
+            |supervisor: &mut TorrentSupervisor, id, bitfield: &[u8], received, failed| {
+                let peer = supervisor.peers.get_mut(&PeerId::new(id)).unwrap();
+                peer.bitfield = BitField::from_bytes(bitfield, 10).unwrap();
+                peer.blocks_received = received;
+                peer.blocks_failed = failed;
+                // Past the grace period
+                peer.connected_at =
+                    coarsetime::Instant::now() - coarsetime::Duration::from_secs(60);
+            };
+
+        // 1: has all the pieces and sent many blocks
+        // 2: has nothing and timed out a lot
+        // 3: has half of the pieces
+        set_peer(&mut supervisor, 1, &[0xFF, 0xC0], 50, 0);
+        set_peer(&mut supervisor, 2, &[0x00, 0x00], 0, 20);
+        set_peer(&mut supervisor, 3, &[0xF8, 0x00], 0, 0);
+
+        let score = |supervisor: &TorrentSupervisor, id| {
+            let peer = &supervisor.peers[&PeerId::new(id)];
+            supervisor.peer_score(peer).value()
+        };
+        let mut ranking: Vec<_> = (1..=3).collect();
+        ranking.sort_by(|a, b| {
+            score(&supervisor, *b)
+                .partial_cmp(&score(&supervisor, *a))
+                .unwrap()
+        });
+        assert_eq!(ranking, vec![1, 3, 2]);
+        assert_eq!(
+            supervisor
+                .peer_score(&supervisor.peers[&PeerId::new(3)])
+                .needed_pieces,
+            5
+        );
+
+        // A 4th peer, unknown yet: the worst one makes room
+        let (peer, recv) = new_peer(4, b"-ZZ0001-000000000004", false);
+        supervisor.process_cmd(AddPeer { peer });
+        receivers.push(recv);
+
+        assert_eq!(supervisor.peers.len(), 3);
+        assert!(!supervisor.peers.contains_key(&PeerId::new(2)));
+        for (index, recv) in receivers.iter().enumerate() {
+            let died = std::iter::from_fn(|| recv.try_recv().ok())
+                .any(|cmd| matches!(cmd, PeerCommand::Die));
+            assert_eq!(died, index == 1);
+        }
+
+        // The dropped peer is dialed after the unknown ones. No slot
+        // is free, nothing is dialed
+        let dropped: std::net::SocketAddr = "127.0.0.2:6000".parse().unwrap();
+        let unknown: std::net::SocketAddr = "127.0.0.9:6000".parse().unwrap();
+        supervisor.process_cmd(PeerDiscovered {
+            addrs: vec![dropped, unknown].into_boxed_slice(),
+            origin: PeerOrigin::Tracker,
+        });
+        supervisor.dial_queued();
+
+        assert_eq!(supervisor.dial_queue, vec![unknown, dropped]);
+
+        // Without a bitfield yet, the new peers would be the worst ones.
+        // They are kept during their grace period
+        set_peer(&mut supervisor, 3, &[0xF8, 0x00], 1, 3);
+        let (peer, _recv) = new_peer(5, b"-ZZ0001-000000000005", false);
+        supervisor.process_cmd(AddPeer { peer });
+
+        assert_eq!(supervisor.peers.len(), 3);
+        assert!(!supervisor.peers.contains_key(&PeerId::new(3)));
+        assert!(supervisor.peers.contains_key(&PeerId::new(4)));
+        assert!(supervisor.peers.contains_key(&PeerId::new(5)));
+    }
+
+    #[test]
+    fn forget_known_peers() {
+        use std::net::SocketAddr;
+
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(10), TorrentOptions::default(), sha1_workers, fs);
+
+        let addr = |index: usize| -> SocketAddr {
+            format!("10.0.{}.{}:6000", index / 256, index % 256)
+                .parse()
+                .unwrap()
+        };
+        let addrs: Vec<_> = (0..super::MAX_KNOWN_PEERS).map(addr).collect();
+        supervisor.process_cmd(PeerDiscovered {
+            addrs: addrs.into_boxed_slice(),
+            origin: PeerOrigin::Tracker,
+        });
+        supervisor.peer_scores.insert(addr(10), 0.01);
+        supervisor.peer_scores.insert(addr(20), 0.9);
+
+        // The worst one makes room, with its score
+        let new = addr(super::MAX_KNOWN_PEERS);
+        supervisor.process_cmd(PeerDiscovered {
+            addrs: vec![new].into_boxed_slice(),
+            origin: PeerOrigin::Pex,
+        });
+
+        assert_eq!(supervisor.known_peers.len(), super::MAX_KNOWN_PEERS);
+        assert!(supervisor.known_peers.contains_key(&new));
+        assert!(!supervisor.known_peers.contains_key(&addr(10)));
+        assert_eq!(supervisor.peer_scores.len(), 1);
+        assert!(supervisor.peer_scores.contains_key(&addr(20)));
+    }
+
+    #[test]
+    fn replace_dropped_peer() {
+        use std::net::SocketAddr;
+
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let options = TorrentOptions {
+            max_peers: 2,
+            ..Default::default()
+        };
+        let mut supervisor = TorrentSupervisor::new(torrent(10), options, sha1_workers, fs);
+
+        let addrs: Vec<SocketAddr> = [
+            "127.0.0.1:6000",
+            "127.0.0.2:6000",
+            "10.0.0.8:6000",
+            "10.0.0.9:6000",
+        ]
+        .iter()
+        .map(|a| a.parse().unwrap())
+        .collect();
+        supervisor.process_cmd(PeerDiscovered {
+            addrs: addrs.clone().into_boxed_slice(),
+            origin: PeerOrigin::Tracker,
+        });
+        supervisor.dial_queue.clear();
+        supervisor.peer_scores.insert(addrs[3], 0.9);
+
+        for id in 1..=2 {
+            let extern_id = format!("-ZZ0001-00000000000{}", id);
+            let (peer, _) = new_peer(id, extern_id.as_bytes(), true);
+            supervisor.process_cmd(AddPeer { peer });
+        }
+
+        // The best peer not connected takes the place of the dropped one
+        supervisor.process_cmd(RemovePeer { id: PeerId::new(1) });
+
+        assert_eq!(supervisor.peers.len(), 1);
+        assert_eq!(supervisor.dial_queue, vec![addrs[3]]);
+
+        // Already removed: not replaced twice
+        supervisor.process_cmd(RemovePeer { id: PeerId::new(1) });
+        assert_eq!(supervisor.dial_queue, vec![addrs[3]]);
+    }
+
+    #[test]
+    fn drop_seed_to_seed() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let options = TorrentOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let mut supervisor = TorrentSupervisor::new(torrent(10), options, sha1_workers, fs);
+
+        supervisor.start_recheck(true);
+        for index in 0..10u32 {
+            supervisor.process_cmd(PieceVerified {
+                piece_index: index.into(),
+                valid: true,
+            });
+        }
+
+        let (seed, seed_recv) = new_peer(1, b"-ZZ0001-000000000001", true);
+        let (leecher, leecher_recv) = new_peer(2, b"-ZZ0001-000000000002", true);
+        let (seed_id, leecher_id) = (seed.id, leecher.id);
+
+        supervisor.process_cmd(AddPeer { peer: seed });
+        supervisor.process_cmd(AddPeer { peer: leecher });
+
+        let all_pieces = BitField::try_from((&[0xFF, 0xC0][..], 10)).unwrap();
+        supervisor.process_cmd(UpdateBitfield {
+            id: seed_id,
+            update: Box::new(BitFieldUpdate::from(all_pieces)),
+        });
+        supervisor.process_cmd(UpdateBitfield {
+            id: leecher_id,
+            update: Box::new(BitFieldUpdate::from(3u32)),
+        });
+
+        // Both ends are complete, the slot is freed
+        assert!(matches!(seed_recv.try_recv(), Ok(PeerCommand::Die)));
+        assert!(!supervisor.peers.contains_key(&seed_id));
+
+        while let Ok(cmd) = leecher_recv.try_recv() {
+            assert!(!matches!(cmd, PeerCommand::Die));
+        }
+        assert!(supervisor.peers.contains_key(&leecher_id));
+    }
+
+    #[test]
+    fn suspect_piece() {
+        let (sha1_workers, sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(10), TorrentOptions::default(), sha1_workers, fs);
+
+        let all_pieces = || {
+            let bitfield = BitField::try_from((&[0xFF, 0xC0][..], 10)).unwrap();
+            Box::new(BitFieldUpdate::from(bitfield))
+        };
+
+        let mut slow_tasks = Vec::new();
+
+        // Each slow peer is working on another piece when its
+        // requests of the piece 0 time out
+        for id in 1..=MAX_ASSEMBLY_FAILURES {
+            let extern_id = format!("-ZZ0001-00000000000{}", id);
+            let (mut peer, _) = new_peer(id, extern_id.as_bytes(), true);
+            let (queue, tasks) = spsc::bounded(16);
+            peer.queue = queue;
+            slow_tasks.push(tasks);
+            let peer_id = peer.id;
+
+            supervisor.process_cmd(AddPeer { peer });
+            supervisor.process_cmd(UpdateBitfield {
+                id: peer_id,
+                update: all_pieces(),
+            });
+
+            assert!(!supervisor.piece_picker.is_suspect(0.into()));
+
+            for _ in 0..2 {
+                supervisor.process_cmd(PieceTimeout {
+                    id: peer_id,
+                    piece_index: 0.into(),
+                });
+            }
+        }
+
+        assert!(supervisor.piece_picker.is_suspect(0.into()));
+
+        // A good peer coming after is given the suspect piece first
+        let (mut good, _good_recv) = new_peer(9, b"-ZZ0001-000000000009", true);
+        let (queue, mut tasks) = spsc::bounded(16);
+        good.queue = queue;
+        let good_id = good.id;
+
+        supervisor.process_cmd(AddPeer { peer: good });
+        supervisor.process_cmd(UpdateBitfield {
+            id: good_id,
+            update: all_pieces(),
+        });
+
+        assert_eq!(
+            tasks.pop().ok(),
+            Some(TaskDownload::Piece {
+                piece_index: 0.into()
+            })
+        );
+
+        for index in &[0, 500] {
+            supervisor.process_cmd(AddBlock {
+                id: good_id,
+                block: Block {
+                    piece_index: 0.into(),
+                    index: (*index).into(),
+                    block: vec![0; 500].into_boxed_slice(),
+                },
+            });
+        }
+
+        // The piece is still verified before being accepted
+        assert!(matches!(
+            sha1_recv.try_recv(),
+            Ok(Sha1Task::CheckSum { piece_index, .. }) if piece_index == 0.into()
+        ));
+
+        supervisor.process_cmd(ValidatePiece {
+            piece_index: 0.into(),
+            valid: true,
+        });
+
+        assert!(supervisor.bitfield.get_bit(0usize));
+        assert!(!supervisor.piece_picker.is_suspect(0.into()));
+        assert!(supervisor.assembly_failures.is_empty());
+    }
+
+    #[test]
+    fn suspect_piece_corrupted() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(10), TorrentOptions::default(), sha1_workers, fs);
+
+        let mut slow_tasks = Vec::new();
+
+        for id in 1..=MAX_ASSEMBLY_FAILURES {
+            let extern_id = format!("-ZZ0001-00000000000{}", id);
+            let (mut peer, _) = new_peer(id, extern_id.as_bytes(), true);
+            let (queue, tasks) = spsc::bounded(16);
+            peer.queue = queue;
+            slow_tasks.push(tasks);
+            let peer_id = peer.id;
+
+            let bitfield = BitField::try_from((&[0xFF, 0xC0][..], 10)).unwrap();
+            supervisor.process_cmd(AddPeer { peer });
+            supervisor.process_cmd(UpdateBitfield {
+                id: peer_id,
+                update: Box::new(BitFieldUpdate::from(bitfield)),
+            });
+            supervisor.process_cmd(PieceTimeout {
+                id: peer_id,
+                piece_index: 0.into(),
+            });
+        }
+
+        assert!(supervisor.piece_picker.is_suspect(0.into()));
+
+        supervisor.process_cmd(ValidatePiece {
+            piece_index: 0.into(),
+            valid: false,
+        });
+
+        // The piece is downloaded again, without being suspect
+        assert!(!supervisor.bitfield.get_bit(0usize));
+        assert!(!supervisor.piece_picker.is_suspect(0.into()));
+        assert!(supervisor.assembly_failures.is_empty());
+    }
+
+    #[test]
+    fn progress() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let mut torrent = torrent(3);
+        // The last piece has 500 bytes
+        torrent.meta.info.files = Single {
+            name: "progress".to_string(),
+            name_utf8: None,
+            length: 2500,
+            md5sum: None,
+        };
+        let mut supervisor =
+            TorrentSupervisor::new(torrent, TorrentOptions::default(), sha1_workers, fs);
+
+        let status = |gauges: &TorrentGauges| {
+            let status = gauges.status(Arc::new([]), Default::default());
+            (status.downloaded_bytes, status.verified_pieces)
+        };
+
+        let block = |piece: u32, index: u32| AddBlock {
+            id: PeerId::new(1),
+            block: Block {
+                piece_index: piece.into(),
+                index: index.into(),
+                block: vec![0; 250].into_boxed_slice(),
+            },
+        };
+
+        supervisor.process_cmd(block(0, 0));
+        supervisor.process_cmd(block(2, 0));
+        supervisor.update_progress();
+        assert_eq!(status(&supervisor.gauges), (500, 0));
+
+        // Complete the pieces, they are verified
+        for (piece, index) in &[(0, 250), (0, 500), (0, 750), (2, 250)] {
+            supervisor.process_cmd(block(*piece, *index));
+        }
+        for piece_index in &[0, 2] {
+            supervisor.process_cmd(ValidatePiece {
+                piece_index: (*piece_index).into(),
+                valid: true,
+            });
+        }
+        supervisor.process_cmd(block(1, 0));
+        supervisor.update_gauges();
+        supervisor.update_progress();
+        assert_eq!(status(&supervisor.gauges), (1000 + 500 + 250, 2));
+
+        let status = supervisor.gauges.status(Arc::new([]), Default::default());
+        assert_eq!((status.total_bytes, status.total_pieces), (2500, 3));
+    }
+
+    #[test]
+    fn subscribe_pieces() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(10), TorrentOptions::default(), sha1_workers, fs);
+
+        let (sender, pieces) = async_channel::unbounded();
+        supervisor.process_cmd(SubscribePieces { sender });
+
+        for (piece_index, valid) in &[(4, true), (2, false), (7, true)] {
+            supervisor.process_cmd(ValidatePiece {
+                piece_index: (*piece_index).into(),
+                valid: *valid,
+            });
+        }
+
+        assert_eq!(pieces.try_recv(), Ok(PieceEvent::PieceVerified(4.into())));
+        assert_eq!(pieces.try_recv(), Ok(PieceEvent::PieceFailed(2.into())));
+        assert_eq!(pieces.try_recv(), Ok(PieceEvent::PieceVerified(7.into())));
+        assert!(pieces.try_recv().is_err());
+
+        // The subscriber is removed once it drops its receiver
+        drop(pieces);
+        supervisor.process_cmd(ValidatePiece {
+            piece_index: 5.into(),
+            valid: true,
+        });
+        assert!(supervisor.piece_subscribers.is_empty());
+    }
+
+    #[test]
+    fn torrent_files() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        // 4 pieces of 1000 bytes: "b" covers the pieces 0 to 2
+        let mut multi = torrent(4);
+        multi.meta.info.files = Multiple {
+            name: "dir".to_string(),
+            name_utf8: None,
+            files: [
+                (&["a"][..], 500),
+                (&["sub", "b"][..], 2000),
+                (&["c"][..], 1500),
+            ]
+            .iter()
+            .map(|(path, length)| MetaFile {
+                length: *length,
+                md5sum: None,
+                path: path.iter().map(|p| p.to_string()).collect(),
+                path_utf8: None,
+            })
+            .collect(),
+        };
+
+        let mut supervisor =
+            TorrentSupervisor::new(multi, TorrentOptions::default(), sha1_workers, fs);
+
+        for piece_index in &[0, 1, 3] {
+            supervisor.process_cmd(ValidatePiece {
+                piece_index: (*piece_index).into(),
+                valid: true,
+            });
+        }
+
+        let (respond, files) = crossbeam_channel::bounded(1);
+        supervisor.process_cmd(TorrentFiles { respond });
+        let files = files.try_recv().unwrap();
+
+        let paths: Vec<_> = files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(
+            paths,
+            &[
+                PathBuf::from("dir/a"),
+                PathBuf::from("dir/sub/b"),
+                PathBuf::from("dir/c"),
+            ]
+        );
+
+        let sizes: Vec<_> = files.iter().map(|f| (f.length, f.downloaded)).collect();
+        assert_eq!(sizes, &[(500, 500), (2000, 1500), (1500, 1000)]);
+        assert!(files[0].is_complete());
+        assert!(!files[1].is_complete());
+
+        // A single-file torrent has 1 entry
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(2), TorrentOptions::default(), sha1_workers, fs);
+
+        let (respond, files) = crossbeam_channel::bounded(1);
+        supervisor.process_cmd(TorrentFiles { respond });
+
+        assert_eq!(
+            files.try_recv().unwrap(),
+            &[FileProgress {
+                path: PathBuf::from("a"),
+                length: 2000,
+                downloaded: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn choked_by_all() {
+        use std::{net::SocketAddr, sync::atomic::Ordering::Relaxed};
+
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(10), TorrentOptions::default(), sha1_workers, fs);
+
+        let addrs: Vec<SocketAddr> = (1..=4)
+            .map(|i| format!("10.0.0.{}:6000", i).parse().unwrap())
+            .collect();
+        supervisor.process_cmd(PeerDiscovered {
+            addrs: addrs.clone().into_boxed_slice(),
+            origin: PeerOrigin::Tracker,
+        });
+        // All dialed, none of them answered
+        supervisor.dial_queue.clear();
+
+        let mut peers = Vec::new();
+        for id in 1..=2 {
+            let extern_id = format!("-ZZ0001-00000000000{}", id);
+            let (mut peer, recv) = new_peer(id, extern_id.as_bytes(), true);
+            let (queue, tasks) = spsc::bounded(16);
+            peer.queue = queue;
+            let (peer_id, shared) = (peer.id, Arc::clone(&peer.shared));
+
+            supervisor.process_cmd(AddPeer { peer });
+            supervisor.process_cmd(UpdateBitfield {
+                id: peer_id,
+                update: Box::new(BitFieldUpdate::from(3u32)),
+            });
+            peers.push((shared, recv, tasks));
+        }
+
+        // A peer unchokes us
+        peers[0].0.am_choked.store(false, Relaxed);
+        supervisor.check_choked();
+
+        assert!(!supervisor.pieces_debug().choked_by_all);
+        assert!(supervisor.dial_queue.is_empty());
+
+        // Now all peers choke us
+        peers[0].0.am_choked.store(true, Relaxed);
+        supervisor.check_choked();
+
+        assert!(supervisor.pieces_debug().choked_by_all);
+        assert_eq!(supervisor.dial_queue.len(), addrs.len());
+        for (_, recv, _) in &peers {
+            assert!(std::iter::from_fn(|| recv.try_recv().ok())
+                .any(|cmd| matches!(cmd, PeerCommand::Interested)));
+        }
+
+        peers[1].0.am_choked.store(false, Relaxed);
+        supervisor.check_choked();
+
+        assert!(!supervisor.pieces_debug().choked_by_all);
+    }
+
+    #[test]
+    fn connection_stats() {
+        use std::sync::atomic::Ordering::Relaxed;
+
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(10), TorrentOptions::default(), sha1_workers, fs);
+
+        let addrs = |ids: &[u8]| {
+            ids.iter()
+                .map(|id| format!("127.0.0.{}:6000", id).parse().unwrap())
+                .collect::<Vec<_>>()
+                .into_boxed_slice()
+        };
+        supervisor.process_cmd(PeerDiscovered {
+            addrs: addrs(&[1, 2]),
+            origin: PeerOrigin::Tracker,
+        });
+        supervisor.process_cmd(PeerDiscovered {
+            addrs: addrs(&[3]),
+            origin: PeerOrigin::Manual,
+        });
+
+        let mut receivers = Vec::new();
+        for id in 1..=4 {
+            let extern_id = format!("-ZZ0001-00000000000{}", id);
+            let (peer, recv) = new_peer(id, extern_id.as_bytes(), true);
+            supervisor.process_cmd(AddPeer { peer });
+            receivers.push(recv);
+        }
+        supervisor.peer_errors.store(2, Relaxed);
+
+        let output = crate::logger::capture(|| supervisor.log_stats());
+        let line = output
+            .lines()
+            .find(|line| line.contains("Connections"))
+            .expect("No summary line");
+
+        for expected in &[
+            "peers: 4",
+            "tracker: 2",
+            "manual: 1",
+            "pex: 0",
+            "unknown: 1",
+            "errors: 2",
+        ] {
+            assert!(line.contains(expected), "{:?} not in {:?}", expected, line);
+        }
+    }
+
+    #[test]
+    fn simultaneous_open() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(10), TorrentOptions::default(), sha1_workers, fs);
+
+        // Our id (-RR..) is lower, the connection we dialed survives
+        let (inbound, inbound_recv) = new_peer(1, b"-ZZ0001-000000000000", false);
+        let (outbound, outbound_recv) = new_peer(2, b"-ZZ0001-000000000000", true);
+
+        supervisor.process_cmd(AddPeer { peer: inbound });
+        supervisor.process_cmd(AddPeer { peer: outbound });
+
+        assert_eq!(supervisor.peers.len(), 1);
+        assert!(supervisor.peers.contains_key(&PeerId::new(2)));
+        assert!(matches!(inbound_recv.try_recv(), Ok(PeerCommand::Die)));
+        assert!(outbound_recv.try_recv().is_err());
+
+        // The peer id is lower, the connection it dialed survives
+        let (inbound, inbound_recv) = new_peer(3, b"-AA0001-000000000000", false);
+        let (outbound, outbound_recv) = new_peer(4, b"-AA0001-000000000000", true);
+
+        supervisor.process_cmd(AddPeer { peer: outbound });
+        supervisor.process_cmd(AddPeer { peer: inbound });
+
+        assert_eq!(supervisor.peers.len(), 2);
+        assert!(supervisor.peers.contains_key(&PeerId::new(3)));
+        assert!(matches!(outbound_recv.try_recv(), Ok(PeerCommand::Die)));
+        assert!(inbound_recv.try_recv().is_err());
+
+        // Same direction: the existing connection is kept
+        let (inbound, inbound_recv) = new_peer(5, b"-AA0001-000000000000", false);
+
+        supervisor.process_cmd(AddPeer { peer: inbound });
+
+        assert_eq!(supervisor.peers.len(), 2);
+        assert!(supervisor.peers.contains_key(&PeerId::new(3)));
+        assert!(matches!(inbound_recv.try_recv(), Ok(PeerCommand::Die)));
+    }
+
+    #[test]
+    fn disk_full() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, _fs_recv) = async_channel::bounded(10);
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(10), TorrentOptions::default(), sha1_workers, fs);
+
+        let (peer, _peer_recv) = new_peer(1, b"-ZZ0001-000000000001", false);
+        supervisor.process_cmd(AddPeer { peer });
+        supervisor.process_cmd(ValidatePiece {
+            piece_index: 2.into(),
+            valid: true,
+        });
+        assert_eq!(supervisor.num_verified, 1);
+
+        // The FS reports the disk is almost full
+        supervisor.process_cmd(WriteRejected {
+            piece_index: 2.into(),
+        });
+        assert!(supervisor.is_paused());
+        assert!(supervisor.peers.is_empty());
+        assert!(!supervisor.bitfield.get_bit(2usize));
+        assert_eq!(supervisor.num_verified, 0);
+
+        // Pieces already on their way to the disk
+        supervisor.process_cmd(WriteRejected {
+            piece_index: 5.into(),
+        });
+        assert!(supervisor.is_paused());
+
+        supervisor.process_cmd(DiskSpaceAvailable);
+        assert!(!supervisor.is_paused());
+        assert!(!supervisor.disk_full);
+    }
+
+    #[test]
+    fn allocation_failed() {
+        let (sha1_workers, _sha1_recv) = crossbeam_channel::bounded(10);
+        let (fs, fs_recv) = async_channel::bounded(10);
+
+        let mut supervisor =
+            TorrentSupervisor::new(torrent(10), TorrentOptions::default(), sha1_workers, fs);
+        let (events, events_recv) = crossbeam_channel::unbounded();
+        supervisor.set_events(events);
+
+        supervisor.process_cmd(AllocationFailed {
+            needed: 10_000,
+            available: 4_000,
+        });
+
+        assert!(supervisor.is_paused());
+        assert_eq!(
+            events_recv.try_recv(),
+            Ok(TorrentEvent::AllocationFailed {
+                info_hash: Arc::new([7; 20]),
+                needed: 10_000,
+                available: 4_000,
+            })
+        );
+
+        // The files are allocated again on resume
+        supervisor.process_cmd(Resume);
+        assert!(!supervisor.is_paused());
+        assert!(matches!(
+            fs_recv.try_recv(),
+            Ok(FSMessage::Allocate { id }) if id == supervisor.id
+        ));
+
+        supervisor.process_cmd(Pause);
+        supervisor.process_cmd(Resume);
+        assert!(fs_recv.try_recv().is_err());
     }
-}
 
-#[cfg(test)]
-mod tests {
     #[test]
     #[cfg(target_pointer_width = "64")]
     fn assert_message_size() {
@@ -1,4 +1,5 @@
 use async_channel::{bounded, Receiver, Sender};
+use tokio::{sync::watch, task::JoinHandle};
 use url::Url;
 
 use std::{
@@ -7,8 +8,11 @@ use std::{
 };
 
 use crate::{
-    actors::tracker::Tracker, errors::TorrentError, metadata::Torrent, peer::peer::PeerExternId,
-    supervisors::torrent::TorrentNotification,
+    actors::tracker::{batch::AnnounceBatcher, Tracker},
+    errors::TorrentError,
+    metadata::Torrent,
+    peer::peer::PeerExternId,
+    supervisors::torrent::{ByteCounters, TorrentGauges, TorrentNotification},
 };
 
 #[derive(Debug)]
@@ -23,6 +27,48 @@ pub struct TrackerData {
     pub supervisor: Sender<TorrentNotification>,
     pub url: Arc<TrackerUrl>,
     pub extern_id: Arc<PeerExternId>,
+    /// Payload bytes are reported to the tracker
+    pub counters: Arc<ByteCounters>,
+    /// The verified bytes, `left` is announced from them
+    pub gauges: Arc<TorrentGauges>,
+    /// Port accepting the peers
+    pub port: u16,
+    /// Group the HTTP announces with the other torrents of the session
+    pub batcher: Option<AnnounceBatcher>,
+    /// Whether the torrent is complete, a `Completed` event is announced
+    /// when it changes to `true`
+    pub completion: watch::Receiver<bool>,
+    /// `false` when `Completed` was announced before a restart, it's
+    /// not announced again
+    pub announce_completed: bool,
+    /// While `true`, the torrent is paused: `Stopped` is announced
+    /// and nothing else until it's resumed
+    pub paused: watch::Receiver<bool>,
+    /// Set to `true` when the torrent shuts down: `Stopped` is announced
+    /// and the tracker ends
+    pub shutdown: watch::Receiver<bool>,
+}
+
+/// Resolves once `shutdown` is `true`. Never resolves when its sender
+/// is dropped without shutting down
+pub(crate) async fn wait_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl TrackerData {
+    /// Bytes left to download. A read-only torrent has nothing to
+    /// download
+    pub fn left(&self) -> u64 {
+        if *self.completion.borrow() {
+            0
+        } else {
+            self.gauges.left()
+        }
+    }
 }
 
 impl From<(&TrackerSupervisor, &Arc<TrackerUrl>)> for TrackerData {
@@ -32,6 +78,14 @@ impl From<(&TrackerSupervisor, &Arc<TrackerUrl>)> for TrackerData {
             supervisor: tracker.supervisor.clone(),
             url: Arc::clone(url),
             extern_id: tracker.extern_id.clone(),
+            counters: Arc::clone(&tracker.counters),
+            gauges: Arc::clone(&tracker.gauges),
+            port: tracker.port,
+            batcher: tracker.batcher.clone(),
+            completion: tracker.completion.clone(),
+            announce_completed: tracker.announce_completed,
+            paused: tracker.paused.clone(),
+            shutdown: tracker.shutdown.clone(),
         }
     }
 }
@@ -71,13 +125,32 @@ pub struct TrackerSupervisor {
     tracker_states: Map<UrlHash, TrackerState>,
     /// Our peer_id we send to trackers
     extern_id: Arc<PeerExternId>,
+    counters: Arc<ByteCounters>,
+    gauges: Arc<TorrentGauges>,
+    port: u16,
+    batcher: Option<AnnounceBatcher>,
+    completion: watch::Receiver<bool>,
+    announce_completed: bool,
+    paused: watch::Receiver<bool>,
+    shutdown: watch::Receiver<bool>,
+    /// Trackers spawned, awaited on shutdown
+    trackers: Vec<JoinHandle<()>>,
 }
 
 impl TrackerSupervisor {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         supervisor: Sender<TorrentNotification>,
         metadata: Arc<Torrent>,
         extern_id: Arc<PeerExternId>,
+        counters: Arc<ByteCounters>,
+        gauges: Arc<TorrentGauges>,
+        port: u16,
+        batcher: Option<AnnounceBatcher>,
+        completion: watch::Receiver<bool>,
+        announce_completed: bool,
+        paused: watch::Receiver<bool>,
+        shutdown: watch::Receiver<bool>,
     ) -> TrackerSupervisor {
         let urls = metadata.get_urls_tiers();
         let (_sender, recv) = bounded(10);
@@ -88,6 +161,15 @@ impl TrackerSupervisor {
             recv,
             _sender,
             extern_id,
+            counters,
+            gauges,
+            port,
+            batcher,
+            completion,
+            announce_completed,
+            paused,
+            shutdown,
+            trackers: Vec::new(),
             tracker_states: Default::default(),
         }
     }
@@ -96,15 +178,38 @@ impl TrackerSupervisor {
         matches!(url.scheme(), "http" | "udp")
     }
 
+    /// Returns on shutdown, once all the trackers announced `Stopped`
     pub async fn start(mut self) {
-        self.loop_until_connected().await;
-        self.wait_on_tracker_msg().await
+        let mut shutdown = self.shutdown.clone();
+
+        tokio::select! {
+            _ = async {
+                self.loop_until_connected().await;
+                self.wait_on_tracker_msg().await
+            } => {}
+            _ = wait_shutdown(&mut shutdown) => {}
+        }
+
+        // The trackers report their last announce, keep receiving
+        let recv = self.recv.clone();
+        let drain = async move { while recv.recv().await.is_ok() {} };
+        let join = async {
+            for tracker in self.trackers.drain(..) {
+                tracker.await.ok();
+            }
+        };
+
+        tokio::select! {
+            _ = join => {}
+            _ = drain => {}
+        }
     }
 
     async fn loop_until_connected(&mut self) {
         let mut pending_status = Vec::with_capacity(10);
+        let urls = self.urls.clone();
 
-        for url in self.urls.as_slice() {
+        for url in &urls {
             self.spawn_tracker(url).await;
 
             // We wait 15 secs, if we aren't connected to this tracker
@@ -130,11 +235,12 @@ impl TrackerSupervisor {
         }
     }
 
-    async fn spawn_tracker(&self, url: &Arc<TrackerUrl>) {
-        let data = Arc::new(TrackerData::from((self, url)));
+    async fn spawn_tracker(&mut self, url: &Arc<TrackerUrl>) {
+        let data = Arc::new(TrackerData::from((&*self, url)));
         let sender = self._sender.clone();
 
-        tokio::spawn(async move { Tracker::new(data, sender).start().await });
+        let handle = tokio::spawn(async move { Tracker::new(data, sender).start().await });
+        self.trackers.push(handle);
     }
 
     fn update_state(&mut self, url: UrlHash, instant: Instant, msg: TrackerStatus) {
@@ -153,10 +259,11 @@ impl TrackerSupervisor {
         }
     }
 
-    async fn try_another_tracker(&self) {
+    async fn try_another_tracker(&mut self) {
         let spawned = { self.tracker_states.keys().copied().collect::<Vec<_>>() };
+        let urls = self.urls.clone();
 
-        for url in self.urls.as_slice() {
+        for url in &urls {
             if !spawned.contains(&url.hash()) {
                 self.spawn_tracker(url).await;
                 return;
@@ -69,6 +69,8 @@ where
     }
 }
 
+/// Compact format: 4 bytes of address and 2 bytes of port, in network
+/// order (big-endian)
 pub fn ipv4_from_slice(slice: &[u8], output: &mut Vec<SocketAddr>) {
     for chunk in slice.chunks_exact(6) {
         let mut cursor = Cursor::new(&chunk[..]);
@@ -78,6 +80,7 @@ pub fn ipv4_from_slice(slice: &[u8], output: &mut Vec<SocketAddr>) {
     }
 }
 
+/// Compact format: 16 bytes of address and 2 bytes of port
 pub fn ipv6_from_slice(slice: &[u8], output: &mut Vec<SocketAddr>) {
     for chunk in slice.chunks_exact(18) {
         let mut cursor = Cursor::new(chunk);
@@ -117,3 +120,37 @@ impl SaturatingDuration for coarsetime::Instant {
         coarsetime::Duration::from_u64(duration)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{ipv4_from_slice, ipv6_from_slice};
+
+    #[test]
+    fn compact_peers_big_endian() {
+        let mut addrs = Vec::new();
+
+        // The port 0x1AE1 is 6881, 57626 when read as little-endian.
+        // The trailing bytes of an incomplete peer are ignored
+        ipv4_from_slice(
+            &[0xC0, 0xA8, 0x00, 0x01, 0x1A, 0xE1, 0x7F, 0x00],
+            &mut addrs,
+        );
+        assert_eq!(
+            addrs,
+            vec!["192.168.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+
+        let mut blob = vec![0x20, 0x01, 0x0D, 0xB8];
+        blob.extend_from_slice(&[0; 11]);
+        blob.extend_from_slice(&[0x01, 0x1A, 0xE1]);
+
+        addrs.clear();
+        ipv6_from_slice(&blob, &mut addrs);
+        assert_eq!(
+            addrs,
+            vec!["[2001:db8::1]:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+}
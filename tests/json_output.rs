@@ -0,0 +1,147 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use rustorrent::bencode::de;
+
+#[test]
+fn json_describe() {
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/scripts/test_torrents/base.torrent"
+    );
+    let torrent = de::read_meta(&std::fs::read(path).unwrap()).unwrap();
+
+    // The direct peer disables the trackers, stdin is closed so
+    // the binary exits right after printing the metadata
+    let output = Command::new(env!("CARGO_BIN_EXE_main"))
+        .args(["--json", "--torrent", path, "--peer", "127.0.0.1:1"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "Exited with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let line = stdout.lines().next().expect("No output");
+    let json: serde_json::Value = serde_json::from_str(line).unwrap();
+
+    let info_hash: String = torrent
+        .info_hash
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    assert_eq!(json["info_hash"], info_hash.as_str());
+    assert_eq!(
+        json["files"].as_array().map(|f| f.len()),
+        Some(torrent.nfiles())
+    );
+    assert_eq!(json["num_pieces"], torrent.meta.info.pieces.len() / 20);
+}
+
+#[test]
+fn json_peer_handshake() {
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/scripts/test_torrents/base.torrent"
+    );
+    let torrent = de::read_meta(&std::fs::read(path).unwrap()).unwrap();
+
+    // The mock peer has the only piece of the torrent
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_main"))
+        .args(["--json", "--torrent", path, "--peer", &addr])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+    // The logs are on stdout too, between the json lines
+    let mut next_json = || loop {
+        let mut line = String::new();
+        assert!(stdout.read_line(&mut line).unwrap() > 0, "No output");
+        if line.starts_with('{') {
+            return serde_json::from_str::<serde_json::Value>(&line).unwrap();
+        }
+    };
+
+    // The description of the torrent comes first
+    assert_eq!(next_json()["num_pieces"], 1);
+
+    listener.set_nonblocking(true).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut socket = loop {
+        match listener.accept() {
+            Ok((socket, _)) => break socket,
+            Err(_) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
+            Err(e) => {
+                child.kill().ok();
+                panic!("The binary didn't connect to the peer: {}", e);
+            }
+        }
+    };
+    socket.set_nonblocking(false).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+
+    let mut handshake = [0; 68];
+    socket.read_exact(&mut handshake).unwrap();
+
+    assert_eq!(handshake[0], 19);
+    assert_eq!(&handshake[1..20], b"BitTorrent protocol");
+    assert_eq!(&handshake[28..48], &torrent.info_hash[..]);
+    assert_eq!(&handshake[48..56], b"-RR0001-");
+
+    let mut answer = Vec::new();
+    answer.extend_from_slice(&handshake[..20]);
+    answer.extend_from_slice(&[0; 8]);
+    answer.extend_from_slice(&torrent.info_hash[..]);
+    answer.extend_from_slice(b"-ZZ0001-000000000001");
+    // BITFIELD with the piece 0, and UNCHOKE
+    answer.extend_from_slice(&[0, 0, 0, 2, 5, 0x80]);
+    answer.extend_from_slice(&[0, 0, 0, 1, 1]);
+    socket.write_all(&answer).unwrap();
+
+    // The status printed for each line of stdin shows the piece
+    // requested to the peer once the handshake is done
+    let status = loop {
+        stdin.write_all(b"\n").unwrap();
+
+        let status = next_json();
+        if status["downloading"] == 1 || Instant::now() > deadline {
+            break status;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+
+    drop(stdin);
+    let exit = child.wait().unwrap();
+
+    let info_hash: String = torrent
+        .info_hash
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    assert_eq!(status["info_hash"], info_hash.as_str());
+    assert_eq!(status["num_pieces"], 1);
+    assert_eq!(status["downloading"], 1, "{}", status);
+    assert_eq!(status["choked_by_all"], false);
+    assert!(exit.success(), "Exited with {}", exit);
+}